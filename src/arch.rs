@@ -0,0 +1,138 @@
+//! Dispatches architecture specific behavior to the `cortexm`, `cortexa` and `riscv` modules
+
+use anyhow::bail;
+use gimli::Register;
+use object::{read::File as ElfFile, Object as _};
+use probe_rs::{Core, CoreRegisterAddress, MemoryInterface};
+
+use crate::{cortexa, cortexm, riscv};
+
+/// The instruction set architecture the firmware was compiled for
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Arch {
+    CortexM,
+    /// ARMv7-A/R, or an ARMv8 core in AArch32 state
+    CortexAR,
+    Aarch64,
+    Riscv32,
+}
+
+impl Arch {
+    pub fn from_elf(elf: &ElfFile) -> anyhow::Result<Self> {
+        match elf.architecture() {
+            // NOTE `cortex-m-rt` programs always have a vector table; A/R programs don't
+            object::Architecture::Arm if elf.section_by_name(".vector_table").is_some() => {
+                Ok(Arch::CortexM)
+            }
+            object::Architecture::Arm => Ok(Arch::CortexAR),
+            object::Architecture::Aarch64 => Ok(Arch::Aarch64),
+            object::Architecture::Riscv32 => Ok(Arch::Riscv32),
+            arch => bail!(
+                "unsupported architecture `{:?}`; only ARM Cortex-M/A/R, AArch64 and RV32 are \
+                supported",
+                arch
+            ),
+        }
+    }
+
+    /// Whether probe-run can unwind the stack (and check it for overflows) on this architecture
+    pub fn supports_backtrace(self) -> bool {
+        matches!(self, Arch::CortexM | Arch::Riscv32)
+    }
+
+    pub fn program_counter(self) -> CoreRegisterAddress {
+        match self {
+            Arch::CortexM => cortexm::PC,
+            Arch::CortexAR => cortexa::PC,
+            Arch::Aarch64 => cortexa::PC_AARCH64,
+            Arch::Riscv32 => riscv::PC,
+        }
+    }
+
+    pub fn stack_pointer(self) -> CoreRegisterAddress {
+        match self {
+            Arch::CortexM => cortexm::SP,
+            Arch::CortexAR => cortexa::SP,
+            Arch::Aarch64 => cortexa::SP_AARCH64,
+            Arch::Riscv32 => riscv::SP,
+        }
+    }
+
+    /// The register that holds the return address: LR on ARM, `ra` on RISC-V
+    pub fn return_address(self) -> CoreRegisterAddress {
+        match self {
+            Arch::CortexM => cortexm::LR,
+            Arch::CortexAR => cortexa::LR,
+            Arch::Aarch64 => cortexa::X30,
+            Arch::Riscv32 => riscv::RA,
+        }
+    }
+
+    /// The return address value that marks the outermost stack frame
+    pub fn end_of_stack(self) -> u32 {
+        match self {
+            Arch::CortexM => cortexm::LR_END,
+            // NOTE not unwound (yet); see `supports_backtrace`
+            Arch::CortexAR | Arch::Aarch64 => 0,
+            Arch::Riscv32 => riscv::RA_END,
+        }
+    }
+
+    /// Maps a DWARF register number to the address probe-rs uses for that register
+    pub fn dwarf_register(self, reg: Register) -> CoreRegisterAddress {
+        match self {
+            Arch::CortexM | Arch::CortexAR | Arch::Aarch64 => CoreRegisterAddress(reg.0),
+            Arch::Riscv32 => riscv::dwarf_register(reg.0),
+        }
+    }
+
+    /// Makes the halted `core` start the program at its reset handler, as if it had been reset
+    ///
+    /// Unlike a reset this leaves the peripherals as they are.
+    pub fn restart(self, core: &mut Core<'_>, entry_point: &EntryPoint) -> anyhow::Result<()> {
+        if let (Arch::CortexM, Some(vector_table)) = (self, entry_point.vector_table) {
+            core.write_word_32(cortexm::VTOR, vector_table)?;
+            core.write_core_reg(cortexm::XPSR, cortexm::XPSR_THUMB)?;
+        }
+        // NOTE the start-up code of A/R programs sets up the stack pointer itself
+        if entry_point.initial_stack_pointer != 0 {
+            core.write_core_reg(self.stack_pointer(), entry_point.initial_stack_pointer)?;
+        }
+        core.write_core_reg(
+            self.program_counter(),
+            self.instruction_address(entry_point.reset),
+        )?;
+        Ok(())
+    }
+
+    /// Strips the bits that encode the execution mode (e.g. the Thumb bit) from a code address
+    pub fn instruction_address(self, addr: u32) -> u32 {
+        match self {
+            Arch::CortexM | Arch::CortexAR => cortexm::clear_thumb_bit(addr),
+            Arch::Aarch64 | Arch::Riscv32 => addr,
+        }
+    }
+}
+
+/// Where the firmware starts executing and which handler probe-run should break on
+#[derive(Debug)]
+pub struct EntryPoint {
+    /// Address of the vector table (ARM only)
+    pub vector_table: Option<u32>,
+    /// `0` if unknown, which it can be on Cortex-A/R
+    pub initial_stack_pointer: u32,
+    pub reset: u32,
+    /// HardFault handler on Cortex-M, `riscv-rt` exception handler on RISC-V; unknown on Cortex-A/R
+    pub fault_handler: Option<u32>,
+}
+
+impl From<cortexm::VectorTable> for EntryPoint {
+    fn from(vector_table: cortexm::VectorTable) -> Self {
+        Self {
+            vector_table: Some(vector_table.location),
+            initial_stack_pointer: vector_table.initial_stack_pointer,
+            reset: vector_table.reset,
+            fault_handler: Some(vector_table.hard_fault),
+        }
+    }
+}
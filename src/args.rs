@@ -0,0 +1,67 @@
+//! Arguments passed after the ELF file path (`cargo run -- <args>`), forwarded to the program
+//!
+//! The arguments are written into a buffer the program exports under `--args-symbol` once it
+//! reaches `main`, i.e. after `.data` and `.bss` have been initialized. The buffer holds the
+//! number of bytes that follow (a little endian `u32`) and then each argument, terminated with a
+//! NUL byte.
+
+use anyhow::{anyhow, bail};
+use object::read::{File as ElfFile, Object as _, ObjectSymbol as _};
+use probe_rs::{Core, MemoryInterface};
+
+use crate::target_info;
+
+pub struct Args {
+    address: u32,
+    bytes: Vec<u8>,
+}
+
+impl Args {
+    /// Returns `None` if there are no arguments to forward
+    pub fn new(args: &[String], symbol_name: &str, elf: &ElfFile) -> anyhow::Result<Option<Self>> {
+        if args.is_empty() {
+            return Ok(None);
+        }
+
+        let symbol = elf
+            .symbols()
+            .find(|symbol| symbol.name() == Ok(symbol_name))
+            .ok_or_else(|| {
+                anyhow!(
+                    "symbol `{}` not found; the program doesn't take arguments through it",
+                    symbol_name
+                )
+            })?;
+
+        let data = args
+            .iter()
+            .flat_map(|arg| arg.bytes().chain(Some(0)))
+            .collect::<Vec<_>>();
+        let mut bytes = (data.len() as u32).to_le_bytes().to_vec();
+        bytes.extend(data);
+        if bytes.len() as u64 > symbol.size() {
+            bail!(
+                "the arguments take {} bytes but `{}` only holds {}",
+                bytes.len(),
+                symbol_name,
+                symbol.size()
+            );
+        }
+
+        Ok(Some(Self {
+            address: target_info::address(symbol.address())?,
+            bytes,
+        }))
+    }
+
+    /// Writes the arguments into the program's buffer; the `core` must be halted at `main`
+    pub fn write(&self, core: &mut Core<'_>) -> anyhow::Result<()> {
+        log::debug!(
+            "writing {} bytes of arguments to 0x{:08X}",
+            self.bytes.len(),
+            self.address
+        );
+        core.write_8(self.address, &self.bytes)?;
+        Ok(())
+    }
+}
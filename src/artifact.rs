@@ -0,0 +1,112 @@
+//! `--bin` and `--example`: finds the ELF file of a binary or example of the current Cargo
+//! workspace by name, so probe-run can be used on its own and not only as a Cargo runner
+//!
+//! The target directory comes from `cargo metadata`, which honors `CARGO_TARGET_DIR` and
+//! `build.target-dir`. The program is not built; run `cargo build` first.
+
+use std::{
+    env, fs,
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+use anyhow::{anyhow, bail, Context as _};
+use serde::Deserialize;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Kind {
+    Bin,
+    Example,
+}
+
+#[derive(Deserialize)]
+struct Metadata {
+    target_directory: PathBuf,
+}
+
+/// Path of the ELF file of binary (or example) `name`, built with the `release` or `dev` profile
+/// for `target`; if `target` is `None` all the target triples that were built for are searched
+pub fn locate(
+    kind: Kind,
+    name: &str,
+    release: bool,
+    target: Option<&str>,
+) -> anyhow::Result<PathBuf> {
+    let target_dir = target_directory()?;
+    let profile = if release { "release" } else { "debug" };
+    let relative_path = match kind {
+        Kind::Bin => PathBuf::from(profile).join(name),
+        Kind::Example => PathBuf::from(profile).join("examples").join(name),
+    };
+
+    let triple_dirs = match target {
+        Some(target) => vec![target_dir.join(target)],
+        None => fs::read_dir(&target_dir)
+            .with_context(|| format!("failed to read {}", target_dir.display()))?
+            .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+            .filter(|path| path.is_dir())
+            .collect(),
+    };
+    let mut candidates = triple_dirs
+        .iter()
+        .map(|dir| dir.join(&relative_path))
+        .filter(|path| path.is_file())
+        .collect::<Vec<_>>();
+
+    match candidates.len() {
+        0 => bail!(
+            "{} `{}` not found in {}; build it first, e.g. with `cargo build {}{}`",
+            describe(kind),
+            name,
+            target_dir.display(),
+            match kind {
+                Kind::Bin => "--bin ",
+                Kind::Example => "--example ",
+            },
+            name
+        ),
+        1 => {
+            let path = candidates.remove(0);
+            log::debug!("running {}", path.display());
+            Ok(path)
+        }
+        _ => {
+            let paths = candidates
+                .iter()
+                .map(|path| format!("    {}", path.display()))
+                .collect::<Vec<_>>();
+            bail!(
+                "{} `{}` was built for several targets; pick one with `--target`:\n{}",
+                describe(kind),
+                name,
+                paths.join("\n")
+            )
+        }
+    }
+}
+
+fn describe(kind: Kind) -> &'static str {
+    match kind {
+        Kind::Bin => "binary",
+        Kind::Example => "example",
+    }
+}
+
+fn target_directory() -> anyhow::Result<PathBuf> {
+    // NOTE Cargo sets `CARGO` when it runs probe-run, e.g. from an alias
+    let cargo = env::var_os("CARGO").unwrap_or_else(|| "cargo".into());
+    let output = Command::new(&cargo)
+        .args(&["metadata", "--format-version", "1", "--no-deps"])
+        .output()
+        .with_context(|| format!("failed to run `{}`", Path::new(&cargo).display()))?;
+    if !output.status.success() {
+        return Err(anyhow!(
+            "{}",
+            String::from_utf8_lossy(&output.stderr).trim_end()
+        ))
+        .context("`cargo metadata` failed; is the current directory in a Cargo workspace?");
+    }
+
+    let metadata = serde_json::from_slice::<Metadata>(&output.stdout)?;
+    Ok(metadata.target_directory)
+}
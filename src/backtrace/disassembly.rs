@@ -0,0 +1,102 @@
+//! `--disassemble`: prints the instructions around the faulting instruction of a crash
+//!
+//! The code is read from the target, so it shows what the core actually executed, e.g. garbage
+//! after a jump through a corrupted function pointer. Thumb instructions have different lengths
+//! and can't be decoded backwards; decoding starts at the beginning of the function, if it's close
+//! enough, or else at the nearest earlier address from which it lands on the faulting instruction.
+
+use anyhow::{anyhow, bail};
+use capstone::{
+    arch::{self, BuildsCapstone as _, BuildsCapstoneExtraMode as _},
+    Capstone,
+};
+use probe_rs::{Core, MemoryInterface};
+
+use crate::theme;
+
+/// Instructions printed before and after the faulting one
+const WINDOW: u32 = 8;
+/// Length of the longest Thumb instruction
+const MAX_INSTRUCTION_LEN: u32 = 4;
+/// Decoding starts at the function's first instruction if that's at most this far from the PC
+const MAX_FUNCTION_OFFSET: u32 = 1024;
+
+/// Prints the Thumb instructions around `pc`, which lies in the function `function`, if known:
+/// its name and address
+pub fn print(core: &mut Core<'_>, pc: u32, function: Option<(&str, u32)>) -> anyhow::Result<()> {
+    let capstone = Capstone::new()
+        .arm()
+        .mode(arch::arm::ArchMode::Thumb)
+        .extra_mode([arch::arm::ArchExtraMode::MClass].iter().copied())
+        .build()
+        .map_err(|e| anyhow!("failed to set up the disassembler: {}", e))?;
+
+    let lookback = WINDOW * MAX_INSTRUCTION_LEN;
+    // NOTE Thumb instructions are halfword aligned
+    let mut starts = (0..=lookback / 2)
+        .map(|halfwords| pc.saturating_sub(lookback - 2 * halfwords))
+        .collect::<Vec<_>>();
+    if let Some((_, address)) = function {
+        if address <= pc && pc - address <= MAX_FUNCTION_OFFSET {
+            starts.insert(0, address);
+        }
+    }
+
+    // NOTE(unwrap) `starts` is never empty
+    let lowest = *starts.iter().min().unwrap();
+    let end = pc + (WINDOW + 1) * MAX_INSTRUCTION_LEN;
+    let mut code = vec![0; (end - lowest) as usize];
+    core.read_8(lowest, &mut code)?;
+
+    for start in starts {
+        let instructions = capstone
+            .disasm_all(&code[(start - lowest) as usize..], u64::from(start))
+            .map_err(|e| anyhow!("failed to disassemble the code: {}", e))?;
+        let instructions = instructions.iter().collect::<Vec<_>>();
+        let position = match instructions
+            .iter()
+            .position(|instruction| instruction.address() == u64::from(pc))
+        {
+            Some(position) => position,
+            None => continue,
+        };
+
+        let header = match function {
+            Some((name, _)) => format!("disassembly around 0x{:08x} (in {}):", pc, name),
+            None => format!("disassembly around 0x{:08x}:", pc),
+        };
+        println!("{}", theme::current().secondary.paint(&header));
+        let first = position.saturating_sub(WINDOW as usize);
+        let last = (position + WINDOW as usize).min(instructions.len() - 1);
+        for (index, instruction) in instructions[first..=last].iter().enumerate() {
+            // NOTE like `objdump`, show the encoding as halfwords
+            let encoding = instruction
+                .bytes()
+                .chunks(2)
+                .map(|halfword| format!("{:02x}{:02x}", halfword[halfword.len() - 1], halfword[0]))
+                .collect::<Vec<_>>()
+                .join(" ");
+            let line = format!(
+                "0x{:08x}:  {:<9}  {} {}",
+                instruction.address(),
+                encoding,
+                instruction.mnemonic().unwrap_or("???"),
+                instruction.op_str().unwrap_or("")
+            );
+            if first + index == position {
+                println!(
+                    "{}",
+                    theme::current().failure.paint(&format!("=> {}", line))
+                );
+            } else {
+                println!("   {}", line);
+            }
+        }
+        return Ok(());
+    }
+
+    bail!(
+        "the code at 0x{:08x} doesn't decode as Thumb instructions",
+        pc
+    )
+}
@@ -0,0 +1,245 @@
+mod disassembly;
+mod pp;
+mod symbolicate;
+mod unwind;
+
+use std::{collections::HashSet, path::Path};
+
+use object::read::{File as ElfFile, Object as _, ObjectSection as _};
+use probe_rs::{config::RamRegion, Core};
+
+use crate::{
+    arch::{Arch, EntryPoint},
+    cli::{BacktraceFormat, MessageFormat},
+    cortexm,
+    fault_address::AddressMap,
+    fpu::FpuState,
+    hooks::Hooks,
+    hyperlink::Hyperlinks,
+    registers::FaultStatus,
+    source::SourceMap,
+};
+use unwind::RawFrame;
+
+/// Options that control how the backtrace is presented
+pub struct Settings<'p> {
+    pub current_dir: &'p Path,
+    /// Print a backtrace even if the program ran successfully
+    pub force_backtrace: bool,
+    /// Frames to unwind at most
+    pub max_backtrace_len: u32,
+    /// Frames to print at most; the ones in the middle are left out
+    pub backtrace_limit: u32,
+    pub message_format: MessageFormat,
+    pub backtrace_format: BacktraceFormat,
+    /// Lines of source code to print before and after the line of each frame
+    pub backtrace_context: u32,
+    /// Disassemble the code around the faulting instruction of a crash
+    pub disassemble: bool,
+    /// Rules for finding the source files of code built elsewhere
+    pub source_map: &'p [SourceMap],
+    /// Links the source locations of the frames
+    pub hyperlinks: Option<&'p Hyperlinks>,
+    /// Explains the faulting address of memory faults
+    pub address_map: &'p AddressMap,
+    pub hooks: &'p Hooks,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum TopException {
+    StackOverflow,
+    HardFault, // generic hard fault
+}
+
+/// A function at a code address, as the backtrace names it
+pub struct Symbol {
+    pub name: String,
+    pub file: Option<String>,
+    pub line: Option<u64>,
+    /// The function was inlined into the next one
+    pub inline: bool,
+}
+
+/// Symbolicates the code `address` like a frame of the backtrace: the functions inlined at it come
+/// first, the function that contains it last
+pub fn symbolicate(
+    address: u32,
+    arch: Arch,
+    elf: &ElfFile,
+    live_functions: &HashSet<&str>,
+    current_dir: &Path,
+    source_map: &[SourceMap],
+) -> anyhow::Result<Vec<Symbol>> {
+    let raw_frame = RawFrame::Subroutine {
+        pc: address,
+        heuristic: false,
+    };
+    let frames = symbolicate::frames(
+        &[raw_frame],
+        arch,
+        elf,
+        live_functions,
+        current_dir,
+        source_map,
+    )?;
+    Ok(frames
+        .into_iter()
+        .filter_map(|frame| match frame {
+            symbolicate::Frame::Subroutine(subroutine) => Some(Symbol {
+                name: subroutine.name,
+                file: subroutine.location.as_ref().map(|loc| loc.file.clone()),
+                line: subroutine.location.as_ref().map(|loc| loc.line),
+                inline: subroutine.is_inline,
+            }),
+            _ => None,
+        })
+        .collect())
+}
+
+#[allow(clippy::too_many_arguments)] // FIXME: clean this up
+pub fn construct(
+    core: &mut Core<'_>,
+    arch: Arch,
+    pc: u32,
+    debug_frame: &[u8],
+    elf: &ElfFile,
+    entry_point: &EntryPoint,
+    sp_ram_region: &Option<RamRegion>,
+    live_functions: &HashSet<&str>,
+    settings: &Settings,
+) -> Result<Option<TopException>, anyhow::Error> {
+    let mut top_exception = None;
+    let fault_handler = entry_point
+        .fault_handler
+        .map(|fault_handler| arch.instruction_address(fault_handler));
+    if fault_handler == Some(arch.instruction_address(pc)) {
+        // HardFaultTrampoline (ARM) / ExceptionHandler (RISC-V)
+        // on exception entry we hit the breakpoint before the subroutine prelude (`push lr`) is
+        // executed so special handling is required
+        // also note that the fault handler will always be the first frame we unwind
+        let sp = core.read_core_reg(arch.stack_pointer())?;
+
+        let stack_overflow = if let Some(sp_ram_region) = sp_ram_region {
+            // NOTE stack is full descending; meaning the stack pointer can be
+            // `ORIGIN(RAM) + LENGTH(RAM)`
+            let range = sp_ram_region.range.start..=sp_ram_region.range.end;
+            !range.contains(&sp)
+        } else {
+            log::warn!(
+                "no RAM region appears to contain the stack; cannot determine if this was a stack overflow"
+            );
+            false
+        };
+        // NOTE an ARMv8-M core faults as soon as the stack pointer goes below MSPLIM, so the
+        // stack pointer is still in RAM
+        let stack_overflow = stack_overflow
+            || (arch == Arch::CortexM
+                && FaultStatus::read(core).map_or(false, |status| status.is_stack_overflow()));
+
+        top_exception = Some(match stack_overflow {
+            true => TopException::StackOverflow,
+            false => TopException::HardFault,
+        });
+    }
+
+    // explain why the core faulted before unwinding; the backtrace only shows where it happened
+    if arch == Arch::CortexM
+        && top_exception.is_some()
+        && settings.message_format != MessageFormat::Json
+    {
+        match FaultStatus::read(core) {
+            Ok(fault_status) => fault_status.print(settings.address_map),
+            Err(e) => log::warn!("failed to read the fault status registers: {}", e),
+        }
+        match FpuState::read(core) {
+            Ok(Some(fpu_state)) => fpu_state.print(),
+            Ok(None) => {}
+            Err(e) => log::warn!("failed to read the floating-point registers: {}", e),
+        }
+    }
+
+    // NOTE without `.text` no frame can be found heuristically
+    let code = elf.section_by_name(".text").map_or(0..0, |text| {
+        let start = text.address() as u32;
+        start..start + text.size() as u32
+    });
+    let unwind = unwind::target(
+        core,
+        arch,
+        pc,
+        debug_frame,
+        &code,
+        settings.max_backtrace_len,
+    );
+    let frames = symbolicate::frames(
+        &unwind.raw_frames,
+        arch,
+        elf,
+        live_functions,
+        settings.current_dir,
+        settings.source_map,
+    )?;
+
+    let exception_entry = unwind
+        .raw_frames
+        .iter()
+        .any(|raw_frame| matches!(raw_frame, RawFrame::Exception { .. }));
+    // NOTE JSON consumers and hooks always get the full backtrace
+    pp::records(&frames, unwind.corrupted, settings.hooks);
+    if settings.message_format != MessageFormat::Json
+        && (settings.force_backtrace
+            || top_exception.is_some()
+            || exception_entry
+            || unwind.corrupted)
+    {
+        pp::backtrace(
+            &frames,
+            unwind.corrupted,
+            settings.backtrace_format,
+            settings.backtrace_context,
+            settings.backtrace_limit,
+            settings.hyperlinks,
+            settings.current_dir,
+        );
+    }
+
+    if settings.disassemble
+        && arch == Arch::CortexM
+        && top_exception.is_some()
+        && settings.message_format != MessageFormat::Json
+    {
+        // NOTE the frame below the exception entry is the code that faulted
+        let fault_pc = unwind
+            .raw_frames
+            .iter()
+            .skip_while(|raw_frame| !matches!(raw_frame, RawFrame::Exception { .. }))
+            .find_map(|raw_frame| match raw_frame {
+                RawFrame::Subroutine { pc, .. } => Some(*pc),
+                _ => None,
+            });
+        if let Some(fault_pc) = fault_pc {
+            let symbol = elf
+                .symbol_map()
+                .get(cortexm::set_thumb_bit(fault_pc).into())
+                .map(|symbol| {
+                    let name = format!("{:#}", rustc_demangle::demangle(symbol.name()));
+                    (name, cortexm::clear_thumb_bit(symbol.address() as u32))
+                });
+            let function = symbol.as_ref().map(|(name, address)| (&**name, *address));
+            let fault_pc = cortexm::clear_thumb_bit(fault_pc);
+            if let Err(e) = disassembly::print(core, fault_pc, function) {
+                log::warn!("failed to disassemble the code around the fault: {}", e);
+            }
+        }
+    }
+
+    if let Some(e) = unwind.error {
+        return Err(e);
+    }
+
+    if unwind.corrupted && top_exception != Some(TopException::StackOverflow) {
+        top_exception = Some(TopException::HardFault);
+    }
+
+    Ok(top_exception)
+}
@@ -0,0 +1,379 @@
+//! Pretty printing the backtrace
+
+use std::path::Path;
+
+use serde::Serialize;
+
+use super::symbolicate::{ExceptionEntry, Frame, Location, Subroutine};
+use crate::{
+    cli::BacktraceFormat, hooks::Hooks, hyperlink::Hyperlinks, json::Record, source, theme,
+};
+
+const CORRUPTED_MESSAGE: &str = "the stack appears to be corrupted beyond this point";
+/// Cycles of up to this many frames are collapsed, e.g. two mutually recursive functions
+const MAX_CYCLE_LEN: usize = 8;
+/// A cycle is collapsed once it occurs this many times in a row
+const MIN_REPETITIONS: usize = 3;
+
+/// Prints the backtrace in the given `format`; the pretty format includes `context` lines of
+/// source code around the line of each frame, and `hyperlinks` link the files of the frames
+///
+/// Repeated cycles of frames, as runaway recursion produces, are collapsed, and at most `limit`
+/// subroutine frames are printed (0 = all of them): the first and the last ones, so that both the
+/// fault site and the root of the call chain are visible.
+pub fn backtrace(
+    frames: &[Frame],
+    corrupted: bool,
+    format: BacktraceFormat,
+    context: u32,
+    limit: u32,
+    hyperlinks: Option<&Hyperlinks>,
+    current_dir: &Path,
+) {
+    let file = |location: &Location| match hyperlinks {
+        Some(hyperlinks) => {
+            hyperlinks.link(&location.file, &location.path, location.line, current_dir)
+        }
+        None => location.file.clone(),
+    };
+    match format {
+        BacktraceFormat::Pretty => {
+            print_backtrace_start();
+            for_each_subroutine(frames, limit, |index, subroutine| {
+                println!("{:>4}: {}{}", index, subroutine.name, markers(subroutine));
+                if let Some(location) = &subroutine.location {
+                    println!("        at {}:{}", file(location), location.line);
+                    if context != 0 {
+                        print_source(location, context);
+                    }
+                }
+            });
+        }
+        BacktraceFormat::Compact => {
+            print_backtrace_start();
+            for_each_subroutine(frames, limit, |index, subroutine| {
+                match &subroutine.location {
+                    Some(location) => println!(
+                        "{:>4}: {}{} ({}:{})",
+                        index,
+                        subroutine.name,
+                        markers(subroutine),
+                        file(location),
+                        location.line
+                    ),
+                    None => println!("{:>4}: {}{}", index, subroutine.name, markers(subroutine)),
+                }
+            });
+        }
+        BacktraceFormat::Gdb => {
+            for_each_subroutine(frames, limit, |index, subroutine| {
+                // NOTE like GDB, leave out the address of inlined frames; it's the one of the
+                // frame they were inlined into
+                let mut line = if subroutine.is_inline {
+                    format!("#{:<3} {} ()", index, subroutine.name)
+                } else {
+                    format!(
+                        "#{:<3} 0x{:08x} in {} ()",
+                        index, subroutine.pc, subroutine.name
+                    )
+                };
+                if let Some(location) = &subroutine.location {
+                    line.push_str(&format!(" at {}:{}", file(location), location.line));
+                }
+                if subroutine.is_heuristic {
+                    line.push_str(" (heuristic frame)");
+                }
+                println!("{}", line);
+            });
+        }
+        BacktraceFormat::Json => {
+            print_json_array(frames, corrupted);
+            return;
+        }
+    }
+
+    if corrupted {
+        println!("error: {}", CORRUPTED_MESSAGE);
+    }
+}
+
+/// Emits the backtrace as `--message-format=json` records
+pub fn records(frames: &[Frame], corrupted: bool, hooks: &Hooks) {
+    let mut index = 0;
+    for frame in frames {
+        match frame {
+            Frame::Subroutine(subroutine) => {
+                hooks.emit(&Record::BacktraceFrame {
+                    index,
+                    pc: subroutine.pc,
+                    function: &subroutine.name,
+                    file: subroutine.location.as_ref().map(|loc| loc.file.clone()),
+                    line: subroutine.location.as_ref().map(|loc| loc.line),
+                    inline: subroutine.is_inline,
+                    heuristic: subroutine.is_heuristic,
+                });
+                index += 1;
+            }
+            Frame::Exception(entry) => hooks.emit(&Record::ExceptionEntry {
+                exception: &entry.exception,
+                interrupted: &entry.interrupted,
+            }),
+            Frame::SecurityTransition => hooks.emit(&Record::SecurityTransition),
+        }
+    }
+
+    if corrupted {
+        hooks.emit(&Record::BacktraceError {
+            message: CORRUPTED_MESSAGE,
+        });
+    }
+}
+
+fn markers(subroutine: &Subroutine) -> &'static str {
+    match (subroutine.is_inline, subroutine.is_heuristic) {
+        (false, false) => "",
+        (true, false) => " (inlined)",
+        (false, true) => " (heuristic frame)",
+        (true, true) => " (inlined, heuristic frame)",
+    }
+}
+
+/// Calls `f` with the index of each subroutine frame that is shown; prints the exception entries
+/// and the collapsed frames in between
+fn for_each_subroutine(frames: &[Frame], limit: u32, mut f: impl FnMut(u32, &Subroutine)) {
+    for row in apply_limit(rows(frames), limit) {
+        match row {
+            Row::Subroutine(index, subroutine) => f(index, subroutine),
+            Row::Marker(Frame::Exception(entry)) => print_exception_entry(entry),
+            Row::Marker(_) => println!("      <called from the secure state>"),
+            Row::Repeat {
+                first,
+                last,
+                cycle_len,
+            } => {
+                let previous = match cycle_len {
+                    1 => "the previous frame".to_string(),
+                    _ => format!("the previous {} frames", cycle_len),
+                };
+                println!(
+                    "      ... frames {}-{} repeat {} ...",
+                    first, last, previous
+                );
+            }
+            Row::Omitted { first, last } => println!(
+                "      ... frames {}-{} omitted; raise `--backtrace-limit` to show them ...",
+                first, last
+            ),
+        }
+    }
+}
+
+/// A line (or, with source code, several lines) of the printed backtrace
+enum Row<'a> {
+    Subroutine(u32, &'a Subroutine),
+    /// An exception entry or a security transition
+    Marker(&'a Frame),
+    /// Subroutine frames `first..=last` repeat the `cycle_len` frames before them
+    Repeat {
+        first: u32,
+        last: u32,
+        cycle_len: usize,
+    },
+    /// Subroutine frames `first..=last` are beyond the limit
+    Omitted {
+        first: u32,
+        last: u32,
+    },
+}
+
+impl Row<'_> {
+    /// The subroutine frames this row stands for
+    fn span(&self) -> Option<(u32, u32)> {
+        match self {
+            Row::Subroutine(index, _) => Some((*index, *index)),
+            Row::Repeat { first, last, .. } | Row::Omitted { first, last } => Some((*first, *last)),
+            Row::Marker(_) => None,
+        }
+    }
+}
+
+fn rows(frames: &[Frame]) -> Vec<Row<'_>> {
+    let mut rows = vec![];
+    let mut index = 0;
+    let mut pos = 0;
+    while pos < frames.len() {
+        if let Some((cycle_len, repetitions)) = find_cycle(&frames[pos..]) {
+            // NOTE the first occurrence of the cycle is shown as is
+            for frame in &frames[pos..pos + cycle_len] {
+                if let Frame::Subroutine(subroutine) = frame {
+                    rows.push(Row::Subroutine(index, subroutine));
+                    index += 1;
+                }
+            }
+            let repeated = (cycle_len * (repetitions - 1)) as u32;
+            rows.push(Row::Repeat {
+                first: index,
+                last: index + repeated - 1,
+                cycle_len,
+            });
+            index += repeated;
+            pos += cycle_len * repetitions;
+            continue;
+        }
+
+        match &frames[pos] {
+            Frame::Subroutine(subroutine) => {
+                rows.push(Row::Subroutine(index, subroutine));
+                index += 1;
+            }
+            frame => rows.push(Row::Marker(frame)),
+        }
+        pos += 1;
+    }
+    rows
+}
+
+/// The length of the shortest cycle of subroutine frames at the start of `frames`, and how often
+/// it occurs in a row
+fn find_cycle(frames: &[Frame]) -> Option<(usize, usize)> {
+    (1..=MAX_CYCLE_LEN).find_map(|cycle_len| {
+        let cycle = frames.get(..cycle_len)?;
+        let repetitions = frames
+            .chunks_exact(cycle_len)
+            .take_while(|chunk| same_subroutines(chunk, cycle))
+            .count();
+        if repetitions >= MIN_REPETITIONS {
+            Some((cycle_len, repetitions))
+        } else {
+            None
+        }
+    })
+}
+
+/// Whether `a` and `b` are the same subroutines at the same addresses; exception entries never
+/// repeat
+fn same_subroutines(a: &[Frame], b: &[Frame]) -> bool {
+    a.iter().zip(b).all(|pair| match pair {
+        (Frame::Subroutine(a), Frame::Subroutine(b)) => a.pc == b.pc && a.name == b.name,
+        _ => false,
+    })
+}
+
+/// Keeps the first and the last rows, `limit` subroutine frames in total, and replaces the rows in
+/// between with a single one
+fn apply_limit(mut rows: Vec<Row<'_>>, limit: u32) -> Vec<Row<'_>> {
+    let positions = rows
+        .iter()
+        .enumerate()
+        .filter(|(_, row)| matches!(row, Row::Subroutine(..)))
+        .map(|(pos, _)| pos)
+        .collect::<Vec<_>>();
+    let limit = limit as usize;
+    if limit == 0 || positions.len() <= limit {
+        return rows;
+    }
+
+    let (head, tail) = ((limit + 1) / 2, limit / 2);
+    let head_end = positions[head - 1] + 1;
+    let tail_start = match tail {
+        0 => rows.len(),
+        _ => positions[positions.len() - tail],
+    };
+    let mut spans = rows[head_end..tail_start].iter().filter_map(Row::span);
+    let (first, mut last) = match spans.next() {
+        Some(span) => span,
+        None => return rows,
+    };
+    if let Some((_, end)) = spans.last() {
+        last = end;
+    }
+
+    rows.drain(head_end..tail_start);
+    rows.insert(head_end, Row::Omitted { first, last });
+    rows
+}
+
+/// Separates the handler's frames above from the frames of the context it interrupted below
+fn print_exception_entry(entry: &ExceptionEntry) {
+    println!("      <exception entry: {}>", entry.exception);
+    println!("      <interrupted {}>", entry.interrupted);
+}
+
+#[derive(Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum JsonFrame<'a> {
+    Subroutine {
+        pc: u32,
+        symbol: &'a str,
+        file: Option<&'a str>,
+        line: Option<u64>,
+        inline: bool,
+        heuristic: bool,
+    },
+    ExceptionEntry {
+        exception: &'a str,
+        interrupted: &'a str,
+    },
+    SecurityTransition,
+    Error {
+        message: &'a str,
+    },
+}
+
+fn print_json_array(frames: &[Frame], corrupted: bool) {
+    let mut array = frames
+        .iter()
+        .map(|frame| match frame {
+            Frame::Subroutine(subroutine) => JsonFrame::Subroutine {
+                pc: subroutine.pc,
+                symbol: &subroutine.name,
+                file: subroutine.location.as_ref().map(|loc| &*loc.file),
+                line: subroutine.location.as_ref().map(|loc| loc.line),
+                inline: subroutine.is_inline,
+                heuristic: subroutine.is_heuristic,
+            },
+            Frame::Exception(entry) => JsonFrame::ExceptionEntry {
+                exception: &entry.exception,
+                interrupted: &entry.interrupted,
+            },
+            Frame::SecurityTransition => JsonFrame::SecurityTransition,
+        })
+        .collect::<Vec<_>>();
+    if corrupted {
+        array.push(JsonFrame::Error {
+            message: CORRUPTED_MESSAGE,
+        });
+    }
+
+    // NOTE(expect) all the frame types serialize to valid JSON
+    println!(
+        "{}",
+        serde_json::to_string_pretty(&array).expect("failed to serialize backtrace")
+    );
+}
+
+/// Prints the source code around `location`, marking its line, like `rustc` diagnostics do
+fn print_source(location: &Location, context: u32) {
+    let lines = match source::lines(&location.path, location.line, context) {
+        Some(lines) => lines,
+        None => return,
+    };
+
+    let width = lines
+        .last()
+        .map_or(0, |(number, _)| number.to_string().len());
+    for (number, text) in lines {
+        let marker = if number == location.line { '>' } else { ' ' };
+        let gutter = format!("{} {:>width$} |", marker, number, width = width);
+        println!(
+            "        {} {}",
+            theme::current().secondary.paint(&gutter),
+            text
+        );
+    }
+}
+
+/// Print a message indicating that the backtrace starts here
+fn print_backtrace_start() {
+    println!("{}", theme::current().secondary.paint("stack backtrace:"));
+}
@@ -0,0 +1,169 @@
+//! Turns the raw frames into named subroutines with source locations
+
+use std::{
+    borrow::Cow,
+    collections::HashSet,
+    path::{Path, PathBuf},
+};
+
+use addr2line::fallible_iterator::FallibleIterator as _;
+use object::read::{File as ElfFile, Object as _};
+
+use super::unwind::RawFrame;
+use crate::{
+    arch::Arch,
+    cortexm,
+    source::{self, SourceMap},
+};
+
+pub enum Frame {
+    Subroutine(Subroutine),
+    Exception(ExceptionEntry),
+    SecurityTransition,
+}
+
+/// The frames above belong to the handler of `exception`, which was taken in the context of
+/// `interrupted`; e.g. `IRQ 37 (USART2)` and `thread mode`
+pub struct ExceptionEntry {
+    pub exception: String,
+    pub interrupted: String,
+}
+
+pub struct Subroutine {
+    pub pc: u32,
+    pub name: String,
+    pub location: Option<Location>,
+    /// The subroutine was inlined into the next frame
+    pub is_inline: bool,
+    /// The frame was found by scanning the stack rather than with unwind info
+    pub is_heuristic: bool,
+}
+
+pub struct Location {
+    /// Relative to the current directory, if the file lives in it
+    pub file: String,
+    /// Where to read the file from, after applying `--source-map`
+    pub path: PathBuf,
+    pub line: u64,
+}
+
+pub fn frames(
+    raw_frames: &[RawFrame],
+    arch: Arch,
+    elf: &ElfFile,
+    live_functions: &HashSet<&str>,
+    current_dir: &Path,
+    source_map: &[SourceMap],
+) -> anyhow::Result<Vec<Frame>> {
+    let addr2line = addr2line::Context::new(elf)?;
+    let symtab = elf.symbol_map();
+
+    let mut frames = vec![];
+    for raw_frame in raw_frames {
+        let (pc, is_heuristic) = match raw_frame {
+            RawFrame::Subroutine { pc, heuristic } => (*pc, *heuristic),
+            RawFrame::Exception {
+                number,
+                interrupted,
+            } => {
+                let describe = |number| {
+                    let irq_name = cortexm::irq_name(elf, number);
+                    cortexm::describe_exception(number, irq_name.as_deref())
+                };
+                frames.push(Frame::Exception(ExceptionEntry {
+                    exception: describe(*number),
+                    interrupted: describe(*interrupted),
+                }));
+                continue;
+            }
+            RawFrame::SecurityTransition => {
+                frames.push(Frame::SecurityTransition);
+                continue;
+            }
+        };
+
+        let addr2line_frames = addr2line.find_frames(pc as u64)?.collect::<Vec<_>>()?;
+        // when the input of `find_frames` is the PC of a subroutine that has no debug information
+        // (e.g. external assembly), it will either return an empty `FrameIter` OR the frames that
+        // correspond to a subroutine GC-ed by the linker, instead of an `Err`or.
+        // To detect the second failure mode we check that the last frame (the non-inline one) is
+        // actually "live" (exists in the final binary).
+        // When there's no debuginfo we fallback to a symtab lookup to at least provide the name of
+        // the function that contains the PC.
+        let has_valid_debuginfo = if let Some(function) = addr2line_frames
+            .last()
+            .and_then(|subroutine| subroutine.function.as_ref())
+        {
+            live_functions.contains(&*function.raw_name()?)
+        } else {
+            false
+        };
+
+        if has_valid_debuginfo {
+            let num_frames = addr2line_frames.len();
+            for (index, frame) in addr2line_frames.iter().enumerate() {
+                let name = frame
+                    .function
+                    .as_ref()
+                    .map(|function| function.demangle())
+                    .transpose()?
+                    .unwrap_or(Cow::Borrowed("???"))
+                    .into_owned();
+
+                let location = frame
+                    .location
+                    .as_ref()
+                    .and_then(|loc| loc.file.and_then(|file| loc.line.map(|line| (file, line))))
+                    .map(|(file, line)| {
+                        // NOTE relative paths are relative to the workspace, i.e. the current
+                        // directory
+                        let path = current_dir.join(source::remap(Path::new(file), source_map));
+                        let relpath = if let Ok(relpath) = path.strip_prefix(current_dir) {
+                            relpath
+                        } else {
+                            // not within current directory; use full path
+                            &path
+                        };
+                        Location {
+                            file: relpath.display().to_string(),
+                            path,
+                            line: line as u64,
+                        }
+                    });
+
+                frames.push(Frame::Subroutine(Subroutine {
+                    pc,
+                    name,
+                    location,
+                    // NOTE `find_frames` yields the innermost (inlined) frames first
+                    is_inline: index + 1 != num_frames,
+                    is_heuristic,
+                }));
+            }
+        } else {
+            // .symtab fallback
+            // the .symtab appears to use address ranges that have their thumb bits set (e.g.
+            // `0x101..0x200`). Passing the `pc` with the thumb bit cleared (e.g. `0x100`) to the
+            // lookup function sometimes returns the *previous* symbol. Work around the issue by
+            // setting `pc`'s thumb bit before looking it up
+            let address = match arch {
+                Arch::CortexM | Arch::CortexAR => cortexm::set_thumb_bit(pc),
+                Arch::Aarch64 | Arch::Riscv32 => pc,
+            } as u64;
+            let name = symtab
+                .get(address)
+                .map(|symbol| symbol.name())
+                .unwrap_or("???");
+
+            frames.push(Frame::Subroutine(Subroutine {
+                pc,
+                name: name.to_string(),
+                location: None,
+                is_inline: false,
+                is_heuristic,
+            }));
+        }
+    }
+
+    Ok(frames)
+}
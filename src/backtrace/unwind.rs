@@ -0,0 +1,284 @@
+//! Unwinds the target's program stack
+
+use std::{mem, ops::Range};
+
+use anyhow::{bail, Context as _};
+use gimli::{
+    read::{DebugFrame, UnwindSection},
+    BaseAddresses, LittleEndian, UninitializedUnwindContext,
+};
+use probe_rs::{Core, MemoryInterface};
+
+use crate::{
+    arch::Arch,
+    cortexm::{self, EXC_RETURN_MARKER},
+    registers::Registers,
+    stacked::Stacked,
+};
+
+const MISSING_DEBUG_INFO: &str = "debug information is missing. Likely fixes:
+1. compile the Rust code with `debug = 1` or higher. This is configured in the `profile.{release,bench}` sections of Cargo.toml (`profile.{dev,test}` default to `debug = 2`)
+2. use a recent version of the `cortex-m` crates (e.g. cortex-m 0.6.3 or newer). Check versions in Cargo.lock
+3. if linking to C code, compile the C code with the `-g` flag";
+
+/// How far up the stack the return address of a subroutine without unwind info is looked for
+const SCAN_LIMIT: u32 = 256;
+
+/// A frame of the stack, before it's symbolicated
+#[derive(Debug)]
+pub enum RawFrame {
+    Subroutine {
+        pc: u32,
+        /// The frame was found by scanning the stack, because the subroutine below it (e.g.
+        /// hand-written assembly or a compiler builtin) has no unwind info
+        heuristic: bool,
+    },
+    /// The handler of exception `number` (IPSR) above was entered from the context of exception
+    /// `interrupted`; `0` is thread mode
+    Exception { number: u32, interrupted: u32 },
+    /// Secure code called the Non-secure code above it
+    SecurityTransition,
+}
+
+pub struct Output {
+    pub raw_frames: Vec<RawFrame>,
+    /// Unwinding stopped because the stack appears to be corrupted
+    pub corrupted: bool,
+    /// Unwinding stopped because of this error; `raw_frames` holds the frames found until then
+    pub error: Option<anyhow::Error>,
+}
+
+/// Unwinds the stack of the halted `core`, starting at `pc`; `code` is the address range of the
+/// program's code
+///
+/// At most `max_len` subroutine frames are returned.
+pub fn target(
+    core: &mut Core<'_>,
+    arch: Arch,
+    pc: u32,
+    debug_frame: &[u8],
+    code: &Range<u32>,
+    max_len: u32,
+) -> Output {
+    let mut output = Output {
+        raw_frames: vec![],
+        corrupted: false,
+        error: None,
+    };
+    if let Err(e) = unwind(&mut output, core, arch, pc, debug_frame, code, max_len) {
+        output.error = Some(e);
+    }
+    output
+}
+
+fn unwind(
+    output: &mut Output,
+    core: &mut Core<'_>,
+    arch: Arch,
+    mut pc: u32,
+    debug_frame: &[u8],
+    code: &Range<u32>,
+    max_len: u32,
+) -> anyhow::Result<()> {
+    let mut debug_frame = DebugFrame::new(debug_frame, LittleEndian);
+    // 32-bit targets -- this defaults to the host's address size which is likely going to be 8
+    debug_frame.set_address_size(mem::size_of::<u32>() as u8);
+
+    let sp = core.read_core_reg(arch.stack_pointer())?;
+    let lr = core.read_core_reg(arch.return_address())?;
+
+    // statically linked binary -- there are no relative addresses
+    let bases = &BaseAddresses::default();
+    let ctx = &mut UninitializedUnwindContext::new();
+
+    // the exception whose handler the frames being unwound belong to
+    let mut active_exception = if arch == Arch::CortexM {
+        core.read_core_reg(cortexm::XPSR)? & cortexm::IPSR_MASK
+    } else {
+        0
+    };
+
+    let mut num_subroutines = 0;
+    let mut registers = Registers::new(arch, lr, sp, core);
+
+    // the frame below was found heuristically
+    let mut heuristic = false;
+    // LR still holds the return address of the subroutine being unwound: in the innermost frame
+    // and in the frame an exception interrupted
+    let mut lr_live = true;
+    loop {
+        output
+            .raw_frames
+            .push(RawFrame::Subroutine { pc, heuristic });
+        num_subroutines += 1;
+        heuristic = false;
+
+        let uwt_row = match debug_frame.unwind_info_for_address(
+            bases,
+            ctx,
+            pc.into(),
+            DebugFrame::cie_from_offset,
+        ) {
+            Ok(uwt_row) => uwt_row,
+            Err(e) => match heuristic_caller(&mut registers, arch, lr_live, code)? {
+                Some((return_address, sp)) => {
+                    log::debug!(
+                        "no unwind info for 0x{:08x}; guessed its return address 0x{:08x}",
+                        pc,
+                        return_address
+                    );
+                    registers.insert(arch.stack_pointer(), sp);
+                    pc = arch.instruction_address(return_address);
+                    heuristic = true;
+                    lr_live = false;
+                    if max_len_reached(num_subroutines, max_len) {
+                        break;
+                    }
+                    continue;
+                }
+                None => return Err(e).context(MISSING_DEBUG_INFO),
+            },
+        };
+        lr_live = false;
+
+        let cfa_changed = registers.update_cfa(uwt_row.cfa())?;
+
+        for (reg, rule) in uwt_row.registers() {
+            registers.update(reg, rule)?;
+        }
+
+        let lr = registers.get(arch.return_address())?;
+        log::debug!("lr=0x{:08x} pc=0x{:08x}", lr, pc);
+
+        if lr == arch.end_of_stack() {
+            break;
+        }
+
+        // Link Register contains an EXC_RETURN value. This deliberately also includes
+        // invalid combinations of final bits 0-6 to prevent futile backtrace re-generation attempts
+        let exception_entry = arch == Arch::CortexM && lr >= EXC_RETURN_MARKER;
+
+        // Since we strip the thumb bit from `pc`, ignore it in this comparison.
+        let program_counter_changed = arch.instruction_address(lr) != arch.instruction_address(pc);
+        // If the frame didn't move, and the program counter didn't change, bail out (otherwise we
+        // might print the same frame over and over).
+        if !cfa_changed && !program_counter_changed {
+            output.corrupted = true;
+            break;
+        }
+
+        if arch == Arch::CortexM && cortexm::is_fnc_return(lr) {
+            output.raw_frames.push(RawFrame::SecurityTransition);
+
+            // `BLXNS` pushed the return address and the partial xPSR onto the Secure stack
+            let secure_sp = cortexm::read_secure_sp(registers.core)?;
+            let return_address = registers.core.read_word_32(secure_sp)?;
+            registers.insert(arch.stack_pointer(), secure_sp + 8);
+            pc = arch.instruction_address(return_address);
+        } else if exception_entry {
+            let fpu = match lr {
+                0xFFFFFFF1 | 0xFFFFFFF9 | 0xFFFFFFFD => false,
+                0xFFFFFFE1 | 0xFFFFFFE9 | 0xFFFFFFED => true,
+                _ if lr & cortexm::EXC_RETURN_DCRS == 0 => bail!(
+                    "LR contains EXC_RETURN value 0x{:08X}; unwinding through the additional \
+                    state context of a Secure exception is not supported",
+                    lr
+                ),
+                // ARMv8-M; the other bits select the security state and stack
+                _ => lr & cortexm::EXC_RETURN_FTYPE == 0,
+            };
+
+            let sp = registers.get(arch.stack_pointer())?;
+            let stacked = Stacked::read(registers.core, sp, fpu)?;
+            output.raw_frames.push(RawFrame::Exception {
+                number: active_exception,
+                interrupted: stacked.ipsr(),
+            });
+            active_exception = stacked.ipsr();
+
+            registers.insert(arch.return_address(), stacked.lr);
+            // adjust the stack pointer for stacked registers
+            registers.insert(arch.stack_pointer(), sp + stacked.size());
+            pc = stacked.pc;
+            lr_live = true;
+        } else {
+            if arch == Arch::CortexM && !cortexm::is_thumb_bit_set(lr) {
+                bail!("bug? LR ({:#010x}) didn't have the Thumb bit set", lr)
+            }
+            pc = arch.instruction_address(lr);
+        }
+
+        if max_len_reached(num_subroutines, max_len) {
+            break;
+        }
+    }
+
+    Ok(())
+}
+
+fn max_len_reached(num_subroutines: u32, max_len: u32) -> bool {
+    if num_subroutines < max_len {
+        return false;
+    }
+    log::warn!(
+        "maximum backtrace length of {} reached; cutting off the rest
+               note: re-run with `--max-backtrace-len=<your maximum>` to extend this limit",
+        max_len
+    );
+    true
+}
+
+/// Guesses the return address of a subroutine without unwind info, and the stack pointer after
+/// it returned (Cortex-M only)
+///
+/// A leaf routine that didn't push anything still has its return address in LR (if `lr_live`);
+/// otherwise the first word up the stack that looks like a return address is taken, assuming it
+/// was pushed last, as `push {.., lr}` does.
+fn heuristic_caller(
+    registers: &mut Registers,
+    arch: Arch,
+    lr_live: bool,
+    code: &Range<u32>,
+) -> anyhow::Result<Option<(u32, u32)>> {
+    if arch != Arch::CortexM {
+        return Ok(None);
+    }
+
+    let sp = registers.get(arch.stack_pointer())?;
+    if lr_live {
+        let lr = registers.get(arch.return_address())?;
+        if is_return_address(registers.core, lr, code)? {
+            return Ok(Some((lr, sp)));
+        }
+    }
+
+    for address in (sp..sp.saturating_add(SCAN_LIMIT)).step_by(4) {
+        let word = registers.core.read_word_32(address)?;
+        if is_return_address(registers.core, word, code)? {
+            return Ok(Some((word, address + 4)));
+        }
+    }
+    Ok(None)
+}
+
+/// Whether `address` looks like a Thumb return address: it has the Thumb bit set, lies in the
+/// code and follows a `BL` or `BLX` instruction
+fn is_return_address(core: &mut Core<'_>, address: u32, code: &Range<u32>) -> anyhow::Result<bool> {
+    if !cortexm::is_thumb_bit_set(address) {
+        return Ok(false);
+    }
+    let address = cortexm::clear_thumb_bit(address);
+    if !code.contains(&address) || address - code.start < 4 {
+        return Ok(false);
+    }
+
+    let mut bytes = [0; 4];
+    core.read_8(address - 4, &mut bytes)?;
+    let first = u16::from_le_bytes([bytes[0], bytes[1]]);
+    let second = u16::from_le_bytes([bytes[2], bytes[3]]);
+    // `BL <label>`: 11110xxxxxxxxxxx 11x1xxxxxxxxxxxx
+    let bl = first & 0xF800 == 0xF000 && second & 0xD000 == 0xD000;
+    // `BLX <Rm>`: 010001111xxxx000
+    let blx = second & 0xFF87 == 0x4780;
+    Ok(bl || blx)
+}
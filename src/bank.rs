@@ -0,0 +1,308 @@
+//! `--flash-bank inactive`: programs the bank of a dual-bank STM32 that the chip doesn't boot
+//! from, then swaps the banks
+//!
+//! The program is linked for the start of the flash, where the active bank is mapped; it's written
+//! at the same offset into the inactive bank. The old program stays intact until the swap takes
+//! effect at the next reset, so flashing that fails half way leaves a bootable chip behind. NOTE
+//! the flash loader runs on the core, so the old program is halted while flashing.
+
+use std::{
+    env, fs, process,
+    str::FromStr,
+    thread,
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, bail, Context as _};
+use probe_rs::{
+    config::MemoryRegion,
+    flashing::{self, BinOptions, DownloadOptions, FlashProgress, Format},
+    Core, MemoryInterface, Session, Target,
+};
+
+use crate::flash;
+
+const TIMEOUT: Duration = Duration::from_secs(1);
+/// How long programming the option bytes may keep the flash interface busy
+const BUSY_TIMEOUT: Duration = Duration::from_secs(5);
+const POLL_INTERVAL: Duration = Duration::from_millis(1);
+
+/// Which flash bank `--flash-bank` programs
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FlashBank {
+    /// The bank the chip boots from; what flashing normally does
+    Active,
+    /// The other bank, which becomes the active one after the reset
+    Inactive,
+}
+
+impl FromStr for FlashBank {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "active" => Ok(Self::Active),
+            "inactive" => Ok(Self::Inactive),
+            _ => bail!(
+                "unknown flash bank `{}`; expected `active` or `inactive`",
+                s
+            ),
+        }
+    }
+}
+
+/// How the banks of a chip are swapped
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Family {
+    /// STM32H7: `SWAP_BANK_OPT` remaps the banks at the next reset
+    Stm32h7,
+    /// STM32L4: `BFB2` makes the system bootloader boot from, and map, bank 2
+    Stm32l4,
+}
+
+impl Family {
+    fn of(chip: &str) -> Option<Self> {
+        let chip = chip.to_ascii_lowercase();
+        if chip.starts_with("stm32h7") {
+            Some(Family::Stm32h7)
+        } else if chip.starts_with("stm32l4") {
+            Some(Family::Stm32l4)
+        } else {
+            None
+        }
+    }
+}
+
+/// Flashes the program into the inactive bank and makes it the active one after the next reset
+///
+/// Returns whether the chip reset itself to load the new option bytes, as the STM32L4 does; the
+/// session has to be opened again in that case, as its state of the core is stale.
+pub fn flash_inactive(
+    sess: &mut Session,
+    target: &Target,
+    elf_bytes: &[u8],
+    progress: Option<&FlashProgress>,
+) -> anyhow::Result<bool> {
+    let family = Family::of(&target.name).ok_or_else(|| {
+        anyhow!(
+            "`--flash-bank inactive` is not supported on `{}`; only dual-bank STM32H7 and STM32L4 \
+            chips are supported",
+            target.name
+        )
+    })?;
+
+    let flash = target
+        .memory_map
+        .iter()
+        .find_map(|region| match region {
+            MemoryRegion::Nvm(region) => Some(region.range.clone()),
+            _ => None,
+        })
+        .ok_or_else(|| anyhow!("{} has no flash", target.name))?;
+    let bank_size = match family {
+        // NOTE dual-bank parts have two banks of 1 MiB; the second one is mapped at 0x0810_0000
+        Family::Stm32h7 => 0x10_0000,
+        Family::Stm32l4 => (flash.end - flash.start) / 2,
+    };
+    let inactive_bank = flash.start + bank_size;
+    if inactive_bank + bank_size > flash.end {
+        bail!("{} has a single flash bank", target.name);
+    }
+
+    // the program becomes one binary image, with the gaps between the segments left erased
+    let segments = flash::loadable_segments(elf_bytes)?;
+    let start = segments
+        .iter()
+        .map(|segment| segment.address)
+        .min()
+        .ok_or_else(|| anyhow!("the program has no loadable segments"))?;
+    let end = segments
+        .iter()
+        .map(|segment| segment.address + segment.data.len() as u32)
+        .max()
+        .unwrap_or(start);
+    if start < flash.start || end > inactive_bank {
+        bail!(
+            "the program (0x{:08X}-0x{:08X}) doesn't fit into the first flash bank \
+            (0x{:08X}-0x{:08X})",
+            start,
+            end - 1,
+            flash.start,
+            inactive_bank - 1
+        );
+    }
+    let mut image = vec![0xFF; (end - start) as usize];
+    for segment in &segments {
+        let offset = (segment.address - start) as usize;
+        image[offset..offset + segment.data.len()].copy_from_slice(segment.data);
+    }
+
+    let swapped = {
+        let mut core = sess.core(0)?;
+        core.halt(TIMEOUT)?;
+        is_swapped(&mut core, family)?
+    };
+    let address = inactive_bank + (start - flash.start);
+    log::info!(
+        "flashing the inactive bank {} at 0x{:08X}",
+        if swapped { 1 } else { 2 },
+        address
+    );
+
+    // NOTE probe-rs flashes images from files; the data is passed through a temporary one
+    let path = env::temp_dir().join(format!("probe-run-bank-{}.bin", process::id()));
+    fs::write(&path, &image)?;
+    let result = flashing::download_file_with_options(
+        sess,
+        &path,
+        Format::Bin(BinOptions {
+            base_address: Some(address),
+            skip: 0,
+        }),
+        DownloadOptions {
+            progress,
+            ..DownloadOptions::default()
+        },
+    );
+    let _ = fs::remove_file(&path);
+    result.context("failed to flash the inactive bank")?;
+
+    let mut core = sess.core(0)?;
+    core.halt(TIMEOUT)?;
+    swap(&mut core, family, !swapped)?;
+    let reset = family == Family::Stm32l4;
+    if reset {
+        log::info!("swapped the flash banks; the chip reset into the new program");
+    } else {
+        log::info!("swapped the flash banks; the new program runs after the reset");
+    }
+    Ok(reset)
+}
+
+/// Whether bank 2 is mapped at the start of the flash
+fn is_swapped(core: &mut Core<'_>, family: Family) -> anyhow::Result<bool> {
+    Ok(match family {
+        Family::Stm32h7 => core.read_word_32(stm32h7::FLASH_OPTSR_CUR)? & stm32h7::SWAP_BANK != 0,
+        Family::Stm32l4 => stm32l4::is_swapped(core)?,
+    })
+}
+
+fn swap(core: &mut Core<'_>, family: Family, swapped: bool) -> anyhow::Result<()> {
+    match family {
+        Family::Stm32h7 => stm32h7::swap(core, swapped),
+        Family::Stm32l4 => stm32l4::swap(core, swapped),
+    }
+}
+
+mod stm32h7 {
+    use super::*;
+
+    const FLASH_OPTKEYR: u32 = 0x5200_2008;
+    const FLASH_OPTCR: u32 = 0x5200_2018;
+    pub const FLASH_OPTSR_CUR: u32 = 0x5200_201C;
+    const FLASH_OPTSR_PRG: u32 = 0x5200_2020;
+    const OPTKEY1: u32 = 0x0819_2A3B;
+    const OPTKEY2: u32 = 0x4C5D_6E7F;
+    const OPTCR_OPTLOCK: u32 = 1 << 0;
+    const OPTCR_OPTSTART: u32 = 1 << 1;
+    const OPTSR_OPT_BUSY: u32 = 1 << 0;
+    pub const SWAP_BANK: u32 = 1 << 31;
+
+    pub fn swap(core: &mut Core<'_>, swapped: bool) -> anyhow::Result<()> {
+        if core.read_word_32(FLASH_OPTCR)? & OPTCR_OPTLOCK != 0 {
+            core.write_word_32(FLASH_OPTKEYR, OPTKEY1)?;
+            core.write_word_32(FLASH_OPTKEYR, OPTKEY2)?;
+        }
+
+        let optsr = core.read_word_32(FLASH_OPTSR_PRG)?;
+        let optsr = if swapped {
+            optsr | SWAP_BANK
+        } else {
+            optsr & !SWAP_BANK
+        };
+        log::debug!("writing 0x{:08X} to FLASH_OPTSR_PRG", optsr);
+        core.write_word_32(FLASH_OPTSR_PRG, optsr)?;
+        let optcr = core.read_word_32(FLASH_OPTCR)?;
+        core.write_word_32(FLASH_OPTCR, optcr | OPTCR_OPTSTART)?;
+        let start = Instant::now();
+        while core.read_word_32(FLASH_OPTSR_CUR)? & OPTSR_OPT_BUSY != 0 {
+            if start.elapsed() > BUSY_TIMEOUT {
+                bail!("timed out waiting for the option bytes to be programmed");
+            }
+            thread::sleep(POLL_INTERVAL);
+        }
+        core.write_word_32(FLASH_OPTCR, optcr | OPTCR_OPTLOCK)?;
+        Ok(())
+    }
+}
+
+mod stm32l4 {
+    use super::*;
+
+    const FLASH_KEYR: u32 = 0x4002_2004;
+    const FLASH_OPTKEYR: u32 = 0x4002_2008;
+    const FLASH_SR: u32 = 0x4002_2010;
+    const FLASH_CR: u32 = 0x4002_2014;
+    const FLASH_OPTR: u32 = 0x4002_2020;
+    const SYSCFG_MEMRMP: u32 = 0x4001_0000;
+    const KEY1: u32 = 0x4567_0123;
+    const KEY2: u32 = 0xCDEF_89AB;
+    const OPTKEY1: u32 = 0x0819_2A3B;
+    const OPTKEY2: u32 = 0x4C5D_6E7F;
+    const SR_BSY: u32 = 1 << 16;
+    const CR_OPTSTRT: u32 = 1 << 17;
+    const CR_OBL_LAUNCH: u32 = 1 << 27;
+    const CR_OPTLOCK: u32 = 1 << 30;
+    const CR_LOCK: u32 = 1 << 31;
+    const OPTR_BFB2: u32 = 1 << 20;
+    const OPTR_DUALBANK: u32 = 1 << 21;
+    const MEMRMP_FB_MODE: u32 = 1 << 8;
+
+    pub fn is_swapped(core: &mut Core<'_>) -> anyhow::Result<bool> {
+        if core.read_word_32(FLASH_OPTR)? & OPTR_DUALBANK == 0 {
+            bail!("the flash is in single-bank mode; set the DUALBANK option bit first");
+        }
+        Ok(core.read_word_32(SYSCFG_MEMRMP)? & MEMRMP_FB_MODE != 0)
+    }
+
+    pub fn swap(core: &mut Core<'_>, swapped: bool) -> anyhow::Result<()> {
+        if core.read_word_32(FLASH_CR)? & CR_LOCK != 0 {
+            core.write_word_32(FLASH_KEYR, KEY1)?;
+            core.write_word_32(FLASH_KEYR, KEY2)?;
+        }
+        if core.read_word_32(FLASH_CR)? & CR_OPTLOCK != 0 {
+            core.write_word_32(FLASH_OPTKEYR, OPTKEY1)?;
+            core.write_word_32(FLASH_OPTKEYR, OPTKEY2)?;
+        }
+
+        let optr = core.read_word_32(FLASH_OPTR)?;
+        let optr = if swapped {
+            optr | OPTR_BFB2
+        } else {
+            optr & !OPTR_BFB2
+        };
+        log::debug!("writing 0x{:08X} to FLASH_OPTR", optr);
+        wait_not_busy(core)?;
+        core.write_word_32(FLASH_OPTR, optr)?;
+        let cr = core.read_word_32(FLASH_CR)?;
+        core.write_word_32(FLASH_CR, cr | CR_OPTSTRT)?;
+        wait_not_busy(core)?;
+        // NOTE the option bytes are only loaded on power-on or OBL_LAUNCH, which resets the chip;
+        // the reset may cut the write's acknowledgement short, so its error doesn't mean much
+        if let Err(e) = core.write_word_32(FLASH_CR, cr | CR_OBL_LAUNCH) {
+            log::debug!("setting OBL_LAUNCH: {}", e);
+        }
+        Ok(())
+    }
+
+    fn wait_not_busy(core: &mut Core<'_>) -> anyhow::Result<()> {
+        let start = Instant::now();
+        while core.read_word_32(FLASH_SR)? & SR_BSY != 0 {
+            if start.elapsed() > BUSY_TIMEOUT {
+                bail!("timed out waiting for the flash interface");
+            }
+            thread::sleep(POLL_INTERVAL);
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,238 @@
+//! `probe-run batch`: runs a sequence of programs, e.g. the test binaries of a hardware-in-the-loop
+//! suite, in one invocation
+//!
+//! The manifest lists one ELF file per line; `*` in the file name matches any sequence of
+//! characters, relative paths are resolved against the manifest's directory and `#` starts a
+//! comment. The programs run in order with the same options, and the session with the probe stays
+//! open between them, which saves attaching to the chip for every program.
+
+use std::{
+    cell::RefCell,
+    fs,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, bail, Context as _};
+use probe_rs::Session;
+
+use crate::{
+    cli::Opts, hooks::Hooks, log_filter, retry, run_once, scripts::Scripts, target_info, theme,
+    Ending, EXIT_FAILURE, EXIT_SUCCESS,
+};
+
+/// Keeps the session with the probe open between the programs of a batch
+#[derive(Default)]
+pub struct SessionSlot {
+    /// The chip the session was opened for, and the session, which RTT shares while a program runs
+    session: RefCell<Option<(String, Arc<Mutex<Session>>)>>,
+}
+
+impl SessionSlot {
+    /// The session the previous program left open, if it was opened for `chip`
+    pub fn take(&self, chip: &str) -> Option<Session> {
+        let (session_chip, session) = self.session.borrow_mut().take()?;
+        if session_chip != chip {
+            return None;
+        }
+        // NOTE once the previous run returned, nothing else holds on to the session
+        Arc::try_unwrap(session).ok()?.into_inner().ok()
+    }
+
+    /// Leaves `session` open for the next program
+    pub fn keep(&self, chip: &str, session: &Arc<Mutex<Session>>) {
+        *self.session.borrow_mut() = Some((chip.to_string(), session.clone()));
+    }
+
+    /// Closes the session, e.g. before a run that opens the probe by other means
+    pub fn close(&self) {
+        self.session.borrow_mut().take();
+    }
+}
+
+/// How the run of one program of the batch ended
+struct Outcome {
+    elf_path: PathBuf,
+    /// The exit code, or the error that stopped the run
+    result: Result<i32, String>,
+    duration: Duration,
+}
+
+/// Runs the programs listed in `manifest`; writes the log and, if the program crashes, the core
+/// dump of each one into `artifacts`. Returns the exit code of the first program that failed
+pub fn run(
+    opts: &mut Opts,
+    manifest: &Path,
+    artifacts: Option<&Path>,
+    hooks: &Hooks,
+) -> anyhow::Result<i32> {
+    if opts.watch {
+        bail!("`--watch` can't be used with `probe-run batch`");
+    }
+    let elf_paths = parse_manifest(manifest)?;
+    if let Some(dir) = artifacts {
+        fs::create_dir_all(dir)
+            .with_context(|| format!("failed to create the directory {}", dir.display()))?;
+    }
+    target_info::load_chip_descriptions(&opts.chip_description)?;
+
+    // NOTE `--power-cycle` talks to the probe while probe-rs doesn't have it open
+    let slot = if opts.power_cycle {
+        None
+    } else {
+        Some(SessionSlot::default())
+    };
+    let mut outcomes = vec![];
+    for (index, elf_path) in elf_paths.iter().enumerate() {
+        log::info!(
+            "running {} ({}/{})",
+            elf_path.display(),
+            index + 1,
+            elf_paths.len()
+        );
+        opts.elf = Some(elf_path.clone());
+        if let Some(dir) = artifacts {
+            // NOTE(unwrap) the manifest only lists files
+            let stem = elf_path.file_stem().unwrap().to_string_lossy();
+            opts.log_file = Some(dir.join(format!("{}.log", stem)));
+            opts.core_dump = Some(dir.join(format!("{}.core", stem)));
+        }
+
+        let start = Instant::now();
+        let scripts = Scripts::new(opts, elf_path);
+        let ending = retry::retry(opts.retries, || {
+            run_once(opts, elf_path, hooks, &scripts, None, slot.as_ref())
+        });
+        let result = match &ending {
+            Ok(Ending::Halted(code)) | Ok(Ending::Interrupted(code)) => Ok(*code),
+            Ok(Ending::ElfChanged) => unreachable!(),
+            Err(e) => {
+                log::error!("{:?}", e);
+                // NOTE the session may be in any state after an error; start over
+                if let Some(slot) = &slot {
+                    slot.close();
+                }
+                Err(format!("{:#}", e))
+            }
+        };
+        scripts.post_run(*result.as_ref().unwrap_or(&EXIT_FAILURE));
+        outcomes.push(Outcome {
+            elf_path: elf_path.clone(),
+            result,
+            duration: start.elapsed(),
+        });
+
+        if let Ok(Ending::Interrupted(_)) = ending {
+            log::info!("interrupted; skipping the rest of the batch");
+            break;
+        }
+    }
+
+    if !hooks.json {
+        print_summary(&outcomes, elf_paths.len());
+    }
+    let code = outcomes
+        .iter()
+        .map(|outcome| *outcome.result.as_ref().unwrap_or(&EXIT_FAILURE))
+        .find(|code| *code != EXIT_SUCCESS)
+        .unwrap_or(EXIT_SUCCESS);
+    Ok(code)
+}
+
+/// The ELF files listed in `manifest`, in order
+fn parse_manifest(manifest: &Path) -> anyhow::Result<Vec<PathBuf>> {
+    let contents = fs::read_to_string(manifest)
+        .with_context(|| format!("failed to read the manifest {}", manifest.display()))?;
+    // NOTE(unwrap) a file that could be read has a parent directory
+    let base = manifest.parent().unwrap();
+
+    let mut elf_paths = vec![];
+    for (number, line) in contents.lines().enumerate() {
+        let line = line.split('#').next().unwrap_or("").trim();
+        if line.is_empty() {
+            continue;
+        }
+        let path = base.join(line);
+        let location = || format!("{}:{}", manifest.display(), number + 1);
+
+        // NOTE(unwrap) the line isn't empty
+        let file_name = path.file_name().unwrap().to_string_lossy().into_owned();
+        if !file_name.contains('*') {
+            if !path.is_file() {
+                bail!("{}: {} doesn't exist", location(), path.display());
+            }
+            elf_paths.push(path);
+            continue;
+        }
+
+        // NOTE(unwrap) the path has a file name, so it has a parent directory
+        let dir = path.parent().unwrap();
+        let mut matches = fs::read_dir(dir)
+            .with_context(|| format!("{}: failed to read {}", location(), dir.display()))?
+            .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+            .filter(|path| {
+                path.is_file()
+                    && path.file_name().map_or(false, |name| {
+                        log_filter::glob_match(&file_name, &name.to_string_lossy())
+                    })
+            })
+            .collect::<Vec<_>>();
+        if matches.is_empty() {
+            return Err(anyhow!("{}: `{}` matches no files", location(), line));
+        }
+        matches.sort();
+        elf_paths.append(&mut matches);
+    }
+
+    if elf_paths.is_empty() {
+        bail!("the manifest {} lists no programs", manifest.display());
+    }
+    Ok(elf_paths)
+}
+
+fn print_summary(outcomes: &[Outcome], total: usize) {
+    eprintln!("\n{}", theme::current().secondary.paint("batch results:"));
+    let mut passed = 0;
+    for outcome in outcomes {
+        let duration = format!("{:.1}s", outcome.duration.as_secs_f64());
+        match &outcome.result {
+            Ok(EXIT_SUCCESS) => {
+                passed += 1;
+                eprintln!(
+                    "  {} {} ({})",
+                    theme::current().success.paint("ok    "),
+                    outcome.elf_path.display(),
+                    duration
+                );
+            }
+            Ok(code) => eprintln!(
+                "  {} {} (exit code {}, {})",
+                theme::current().failure.paint("failed"),
+                outcome.elf_path.display(),
+                code,
+                duration
+            ),
+            Err(e) => eprintln!(
+                "  {} {} ({}, {})",
+                theme::current().failure.paint("error "),
+                outcome.elf_path.display(),
+                e,
+                duration
+            ),
+        }
+    }
+
+    let failed = outcomes.len() - passed;
+    let skipped = total - outcomes.len();
+    let mut counts = format!("{} passed, {} failed", passed, failed);
+    if skipped != 0 {
+        counts += &format!(", {} skipped", skipped);
+    }
+    let style = if failed == 0 && skipped == 0 {
+        theme::current().success
+    } else {
+        theme::current().failure
+    };
+    eprintln!("{}", style.paint(&counts));
+}
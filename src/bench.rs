@@ -0,0 +1,135 @@
+//! `--bench`: cycles spent between calls to `bench_start` and `bench_stop`
+//!
+//! The program marks the code to measure by calling two empty, `#[inline(never)]` and
+//! `#[no_mangle]` functions. Hardware breakpoints on them halt the core just long enough to sample
+//! the DWT cycle counter, which doesn't count while the core is halted.
+
+use anyhow::{anyhow, bail};
+use object::read::{File as ElfFile, Object as _, ObjectSymbol as _};
+use probe_rs::{Core, MemoryInterface};
+
+use crate::{arch::Arch, cortexm, hooks::Hooks, json::Record, target_info, theme};
+
+pub const START_SYMBOL: &str = "bench_start";
+pub const STOP_SYMBOL: &str = "bench_stop";
+
+/// Debug Exception and Monitor Control Register
+const DEMCR: u32 = 0xE000_EDFC;
+/// Enables the DWT
+const DEMCR_TRCENA: u32 = 1 << 24;
+/// Debug Watchpoint and Trace Control Register
+const DWT_CTRL: u32 = 0xE000_1000;
+const DWT_CTRL_CYCCNTENA: u32 = 1 << 0;
+const DWT_CYCCNT: u32 = 0xE000_1004;
+
+pub struct Bench {
+    start: u32,
+    stop: u32,
+    /// Value of the cycle counter at the last `bench_start`
+    started_at: Option<u32>,
+    /// Cycles of each `bench_start`..`bench_stop` run
+    runs: Vec<u32>,
+}
+
+impl Bench {
+    pub fn new(elf: &ElfFile, arch: Arch) -> anyhow::Result<Self> {
+        if arch != Arch::CortexM {
+            bail!("`--bench` is only supported on Cortex-M targets");
+        }
+
+        let address = |name| -> anyhow::Result<u32> {
+            let symbol = elf
+                .symbols()
+                .find(|symbol| symbol.name() == Ok(name))
+                .ok_or_else(|| anyhow!("`--bench` needs the program to define `{}`", name))?;
+            Ok(arch.instruction_address(target_info::address(symbol.address())?))
+        };
+        Ok(Self {
+            start: address(START_SYMBOL)?,
+            stop: address(STOP_SYMBOL)?,
+            started_at: None,
+            runs: vec![],
+        })
+    }
+
+    /// Enables the cycle counter and sets the breakpoints on the halted `core`; returns the number
+    /// of breakpoint units it took
+    pub fn set(&self, core: &mut Core<'_>) -> anyhow::Result<u32> {
+        let demcr = core.read_word_32(DEMCR)?;
+        core.write_word_32(DEMCR, demcr | DEMCR_TRCENA)?;
+        let ctrl = core.read_word_32(DWT_CTRL)?;
+        core.write_word_32(DWT_CTRL, ctrl | DWT_CTRL_CYCCNTENA)?;
+
+        core.set_hw_breakpoint(self.start)?;
+        core.set_hw_breakpoint(self.stop)?;
+        Ok(2)
+    }
+
+    /// Samples the cycle counter if the halted `core` sits at `bench_start` or `bench_stop` and
+    /// resumes it; returns `false` if the core halted for another reason
+    pub fn handle(&mut self, core: &mut Core<'_>) -> anyhow::Result<bool> {
+        let pc = cortexm::clear_thumb_bit(core.read_core_reg(cortexm::PC)?);
+        if pc != self.start && pc != self.stop {
+            return Ok(false);
+        }
+
+        let cycles = core.read_word_32(DWT_CYCCNT)?;
+        if pc == self.start {
+            self.started_at = Some(cycles);
+        } else if let Some(started_at) = self.started_at.take() {
+            // NOTE the counter wraps around after 2^32 cycles
+            self.runs.push(cycles.wrapping_sub(started_at));
+        }
+
+        // step over the breakpoint; it would halt the core again right away
+        core.clear_hw_breakpoint(pc)?;
+        core.step()?;
+        core.set_hw_breakpoint(pc)?;
+        core.run()?;
+        Ok(true)
+    }
+
+    /// Prints the number of cycles of the runs; also in microseconds if the core clock frequency
+    /// (`--core-freq`, in Hz) is known
+    pub fn report(&self, core_freq: Option<u32>, hooks: &Hooks) {
+        if self.runs.is_empty() {
+            log::warn!(
+                "`{}` was not followed by `{}`; nothing was measured",
+                START_SYMBOL,
+                STOP_SYMBOL
+            );
+            return;
+        }
+
+        let min = *self.runs.iter().min().unwrap_or(&0);
+        let max = *self.runs.iter().max().unwrap_or(&0);
+        let mean = self
+            .runs
+            .iter()
+            .map(|cycles| u64::from(*cycles))
+            .sum::<u64>() as f64
+            / self.runs.len() as f64;
+        let micros = |cycles: f64| core_freq.map(|freq| cycles * 1e6 / f64::from(freq));
+
+        hooks.emit(&Record::Bench {
+            runs: self.runs.len(),
+            min_cycles: min,
+            mean_cycles: mean,
+            max_cycles: max,
+            mean_micros: micros(mean),
+        });
+        if hooks.json {
+            return;
+        }
+
+        let format = |cycles: f64| match micros(cycles) {
+            Some(micros) => format!("{:.0} cycles ({:.2} µs)", cycles, micros),
+            None => format!("{:.0} cycles", cycles),
+        };
+        println!("{}", theme::current().emphasis.paint("benchmark"));
+        println!("    runs: {}", self.runs.len());
+        println!("    min:  {}", format(f64::from(min)));
+        println!("    mean: {}", format(mean));
+        println!("    max:  {}", format(f64::from(max)));
+    }
+}
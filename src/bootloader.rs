@@ -0,0 +1,86 @@
+//! `--skip-region` and `--app-offset`: programs that run behind a bootloader or nRF SoftDevice
+//!
+//! The skipped regions (e.g. MCUboot, an MBR + SoftDevice, or the RAM the SoftDevice reserves) are
+//! never written: the program must not overlap them, flashing keeps the unwritten bytes of the
+//! sectors it shares with them, and the stack canary isn't painted over them. With
+//! `--app-offset` the flash below the application counts as skipped and the application is
+//! started directly, with VTOR pointing at its vector table, instead of through the bootloader.
+
+use std::ops::Range;
+
+use anyhow::bail;
+use object::read::File as ElfFile;
+
+use crate::{arch::EntryPoint, flash, preserve};
+
+pub struct Layout {
+    skipped: Vec<Range<u32>>,
+    app_offset: Option<u32>,
+}
+
+impl Layout {
+    pub fn new(
+        skip_regions: &[preserve::Region],
+        app_offset: Option<u32>,
+        elf: &ElfFile,
+        entry_point: &EntryPoint,
+    ) -> anyhow::Result<Self> {
+        let mut skipped = preserve::ranges(skip_regions, elf)?;
+        if let Some(app_offset) = app_offset {
+            match entry_point.vector_table {
+                Some(vector_table) if vector_table == app_offset => {}
+                Some(vector_table) => bail!(
+                    "the program's vector table is at {:#010x}, not at the application offset \
+                    {:#010x}; is it linked for the bootloader?",
+                    vector_table,
+                    app_offset
+                ),
+                None => bail!("`--app-offset` needs a program with a Cortex-M vector table"),
+            }
+            // NOTE the bootloader lives below the application; address 0 is an alias of the
+            // start of flash on some chips (e.g. STM32), so that range is skipped as well
+            skipped.push(0..app_offset);
+        }
+
+        for range in &skipped {
+            log::debug!("skipping {:#010x}..{:#010x}", range.start, range.end);
+        }
+        Ok(Self {
+            skipped,
+            app_offset,
+        })
+    }
+
+    /// The address ranges flashing and the stack canary must leave alone
+    pub fn skipped(&self) -> &[Range<u32>] {
+        &self.skipped
+    }
+
+    /// Whether the application is started directly, bypassing the bootloader
+    pub fn starts_app(&self) -> bool {
+        self.app_offset.is_some()
+    }
+
+    /// Fails if a loadable segment of the ELF file overlaps a skipped region
+    pub fn check_segments(&self, elf_bytes: &[u8]) -> anyhow::Result<()> {
+        for segment in flash::loadable_segments(elf_bytes)? {
+            let start = u64::from(segment.address);
+            let end = start + segment.data.len() as u64;
+            if let Some(range) = self
+                .skipped
+                .iter()
+                .find(|range| start < u64::from(range.end) && u64::from(range.start) < end)
+            {
+                bail!(
+                    "segment at 0x{:08X}-0x{:08X} overlaps the skipped region at \
+                    0x{:08X}-0x{:08X}; flashing it would overwrite the bootloader",
+                    start,
+                    end - 1,
+                    range.start,
+                    range.end - 1
+                );
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,76 @@
+//! `--break-on` and `--break-on-panic`: breakpoints on functions that end the run with an error
+//!
+//! A panic handler that spins instead of executing `BKPT` never halts the core on its own; a
+//! breakpoint on the panic handler, `rust_begin_unwind`, makes it halt anyway.
+
+use anyhow::anyhow;
+use object::read::{File as ElfFile, Object as _, ObjectSymbol as _};
+use probe_rs::Core;
+
+use crate::{arch::Arch, target_info};
+
+/// The function `#[panic_handler]` defines
+pub const PANIC_HANDLER: &str = "rust_begin_unwind";
+
+#[derive(Default)]
+pub struct Breakpoints {
+    /// Symbol names and the address of their first instruction
+    symbols: Vec<(String, u32)>,
+}
+
+impl Breakpoints {
+    /// Looks up the addresses of `symbols`, plus the panic handler if `on_panic` is set
+    pub fn new(
+        symbols: &[String],
+        on_panic: bool,
+        elf: &ElfFile,
+        arch: Arch,
+    ) -> anyhow::Result<Self> {
+        let mut names = symbols.iter().map(String::as_str).collect::<Vec<_>>();
+        if on_panic && !names.contains(&PANIC_HANDLER) {
+            names.push(PANIC_HANDLER);
+        }
+
+        let mut breakpoints = Self::default();
+        for name in names {
+            let symbol = elf
+                .symbols()
+                .find(|symbol| symbol.name() == Ok(name))
+                .ok_or_else(|| anyhow!("symbol `{}` not found", name))?;
+            let address = arch.instruction_address(target_info::address(symbol.address())?);
+            log::debug!("breakpoint on `{}` at 0x{:08X}", name, address);
+            breakpoints.symbols.push((name.to_string(), address));
+        }
+        Ok(breakpoints)
+    }
+
+    /// Sets the breakpoints on the halted `core`; `num_in_use` breakpoint units are taken already
+    pub fn set(&self, core: &mut Core<'_>, num_in_use: u32) -> anyhow::Result<()> {
+        let num_available = core.get_available_breakpoint_units()?;
+        for (index, (name, address)) in self.symbols.iter().enumerate() {
+            if num_in_use + index as u32 >= num_available {
+                log::warn!(
+                    "out of hardware breakpoints; reaching `{}` will NOT end the run",
+                    name
+                );
+                continue;
+            }
+            core.set_hw_breakpoint(*address)?;
+        }
+        Ok(())
+    }
+
+    /// The symbol whose breakpoint the halted `core` is sitting on, if any
+    pub fn hit(&self, core: &mut Core<'_>, arch: Arch) -> anyhow::Result<Option<&str>> {
+        if self.symbols.is_empty() {
+            return Ok(None);
+        }
+
+        let pc = arch.instruction_address(core.read_core_reg(arch.program_counter())?);
+        Ok(self
+            .symbols
+            .iter()
+            .find(|(_, address)| *address == pc)
+            .map(|(name, _)| name.as_str()))
+    }
+}
@@ -0,0 +1,102 @@
+//! What previous runs learned about a probe and chip combination
+//!
+//! Kept in `$XDG_CACHE_HOME/probe-run/state.toml` (falling back to `~/.cache`), so that later runs
+//! can skip work: negotiating the probe speed, flashing a program that's on the device already
+//! and scanning the RAM for the RTT control block. `--no-cache` turns the cache off.
+
+use std::{collections::BTreeMap, env, fs, path::PathBuf};
+
+use anyhow::anyhow;
+use crc32fast::Hasher;
+use probe_rs::DebugProbeInfo;
+use serde::{Deserialize, Serialize};
+
+use crate::flash;
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(default, rename_all = "kebab-case")]
+pub struct State {
+    /// Probe speed `--speed auto` settled on, in kHz
+    pub speed: Option<u32>,
+    /// Hash of the loadable segments of the program flashed last; see [`hash`]
+    pub elf_hash: Option<String>,
+    /// Address of the RTT control block found by scanning the RAM
+    pub rtt_address: Option<u32>,
+}
+
+pub struct Cache {
+    /// `None` if the cache is turned off or there's no cache directory
+    path: Option<PathBuf>,
+    key: String,
+}
+
+impl Cache {
+    pub fn new(probe_info: &DebugProbeInfo, chip: &str, enabled: bool) -> Self {
+        let key = format!(
+            "{:04x}:{:04x}:{}/{}",
+            probe_info.vendor_id,
+            probe_info.product_id,
+            probe_info.serial_number.as_deref().unwrap_or(""),
+            chip
+        );
+        Self {
+            path: if enabled { path() } else { None },
+            key,
+        }
+    }
+
+    pub fn state(&self) -> State {
+        load(self.path.as_ref())
+            .remove(&self.key)
+            .unwrap_or_default()
+    }
+
+    /// Changes the cached state; failing to write the cache is not an error
+    pub fn update(&self, f: impl FnOnce(&mut State)) {
+        let path = match &self.path {
+            Some(path) => path,
+            None => return,
+        };
+
+        // NOTE read the file again; another process may have written it in the meantime
+        let mut states = load(Some(path));
+        f(states.entry(self.key.clone()).or_default());
+        let result = toml::to_string(&states)
+            .map_err(|e| anyhow!(e))
+            .and_then(|contents| {
+                // NOTE(unwrap) `path` returns a file inside a directory
+                fs::create_dir_all(path.parent().unwrap())?;
+                fs::write(path, contents)?;
+                Ok(())
+            });
+        if let Err(e) = result {
+            log::warn!("failed to write the cache {}: {}", path.display(), e);
+        }
+    }
+}
+
+/// Hashes the loadable segments of the ELF file; the debug info doesn't end up on the device
+///
+/// NOTE the hash is stored, so it must not change between Rust versions like `DefaultHasher` may
+pub fn hash(elf_bytes: &[u8]) -> anyhow::Result<String> {
+    let mut hasher = Hasher::new();
+    for segment in flash::loadable_segments(elf_bytes)? {
+        hasher.update(&segment.address.to_le_bytes());
+        hasher.update(&(segment.data.len() as u32).to_le_bytes());
+        hasher.update(segment.data);
+    }
+    Ok(format!("{:08x}", hasher.finalize()))
+}
+
+fn path() -> Option<PathBuf> {
+    let dir = env::var_os("XDG_CACHE_HOME")
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".cache")))?;
+    Some(dir.join("probe-run").join("state.toml"))
+}
+
+fn load(path: Option<&PathBuf>) -> BTreeMap<String, State> {
+    path.and_then(|path| fs::read_to_string(path).ok())
+        .and_then(|contents| toml::from_str(&contents).ok())
+        .unwrap_or_default()
+}
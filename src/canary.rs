@@ -0,0 +1,208 @@
+//! Stack overflow detection and stack usage measurement
+//!
+//! Before the program starts, (a part of) the stack is painted with a known value. Once the
+//! program has halted the painted area is read back; the lowest overwritten byte shows how deep
+//! the stack has grown.
+
+use std::str::FromStr;
+
+use anyhow::bail;
+use probe_rs::{Core, MemoryInterface};
+
+use crate::target_info::TargetInfo;
+
+const CANARY_VALUE: u8 = 0xAA;
+
+/// How much of the stack `--canary-strategy` paints
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CanaryStrategy {
+    /// The whole unused stack; reports the stack usage but takes a moment to paint
+    Full,
+    /// A band at the bottom of the stack; only detects overflows, but is painted quickly
+    Sentinel,
+    /// Nothing
+    None,
+}
+
+impl CanaryStrategy {
+    pub fn name(self) -> &'static str {
+        match self {
+            CanaryStrategy::Full => "full",
+            CanaryStrategy::Sentinel => "sentinel",
+            CanaryStrategy::None => "none",
+        }
+    }
+}
+
+impl FromStr for CanaryStrategy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "full" => Ok(CanaryStrategy::Full),
+            "sentinel" => Ok(CanaryStrategy::Sentinel),
+            "none" => Ok(CanaryStrategy::None),
+            _ => bail!(
+                "unknown canary strategy `{}`; expected `full`, `sentinel` or `none`",
+                s
+            ),
+        }
+    }
+}
+
+pub struct Canary {
+    /// Lowest address of the painted area
+    address: u32,
+    /// Size of the painted area, in bytes
+    size: u32,
+    /// Size of the band at the bottom of the stack that's considered a stack overflow when touched
+    overflow_band: u32,
+    /// Highest address of the stack, i.e. the initial stack pointer
+    stack_top: u32,
+    strategy: CanaryStrategy,
+}
+
+/// Result of reading back the canary
+pub struct StackUsage {
+    /// Lower bound of the program's stack usage, in bytes; `None` if it couldn't be determined
+    pub min_stack_usage: Option<u32>,
+    /// The program has (likely) overflowed its stack
+    pub canary_touched: bool,
+}
+
+impl Canary {
+    /// Decides if and where to place the stack canary and paints it
+    ///
+    /// `size` overrides the size of the band at the bottom of the stack that's considered a stack
+    /// overflow when touched.
+    pub fn install(
+        core: &mut Core<'_>,
+        target_info: &TargetInfo,
+        uses_heap: bool,
+        strategy: CanaryStrategy,
+        size: Option<u32>,
+    ) -> anyhow::Result<Option<Self>> {
+        let measure_stack = match strategy {
+            CanaryStrategy::Full => true,
+            CanaryStrategy::Sentinel => false,
+            CanaryStrategy::None => {
+                log::debug!("`--canary-strategy none`, not placing stack canary");
+                return Ok(None);
+            }
+        };
+
+        let stack_info = match &target_info.stack_info {
+            Some(stack_info) => stack_info,
+            None => {
+                log::debug!("couldn't find valid stack range, not placing stack canary");
+                return Ok(None);
+            }
+        };
+
+        if uses_heap {
+            if measure_stack {
+                log::warn!("heap is in use; the measured stack usage may include heap memory");
+            } else {
+                log::debug!("heap is in use, not placing stack canary");
+                return Ok(None);
+            }
+        }
+
+        if !stack_info.data_below_stack && !measure_stack {
+            log::debug!("no data below the stack, not placing stack canary");
+            return Ok(None);
+        }
+
+        let address = *stack_info.range.start();
+        let stack_top = *stack_info.range.end();
+        let stack_available = stack_top - address;
+
+        // We consider >90% stack usage a potential stack overflow, but don't go beyond 1 kb since
+        // filling a lot of RAM is slow (and 1 kb should be "good enough" for what we're doing).
+        let overflow_band = if stack_info.data_below_stack {
+            size.unwrap_or_else(|| 1024.min(stack_available / 10))
+                .min(stack_available)
+        } else {
+            0
+        };
+        let size = if measure_stack {
+            stack_available
+        } else {
+            overflow_band
+        };
+
+        log::debug!(
+            "{} bytes of stack available (0x{:08X}-0x{:08X}), painting {} bytes",
+            stack_available,
+            address,
+            stack_top,
+            size,
+        );
+
+        core.write_8(address, &vec![CANARY_VALUE; size as usize])?;
+
+        Ok(Some(Self {
+            address,
+            size,
+            overflow_band,
+            stack_top,
+            strategy,
+        }))
+    }
+
+    pub fn strategy(&self) -> CanaryStrategy {
+        self.strategy
+    }
+
+    /// Size of the painted area, in bytes
+    pub fn size(&self) -> u32 {
+        self.size
+    }
+
+    /// Reads back the painted area and reports the stack usage
+    pub fn read_back(&self, core: &mut Core<'_>) -> anyhow::Result<StackUsage> {
+        let mut buf = vec![0; self.size as usize];
+        core.read_8(self.address, &mut buf)?;
+
+        let touched_addr = match buf.iter().position(|b| *b != CANARY_VALUE) {
+            Some(pos) => self.address + pos as u32,
+            None => {
+                log::debug!("stack canary intact");
+                if self.strategy == CanaryStrategy::Full {
+                    log::info!("program has not used any of the painted stack space");
+                }
+                return Ok(StackUsage {
+                    min_stack_usage: None,
+                    canary_touched: false,
+                });
+            }
+        };
+        log::debug!("canary was touched at 0x{:08X}", touched_addr);
+
+        let stack_usage = self.stack_top - touched_addr;
+        let canary_touched = touched_addr < self.address + self.overflow_band;
+
+        if canary_touched {
+            log::warn!(
+                "program has used at least {} bytes of stack space, data segments \
+                may be corrupted due to stack overflow",
+                stack_usage,
+            );
+        }
+
+        if self.strategy == CanaryStrategy::Full {
+            let stack_available = self.stack_top - self.address;
+            log::info!(
+                "program has used {} bytes of stack space ({:.1}% of the {} bytes available)",
+                stack_usage,
+                stack_usage as f64 / stack_available as f64 * 100.0,
+                stack_available,
+            );
+        }
+
+        Ok(StackUsage {
+            min_stack_usage: Some(stack_usage),
+            canary_touched,
+        })
+    }
+}
@@ -0,0 +1,199 @@
+//! `--catch`: halt on exceptions other than HardFault and report them like a crash
+//!
+//! Faults are trapped with the vector catch bits of DEMCR, which halt the core on exception entry,
+//! before the firmware's handler runs. Interrupts have no vector catch bits; a breakpoint on their
+//! handler does the job instead.
+
+use std::str::FromStr;
+
+use anyhow::{anyhow, bail};
+use probe_rs::{Core, MemoryInterface};
+
+use crate::{
+    arch::{Arch, EntryPoint},
+    cortexm,
+};
+
+/// Debug Exception and Monitor Control Register
+const DEMCR: u32 = 0xE000_EDFC;
+const DEMCR_VC_MMERR: u32 = 1 << 4;
+const DEMCR_VC_NOCPERR: u32 = 1 << 5;
+const DEMCR_VC_CHKERR: u32 = 1 << 6;
+const DEMCR_VC_STATERR: u32 = 1 << 7;
+const DEMCR_VC_BUSERR: u32 = 1 << 8;
+/// Debug Fault Status Register; its bits are sticky and cleared by writing ones
+const DFSR: u32 = 0xE000_ED30;
+const DFSR_VCATCH: u32 = 1 << 3;
+const DFSR_BKPT: u32 = 1 << 1;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Exception {
+    MemManage,
+    BusFault,
+    UsageFault,
+    Irq(u16),
+}
+
+impl Exception {
+    fn number(self) -> u32 {
+        match self {
+            Exception::MemManage => 4,
+            Exception::BusFault => 5,
+            Exception::UsageFault => 6,
+            Exception::Irq(irq) => cortexm::FIRST_IRQ + u32::from(irq),
+        }
+    }
+
+    fn vector_catch_bits(self) -> u32 {
+        match self {
+            Exception::MemManage => DEMCR_VC_MMERR,
+            Exception::BusFault => DEMCR_VC_BUSERR,
+            Exception::UsageFault => DEMCR_VC_STATERR | DEMCR_VC_CHKERR | DEMCR_VC_NOCPERR,
+            Exception::Irq(_) => 0,
+        }
+    }
+
+    /// Faults print the fault status registers; interrupts are not an error by themselves
+    pub fn is_fault(self) -> bool {
+        !matches!(self, Exception::Irq(_))
+    }
+
+    pub fn description(self) -> String {
+        match self {
+            Exception::MemManage => "MemManage fault".to_string(),
+            Exception::BusFault => "BusFault".to_string(),
+            Exception::UsageFault => "UsageFault".to_string(),
+            Exception::Irq(irq) => format!("interrupt {}", irq),
+        }
+    }
+}
+
+impl FromStr for Exception {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "memmanage" => Ok(Self::MemManage),
+            "busfault" => Ok(Self::BusFault),
+            "usagefault" => Ok(Self::UsageFault),
+            other => match other.strip_prefix("irq") {
+                Some(irq) => irq
+                    .parse()
+                    .map(Self::Irq)
+                    .map_err(|_| anyhow!("invalid interrupt number in `{}`", s)),
+                None => bail!(
+                    "unknown exception `{}`; expected memmanage, busfault, usagefault or irq<N>",
+                    s
+                ),
+            },
+        }
+    }
+}
+
+pub struct Catch {
+    exceptions: Vec<Exception>,
+}
+
+impl Catch {
+    pub fn new(exceptions: &[Exception], arch: Arch) -> anyhow::Result<Self> {
+        if !exceptions.is_empty() && arch != Arch::CortexM {
+            bail!("`--catch` is only supported on Cortex-M targets");
+        }
+        Ok(Self {
+            exceptions: exceptions.to_vec(),
+        })
+    }
+
+    /// Programs the vector catch bits and the breakpoints on interrupt handlers of the halted
+    /// `core`; returns the number of breakpoint units it took
+    ///
+    /// `num_in_use` breakpoint units are taken already.
+    pub fn set(
+        &self,
+        core: &mut Core<'_>,
+        entry_point: &EntryPoint,
+        num_in_use: u32,
+    ) -> anyhow::Result<u32> {
+        if self.exceptions.is_empty() {
+            return Ok(0);
+        }
+
+        let vector_catch = self
+            .exceptions
+            .iter()
+            .fold(0, |bits, exception| bits | exception.vector_catch_bits());
+        if vector_catch != 0 {
+            let demcr = core.read_word_32(DEMCR)?;
+            core.write_word_32(DEMCR, demcr | vector_catch)?;
+        }
+        // NOTE clear the sticky status left over from before, so `hit` only sees this run's halt
+        core.write_word_32(DFSR, DFSR_VCATCH | DFSR_BKPT)?;
+
+        let num_available = core.get_available_breakpoint_units()?;
+        let mut num_used = 0;
+        for exception in &self.exceptions {
+            if let Exception::Irq(_) = exception {
+                let vector_table = entry_point
+                    .vector_table
+                    .ok_or_else(|| anyhow!("the vector table is unknown"))?;
+                let handler = core.read_word_32(vector_table + 4 * exception.number())?;
+                if num_in_use + num_used >= num_available {
+                    log::warn!(
+                        "out of hardware breakpoints; {} will NOT be caught",
+                        exception.description()
+                    );
+                    continue;
+                }
+                core.set_hw_breakpoint(cortexm::clear_thumb_bit(handler))?;
+                num_used += 1;
+            }
+        }
+        Ok(num_used)
+    }
+
+    /// The caught exception the halted `core` is sitting at the entry of, if any
+    pub fn hit(&self, core: &mut Core<'_>) -> anyhow::Result<Option<Exception>> {
+        if self.exceptions.is_empty() {
+            return Ok(None);
+        }
+
+        let dfsr = core.read_word_32(DFSR)?;
+        if dfsr & (DFSR_VCATCH | DFSR_BKPT) == 0 {
+            return Ok(None);
+        }
+        let active = core.read_core_reg(cortexm::XPSR)? & cortexm::IPSR_MASK;
+        let caught = self
+            .exceptions
+            .iter()
+            .find(|exception| exception.number() == active)
+            .copied();
+        // NOTE a breakpoint elsewhere in an interrupt handler is not the interrupt's entry
+        if let Some(Exception::Irq(_)) = caught {
+            if dfsr & DFSR_VCATCH == 0 && !self.at_handler_entry(core)? {
+                return Ok(None);
+            }
+        }
+        Ok(caught)
+    }
+
+    fn at_handler_entry(&self, core: &mut Core<'_>) -> anyhow::Result<bool> {
+        let pc = core.read_core_reg(cortexm::PC)?;
+        let vector_table = core.read_word_32(cortexm::VTOR)?;
+        let active = core.read_core_reg(cortexm::XPSR)? & cortexm::IPSR_MASK;
+        let handler = core.read_word_32(vector_table + 4 * active)?;
+        Ok(cortexm::clear_thumb_bit(handler) == cortexm::clear_thumb_bit(pc))
+    }
+
+    /// Clears the vector catch bits; unlike breakpoints they survive a system reset
+    pub fn clear(&self, core: &mut Core<'_>) -> anyhow::Result<()> {
+        let vector_catch = self
+            .exceptions
+            .iter()
+            .fold(0, |bits, exception| bits | exception.vector_catch_bits());
+        if vector_catch != 0 {
+            let demcr = core.read_word_32(DEMCR)?;
+            core.write_word_32(DEMCR, demcr & !vector_catch)?;
+        }
+        Ok(())
+    }
+}
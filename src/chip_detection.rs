@@ -0,0 +1,163 @@
+//! Identifies the chip connected to the probe when `--chip` was not specified
+
+use std::{
+    io::{self, Write as _},
+    ops::Range,
+};
+
+use anyhow::{anyhow, bail};
+use object::{read::File as ElfFile, Object as _, ObjectSegment as _};
+use probe_rs::{
+    config::{registry, MemoryRegion, TargetSelector},
+    DebugProbeInfo, Target,
+};
+
+use crate::arch::EntryPoint;
+
+/// Maximum number of candidates the user is asked to pick from
+const MAX_CANDIDATES: usize = 20;
+
+/// Detects the target chip
+///
+/// First probe-rs reads the debug port's IDCODE and the part numbers in the ROM table and looks
+/// them up in its registry. If that fails, the chips whose memory map can hold the firmware are
+/// offered to the user to pick from; a single one still has to be confirmed, as fitting the memory
+/// map doesn't make it the connected chip. Without a terminal to ask on, `--chip` is required.
+pub fn detect(
+    elf: &ElfFile,
+    entry_point: &EntryPoint,
+    probe_info: &DebugProbeInfo,
+) -> anyhow::Result<Target> {
+    log::info!("`--chip` was not specified; detecting the connected chip");
+
+    // NOTE(scope) the session must be dropped to release the probe
+    {
+        let probe = probe_info.open()?;
+        match probe.attach(TargetSelector::Auto) {
+            Ok(sess) => {
+                let target = sess.target().clone();
+                log::info!("detected chip `{}`", target.name);
+                return Ok(target);
+            }
+            Err(e) => log::debug!("probe-rs could not identify the chip: {}", e),
+        }
+    }
+
+    let candidates = candidates(elf, entry_point)?;
+    let name = match candidates.len() {
+        0 => bail!("could not detect the chip; use `--chip` to specify it"),
+        1 => confirm(&candidates[0])?,
+        n if n > MAX_CANDIDATES => {
+            eprintln!(
+                "the firmware fits these chips (showing {} out of {}):",
+                MAX_CANDIDATES, n
+            );
+            for name in &candidates[..MAX_CANDIDATES] {
+                eprintln!("    {}", name);
+            }
+            bail!("could not detect the chip; use `--chip` to specify it")
+        }
+        _ => prompt(&candidates)?,
+    };
+
+    Ok(registry::get_target_by_name(name)?)
+}
+
+/// Names of the chips whose memory map contains the initial stack pointer and all of the
+/// firmware's loadable segments
+fn candidates(elf: &ElfFile, entry_point: &EntryPoint) -> anyhow::Result<Vec<String>> {
+    let segments = elf
+        .segments()
+        .filter(|segment| segment.size() != 0)
+        .map(|segment| (segment.address(), segment.size()))
+        .collect::<Vec<_>>();
+
+    let mut candidates = vec![];
+    for family in registry::families()? {
+        for variant in family.variants.iter() {
+            // NOTE the initial stack pointer of Cortex-A/R programs may be unknown (`0`)
+            let stack_fits = entry_point.initial_stack_pointer == 0
+                || variant.memory_map.iter().any(|region| match region {
+                    MemoryRegion::Ram(ram) => {
+                        // NOTE stack is full descending; meaning the stack pointer can be
+                        // `ORIGIN(RAM) + LENGTH(RAM)`
+                        (ram.range.start..=ram.range.end)
+                            .contains(&entry_point.initial_stack_pointer)
+                    }
+                    _ => false,
+                });
+
+            let segments_fit = segments.iter().all(|(start, size)| {
+                let end = start + size;
+                variant.memory_map.iter().any(|region| {
+                    let range = region_range(region);
+                    u64::from(range.start) <= *start && end <= u64::from(range.end)
+                })
+            });
+
+            if stack_fits && segments_fit {
+                candidates.push(variant.name.clone());
+            }
+        }
+    }
+
+    Ok(candidates)
+}
+
+fn region_range(region: &MemoryRegion) -> Range<u32> {
+    match region {
+        MemoryRegion::Ram(region) => region.range.clone(),
+        MemoryRegion::Generic(region) => region.range.clone(),
+        MemoryRegion::Nvm(region) => region.range.clone(),
+    }
+}
+
+/// Asks the user whether `candidate`, the only chip that fits the firmware, is the connected one
+fn confirm(candidate: &str) -> anyhow::Result<&str> {
+    if !atty::is(atty::Stream::Stdin) {
+        bail!(
+            "could not detect the chip; the only one that fits the firmware is `{0}`: use \
+            `--chip {0}` if that's the connected chip",
+            candidate
+        );
+    }
+    eprint!(
+        "could not identify the chip; the only one that fits the firmware is `{}`. use it? [y/N] ",
+        candidate
+    );
+    io::stderr().flush()?;
+
+    let mut input = String::new();
+    io::stdin().read_line(&mut input)?;
+    if !matches!(input.trim(), "y" | "Y" | "yes") {
+        bail!("no chip was selected; use `--chip` to specify it");
+    }
+    Ok(candidate)
+}
+
+/// Asks the user to pick one of the `candidates`
+fn prompt(candidates: &[String]) -> anyhow::Result<&str> {
+    if !atty::is(atty::Stream::Stdin) {
+        bail!("could not detect the chip; use `--chip` to specify it");
+    }
+    eprintln!("could not identify the chip; the firmware fits these chips:");
+    for (index, name) in candidates.iter().enumerate() {
+        eprintln!("[{}]: {}", index, name);
+    }
+    eprint!("select a chip: ");
+    io::stderr().flush()?;
+
+    let mut input = String::new();
+    if io::stdin().read_line(&mut input)? == 0 {
+        bail!("no chip was selected; use `--chip` to specify it");
+    }
+
+    let index = input
+        .trim()
+        .parse::<usize>()
+        .ok()
+        .filter(|index| *index < candidates.len())
+        .ok_or_else(|| anyhow!("invalid selection `{}`", input.trim()))?;
+
+    Ok(&candidates[index])
+}
@@ -0,0 +1,89 @@
+//! `--chip-search`: finding the name of a chip in the probe-rs registry
+//!
+//! The chips whose name contains the pattern (ignoring case) are listed with their family. When
+//! only one chip matches, or the pattern is the exact name of a chip, its cores, memory map and
+//! flash algorithms are shown as well, to check the target before the first run.
+
+use std::path::PathBuf;
+
+use anyhow::bail;
+use probe_rs::config::{registry, MemoryRegion};
+
+use crate::target_info;
+
+pub fn search(pattern: &str, chip_descriptions: &[PathBuf]) -> anyhow::Result<()> {
+    target_info::load_chip_descriptions(chip_descriptions)?;
+    let lowercase = pattern.to_lowercase();
+    let mut matches = vec![];
+    for family in registry::families()? {
+        for variant in family.variants.iter() {
+            if variant.name.to_lowercase().contains(&lowercase) {
+                matches.push((family.name.clone(), variant.name.clone()));
+            }
+        }
+    }
+
+    let selected = match &*matches {
+        [] => bail!(
+            "no chip matches `{}`; `--list-chips` lists all of them",
+            pattern
+        ),
+        [(_, name)] => name.clone(),
+        _ => {
+            for (family, name) in &matches {
+                println!("{:<32} ({})", name, family);
+            }
+            match matches
+                .iter()
+                .find(|(_, name)| name.eq_ignore_ascii_case(pattern))
+            {
+                Some((_, name)) => {
+                    println!();
+                    name.clone()
+                }
+                None => return Ok(()),
+            }
+        }
+    };
+
+    print_details(&selected)
+}
+
+/// Prints the cores, memory regions and flash algorithms of chip `name`
+fn print_details(name: &str) -> anyhow::Result<()> {
+    let target = registry::get_target_by_name(name)?;
+    println!("{}", target.name);
+    println!("    core: {:?}", target.core_type);
+
+    println!("    memory map:");
+    for region in &target.memory_map {
+        let (kind, range) = match region {
+            MemoryRegion::Ram(region) => ("RAM", &region.range),
+            MemoryRegion::Nvm(region) => ("FLASH", &region.range),
+            MemoryRegion::Generic(region) => ("other", &region.range),
+        };
+        println!(
+            "        {:<5} 0x{:08X}-0x{:08X} {:>10.02} KiB",
+            kind,
+            range.start,
+            range.end - 1,
+            (range.end - range.start) as f64 / 1024.0
+        );
+    }
+
+    println!("    flash algorithms:");
+    if target.flash_algorithms.is_empty() {
+        println!("        (none; the chip can't be flashed)");
+    }
+    for algorithm in &target.flash_algorithms {
+        let range = &algorithm.flash_properties.address_range;
+        println!(
+            "        {} 0x{:08X}-0x{:08X}{}",
+            algorithm.name,
+            range.start,
+            range.end - 1,
+            if algorithm.default { " (default)" } else { "" }
+        );
+    }
+    Ok(())
+}
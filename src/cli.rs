@@ -0,0 +1,754 @@
+use std::{ops::Range, path::PathBuf, str::FromStr, time::Duration};
+
+use anyhow::anyhow;
+
+use probe_rs::config::registry;
+use regex::Regex;
+use structopt::{clap::AppSettings, StructOpt};
+
+use crate::{
+    artifact,
+    bank::FlashBank,
+    canary::CanaryStrategy,
+    catch::Exception,
+    decoder, exit_code,
+    external_decoder::Decoder,
+    flash_hooks::Hook,
+    image::{self, Preflash},
+    log_filter::LogFilter,
+    option_bytes::OptionWord,
+    preserve::Region,
+    rtt::{ChannelMapping, ChannelSelector},
+    serial::{SerialFormat, SerialSpec},
+    serve::Endpoint,
+    source::SourceMap,
+    speed::Speed,
+    startup, target_info,
+    theme::{self, ColorChoice},
+    timestamp,
+    watch_var::Spec,
+};
+
+/// A Cargo runner for microcontrollers.
+#[derive(StructOpt)]
+#[structopt(
+    name = "probe-run",
+    setting = AppSettings::TrailingVarArg,
+    setting = AppSettings::SubcommandsNegateReqs
+)]
+pub struct Opts {
+    /// List supported chips and exit.
+    #[structopt(long, alias = "chip-list")]
+    pub list_chips: bool,
+
+    /// List the supported chips whose name contains this pattern and exit; shows the memory map
+    /// of the chip if only one matches.
+    #[structopt(long, value_name = "pattern")]
+    pub chip_search: Option<String>,
+
+    /// Lists all the connected probes and exit.
+    #[structopt(long)]
+    pub list_probes: bool,
+
+    /// The chip to program. Detected from the connected target if omitted.
+    #[structopt(long, env = "PROBE_RUN_CHIP")]
+    pub chip: Option<String>,
+
+    /// Load the chips described in a probe-rs target description (YAML) file; can be repeated.
+    #[structopt(long, parse(from_os_str), number_of_values = 1)]
+    pub chip_description: Vec<PathBuf>,
+
+    /// Use this RAM region (`START..END` or `START+LENGTH`) instead of the ones the chip's
+    /// description lists, e.g. when they are wrong; can be repeated.
+    #[structopt(long, parse(try_from_str = image::parse_range), number_of_values = 1)]
+    pub ram_region: Vec<Range<u32>>,
+
+    /// Use this flash region (`START..END` or `START+LENGTH`) instead of the ones the chip's
+    /// description lists; can be repeated.
+    #[structopt(long, parse(try_from_str = image::parse_range), number_of_values = 1)]
+    pub flash_region: Vec<Range<u32>>,
+
+    /// The probe to use (eg. `VID:PID`, `VID:PID:Serial`, just `Serial`, `index:<n>` or an alias).
+    #[structopt(long, env = "PROBE_RUN_PROBE")]
+    pub probe: Option<String>,
+
+    /// Run the program on each of these probes in parallel (comma-separated, like `--probe`).
+    #[structopt(long, use_delimiter = true)]
+    pub probes: Vec<String>,
+
+    /// The probe clock frequency in kHz, or `auto` to use the fastest one that works.
+    #[structopt(long)]
+    pub speed: Option<Speed>,
+
+    /// The core that runs the program.
+    #[structopt(long, default_value = "0")]
+    pub core: usize,
+
+    /// Monitor all the cores of the chip and print a backtrace for each of them.
+    #[structopt(long)]
+    pub all_cores: bool,
+
+    /// Path to an ELF firmware file, or an Intel HEX file.
+    #[structopt(name = "ELF", parse(from_os_str), required_unless_one(&["list-chips", "chip-search", "list-probes", "version", "bin", "example"]))]
+    pub elf: Option<PathBuf>,
+
+    /// Run this binary of the current Cargo workspace instead of the ELF file given by path.
+    #[structopt(long)]
+    pub bin: Option<String>,
+
+    /// Run this example of the current Cargo workspace instead of the ELF file given by path.
+    #[structopt(long, conflicts_with = "bin")]
+    pub example: Option<String>,
+
+    /// With `--bin` or `--example`, run the program built with the `release` profile.
+    #[structopt(long)]
+    pub release: bool,
+
+    /// With `--bin` or `--example`, the target triple the program was built for. Only needed if it
+    /// was built for several.
+    #[structopt(long)]
+    pub target: Option<String>,
+
+    /// Read the symbols and debug info from this ELF file instead, e.g. when the flashed program
+    /// is stripped.
+    #[structopt(long, parse(from_os_str))]
+    pub symbols: Option<PathBuf>,
+
+    /// Program the flash region this flash algorithm covers with it, rather than with the chip's
+    /// default algorithm; can be repeated.
+    #[structopt(long, number_of_values = 1)]
+    pub flash_loader: Vec<String>,
+
+    /// Flash a binary (`<file>@<address>`), HEX or ELF image before the program; can be repeated.
+    #[structopt(long, number_of_values = 1)]
+    pub preflash: Vec<Preflash>,
+
+    /// Keep the contents of a flash region (`START..END`, `START+LENGTH` or a section name such as
+    /// `.nvm`) when flashing; can be repeated.
+    #[structopt(long, number_of_values = 1)]
+    pub preserve: Vec<Region>,
+
+    /// Never write a flash or RAM region (`START..END`, `START+LENGTH` or a section name), e.g. the
+    /// one of a bootloader or SoftDevice; can be repeated.
+    #[structopt(long, number_of_values = 1, conflicts_with_all(&["erase-all", "ram-exec"]))]
+    pub skip_region: Vec<Region>,
+
+    /// Address of the application's vector table when it runs behind a bootloader; the flash
+    /// below it is skipped and the application is started directly.
+    #[structopt(long, parse(try_from_str = image::parse_address), conflicts_with_all(&["erase-all", "ram-exec"]))]
+    pub app_offset: Option<u32>,
+
+    /// Write a word of the nRF UICR or the STM32F4 option bytes (`ADDRESS=VALUE`) after flashing;
+    /// can be repeated.
+    #[structopt(long, number_of_values = 1)]
+    pub write_uicr: Vec<OptionWord>,
+
+    /// Let `--write-uicr` lock the chip for good, i.e. set RDP level 2 on STM32F4.
+    #[structopt(long)]
+    pub allow_permanent_lock: bool,
+
+    /// Skip writing the application binary to flash.
+    #[structopt(long, conflicts_with = "defmt")]
+    pub no_flash: bool,
+
+    /// Memory accesses done on the target while flashing; only set by the configuration file
+    #[structopt(skip)]
+    pub flash_hooks: Vec<Hook>,
+
+    /// Check the program and the configuration against the chip, and print what would be flashed,
+    /// without connecting to a probe.
+    #[structopt(long)]
+    pub dry_run: bool,
+
+    /// With `--no-flash` or `--attach`, decode the defmt logs even if the firmware on the device
+    /// doesn't match the ELF file.
+    #[structopt(long)]
+    pub force_decode: bool,
+
+    /// Attach to the running program without flashing or resetting the device.
+    #[structopt(long, conflicts_with = "no-flash")]
+    pub attach: bool,
+
+    /// Load the program into RAM and run it from there, without touching the flash; the program
+    /// must be linked to run from RAM.
+    #[structopt(long, conflicts_with_all(&["no-flash", "attach", "erase-all", "skip-unchanged", "verify", "reset-type", "write-uicr"]))]
+    pub ram_exec: bool,
+
+    /// Flash bank to program on dual-bank STM32H7 and STM32L4 chips: `inactive` programs the bank
+    /// the chip doesn't boot from and swaps the banks, so the old program survives a failed update.
+    #[structopt(long, possible_values = &["active", "inactive"], conflicts_with_all(&["no-flash", "attach", "ram-exec", "erase-all", "skip-unchanged", "verify", "preserve"]))]
+    pub flash_bank: Option<FlashBank>,
+
+    /// Erase the whole flash, not just the sectors the program occupies, before flashing.
+    #[structopt(long, conflicts_with_all(&["no-flash", "attach"]))]
+    pub erase_all: bool,
+
+    /// Remove the readout protection of a locked chip (nRF APPROTECT, STM32F4 RDP level 1); this
+    /// erases the chip.
+    #[structopt(long, conflicts_with = "attach")]
+    pub chip_unlock: bool,
+
+    /// Skip flashing if the device's flash already holds the program.
+    #[structopt(long, conflicts_with_all(&["no-flash", "attach"]))]
+    pub skip_unchanged: bool,
+
+    /// Read back the flash after programming it and fail if it doesn't hold the program.
+    #[structopt(long, conflicts_with_all(&["no-flash", "attach"]))]
+    pub verify: bool,
+
+    /// Don't use or update the state cached by previous runs (probe speed, flashed program, RTT
+    /// control block address).
+    #[structopt(long)]
+    pub no_cache: bool,
+
+    /// Log how long erasing, programming and verifying the flash took.
+    #[structopt(long, conflicts_with_all(&["no-flash", "attach", "ram-exec"]))]
+    pub timings: bool,
+
+    /// Print how long the run took, broken down into attaching, flashing, the time from the reset
+    /// to the first log data and streaming the logs, and the number of defmt frames.
+    #[structopt(long)]
+    pub stats: bool,
+
+    /// Unwind the Secure state's stack on ARMv8-M chips with TrustZone.
+    #[structopt(long, conflicts_with = "non-secure")]
+    pub secure: bool,
+
+    /// Unwind the Non-secure state's stack on ARMv8-M chips with TrustZone.
+    #[structopt(long)]
+    pub non_secure: bool,
+
+    /// Retry this many times, with exponential backoff, when the probe fails (e.g. USB or SWD
+    /// errors); the probe is opened again and the program restarted, or re-attached to with
+    /// `--attach`.
+    #[structopt(long, default_value = "0")]
+    pub retries: u32,
+
+    /// Shell command to run before connecting to the probe, e.g. to power-cycle the board; see the
+    /// README for the environment variables it gets.
+    #[structopt(long)]
+    pub pre_run: Option<String>,
+
+    /// Shell command to run once the program has ended, with `PROBE_RUN_EXIT_CODE` set.
+    #[structopt(long)]
+    pub post_run: Option<String>,
+
+    /// Connect to device when NRST is pressed.
+    #[structopt(long)]
+    pub connect_under_reset: bool,
+
+    /// Don't connect under reset, even if the configuration file says so.
+    #[structopt(long, conflicts_with = "connect-under-reset")]
+    pub no_connect_under_reset: bool,
+
+    /// Switch the target's power off and on again before connecting to it; J-Link only, through
+    /// the 5 V supply on pin 19.
+    #[structopt(long)]
+    pub power_cycle: bool,
+
+    /// Drive the probe's TRST pin high while the program runs and pulse it if the run fails, e.g.
+    /// to light an LED (J-Link, CMSIS-DAP v1).
+    #[structopt(long)]
+    pub run_indicator: bool,
+
+    /// How to reset the device before running the program: `hardware` (NRST pin), `software`
+    /// (the default) or `none` (jump to the reset handler).
+    #[structopt(long, possible_values = &["hardware", "software", "none"])]
+    pub reset_type: Option<ResetType>,
+
+    /// Print how much of each flash and RAM region the program occupies before flashing it.
+    #[structopt(long)]
+    pub print_memory_usage: bool,
+
+    /// Reset the device and let it run when `probe-run` exits, instead of leaving it halted.
+    #[structopt(long)]
+    pub reset_on_exit: bool,
+
+    /// Paint the whole stack before running the program and report its maximum stack usage; same
+    /// as `--canary-strategy full`.
+    #[structopt(long, conflicts_with = "canary-strategy")]
+    pub measure_stack: bool,
+
+    /// How much of the stack to paint: `full` (reports the stack usage, slower start), `sentinel`
+    /// (a band at the bottom of the stack; only detects overflows; the default) or `none`.
+    #[structopt(long, possible_values = &["full", "sentinel", "none"])]
+    pub canary_strategy: Option<CanaryStrategy>,
+
+    /// Size of the band at the bottom of the stack that counts as a stack overflow when touched.
+    /// Defaults to 10% of the stack, at most 1 KiB.
+    #[structopt(long)]
+    pub canary_size: Option<u32>,
+
+    /// Fail before flashing if less than this many bytes of RAM are left for the stack, e.g.
+    /// because `.bss` grew.
+    #[structopt(long)]
+    pub min_stack: Option<u32>,
+
+    /// Warn before flashing if the static data and the heap leave less than this many bytes of
+    /// RAM for the stack. Defaults to 1 KiB.
+    #[structopt(long)]
+    pub stack_threshold: Option<u32>,
+
+    /// Fail instead of warning when the stack gets less RAM than `--stack-threshold`.
+    #[structopt(long)]
+    pub strict_memory: bool,
+
+    /// Only warn about the stack's RAM, even if the configuration file sets `strict-memory`.
+    #[structopt(long, conflicts_with = "strict-memory")]
+    pub no_strict_memory: bool,
+
+    /// Report the program's peak heap usage and the fragmentation of its heap when it ends.
+    #[structopt(long)]
+    pub measure_heap: bool,
+
+    /// With `--measure-heap`, also print the heap usage at this interval while the program runs.
+    #[structopt(long, requires = "measure-heap", parse(try_from_str = humantime::parse_duration))]
+    pub heap_interval: Option<Duration>,
+
+    /// Set a breakpoint on the panic handler (`rust_begin_unwind`), so that a panic ends the run
+    /// with an error even if the panic handler spins instead of halting the core.
+    #[structopt(long)]
+    pub break_on_panic: bool,
+
+    /// CMSIS-SVD file that describes the chip's peripherals, for `--dump-peripherals`.
+    #[structopt(long, parse(from_os_str))]
+    pub svd: Option<PathBuf>,
+
+    /// Print these peripherals (`USART2`) or registers (`USART2.SR`) when the program crashes
+    /// (comma separated); needs `--svd`.
+    #[structopt(long, use_delimiter = true)]
+    pub dump_peripherals: Vec<String>,
+
+    /// Halt on these exceptions and end the run with a backtrace: memmanage, busfault,
+    /// usagefault or irq<N> (comma separated).
+    #[structopt(long, use_delimiter = true)]
+    pub catch: Vec<Exception>,
+
+    /// End the run with an error when the program reaches the function with this symbol name;
+    /// can be repeated.
+    #[structopt(long, number_of_values = 1)]
+    pub break_on: Vec<String>,
+
+    /// Write an ELF core dump of the registers and RAM to this path if the program crashes.
+    #[structopt(long, parse(from_os_str))]
+    pub core_dump: Option<PathBuf>,
+
+    /// Allow the program to open the files in this directory through semihosting.
+    #[structopt(long, parse(from_os_str))]
+    pub semihosting_fs: Option<PathBuf>,
+
+    /// Open an interactive prompt to inspect the device (memory, registers, backtrace, variables)
+    /// after the program halted; stdin is not forwarded to the device.
+    #[structopt(long, conflicts_with_all(&["interactive", "gdb-on-crash"]))]
+    pub repl: bool,
+
+    /// Keep the core halted after a crash and serve it to GDB.
+    #[structopt(long)]
+    pub gdb_on_crash: bool,
+
+    /// Address the GDB server listens on.
+    #[structopt(long, default_value = "127.0.0.1:1337")]
+    pub gdb_connection_string: String,
+
+    /// Collect ITM packets over SWO and print the output of the stimulus ports (Cortex-M only).
+    #[structopt(long, requires = "core-freq")]
+    pub itm: bool,
+
+    /// With `--itm`, trace exception entries and exits and print how often and how long each
+    /// interrupt handler ran.
+    #[structopt(long, requires = "itm")]
+    pub irq_profile: bool,
+
+    /// Measure the cycles between calls to the program's `bench_start` and `bench_stop`
+    /// functions.
+    #[structopt(long, conflicts_with = "attach")]
+    pub bench: bool,
+
+    /// Frequency of the core clock in Hz, used to configure SWO and to convert `--bench` cycles.
+    #[structopt(long)]
+    pub core_freq: Option<u32>,
+
+    /// Baud rate of SWO.
+    #[structopt(long, default_value = "2000000")]
+    pub swo_baud: u32,
+
+    /// Only print the defmt frames that pass this filter (e.g. `my_crate::radio=trace,warn`).
+    #[structopt(long, env = "PROBE_RUN_LOG_FILTER")]
+    pub log_filter: Option<LogFilter>,
+
+    /// Timestamps printed in front of the defmt frames: `target` (the firmware's, the default),
+    /// `host` (the host's wall clock) or `both`, plus the drift between the two clocks.
+    #[structopt(long, default_value = "target", possible_values = &["host", "target", "both"])]
+    pub timestamp: timestamp::Mode,
+
+    /// Broadcast the decoded defmt frames, as JSON lines, to the clients of a socket, e.g.
+    /// `probe-run tail`: `[tcp://]<address>:<port>` or `unix:<path>`; can be repeated.
+    #[structopt(long, alias = "publish", number_of_values = 1)]
+    pub serve: Vec<Endpoint>,
+
+    /// With `--serve`, send the raw defmt stream instead of the decoded frames.
+    #[structopt(long, requires = "serve")]
+    pub serve_raw: bool,
+
+    /// Answer `GET /symbolize?address=<address>` requests of other host tools with the function
+    /// and source location at the address, on this port of localhost.
+    #[structopt(long)]
+    pub symbolizer_port: Option<u16>,
+
+    /// Print how many defmt frames each level, module and call site logged when the program ends.
+    #[structopt(long)]
+    pub log_stats: bool,
+
+    /// Write the numeric samples the program logs (defmt messages starting with `@plot` and lines
+    /// of RTT channels mapped to `plot`) to this file; CSV if it ends in `.csv`, NDJSON otherwise.
+    #[structopt(long, parse(from_os_str))]
+    pub plot_out: Option<PathBuf>,
+
+    /// Also write the program's output (decoded defmt frames and text) to this file, without ANSI
+    /// escape sequences.
+    #[structopt(long, parse(from_os_str))]
+    pub log_file: Option<PathBuf>,
+
+    /// Rotate the `--log-file` once it would grow beyond this many bytes.
+    #[structopt(long, requires = "log-file")]
+    pub log_file_max_size: Option<u64>,
+
+    /// Rotate the `--log-file` once it is this old (e.g. `1h`).
+    #[structopt(long, requires = "log-file", parse(try_from_str = humantime::parse_duration))]
+    pub log_file_rotate: Option<Duration>,
+
+    /// Only write the program's output to the `--log-file`, not to stdout.
+    #[structopt(long, requires = "log-file")]
+    pub quiet_stdout: bool,
+
+    /// Don't decode the defmt frames while the program runs; spill them to a file and decode them
+    /// once it has ended, so that RTT is drained at full speed.
+    #[structopt(long, conflicts_with_all(&["success-pattern", "failure-pattern"]))]
+    pub defer_decode: bool,
+
+    /// Record the data received over RTT to this file; see the `replay` subcommand.
+    #[structopt(long, parse(from_os_str))]
+    pub record: Option<PathBuf>,
+
+    /// If the program hasn't reached `main` after this long, e.g. because it hangs while starting
+    /// up, report where it is; again after every further period.
+    #[structopt(long, default_value = "5s", parse(try_from_str = startup::parse_grace_period))]
+    pub startup_grace_period: Duration,
+
+    /// Stop the program after this long (e.g. `30s`, `5min`) and exit with code 124 (by default).
+    #[structopt(long, parse(try_from_str = humantime::parse_duration))]
+    pub timeout: Option<Duration>,
+
+    /// Fail once the program reset itself more than this many times (Cortex-M); by default
+    /// probe-run re-attaches after every reset.
+    #[structopt(long)]
+    pub max_resets: Option<u32>,
+
+    /// Detach and exit with code 0 when a log line matches this regular expression.
+    #[structopt(long)]
+    pub success_pattern: Option<Regex>,
+
+    /// Detach and exit with code 1 when a log line matches this regular expression.
+    #[structopt(long)]
+    pub failure_pattern: Option<Regex>,
+
+    /// Who decodes the defmt frames: `builtin` (the default) or `external:<cmd>`, a shell command
+    /// that reads the raw frames from its stdin and prints the logs, e.g. `external:defmt-print -e
+    /// target/thumbv7em-none-eabihf/debug/app`. `PROBE_RUN_ELF` is set for the command.
+    #[structopt(long)]
+    pub decoder: Option<Decoder>,
+
+    /// Exit with this code when the run ends for this reason (`REASON=CODE`, e.g.
+    /// `stack_overflow=3`); can be repeated. The reasons are those of the `exit` JSON record.
+    #[structopt(long, number_of_values = 1)]
+    pub exit_code_map: Vec<exit_code::Mapping>,
+
+    /// Keep running; reflash and restart the program whenever the ELF file changes.
+    #[structopt(long, conflicts_with_all(&["no-flash", "attach"]))]
+    pub watch: bool,
+
+    /// Only run the tests of a `defmt-test` binary whose name contains this string, like
+    /// `cargo test <filter>`.
+    #[structopt(long)]
+    pub test_filter: Option<String>,
+
+    /// Write the results of a `defmt-test` binary to this file, as JUnit XML.
+    #[structopt(long, parse(from_os_str))]
+    pub junit: Option<PathBuf>,
+
+    /// Print the value of a global variable whenever it changes: `NAME[:TYPE][@INTERVAL]`, e.g.
+    /// `HEAP_USED:u32@1s`; can be repeated.
+    #[structopt(long, number_of_values = 1)]
+    pub watch_var: Vec<Spec>,
+
+    /// Enable more verbose logging.
+    #[structopt(short, long, parse(from_occurrences))]
+    pub verbose: u32,
+
+    /// Prints version information
+    #[structopt(short = "V", long)]
+    pub version: bool,
+
+    /// Print a backtrace even if the program ran successfully
+    #[structopt(long)]
+    pub force_backtrace: bool,
+
+    /// Configure the number of frames to unwind before a backtrace gets cut off
+    #[structopt(long, default_value = "2000")]
+    pub max_backtrace_len: u32,
+
+    /// Print at most this many backtrace frames, the first and the last ones; 0 prints all of
+    /// them.
+    #[structopt(long, default_value = "50")]
+    pub backtrace_limit: u32,
+
+    /// Backtrace format: `pretty`, `compact`, `gdb` or a `json` array.
+    #[structopt(long, default_value = "pretty", possible_values = &["pretty", "compact", "gdb", "json"])]
+    pub backtrace_format: BacktraceFormat,
+
+    /// Print this many lines of source code before and after the line of each backtrace frame.
+    #[structopt(long, default_value = "0")]
+    pub backtrace_context: u32,
+
+    /// On a crash, disassemble the code around the instruction that faulted.
+    #[structopt(long)]
+    pub disassemble: bool,
+
+    /// Look up the source files under `OLD` in `NEW` instead, e.g. for code built in a container
+    /// (`OLD=NEW`); can be repeated.
+    #[structopt(long, number_of_values = 1)]
+    pub source_map: Vec<SourceMap>,
+
+    /// The RTT up channel to read the logs from: `<index>` or `name=<name>`.
+    #[structopt(long, default_value = "0")]
+    pub rtt_channel: ChannelSelector,
+
+    /// Route an additional RTT up channel, given by its index or its name: `<channel>=defmt`,
+    /// `<channel>=text` or `<channel>=file:<path>` (can be used multiple times).
+    #[structopt(long, number_of_values = 1)]
+    pub rtt_map: Vec<ChannelMapping>,
+
+    /// Print how much data each RTT channel transferred when the program ends.
+    #[structopt(long)]
+    pub rtt_stats: bool,
+
+    /// Also read logs from this serial port (`PORT[:BAUD]`, e.g. `/dev/ttyACM0:115200`), e.g. if
+    /// the board has no RTT support.
+    #[structopt(long)]
+    pub serial: Option<SerialSpec>,
+
+    /// How to decode the data received over `--serial`: `text` or `defmt`.
+    #[structopt(long, default_value = "text", possible_values = &["text", "defmt"])]
+    pub serial_format: SerialFormat,
+
+    /// Symbol of the buffer the program receives its arguments in.
+    #[structopt(long, default_value = "PROBE_RUN_ARGS")]
+    pub args_symbol: String,
+
+    /// Forward the contents of this file, instead of stdin, to RTT down channel 0.
+    #[structopt(long, parse(from_os_str))]
+    pub input_file: Option<PathBuf>,
+
+    /// Send keystrokes to RTT down channel 0 as they are typed, without waiting for Enter.
+    #[structopt(long, conflicts_with = "input-file")]
+    pub interactive: bool,
+
+    /// Ask the running program to change the level of its defmt run-time filter (`trace`,
+    /// `debug`, `info`, `warn` or `error`) over its `defmt-level` RTT down channel.
+    #[structopt(long, possible_values = &["trace", "debug", "info", "warn", "error"])]
+    pub set_log_level: Option<log::Level>,
+
+    /// Output format: `human` or newline-delimited `json`
+    #[structopt(long, default_value = "human", possible_values = &["human", "json"])]
+    pub message_format: MessageFormat,
+
+    /// Whether to color the output: `auto` colors a terminal unless `NO_COLOR` is set (or
+    /// `CLICOLOR_FORCE` is), `always` or `never`.
+    #[structopt(long, default_value = "auto", possible_values = &["auto", "always", "never"])]
+    pub color: ColorChoice,
+
+    /// Link the source locations of log and backtrace frames (OSC 8), if the terminal can show
+    /// hyperlinks.
+    #[structopt(long)]
+    pub hyperlinks: bool,
+
+    /// URL of the `--hyperlinks`, with the placeholders `{path}` and `{line}`, e.g.
+    /// `vscode://file/{path}:{line}` [default: `file://{path}`]
+    #[structopt(long, requires = "hyperlinks")]
+    pub hyperlink_url: Option<String>,
+
+    /// How to style the output: `default`, or `ascii` to draw only with ASCII characters.
+    #[structopt(long, default_value = "default", parse(try_from_str = theme::parse_name))]
+    pub theme: String,
+
+    #[structopt(subcommand)]
+    pub subcommand: Option<Subcommand>,
+
+    /// Arguments passed after the ELF file path are forwarded to the program through the buffer
+    /// named by `--args-symbol`
+    #[structopt(name = "ARGS")]
+    pub args: Vec<String>,
+}
+
+impl Opts {
+    /// The security state selected with `--secure` or `--non-secure`, if any; `true` if Secure
+    pub fn security_state(&self) -> Option<bool> {
+        match (self.secure, self.non_secure) {
+            (true, _) => Some(true),
+            (_, true) => Some(false),
+            _ => None,
+        }
+    }
+
+    /// The workspace binary or example selected with `--bin` or `--example`
+    pub fn artifact(&self) -> Option<(artifact::Kind, &str)> {
+        match (&self.bin, &self.example) {
+            (Some(name), _) => Some((artifact::Kind::Bin, name)),
+            (None, Some(name)) => Some((artifact::Kind::Example, name)),
+            (None, None) => None,
+        }
+    }
+
+    /// The canary strategy selected with `--canary-strategy` or `--measure-stack`
+    pub fn canary_strategy(&self) -> CanaryStrategy {
+        match self.canary_strategy {
+            Some(strategy) => strategy,
+            None if self.measure_stack => CanaryStrategy::Full,
+            None => CanaryStrategy::Sentinel,
+        }
+    }
+}
+
+#[derive(StructOpt)]
+pub enum Subcommand {
+    /// List all the connected probes.
+    ListProbes,
+    /// Decode and print a recording made with `--record`, without a target.
+    Replay {
+        /// Path to the recording.
+        #[structopt(parse(from_os_str))]
+        recording: PathBuf,
+
+        /// Path to the ELF firmware file the recording was made with.
+        #[structopt(parse(from_os_str))]
+        elf: PathBuf,
+    },
+    /// Halt the core of a device that is already running (or wedged) and print its backtrace,
+    /// without flashing or resetting it.
+    Backtrace {
+        /// Path to the ELF firmware file the device runs.
+        #[structopt(parse(from_os_str))]
+        elf: PathBuf,
+    },
+    /// Follow the decoded defmt frames that another probe-run serves with `--publish`, without
+    /// touching the probe.
+    Tail {
+        /// The socket the frames are served on: `[tcp://]<address>:<port>` or `unix:<path>`.
+        endpoint: Endpoint,
+    },
+    /// Run the programs listed in a manifest one after the other, on the same probe, and print a
+    /// summary.
+    Batch {
+        /// Path to the manifest: one ELF file per line; `*` in a file name matches any characters.
+        #[structopt(parse(from_os_str))]
+        manifest: PathBuf,
+
+        /// Write the log and the core dump (if it crashes) of each program into this directory.
+        #[structopt(long, parse(from_os_str))]
+        artifacts: Option<PathBuf>,
+    },
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BacktraceFormat {
+    Pretty,
+    Compact,
+    Gdb,
+    Json,
+}
+
+impl FromStr for BacktraceFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "pretty" => Ok(Self::Pretty),
+            "compact" => Ok(Self::Compact),
+            "gdb" => Ok(Self::Gdb),
+            "json" => Ok(Self::Json),
+            _ => Err(anyhow!("unknown backtrace format `{}`", s)),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ResetType {
+    /// Pulse the reset pin by connecting under reset
+    Hardware,
+    /// Request a system reset through the debug interface
+    Software,
+    /// Don't reset; start the program at its reset handler with the current peripheral state
+    None,
+}
+
+impl Default for ResetType {
+    fn default() -> Self {
+        Self::Software
+    }
+}
+
+impl FromStr for ResetType {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "hardware" => Ok(Self::Hardware),
+            "software" => Ok(Self::Software),
+            "none" => Ok(Self::None),
+            _ => Err(anyhow!("unknown reset type `{}`", s)),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MessageFormat {
+    Human,
+    Json,
+}
+
+impl FromStr for MessageFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "human" => Ok(Self::Human),
+            "json" => Ok(Self::Json),
+            _ => Err(anyhow!("unknown message format `{}`", s)),
+        }
+    }
+}
+
+/// Lists the chips of the probe-rs registry and of the `chip_descriptions` files
+pub fn print_chips(chip_descriptions: &[PathBuf]) -> anyhow::Result<()> {
+    target_info::load_chip_descriptions(chip_descriptions)?;
+    let registry = registry::families().expect("Could not retrieve chip family registry");
+    for chip_family in registry {
+        println!("{}\n    Variants:", chip_family.name);
+        for variant in chip_family.variants.iter() {
+            println!("        {}", variant.name);
+        }
+    }
+    Ok(())
+}
+
+/// The string reported by the `--version` flag
+pub fn print_version() {
+    const VERSION: &str = env!("CARGO_PKG_VERSION"); // version from Cargo.toml e.g. "0.1.4"
+    const HASH: &str = include_str!(concat!(env!("OUT_DIR"), "/git-info.txt")); // "" OR git hash e.g. "34019f8" -- this is generated in build.rs
+    println!(
+        "{}{}\nsupported defmt versions: {}",
+        VERSION,
+        HASH,
+        decoder::SUPPORTED_VERSIONS.join(", ")
+    );
+}
@@ -0,0 +1,245 @@
+//! Settings loaded from `.probe-run.toml` and `Embed.toml` files
+//!
+//! Command line flags (and the environment variables backing them) take precedence over
+//! `.probe-run.toml`, which takes precedence over `Embed.toml`.
+
+use std::{
+    collections::BTreeMap,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use anyhow::Context as _;
+use serde::Deserialize;
+
+use crate::{cli::Opts, exit_code, flash_hooks, image, speed::Speed};
+
+const CONFIG_FILE_NAME: &str = ".probe-run.toml";
+const EMBED_FILE_NAME: &str = "Embed.toml";
+
+#[derive(Default, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Config {
+    chip: Option<String>,
+    /// Directory of probe-rs target description (YAML) files, relative to the configuration file
+    chip_descriptions: Option<PathBuf>,
+    /// Corrected memory regions of the chip (`START..END`), like `--ram-region`/`--flash-region`
+    ram_regions: Vec<String>,
+    flash_regions: Vec<String>,
+    probe: Option<String>,
+    /// Probes to run the program on in parallel, like `--probes`
+    probes: Vec<String>,
+    /// CMSIS-SVD file of the chip, relative to the configuration file
+    svd: Option<PathBuf>,
+    speed: Option<u32>,
+    /// Bytes of RAM the stack needs at least, like `--min-stack`
+    min_stack: Option<u32>,
+    /// Like `--stack-threshold` and `--strict-memory`
+    stack_threshold: Option<u32>,
+    strict_memory: Option<bool>,
+    /// `full`, `sentinel` or `none`, like `--canary-strategy`
+    canary_strategy: Option<String>,
+    canary_size: Option<u32>,
+    connect_under_reset: Option<bool>,
+    reset_type: Option<String>,
+    log_filter: Option<String>,
+    /// UICR or option byte words (`ADDRESS=VALUE`) to write after flashing, like `--write-uicr`
+    option_bytes: Vec<String>,
+    /// Regions of a bootloader or SoftDevice, like `--skip-region`
+    skip_regions: Vec<String>,
+    /// Address of the application behind the bootloader, like `--app-offset`
+    app_offset: Option<u32>,
+    /// Shell commands run before connecting to the probe and after the program ended
+    pre_run: Option<String>,
+    post_run: Option<String>,
+    /// Names for probe selectors, usable with `--probe`
+    probe_aliases: BTreeMap<String, String>,
+    /// Exit codes by reason, like `--exit-code-map`
+    exit_codes: BTreeMap<String, i32>,
+    /// Memory accesses done at fixed points of the flash phase; there's no command line flag
+    flash_hooks: Vec<flash_hooks::Hook>,
+}
+
+/// The parts of cargo-embed's configuration file that `probe-run` understands
+#[derive(Default, Deserialize)]
+#[serde(default)]
+struct Embed {
+    default: EmbedProfile,
+}
+
+#[derive(Default, Deserialize)]
+#[serde(default)]
+struct EmbedProfile {
+    general: EmbedGeneral,
+    probe: EmbedProbe,
+}
+
+#[derive(Default, Deserialize)]
+#[serde(default)]
+struct EmbedGeneral {
+    chip: Option<String>,
+    connect_under_reset: Option<bool>,
+}
+
+#[derive(Default, Deserialize)]
+#[serde(default)]
+struct EmbedProbe {
+    probe_selector: Option<String>,
+    speed: Option<u32>,
+}
+
+impl Config {
+    /// Loads the configuration files found in the directory of `elf_path` or any of its ancestors
+    pub fn load(elf_path: &Path) -> anyhow::Result<Self> {
+        let mut config = match find(elf_path, CONFIG_FILE_NAME)? {
+            Some(path) => {
+                let mut config = toml::from_str::<Self>(&fs::read_to_string(&path)?)
+                    .with_context(|| format!("failed to parse {}", path.display()))?;
+                // NOTE(unwrap) `find` returns a file inside a directory
+                let dir = path.parent().unwrap();
+                config.chip_descriptions = config.chip_descriptions.map(|path| dir.join(path));
+                config.svd = config.svd.map(|path| dir.join(path));
+                config
+            }
+            None => Self::default(),
+        };
+
+        if let Some(path) = find(elf_path, EMBED_FILE_NAME)? {
+            let embed = toml::from_str::<Embed>(&fs::read_to_string(&path)?)
+                .with_context(|| format!("failed to parse {}", path.display()))?
+                .default;
+            config.chip = config.chip.or(embed.general.chip);
+            config.probe = config.probe.or(embed.probe.probe_selector);
+            config.speed = config.speed.or(embed.probe.speed);
+            config.connect_under_reset = config
+                .connect_under_reset
+                .or(embed.general.connect_under_reset);
+        }
+
+        Ok(config)
+    }
+
+    /// Fills in the settings that were not passed on the command line
+    pub fn apply(self, opts: &mut Opts) -> anyhow::Result<()> {
+        opts.chip = opts.chip.take().or(self.chip);
+        if let Some(dir) = &self.chip_descriptions {
+            opts.chip_description.extend(yaml_files(dir)?);
+        }
+        if opts.ram_region.is_empty() {
+            opts.ram_region = self
+                .ram_regions
+                .iter()
+                .map(|region| image::parse_range(region))
+                .collect::<Result<_, _>>()?;
+        }
+        if opts.flash_region.is_empty() {
+            opts.flash_region = self
+                .flash_regions
+                .iter()
+                .map(|region| image::parse_range(region))
+                .collect::<Result<_, _>>()?;
+        }
+        // NOTE an explicit `--probe` overrides the probes of the configuration file
+        if opts.probes.is_empty() && opts.probe.is_none() {
+            opts.probes = self.probes;
+        }
+        opts.probe = opts.probe.take().or(self.probe);
+        if let Some(selector) = opts
+            .probe
+            .as_ref()
+            .and_then(|probe| self.probe_aliases.get(probe))
+        {
+            opts.probe = Some(selector.clone());
+        }
+        for probe in &mut opts.probes {
+            if let Some(selector) = self.probe_aliases.get(probe) {
+                *probe = selector.clone();
+            }
+        }
+        opts.svd = opts.svd.take().or(self.svd);
+        opts.pre_run = opts.pre_run.take().or(self.pre_run);
+        opts.post_run = opts.post_run.take().or(self.post_run);
+        opts.speed = opts.speed.or_else(|| self.speed.map(Speed::Khz));
+        opts.min_stack = opts.min_stack.or(self.min_stack);
+        opts.stack_threshold = opts.stack_threshold.or(self.stack_threshold);
+        // NOTE the `--no-…` flags override a `true` of the configuration file
+        opts.strict_memory |= !opts.no_strict_memory && self.strict_memory.unwrap_or(false);
+        // NOTE `--measure-stack` selects the `full` strategy
+        if let (None, false, Some(strategy)) = (
+            &opts.canary_strategy,
+            opts.measure_stack,
+            self.canary_strategy,
+        ) {
+            opts.canary_strategy = Some(strategy.parse()?);
+        }
+        opts.canary_size = opts.canary_size.or(self.canary_size);
+        opts.connect_under_reset |=
+            !opts.no_connect_under_reset && self.connect_under_reset.unwrap_or(false);
+        if let (None, Some(reset_type)) = (&opts.reset_type, self.reset_type) {
+            opts.reset_type = Some(reset_type.parse()?);
+        }
+        if let (None, Some(log_filter)) = (&opts.log_filter, self.log_filter) {
+            opts.log_filter = Some(log_filter.parse()?);
+        }
+        // NOTE the mappings on the command line come last, so they take precedence
+        let mut exit_code_map = self
+            .exit_codes
+            .iter()
+            .map(|(reason, code)| exit_code::Mapping::new(reason, *code))
+            .collect::<Result<Vec<_>, _>>()?;
+        exit_code_map.append(&mut opts.exit_code_map);
+        opts.exit_code_map = exit_code_map;
+        if opts.write_uicr.is_empty() {
+            opts.write_uicr = self
+                .option_bytes
+                .iter()
+                .map(|word| word.parse())
+                .collect::<Result<_, _>>()?;
+        }
+        if opts.skip_region.is_empty() {
+            opts.skip_region = self
+                .skip_regions
+                .iter()
+                .map(|region| region.parse())
+                .collect::<Result<_, _>>()?;
+        }
+        opts.app_offset = opts.app_offset.or(self.app_offset);
+        if opts.flash_hooks.is_empty() {
+            opts.flash_hooks = self.flash_hooks;
+        }
+        Ok(())
+    }
+}
+
+/// Lists the YAML files in `dir`, sorted by name
+fn yaml_files(dir: &Path) -> anyhow::Result<Vec<PathBuf>> {
+    let mut paths = vec![];
+    for entry in fs::read_dir(dir)
+        .with_context(|| format!("failed to read the chip descriptions in {}", dir.display()))?
+    {
+        let path = entry?.path();
+        if matches!(
+            path.extension().and_then(|ext| ext.to_str()),
+            Some("yaml") | Some("yml")
+        ) {
+            paths.push(path);
+        }
+    }
+    paths.sort();
+    Ok(paths)
+}
+
+/// Walks up from the directory that contains `elf_path` looking for a file named `file_name`
+fn find(elf_path: &Path, file_name: &str) -> anyhow::Result<Option<PathBuf>> {
+    let elf_path = elf_path.canonicalize()?;
+
+    for dir in elf_path.ancestors().skip(1) {
+        let path = dir.join(file_name);
+        if path.is_file() {
+            log::debug!("using configuration file {}", path.display());
+            return Ok(Some(path));
+        }
+    }
+
+    Ok(None)
+}
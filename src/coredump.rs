@@ -0,0 +1,178 @@
+//! Writes the state of a halted target to an ELF core file that GDB can load
+
+use std::{fs, path::Path};
+
+use anyhow::bail;
+
+use probe_rs::{config::MemoryRegion, Core, CoreRegisterAddress, MemoryInterface};
+
+use crate::{arch::Arch, riscv, target_info::TargetInfo};
+
+const ELF_HEADER_SIZE: u32 = 52;
+const PROGRAM_HEADER_SIZE: u32 = 32;
+
+const ET_CORE: u16 = 4;
+const EM_ARM: u16 = 40;
+const EM_RISCV: u16 = 243;
+
+const PT_LOAD: u32 = 1;
+const PT_NOTE: u32 = 4;
+const PF_RWX: u32 = 0b111;
+
+const NT_PRSTATUS: u32 = 1;
+/// Size of the `elf_prstatus` fields that precede `pr_reg`
+const PRSTATUS_HEADER_SIZE: usize = 72;
+
+/// `xPSR` as numbered by the Debug Core Register Selector Register
+const XPSR: CoreRegisterAddress = CoreRegisterAddress(16);
+
+/// Dumps the registers and all RAM regions of the target into the core file at `path`
+pub fn write(
+    path: &Path,
+    core: &mut Core<'_>,
+    arch: Arch,
+    target_info: &TargetInfo,
+) -> anyhow::Result<()> {
+    let registers = read_registers(core, arch)?;
+
+    let mut segments = vec![];
+    for region in &target_info.probe_target.memory_map {
+        if let MemoryRegion::Ram(ram) = region {
+            let mut data = vec![0; (ram.range.end - ram.range.start) as usize];
+            match core.read_8(ram.range.start, &mut data) {
+                Ok(()) => segments.push((ram.range.start, data)),
+                Err(e) => log::warn!(
+                    "could not read RAM region 0x{:08X}-0x{:08X}; it will be missing from the core dump: {}",
+                    ram.range.start,
+                    ram.range.end - 1,
+                    e
+                ),
+            }
+        }
+    }
+
+    let note = prstatus_note(&registers);
+    let num_program_headers = 1 + segments.len() as u32;
+
+    let mut elf = vec![];
+    // ELF header
+    elf.extend_from_slice(&[0x7f, b'E', b'L', b'F']);
+    elf.extend_from_slice(&[1, 1, 1, 0]); // 32-bit, little endian, version 1, System V ABI
+    elf.extend_from_slice(&[0; 8]);
+    push_u16(&mut elf, ET_CORE);
+    push_u16(
+        &mut elf,
+        match arch {
+            Arch::CortexM => EM_ARM,
+            Arch::Riscv32 => EM_RISCV,
+            // NOTE `read_registers` rejects these
+            Arch::CortexAR | Arch::Aarch64 => unreachable!(),
+        },
+    );
+    push_u32(&mut elf, 1); // e_version
+    push_u32(&mut elf, 0); // e_entry
+    push_u32(&mut elf, ELF_HEADER_SIZE); // e_phoff
+    push_u32(&mut elf, 0); // e_shoff
+    push_u32(&mut elf, 0); // e_flags
+    push_u16(&mut elf, ELF_HEADER_SIZE as u16);
+    push_u16(&mut elf, PROGRAM_HEADER_SIZE as u16);
+    push_u16(&mut elf, num_program_headers as u16);
+    push_u16(&mut elf, 0); // e_shentsize
+    push_u16(&mut elf, 0); // e_shnum
+    push_u16(&mut elf, 0); // e_shstrndx
+
+    // program headers; the segment data follows them in the same order
+    let mut offset = ELF_HEADER_SIZE + num_program_headers * PROGRAM_HEADER_SIZE;
+    push_program_header(&mut elf, PT_NOTE, offset, 0, note.len() as u32, 0);
+    offset += note.len() as u32;
+    for (address, data) in &segments {
+        push_program_header(
+            &mut elf,
+            PT_LOAD,
+            offset,
+            *address,
+            data.len() as u32,
+            PF_RWX,
+        );
+        offset += data.len() as u32;
+    }
+
+    elf.extend_from_slice(&note);
+    for (_, data) in &segments {
+        elf.extend_from_slice(data);
+    }
+
+    fs::write(path, elf)?;
+    Ok(())
+}
+
+/// Reads the registers in the order the architecture's `elf_prstatus.pr_reg` expects them
+fn read_registers(core: &mut Core<'_>, arch: Arch) -> anyhow::Result<Vec<u32>> {
+    let mut registers = vec![];
+    match arch {
+        Arch::CortexM => {
+            // r0-r15, cpsr, orig_r0
+            for reg in 0..16 {
+                registers.push(core.read_core_reg(CoreRegisterAddress(reg))?);
+            }
+            registers.push(core.read_core_reg(XPSR)?);
+            registers.push(registers[0]);
+        }
+        Arch::Riscv32 => {
+            // pc, x1-x31
+            registers.push(core.read_core_reg(riscv::PC)?);
+            for reg in 1..32 {
+                registers.push(core.read_core_reg(riscv::dwarf_register(reg))?);
+            }
+        }
+        Arch::CortexAR | Arch::Aarch64 => {
+            bail!("core dumps are only supported on Cortex-M and RISC-V targets")
+        }
+    }
+    Ok(registers)
+}
+
+/// Builds a `NT_PRSTATUS` note that holds `registers`
+fn prstatus_note(registers: &[u32]) -> Vec<u8> {
+    const NAME: &[u8] = b"CORE\0\0\0\0"; // NUL terminated and padded to 4 bytes
+
+    let mut desc = vec![0; PRSTATUS_HEADER_SIZE];
+    for register in registers {
+        push_u32(&mut desc, *register);
+    }
+    push_u32(&mut desc, 0); // pr_fpvalid
+
+    let mut note = vec![];
+    push_u32(&mut note, 5); // n_namesz, including the NUL terminator
+    push_u32(&mut note, desc.len() as u32);
+    push_u32(&mut note, NT_PRSTATUS);
+    note.extend_from_slice(NAME);
+    note.extend_from_slice(&desc);
+    note
+}
+
+fn push_program_header(
+    elf: &mut Vec<u8>,
+    kind: u32,
+    offset: u32,
+    address: u32,
+    size: u32,
+    flags: u32,
+) {
+    push_u32(elf, kind);
+    push_u32(elf, offset);
+    push_u32(elf, address); // p_vaddr
+    push_u32(elf, address); // p_paddr
+    push_u32(elf, size); // p_filesz
+    push_u32(elf, size); // p_memsz
+    push_u32(elf, flags);
+    push_u32(elf, if kind == PT_LOAD { 4 } else { 0 }); // p_align
+}
+
+fn push_u16(buf: &mut Vec<u8>, value: u16) {
+    buf.extend_from_slice(&value.to_le_bytes());
+}
+
+fn push_u32(buf: &mut Vec<u8>, value: u32) {
+    buf.extend_from_slice(&value.to_le_bytes());
+}
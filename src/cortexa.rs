@@ -0,0 +1,51 @@
+//! Cortex-A/R specific knowledge: ARMv7-A/R and AArch64 registers and entry point
+//!
+//! These cores have no vector table that holds the initial stack pointer; the program starts at the
+//! ELF entry point and sets up its own stack. Only flashing and logging are supported so far; the
+//! stack is not unwound.
+
+use object::{read::File as ElfFile, Object as _, ObjectSymbol as _};
+use probe_rs::CoreRegisterAddress;
+
+use crate::{arch::EntryPoint, target_info};
+
+/// Stack pointer (`r13`) in AArch32 state
+pub const SP: CoreRegisterAddress = CoreRegisterAddress(13);
+/// Link register (`r14`) in AArch32 state
+pub const LR: CoreRegisterAddress = CoreRegisterAddress(14);
+/// Program counter (`r15`) in AArch32 state
+pub const PC: CoreRegisterAddress = CoreRegisterAddress(15);
+
+/// Link register (`x30`) in AArch64 state
+pub const X30: CoreRegisterAddress = CoreRegisterAddress(30);
+/// Stack pointer in AArch64 state
+pub const SP_AARCH64: CoreRegisterAddress = CoreRegisterAddress(31);
+/// Program counter in AArch64 state
+pub const PC_AARCH64: CoreRegisterAddress = CoreRegisterAddress(32);
+
+/// Symbols that start-up code and linker scripts commonly define for the top of the stack
+const STACK_TOP_SYMBOLS: &[&str] = &["_stack_start", "__stack_top", "_stack_top", "__StackTop"];
+
+/// Extracts the entry point from the ELF header; the initial stack pointer is known if the linker
+/// script defines one of the `STACK_TOP_SYMBOLS`
+pub fn entry_point(elf: &ElfFile) -> anyhow::Result<EntryPoint> {
+    let mut stack_top = None;
+    for symbol in elf.symbols() {
+        if let Ok(name) = symbol.name() {
+            if STACK_TOP_SYMBOLS.contains(&name) {
+                stack_top = Some(target_info::address(symbol.address())?);
+            }
+        }
+    }
+
+    if stack_top.is_none() {
+        log::debug!("top of the stack is unknown; stack overflows will not be detected");
+    }
+
+    Ok(EntryPoint {
+        vector_table: None,
+        initial_stack_pointer: stack_top.unwrap_or(0),
+        reset: target_info::address(elf.entry())?,
+        fault_handler: None,
+    })
+}
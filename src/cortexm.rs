@@ -0,0 +1,165 @@
+//! Cortex-M specific knowledge: vector table layout, Thumb mode and exception entry
+
+use arrayref::array_ref;
+use object::read::{File as ElfFile, Object as _, ObjectSection as _};
+use probe_rs::{Core, CoreRegisterAddress, MemoryInterface};
+
+pub const LR: CoreRegisterAddress = CoreRegisterAddress(14);
+pub const PC: CoreRegisterAddress = CoreRegisterAddress(15);
+pub const SP: CoreRegisterAddress = CoreRegisterAddress(13);
+pub const XPSR: CoreRegisterAddress = CoreRegisterAddress(16);
+
+/// The Thumb state bit of XPSR; must be set, Cortex-M cores only execute Thumb code
+pub const XPSR_THUMB: u32 = 1 << 24;
+
+/// Vector Table Offset Register
+pub const VTOR: u32 = 0xE000_ED08;
+
+/// Bits of XPSR that hold IPSR, the number of the active exception; `0` in thread mode
+pub const IPSR_MASK: u32 = 0x1FF;
+/// Exception number of the first external interrupt (IRQ 0)
+pub const FIRST_IRQ: u32 = 16;
+
+/// Value of the Link Register in the reset handler; marks the end of the stack
+pub const LR_END: u32 = 0xFFFF_FFFF;
+
+/// Lowest EXC_RETURN value; ARMv8-M (TrustZone) values go below the ARMv7-M ones (`0xFFFF_FFE1`)
+pub const EXC_RETURN_MARKER: u32 = 0xFFFF_FF80;
+/// EXC_RETURN bit that is clear if the exception stacked floating-point registers (ARMv8-M)
+pub const EXC_RETURN_FTYPE: u32 = 1 << 4;
+/// EXC_RETURN bit that is clear if the exception stacked the callee-saved registers as well
+pub const EXC_RETURN_DCRS: u32 = 1 << 5;
+
+/// Return address Secure code uses when it calls a Non-secure function (`BLXNS`)
+pub const FNC_RETURN: u32 = 0xFEFF_FFFE;
+
+/// Debug Security Control and Status Register (ARMv8-M with the Security Extension)
+const DSCSR: u32 = 0xE000_EE08;
+/// Select the banked registers of the Secure (set) or Non-secure (clear) state
+const DSCSR_SBRSEL: u32 = 1 << 0;
+/// Let `SBRSEL` pick the bank, rather than the current security state
+const DSCSR_SBRSELEN: u32 = 1 << 1;
+/// Set if the core is in the Secure state
+const DSCSR_CDS: u32 = 1 << 16;
+
+/// Mask of the bit that distinguishes the Secure alias of a memory region from the Non-secure one
+/// on most ARMv8-M chips (e.g. `0x3000_0000` vs `0x2000_0000`)
+pub const SECURITY_ALIAS_BIT: u32 = 1 << 28;
+
+const THUMB_BIT: u32 = 1;
+
+/// Names the exception with this number, e.g. `SysTick` or `IRQ 37`; `irq_name` is the name of
+/// an external interrupt, if known
+pub fn describe_exception(number: u32, irq_name: Option<&str>) -> String {
+    let name = match number {
+        0 => "thread mode",
+        2 => "NMI",
+        3 => "HardFault",
+        4 => "MemManage",
+        5 => "BusFault",
+        6 => "UsageFault",
+        7 => "SecureFault",
+        11 => "SVCall",
+        12 => "DebugMonitor",
+        14 => "PendSV",
+        15 => "SysTick",
+        _ if number >= FIRST_IRQ => {
+            let irq = number - FIRST_IRQ;
+            return match irq_name {
+                Some(name) => format!("IRQ {} ({})", irq, name),
+                None => format!("IRQ {}", irq),
+            };
+        }
+        _ => return format!("exception {}", number),
+    };
+    name.to_string()
+}
+
+/// The name of the handler of external interrupt `number` in the vector table; device crates name
+/// the handlers after the interrupts, e.g. `USART2`
+pub fn irq_name(elf: &ElfFile, number: u32) -> Option<String> {
+    if number < FIRST_IRQ {
+        return None;
+    }
+
+    let vector_table = elf.section_by_name(".vector_table")?.data().ok()?;
+    let offset = 4 * number as usize;
+    let entry = vector_table.get(offset..offset + 4)?;
+    let handler = u32::from_le_bytes(*array_ref!(entry, 0, 4));
+    // NOTE the addresses of Thumb functions in the symbol table have their thumb bit set
+    let symtab = elf.symbol_map();
+    let name = symtab.get(set_thumb_bit(handler).into())?.name();
+    if name.starts_with("DefaultHandler") {
+        None
+    } else {
+        Some(name.to_string())
+    }
+}
+
+pub fn clear_thumb_bit(addr: u32) -> u32 {
+    addr & !THUMB_BIT
+}
+
+pub fn set_thumb_bit(addr: u32) -> u32 {
+    addr | THUMB_BIT
+}
+
+pub fn is_thumb_bit_set(addr: u32) -> bool {
+    addr & THUMB_BIT == THUMB_BIT
+}
+
+pub fn is_fnc_return(lr: u32) -> bool {
+    clear_thumb_bit(lr) == FNC_RETURN
+}
+
+/// Makes the banked registers (e.g. SP) of the Secure (`true`) or Non-secure state visible to
+/// the debugger, independent of the state the core is in
+pub fn select_security_state(core: &mut Core<'_>, secure: bool) -> anyhow::Result<()> {
+    let dscsr = core.read_word_32(DSCSR)? & !DSCSR_SBRSEL;
+    let sbrsel = if secure { DSCSR_SBRSEL } else { 0 };
+    core.write_word_32(DSCSR, dscsr | DSCSR_SBRSELEN | sbrsel)?;
+    Ok(())
+}
+
+/// Whether the halted core is in the Secure state; always `false` without the Security Extension
+pub fn is_secure(core: &mut Core<'_>) -> anyhow::Result<bool> {
+    // NOTE DSCSR reads as zero on cores without the Security Extension
+    Ok(core.read_word_32(DSCSR)? & DSCSR_CDS != 0)
+}
+
+/// Reads the stack pointer of the Secure state
+pub fn read_secure_sp(core: &mut Core<'_>) -> anyhow::Result<u32> {
+    let dscsr = core.read_word_32(DSCSR)?;
+    select_security_state(core, true)?;
+    let sp = core.read_core_reg(SP);
+    core.write_word_32(DSCSR, dscsr)?;
+    Ok(sp?)
+}
+
+/// The contents of the vector table
+#[derive(Debug)]
+pub struct VectorTable {
+    pub location: u32,
+    // entry 0
+    pub initial_stack_pointer: u32,
+    // entry 1: Reset handler
+    pub reset: u32,
+    // entry 3: HardFault handler
+    pub hard_fault: u32,
+}
+
+impl VectorTable {
+    /// Parses the `.vector_table` section, given as a sequence of 32-bit words
+    pub fn parse(location: u32, words: &[u32]) -> anyhow::Result<Self> {
+        if words.len() < 4 {
+            anyhow::bail!("`.vector_table` section is too small");
+        }
+
+        Ok(Self {
+            location,
+            initial_stack_pointer: words[0],
+            reset: words[1],
+            hard_fault: words[3],
+        })
+    }
+}
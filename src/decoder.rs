@@ -0,0 +1,202 @@
+//! Decoding the defmt frames of programs built against different defmt versions
+//!
+//! The defmt wire format changes between minor versions. Programs record the version they were
+//! built against in a symbol; `probe-run` bundles a decoder for each version it supports and picks
+//! the matching one. Programs built against a version it doesn't know are decoded with the newest
+//! decoder, after a warning, instead of refusing to run.
+
+use std::collections::BTreeMap;
+
+use defmt_decoder::{DecodeError, Level, Location, Locations, DEFMT_VERSION};
+use object::read::{File as ElfFile, Object as _, ObjectSymbol as _};
+
+use crate::theme;
+
+/// The wire format of defmt 0.1.x
+const V0_1: &str = "0.1";
+
+/// Prefix of the symbol that holds the defmt version of the program
+const VERSION_SYMBOL_PREFIX: &str = "_defmt_version_ = ";
+
+/// The defmt versions this build of `probe-run` can decode, newest first
+pub const SUPPORTED_VERSIONS: &[&str] = &[DEFMT_VERSION, V0_1];
+
+/// The defmt table of the program, parsed by the decoder of its defmt version
+pub enum Table {
+    Current(defmt_decoder::Table),
+    V0_1(defmt_decoder_v0_1::Table),
+}
+
+/// A decoded defmt frame
+pub enum Frame<'t> {
+    Current(defmt_decoder::Frame<'t>),
+    V0_1(defmt_decoder_v0_1::Frame<'t>),
+}
+
+/// Parses the defmt table and, if the DWARF info is complete, the locations of its entries
+pub fn parse(bytes: &[u8]) -> anyhow::Result<(Option<Table>, Option<Locations>)> {
+    let version = match version(bytes)? {
+        Some(version) => version,
+        None => return Ok((None, None)),
+    };
+
+    let table = if version == DEFMT_VERSION {
+        defmt_decoder::Table::parse(bytes)?.map(Table::Current)
+    } else if version == V0_1 {
+        log::debug!("decoding defmt frames as version {}", V0_1);
+        defmt_elf2table::parse(bytes)?.map(Table::V0_1)
+    } else {
+        log::warn!(
+            "the program uses defmt version `{}`, which this probe-run doesn't support (supported: {}); \
+            decoding its logs as version {} -- upgrade probe-run if they look garbled",
+            version,
+            SUPPORTED_VERSIONS.join(", "),
+            DEFMT_VERSION
+        );
+        defmt_decoder::Table::parse_ignore_version(bytes)?.map(Table::Current)
+    };
+
+    let table = match table {
+        Some(table) => table,
+        None => return Ok((None, None)),
+    };
+
+    // Extract the `Locations` from the table
+    let (locs, indices) = match &table {
+        Table::Current(table) => (
+            table.get_locations(bytes)?,
+            table.indices().map(|idx| idx as u64).collect::<Vec<_>>(),
+        ),
+        Table::V0_1(table) => {
+            let locs = defmt_elf2table::get_locations(bytes, table)?
+                .into_iter()
+                .map(|(index, loc)| {
+                    let loc = Location {
+                        file: loc.file,
+                        line: loc.line,
+                        module: loc.module,
+                    };
+                    (index, loc)
+                })
+                .collect::<BTreeMap<_, _>>();
+            let indices = locs.keys().copied().collect();
+            (locs, indices)
+        }
+    };
+
+    let locs = if !indices.is_empty() && locs.is_empty() {
+        log::warn!("insufficient DWARF info; compile your program with `debug = 2` to enable location info");
+        None
+    } else if indices.iter().all(|idx| locs.contains_key(idx)) {
+        Some(locs)
+    } else {
+        log::warn!("(BUG) location info is incomplete; it will be omitted from the output");
+        None
+    };
+    Ok((Some(table), locs))
+}
+
+/// The defmt version recorded in the program, if it uses defmt
+fn version(bytes: &[u8]) -> anyhow::Result<Option<String>> {
+    let elf = ElfFile::parse(bytes)?;
+    Ok(elf.symbols().find_map(|symbol| {
+        let name = symbol.name().ok()?;
+        if name.starts_with(VERSION_SYMBOL_PREFIX) {
+            Some(name[VERSION_SYMBOL_PREFIX.len()..].to_string())
+        } else {
+            None
+        }
+    }))
+}
+
+impl Table {
+    /// Decodes the first frame in `bytes`; returns it along with the number of bytes it took
+    pub fn decode<'t>(&'t self, bytes: &[u8]) -> Result<(Frame<'t>, usize), DecodeError> {
+        match self {
+            Table::Current(table) => table
+                .decode(bytes)
+                .map(|(frame, consumed)| (Frame::Current(frame), consumed)),
+            Table::V0_1(table) => match defmt_decoder_v0_1::decode(bytes, table) {
+                Ok((frame, consumed)) => Ok((Frame::V0_1(frame), consumed)),
+                Err(defmt_decoder_v0_1::DecodeError::UnexpectedEof) => {
+                    Err(DecodeError::UnexpectedEof)
+                }
+                Err(defmt_decoder_v0_1::DecodeError::Malformed) => Err(DecodeError::Malformed),
+            },
+        }
+    }
+}
+
+impl Frame<'_> {
+    pub fn index(&self) -> u64 {
+        match self {
+            Frame::Current(frame) => frame.index(),
+            Frame::V0_1(frame) => frame.index(),
+        }
+    }
+
+    pub fn level(&self) -> Level {
+        match self {
+            Frame::Current(frame) => frame.level(),
+            Frame::V0_1(frame) => match frame.level() {
+                defmt_decoder_v0_1::Level::Trace => Level::Trace,
+                defmt_decoder_v0_1::Level::Debug => Level::Debug,
+                defmt_decoder_v0_1::Level::Info => Level::Info,
+                defmt_decoder_v0_1::Level::Warn => Level::Warn,
+                defmt_decoder_v0_1::Level::Error => Level::Error,
+            },
+        }
+    }
+
+    pub fn message(&self) -> String {
+        match self {
+            Frame::Current(frame) => frame.display_message().to_string(),
+            Frame::V0_1(frame) => frame.display_args().to_string(),
+        }
+    }
+
+    pub fn timestamp(&self) -> Option<String> {
+        match self {
+            Frame::Current(frame) => frame
+                .display_timestamp()
+                .map(|timestamp| timestamp.to_string()),
+            // NOTE defmt 0.1 timestamps are mandatory and in microseconds
+            Frame::V0_1(frame) => Some(format!("{:.6}", frame.timestamp() as f64 / 1e6)),
+        }
+    }
+
+    /// Prints the frame the way the defmt logger does
+    pub fn log(&self, file: Option<&str>, line: Option<u32>, mod_path: Option<&str>) {
+        match self {
+            // Forward the defmt frame to our logger.
+            Frame::Current(frame) => defmt_decoder::log::log_defmt(frame, file, line, mod_path),
+            // NOTE the logger only formats frames of the current version; print these ourselves
+            Frame::V0_1(_) => {
+                let theme = theme::current();
+                let (label, level) = match self.level() {
+                    Level::Trace => ("TRACE", log::Level::Trace),
+                    Level::Debug => ("DEBUG", log::Level::Debug),
+                    Level::Info => ("INFO ", log::Level::Info),
+                    Level::Warn => ("WARN ", log::Level::Warn),
+                    Level::Error => ("ERROR", log::Level::Error),
+                };
+                println!(
+                    "{} {} {}",
+                    self.timestamp().unwrap_or_default(),
+                    theme.level(level).paint(label),
+                    self.message()
+                );
+                if let (Some(file), Some(line)) = (file, line) {
+                    let location = format!(
+                        "{}{} @ {}:{}",
+                        theme.location_prefix,
+                        mod_path.unwrap_or(""),
+                        file,
+                        line
+                    );
+                    println!("{}", theme.secondary.paint(&location));
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,211 @@
+//! Per-test reporting for `defmt-test` binaries
+//!
+//! `defmt-test` logs `(1/3) running `name`...` before each test and `all tests passed!` after the
+//! last one; a test that fails panics, which ends the run. The time between two markers is taken
+//! as the duration of a test.
+
+use std::{
+    cell::RefCell,
+    fmt::Write as _,
+    fs,
+    path::Path,
+    time::{Duration, Instant},
+};
+
+use defmt_decoder::Level;
+
+use crate::theme;
+
+const RUNNING_PREFIX: &str = ") running `";
+const RUNNING_SUFFIX: &str = "`...";
+const ALL_PASSED: &str = "all tests passed!";
+
+/// Symbol of the buffer `defmt-test` reads `--test-filter` from; laid out like `PROBE_RUN_ARGS`
+pub const FILTER_SYMBOL: &str = "DEFMT_TEST_FILTER";
+
+pub struct Test {
+    pub name: String,
+    pub duration: Duration,
+    /// The last error logged while the test was running; `None` if the test passed
+    pub failure: Option<String>,
+}
+
+#[derive(Default)]
+pub struct Tests {
+    state: RefCell<State>,
+}
+
+#[derive(Default)]
+struct State {
+    finished: Vec<Test>,
+    running: Option<Running>,
+    /// Number of tests in the binary, as announced by the markers
+    total: Option<usize>,
+}
+
+struct Running {
+    name: String,
+    start: Instant,
+    last_error: Option<String>,
+}
+
+impl Tests {
+    /// Looks for test markers in a decoded defmt log message
+    pub fn observe(&self, level: Level, message: &str) {
+        let mut state = self.state.borrow_mut();
+
+        if let Some((total, name)) = parse_running(message) {
+            state.pass_running();
+            state.total = Some(total);
+            state.running = Some(Running {
+                name: name.to_string(),
+                start: Instant::now(),
+                last_error: None,
+            });
+        } else if message == ALL_PASSED {
+            state.pass_running();
+        } else if level == Level::Error {
+            if let Some(running) = &mut state.running {
+                running.last_error = Some(message.to_string());
+            }
+        }
+    }
+
+    /// Ends the run; a test that was still running when the program halted has failed
+    pub fn finish(&self) {
+        let mut state = self.state.borrow_mut();
+        if let Some(running) = state.running.take() {
+            let failure = running
+                .last_error
+                .unwrap_or_else(|| "the program halted while the test was running".to_string());
+            state.finished.push(Test {
+                name: running.name,
+                duration: running.start.elapsed(),
+                failure: Some(failure),
+            });
+        }
+    }
+
+    /// Returns `true` if the program is a `defmt-test` binary
+    pub fn found(&self) -> bool {
+        let state = self.state.borrow();
+        state.total.is_some()
+    }
+
+    pub fn print_summary(&self) {
+        let state = self.state.borrow();
+        println!("\n{}", theme::current().secondary.paint("test results:"));
+        for test in &state.finished {
+            let outcome = match test.failure {
+                None => theme::current().success.paint("PASS"),
+                Some(_) => theme::current().failure.paint("FAIL"),
+            };
+            println!(
+                "  {} {} ({:.3}s)",
+                outcome,
+                test.name,
+                test.duration.as_secs_f64()
+            );
+            if let Some(failure) = &test.failure {
+                println!("       {}", failure);
+            }
+        }
+
+        let failed = state.num_failed();
+        let mut summary = format!(
+            "{} passed; {} failed",
+            state.finished.len() - failed,
+            failed
+        );
+        let not_run = state.num_not_run();
+        if not_run != 0 {
+            summary.push_str(&format!("; {} not run", not_run));
+        }
+        println!("{}", summary);
+    }
+
+    /// Writes the results as a JUnit XML report
+    pub fn write_junit(&self, path: &Path, suite: &str) -> anyhow::Result<()> {
+        let state = self.state.borrow();
+        let time: Duration = state.finished.iter().map(|test| test.duration).sum();
+
+        let mut xml = String::new();
+        // NOTE(unwrap) writing into a `String` doesn't fail
+        writeln!(xml, r#"<?xml version="1.0" encoding="UTF-8"?>"#).unwrap();
+        writeln!(xml, "<testsuites>").unwrap();
+        writeln!(
+            xml,
+            r#"  <testsuite name="{}" tests="{}" failures="{}" skipped="{}" time="{:.3}">"#,
+            escape(suite),
+            state.finished.len() + state.num_not_run(),
+            state.num_failed(),
+            state.num_not_run(),
+            time.as_secs_f64()
+        )
+        .unwrap();
+        for test in &state.finished {
+            let start = format!(
+                r#"    <testcase name="{}" classname="{}" time="{:.3}""#,
+                escape(&test.name),
+                escape(suite),
+                test.duration.as_secs_f64()
+            );
+            match &test.failure {
+                None => writeln!(xml, "{}/>", start).unwrap(),
+                Some(failure) => {
+                    writeln!(xml, "{}>", start).unwrap();
+                    writeln!(xml, r#"      <failure message="{}"/>"#, escape(failure)).unwrap();
+                    writeln!(xml, "    </testcase>").unwrap();
+                }
+            }
+        }
+        writeln!(xml, "  </testsuite>").unwrap();
+        writeln!(xml, "</testsuites>").unwrap();
+
+        fs::write(path, xml)?;
+        Ok(())
+    }
+}
+
+impl State {
+    fn pass_running(&mut self) {
+        if let Some(running) = self.running.take() {
+            self.finished.push(Test {
+                name: running.name,
+                duration: running.start.elapsed(),
+                failure: None,
+            });
+        }
+    }
+
+    fn num_failed(&self) -> usize {
+        self.finished
+            .iter()
+            .filter(|test| test.failure.is_some())
+            .count()
+    }
+
+    fn num_not_run(&self) -> usize {
+        self.total
+            .map_or(0, |total| total.saturating_sub(self.finished.len()))
+    }
+}
+
+/// Parses `(1/3) running `name`...` into the total number of tests and the test name
+fn parse_running(message: &str) -> Option<(usize, &str)> {
+    let message = message.strip_prefix('(')?;
+    let (counts, rest) = message.split_at(message.find(RUNNING_PREFIX)?);
+    let total = counts.split('/').nth(1)?.parse().ok()?;
+    let name = rest
+        .strip_prefix(RUNNING_PREFIX)?
+        .strip_suffix(RUNNING_SUFFIX)?;
+    Some((total, name))
+}
+
+fn escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&apos;")
+}
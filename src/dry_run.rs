@@ -0,0 +1,100 @@
+//! `--dry-run`: validates the program and the configuration without a probe, e.g. in a CI lint
+//! job
+//!
+//! Everything up to connecting to the probe runs as usual: the ELF file is parsed, the chip is
+//! resolved and the stack and RAM budget are checked. On top of that the defmt version must be
+//! supported and every loadable segment must fit in the target's memory map; the segments that
+//! would be flashed are printed.
+
+use std::ops::Range;
+
+use anyhow::bail;
+use object::read::File as ElfFile;
+use probe_rs::config::MemoryRegion;
+
+use crate::{decoder, flash, memory_usage, target_info::TargetInfo, EXIT_SUCCESS};
+
+/// Prints what would be flashed to the chip of `target_info`; fails if any check did
+pub fn report(
+    elf: &ElfFile,
+    elf_bytes: &[u8],
+    debug_bytes: &[u8],
+    target_info: &TargetInfo,
+    ram_exec: bool,
+) -> anyhow::Result<i32> {
+    let target = &target_info.probe_target;
+    let mut problems = vec![];
+    println!("chip: {}", target.name);
+
+    match decoder::version(debug_bytes)? {
+        Some(version) if decoder::SUPPORTED_VERSIONS.contains(&&*version) => {
+            println!("defmt version: {}", version)
+        }
+        Some(version) => problems.push(format!(
+            "the program uses defmt version `{}`, which this probe-run doesn't support \
+            (supported: {})",
+            version,
+            decoder::SUPPORTED_VERSIONS.join(", ")
+        )),
+        None => println!("defmt version: (the program doesn't use defmt)"),
+    }
+
+    match &target_info.stack_info {
+        Some(stack_info) => println!(
+            "stack: {} bytes (0x{:08X}-0x{:08X}), {} bytes of static data below it",
+            stack_info.size(),
+            stack_info.range.start(),
+            stack_info.range.end(),
+            stack_info.static_data
+        ),
+        None => println!("stack: unknown; no RAM region contains the initial stack pointer"),
+    }
+
+    // NOTE `--ram-exec` loads the segments into RAM instead
+    let destination = if ram_exec { "RAM" } else { "flash" };
+    println!("would load into {}:", destination);
+    let mut total = 0;
+    for segment in flash::loadable_segments(elf_bytes)? {
+        if segment.data.is_empty() {
+            continue;
+        }
+        let range = segment.address..segment.address + segment.data.len() as u32;
+        total += segment.data.len();
+        println!(
+            "    0x{:08X}-0x{:08X} {:>10} bytes",
+            range.start,
+            range.end - 1,
+            segment.data.len()
+        );
+
+        let fits = target.memory_map.iter().any(|region| match region {
+            MemoryRegion::Nvm(region) => !ram_exec && contains(&region.range, &range),
+            MemoryRegion::Ram(region) => ram_exec && contains(&region.range, &range),
+            MemoryRegion::Generic(_) => false,
+        });
+        if !fits {
+            problems.push(format!(
+                "segment 0x{:08X}-0x{:08X} doesn't fit in the {} of {}",
+                range.start,
+                range.end - 1,
+                destination,
+                target.name
+            ));
+        }
+    }
+    println!("    total: {} bytes", total);
+    memory_usage::print(elf, elf_bytes, target)?;
+
+    if problems.is_empty() {
+        log::info!("dry run: no problems found");
+        return Ok(EXIT_SUCCESS);
+    }
+    for problem in &problems {
+        log::error!("{}", problem);
+    }
+    bail!("dry run found {} problem(s)", problems.len())
+}
+
+fn contains(region: &Range<u32>, range: &Range<u32>) -> bool {
+    region.start <= range.start && range.end <= region.end
+}
@@ -0,0 +1,46 @@
+//! Access to the DWARF debug info of the ELF file
+
+use std::borrow::Cow;
+
+use gimli::{
+    AttributeValue, DebuggingInformationEntry, EndianSlice, LittleEndian, Operation, Unit,
+};
+use object::read::{File as ElfFile, Object as _, ObjectSection as _};
+
+pub type Reader<'a> = EndianSlice<'a, LittleEndian>;
+
+/// Loads the DWARF sections of `elf`; borrow the result with [`borrow`] to read it
+pub fn load<'a>(elf: &'a ElfFile) -> anyhow::Result<gimli::Dwarf<Cow<'a, [u8]>>> {
+    let load_section = |id: gimli::SectionId| -> Result<Cow<[u8]>, gimli::Error> {
+        Ok(elf
+            .section_by_name(id.name())
+            .and_then(|section| section.uncompressed_data().ok())
+            .unwrap_or(Cow::Borrowed(&[][..])))
+    };
+    let load_sup =
+        |_: gimli::SectionId| -> Result<Cow<[u8]>, gimli::Error> { Ok(Cow::Borrowed(&[][..])) };
+    Ok(gimli::Dwarf::load(load_section, load_sup)?)
+}
+
+pub fn borrow<'a>(dwarf: &'a gimli::Dwarf<Cow<[u8]>>) -> gimli::Dwarf<Reader<'a>> {
+    dwarf.borrow(|section| EndianSlice::new(&*section, LittleEndian))
+}
+
+/// Returns the address of a `static` variable, i.e. one whose location is a fixed address
+pub fn static_address(
+    unit: &Unit<Reader>,
+    entry: &DebuggingInformationEntry<Reader>,
+) -> anyhow::Result<Option<u64>> {
+    if entry.tag() != gimli::DW_TAG_variable {
+        return Ok(None);
+    }
+
+    let expr = match entry.attr_value(gimli::DW_AT_location)? {
+        Some(AttributeValue::Exprloc(expr)) => expr,
+        _ => return Ok(None),
+    };
+    match expr.operations(unit.encoding()).next()? {
+        Some(Operation::Address { address }) => Ok(Some(address)),
+        _ => Ok(None),
+    }
+}
@@ -0,0 +1,91 @@
+//! `--exit-code-map`: which exit code each way the run can end maps to
+//!
+//! The reasons are those of the `exit` JSON record. The defaults are stable, so scripts can branch
+//! on them; `--exit-code-map` and the `[exit-codes]` section of `.probe-run.toml` change them.
+
+use std::str::FromStr;
+
+use anyhow::{anyhow, bail};
+
+use crate::{EXIT_FAILURE, EXIT_SUCCESS};
+
+/// The program was still running when `--timeout` elapsed; matches the exit code of `timeout(1)`
+const EXIT_TIMEOUT: i32 = 124;
+const SIGABRT: i32 = 134;
+
+/// The reasons the run can end for, with their default exit codes
+///
+/// NOTE `exited` (semihosting `SYS_EXIT`) is missing on purpose: it exits with the program's code
+pub const DEFAULTS: &[(&str, i32)] = &[
+    ("halted", EXIT_SUCCESS),
+    ("success_pattern", EXIT_SUCCESS),
+    ("failure_pattern", EXIT_FAILURE),
+    ("timeout", EXIT_TIMEOUT),
+    ("too_many_resets", EXIT_FAILURE),
+    ("panic", SIGABRT),
+    ("hard_fault", SIGABRT),
+    ("stack_overflow", SIGABRT),
+    ("caught_exception", SIGABRT),
+    ("breakpoint", SIGABRT),
+];
+
+/// A `REASON=CODE` pair of `--exit-code-map`
+#[derive(Clone, Debug)]
+pub struct Mapping {
+    reason: &'static str,
+    code: i32,
+}
+
+impl Mapping {
+    pub fn new(reason: &str, code: i32) -> anyhow::Result<Self> {
+        let reason = DEFAULTS
+            .iter()
+            .map(|(known, _)| *known)
+            .find(|known| *known == reason)
+            .ok_or_else(|| {
+                anyhow!(
+                    "unknown exit reason `{}`; expected one of {}",
+                    reason,
+                    DEFAULTS
+                        .iter()
+                        .map(|(reason, _)| *reason)
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                )
+            })?;
+        Ok(Self { reason, code })
+    }
+}
+
+impl FromStr for Mapping {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.splitn(2, '=');
+        // NOTE(unwrap) `splitn` yields at least one part
+        let reason = parts.next().unwrap();
+        let code = match parts.next() {
+            Some(code) => code
+                .parse()
+                .map_err(|_| anyhow!("invalid exit code `{}`", code))?,
+            None => bail!("expected `REASON=CODE`, got `{}`", s),
+        };
+        Mapping::new(reason, code)
+    }
+}
+
+/// The exit code the run ends with for `reason`; later mappings take precedence
+pub fn code(mappings: &[Mapping], reason: &str) -> i32 {
+    mappings
+        .iter()
+        .rev()
+        .find(|mapping| mapping.reason == reason)
+        .map(|mapping| mapping.code)
+        .or_else(|| {
+            DEFAULTS
+                .iter()
+                .find(|(known, _)| *known == reason)
+                .map(|(_, code)| *code)
+        })
+        .unwrap_or(EXIT_FAILURE)
+}
@@ -0,0 +1,125 @@
+//! `--decoder external:<cmd>`: defmt frames decoded by another program
+//!
+//! `probe-run` only transports the raw defmt bytes into the command's stdin; the command (e.g. a
+//! `defmt-print` matching the program's defmt version) prints the logs to the shared stdout. So
+//! new defmt versions can be decoded without a new `probe-run`. Flashing, exit detection and
+//! backtraces stay with `probe-run`; the features that look at the decoded frames (log filters,
+//! patterns, `defmt-test` results, log statistics and plots) don't see them.
+
+use std::{
+    cell::RefCell,
+    io::Write as _,
+    path::Path,
+    process::{Child, ChildStdin, Stdio},
+    str::FromStr,
+    thread,
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, bail, Context as _};
+
+use crate::scripts;
+
+/// How long the command gets to print the rest of the logs once its stdin was closed
+const FINISH_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Who decodes the defmt frames
+#[derive(Clone, Debug, PartialEq)]
+pub enum Decoder {
+    Builtin,
+    /// A shell command that reads the raw defmt frames from its stdin
+    External(String),
+}
+
+impl FromStr for Decoder {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "builtin" => Ok(Decoder::Builtin),
+            _ if s.starts_with("external:") => {
+                let command = &s["external:".len()..];
+                if command.trim().is_empty() {
+                    bail!("`external:` needs a command, e.g. `external:defmt-print -e app`");
+                }
+                Ok(Decoder::External(command.to_string()))
+            }
+            _ => bail!(
+                "unknown decoder `{}`; expected `builtin` or `external:<cmd>`",
+                s
+            ),
+        }
+    }
+}
+
+pub struct ExternalDecoder {
+    command: String,
+    child: RefCell<Child>,
+    /// `None` once closed
+    stdin: RefCell<Option<ChildStdin>>,
+}
+
+impl ExternalDecoder {
+    /// Starts `command` with `PROBE_RUN_ELF` set to `elf_path`
+    pub fn spawn(command: &str, elf_path: &Path) -> anyhow::Result<Self> {
+        log::info!("decoding defmt frames with `{}`", command);
+        let mut child = scripts::shell(command)
+            .env("PROBE_RUN_ELF", elf_path)
+            .stdin(Stdio::piped())
+            .spawn()
+            .with_context(|| format!("failed to run `{}`", command))?;
+        // NOTE(unwrap) stdin was piped
+        let stdin = child.stdin.take().unwrap();
+        Ok(Self {
+            command: command.to_string(),
+            child: RefCell::new(child),
+            stdin: RefCell::new(Some(stdin)),
+        })
+    }
+
+    /// Passes raw defmt bytes on to the command
+    pub fn write(&self, data: &[u8]) -> anyhow::Result<()> {
+        let mut stdin = self.stdin.borrow_mut();
+        let stdin = stdin
+            .as_mut()
+            .ok_or_else(|| anyhow!("the external decoder was already closed"))?;
+        stdin
+            .write_all(data)
+            .and_then(|_| stdin.flush())
+            .with_context(|| format!("the external decoder `{}` stopped reading", self.command))
+    }
+
+    /// Closes the command's stdin and waits for it to print the rest of the logs
+    pub fn finish(&self) -> anyhow::Result<()> {
+        drop(self.stdin.borrow_mut().take());
+
+        let mut child = self.child.borrow_mut();
+        let start = Instant::now();
+        while start.elapsed() < FINISH_TIMEOUT {
+            if let Some(status) = child.try_wait()? {
+                if !status.success() {
+                    log::warn!("external decoder `{}` failed ({})", self.command, status);
+                }
+                return Ok(());
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        log::warn!(
+            "external decoder `{}` did not exit after its input ended; stopping it",
+            self.command
+        );
+        child.kill()?;
+        child.wait()?;
+        Ok(())
+    }
+}
+
+impl Drop for ExternalDecoder {
+    fn drop(&mut self) {
+        if self.stdin.get_mut().is_some() {
+            let child = self.child.get_mut();
+            let _ = child.kill();
+            let _ = child.wait();
+        }
+    }
+}
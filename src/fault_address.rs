@@ -0,0 +1,177 @@
+//! What the faulting address of a MemManage fault or precise BusFault points at
+//!
+//! The address is looked up in the ELF symbols, the chip's memory map and, with `--svd`, its
+//! peripherals, e.g. "access to 0x00000008 -- null-pointer-like dereference" or "access to
+//! 0x2000_0410, 16 bytes past the end of `app::BUFFER` (.bss)".
+
+use std::{ops::Range, path::Path};
+
+use object::read::{File as ElfFile, Object as _, ObjectSection as _, ObjectSymbol as _};
+use probe_rs::config::{MemoryRegion, Target};
+
+use crate::{registers::FaultingAccess, svd};
+
+/// Accesses below this address are most likely through a null pointer plus a field offset
+const NULL_PAGE_END: u32 = 0x100;
+/// How far past the end of a symbol an access is still attributed to it
+const OVERRUN_LIMIT: u32 = 256;
+/// Cortex-M peripheral region
+const PERIPHERALS: Range<u32> = 0x4000_0000..0x6000_0000;
+/// Private peripheral bus and vendor specific system region
+const SYSTEM_START: u32 = 0xE000_0000;
+/// NOTE SVD files only give the base address of each peripheral; peripherals are assumed to
+/// span at most this many bytes, which holds for the register blocks of common vendors
+const PERIPHERAL_SPAN: u32 = 0x1000;
+
+/// The memory map of the target together with the program's data symbols
+pub struct AddressMap {
+    /// Name (`RAM` or `FLASH`) and range of the memory regions
+    regions: Vec<(&'static str, Range<u32>)>,
+    /// Demangled name, section and address range of the data symbols, sorted by address
+    symbols: Vec<(String, String, Range<u32>)>,
+    /// Name and base address of the peripherals, sorted by address; empty without `--svd`
+    peripherals: Vec<(String, u32)>,
+}
+
+impl AddressMap {
+    pub fn new(elf: &ElfFile, target: &Target, svd_path: Option<&Path>) -> Self {
+        let regions = target
+            .memory_map
+            .iter()
+            .filter_map(|region| match region {
+                MemoryRegion::Ram(region) => Some(("RAM", region.range.clone())),
+                MemoryRegion::Nvm(region) => Some(("FLASH", region.range.clone())),
+                _ => None,
+            })
+            .collect();
+
+        let mut symbols = elf
+            .symbols()
+            .filter(|symbol| symbol.kind() == object::SymbolKind::Data && symbol.size() != 0)
+            .filter_map(|symbol| {
+                let name = format!("{:#}", rustc_demangle::demangle(symbol.name().ok()?));
+                let section = symbol
+                    .section_index()
+                    .and_then(|index| elf.section_by_index(index).ok())
+                    .and_then(|section| section.name().ok().map(str::to_string))
+                    .unwrap_or_default();
+                let start = symbol.address() as u32;
+                Some((name, section, start..start + symbol.size() as u32))
+            })
+            .collect::<Vec<_>>();
+        symbols.sort_by_key(|(_, _, range)| range.start);
+
+        let peripherals = match svd_path {
+            Some(svd_path) => match svd::peripheral_bases(svd_path) {
+                Ok(mut peripherals) => {
+                    peripherals.sort_by_key(|(_, base_address)| *base_address);
+                    peripherals
+                }
+                Err(e) => {
+                    log::warn!("failed to load the peripherals for fault reports: {:?}", e);
+                    vec![]
+                }
+            },
+            None => vec![],
+        };
+
+        Self {
+            regions,
+            symbols,
+            peripherals,
+        }
+    }
+
+    /// Explains what the faulting `access` likely ran into
+    pub fn describe(&self, access: FaultingAccess) -> String {
+        let address = access.address();
+        let prefix = format!("access to {:#010x}", address);
+
+        if address < NULL_PAGE_END {
+            return format!("{} -- null-pointer-like dereference", prefix);
+        }
+
+        if let Some(description) = self.describe_symbol(address) {
+            return format!("{}, {}", prefix, description);
+        }
+
+        if PERIPHERALS.contains(&address) {
+            let peripheral = self
+                .peripheral(address)
+                .map(|name| format!(" ({})", name))
+                .unwrap_or_default();
+            return match access {
+                // NOTE a peripheral whose clock is gated doesn't respond on the bus
+                FaultingAccess::Bus(_) => format!(
+                    "{} in the peripheral region{} -- is the peripheral's clock enabled?",
+                    prefix, peripheral
+                ),
+                FaultingAccess::MemManage(_) => format!(
+                    "{} in the peripheral region{}, denied by the MPU",
+                    prefix, peripheral
+                ),
+            };
+        }
+
+        if address >= SYSTEM_START {
+            return format!("{} in the system region (core peripherals)", prefix);
+        }
+
+        match self
+            .regions
+            .iter()
+            .find(|(_, range)| range.contains(&address))
+        {
+            Some((name, range)) => format!(
+                "{} in {} ({:#010x}..{:#010x}), outside any symbol",
+                prefix, name, range.start, range.end
+            ),
+            None => format!(
+                "{} -- not in any memory region of the chip; wild or corrupted pointer?",
+                prefix
+            ),
+        }
+    }
+
+    /// Describes `address` relative to the symbol that contains it or that ends right below it
+    fn describe_symbol(&self, address: u32) -> Option<String> {
+        let (name, section, range) = self
+            .symbols
+            .iter()
+            .rev()
+            .find(|(_, _, range)| range.start <= address)?;
+        let section = if section.is_empty() {
+            String::new()
+        } else {
+            format!(" ({})", section)
+        };
+
+        if range.contains(&address) {
+            Some(format!(
+                "offset {} into `{}`{}",
+                address - range.start,
+                name,
+                section
+            ))
+        } else if address - range.end < OVERRUN_LIMIT {
+            Some(format!(
+                "{} bytes past the end of `{}`{}",
+                address - range.end,
+                name,
+                section
+            ))
+        } else {
+            None
+        }
+    }
+
+    /// The peripheral whose register block contains `address`
+    fn peripheral(&self, address: u32) -> Option<&str> {
+        self.peripherals
+            .iter()
+            .rev()
+            .find(|(_, base_address)| *base_address <= address)
+            .filter(|(_, base_address)| address - base_address < PERIPHERAL_SPAN)
+            .map(|(name, _)| name.as_str())
+    }
+}
@@ -0,0 +1,262 @@
+//! Comparison of the target's flash contents against the ELF file
+
+use std::{convert::TryInto, ops::Range};
+
+use anyhow::{anyhow, bail};
+use arrayref::array_ref;
+use probe_rs::{config::MemoryRegion, MemoryInterface, Session};
+
+use crate::{progress::Progress, target_info};
+
+const ELFMAG: &[u8] = b"\x7fELF";
+/// Offset of the byte in the ELF identification that tells 32- and 64-bit files apart
+const EI_CLASS: usize = 4;
+const ELFCLASS32: u8 = 1;
+const ELFCLASS64: u8 = 2;
+/// Offset of the byte in the ELF identification that tells the byte order
+const EI_DATA: usize = 5;
+const ELFDATA2LSB: u8 = 1;
+const PT_LOAD: u32 = 1;
+/// Number of bytes read back at once; the verify progress advances in steps of this size
+const READ_CHUNK_SIZE: usize = 4 * 1024;
+/// Number of bytes at the start of each segment [`spot_check`] compares; enough to hold the vector
+/// table
+const SPOT_CHECK_SIZE: usize = 1024;
+
+/// A loadable segment of the ELF file, at its load (physical) address
+pub struct Segment<'a> {
+    pub address: u32,
+    pub data: &'a [u8],
+}
+
+/// Checks if the flash already holds the loadable segments of the ELF file
+///
+/// The segments that live in flash are read back and compared against the ELF file. This is a lot
+/// faster than erasing and programming the flash again. The `ignored` ranges are not compared.
+pub fn is_up_to_date(
+    sess: &mut Session,
+    core_index: usize,
+    elf_bytes: &[u8],
+    ignored: &[Range<u32>],
+) -> anyhow::Result<bool> {
+    Ok(mismatches(sess, core_index, elf_bytes, ignored, None, None)?.is_empty())
+}
+
+/// Checks if the flash holds the start of each loadable segment of the ELF file
+///
+/// Only a kilobyte per segment is read back, so this doesn't prove the program is on the device;
+/// it backs up the cache, which can't tell if another program was flashed since.
+pub fn spot_check(
+    sess: &mut Session,
+    core_index: usize,
+    elf_bytes: &[u8],
+    ignored: &[Range<u32>],
+) -> anyhow::Result<bool> {
+    Ok(mismatches(
+        sess,
+        core_index,
+        elf_bytes,
+        ignored,
+        Some(SPOT_CHECK_SIZE),
+        None,
+    )?
+    .is_empty())
+}
+
+/// Reads back the flash and fails if it doesn't hold the loadable segments of the ELF file
+pub fn verify(
+    sess: &mut Session,
+    core_index: usize,
+    elf_bytes: &[u8],
+    ignored: &[Range<u32>],
+    progress: &Progress,
+) -> anyhow::Result<()> {
+    const MAX_REPORTED_RANGES: usize = 10;
+
+    let mismatches = mismatches(sess, core_index, elf_bytes, ignored, None, Some(progress))?;
+    if mismatches.is_empty() {
+        return Ok(());
+    }
+
+    eprintln!("flash contents differ from the ELF file at:");
+    for range in mismatches.iter().take(MAX_REPORTED_RANGES) {
+        eprintln!(
+            "    0x{:08X}-0x{:08X} ({} bytes)",
+            range.start,
+            range.end - 1,
+            range.end - range.start
+        );
+    }
+    if mismatches.len() > MAX_REPORTED_RANGES {
+        eprintln!(
+            "    ... and {} more",
+            mismatches.len() - MAX_REPORTED_RANGES
+        );
+    }
+    bail!("flash verification failed")
+}
+
+/// Checks if two ELF files have the same loadable segments, e.g. a program and its stripped copy
+pub fn same_segments(elf_bytes: &[u8], other_elf_bytes: &[u8]) -> anyhow::Result<bool> {
+    let segments = loadable_segments(elf_bytes)?;
+    let other_segments = loadable_segments(other_elf_bytes)?;
+    Ok(segments.len() == other_segments.len()
+        && segments
+            .iter()
+            .zip(&other_segments)
+            .all(|(a, b)| a.address == b.address && a.data == b.data))
+}
+
+/// Address ranges of the flash whose contents differ from the loadable segments of the ELF file;
+/// only the first `max_len` bytes of each segment are compared, if given
+fn mismatches(
+    sess: &mut Session,
+    core_index: usize,
+    elf_bytes: &[u8],
+    ignored: &[Range<u32>],
+    max_len: Option<usize>,
+    progress: Option<&Progress>,
+) -> anyhow::Result<Vec<Range<u32>>> {
+    let nvm_ranges = sess
+        .target()
+        .memory_map
+        .iter()
+        .filter_map(|region| match region {
+            MemoryRegion::Nvm(region) => Some(region.range.clone()),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+
+    let segments = loadable_segments(elf_bytes)?
+        .into_iter()
+        .filter(|segment| {
+            nvm_ranges
+                .iter()
+                .any(|range| range.contains(&segment.address))
+        })
+        .map(|segment| {
+            let len = max_len.map_or(segment.data.len(), |max_len| {
+                max_len.min(segment.data.len())
+            });
+            Segment {
+                address: segment.address,
+                data: &segment.data[..len],
+            }
+        })
+        .collect::<Vec<_>>();
+    if let Some(progress) = progress {
+        let total = segments
+            .iter()
+            .map(|segment| segment.data.len() as u64)
+            .sum();
+        progress.start("verify", total);
+    }
+
+    let mut mismatches = vec![];
+    let mut core = sess.core(core_index)?;
+    for segment in segments {
+        let mut contents = vec![0; segment.data.len()];
+        for (index, chunk) in contents.chunks_mut(READ_CHUNK_SIZE).enumerate() {
+            let address = segment.address + (index * READ_CHUNK_SIZE) as u32;
+            core.read_8(address, chunk)?;
+            if let Some(progress) = progress {
+                progress.advance(chunk.len() as u64);
+            }
+        }
+
+        // collect the runs of differing bytes
+        let mut start = None;
+        for (offset, (actual, expected)) in contents.iter().zip(segment.data).enumerate() {
+            let address = segment.address + offset as u32;
+            let matches =
+                actual == expected || ignored.iter().any(|range| range.contains(&address));
+            match (matches, start) {
+                (false, None) => start = Some(address),
+                (true, Some(run_start)) => {
+                    mismatches.push(run_start..address);
+                    start = None;
+                }
+                _ => {}
+            }
+        }
+        if let Some(run_start) = start {
+            mismatches.push(run_start..segment.address + contents.len() as u32);
+        }
+    }
+    if let Some(progress) = progress {
+        progress.finish();
+    }
+
+    Ok(mismatches)
+}
+
+/// Parses the program headers of the (32- or 64-bit, little endian) ELF file
+///
+/// NOTE the load address (`p_paddr`) is used, rather than the address the segment executes from;
+/// the initial values of `.data` live in flash but are copied to RAM on boot
+pub fn loadable_segments(elf_bytes: &[u8]) -> anyhow::Result<Vec<Segment<'_>>> {
+    let malformed = || anyhow!("malformed ELF program headers");
+    let u64_at = |offset: usize| -> anyhow::Result<u64> {
+        let bytes = elf_bytes.get(offset..offset + 8).ok_or_else(malformed)?;
+        Ok(u64::from_le_bytes(*array_ref!(bytes, 0, 8)))
+    };
+    let u32_at = |offset: usize| -> anyhow::Result<u32> {
+        let bytes = elf_bytes.get(offset..offset + 4).ok_or_else(malformed)?;
+        Ok(u32::from_le_bytes(*array_ref!(bytes, 0, 4)))
+    };
+    let u16_at = |offset: usize| -> anyhow::Result<u16> {
+        let bytes = elf_bytes.get(offset..offset + 2).ok_or_else(malformed)?;
+        Ok(u16::from_le_bytes(*array_ref!(bytes, 0, 2)))
+    };
+
+    if !elf_bytes.starts_with(ELFMAG) {
+        bail!("not an ELF file");
+    }
+    // NOTE the fields below are read as little endian, like all the targets probe-run supports
+    if elf_bytes.get(EI_DATA) != Some(&ELFDATA2LSB) {
+        bail!("big endian ELF files are not supported");
+    }
+    // NOTE the fields of a 64-bit ELF file that hold addresses and offsets are 64 bits wide
+    let is_64_bit = match elf_bytes.get(EI_CLASS) {
+        Some(&ELFCLASS32) => false,
+        Some(&ELFCLASS64) => true,
+        _ => bail!("unknown ELF class"),
+    };
+    let word_at = |offset: usize| -> anyhow::Result<u64> {
+        if is_64_bit {
+            u64_at(offset)
+        } else {
+            u32_at(offset).map(u64::from)
+        }
+    };
+    // (e_phoff, e_phentsize, e_phnum) and (p_offset, p_paddr, p_filesz)
+    let (header_fields, program_header_fields) = if is_64_bit {
+        ((32, 54, 56), (8, 24, 32))
+    } else {
+        ((28, 42, 44), (4, 12, 16))
+    };
+
+    let phoff: usize = word_at(header_fields.0)?.try_into()?;
+    let phentsize = u16_at(header_fields.1)? as usize;
+    let phnum = u16_at(header_fields.2)? as usize;
+
+    let mut segments = vec![];
+    for index in 0..phnum {
+        let header = phoff + index * phentsize;
+        if u32_at(header)? != PT_LOAD {
+            continue;
+        }
+
+        let offset: usize = word_at(header + program_header_fields.0)?.try_into()?;
+        let address = target_info::address(word_at(header + program_header_fields.1)?)?;
+        let size: usize = word_at(header + program_header_fields.2)?.try_into()?;
+        if size == 0 {
+            continue;
+        }
+
+        let data = elf_bytes.get(offset..offset + size).ok_or_else(malformed)?;
+        segments.push(Segment { address, data });
+    }
+
+    Ok(segments)
+}
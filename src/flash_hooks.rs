@@ -0,0 +1,139 @@
+//! `[[flash-hooks]]`: memory accesses done on the target at fixed points of the flash phase, for
+//! chips that need e.g. a watchdog disabled or a clock enabled before their flash can be erased
+//!
+//! Each hook is a sequence of steps, configured in `.probe-run.toml`:
+//!
+//! ``` toml
+//! [[flash-hooks]]
+//! at = "pre-erase"
+//! steps = [
+//!     { write = 0x40003000, value = 0x0000AAAA },
+//!     { read = 0x40003004, mask = 0x1, expect = 0x0, timeout-ms = 100 },
+//!     { delay-ms = 10 },
+//! ]
+//! ```
+
+use std::{
+    thread,
+    time::{Duration, Instant},
+};
+
+use anyhow::bail;
+use probe_rs::{MemoryInterface, Session};
+use serde::Deserialize;
+
+/// When a hook runs
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Point {
+    /// Before the flash is erased and programmed
+    PreErase,
+    /// After the program was written, before it's verified and started
+    PostProgram,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Hook {
+    at: Point,
+    steps: Vec<Step>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum Step {
+    Write(Write),
+    Expect(Expect),
+    Delay(Delay),
+}
+
+/// Writes the bits of `value` selected by `mask` to the word at address `write`
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Write {
+    write: u32,
+    value: u32,
+    #[serde(default = "all_bits")]
+    mask: u32,
+}
+
+/// Reads the word at address `read` until the bits selected by `mask` equal `expect`, for up to
+/// `timeout-ms` milliseconds
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+struct Expect {
+    read: u32,
+    expect: u32,
+    #[serde(default = "all_bits")]
+    mask: u32,
+    #[serde(default)]
+    timeout_ms: u64,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+struct Delay {
+    delay_ms: u64,
+}
+
+fn all_bits() -> u32 {
+    !0
+}
+
+impl Point {
+    fn name(self) -> &'static str {
+        match self {
+            Point::PreErase => "pre-erase",
+            Point::PostProgram => "post-program",
+        }
+    }
+}
+
+/// Runs the hooks configured for `point`, in order, on core 0
+pub fn run(sess: &mut Session, hooks: &[Hook], point: Point) -> anyhow::Result<()> {
+    for hook in hooks.iter().filter(|hook| hook.at == point) {
+        log::debug!("running {} flash hook", point.name());
+        let mut core = sess.core(0)?;
+        for step in &hook.steps {
+            match *step {
+                Step::Write(Write { write, value, mask }) => {
+                    let value = if mask == all_bits() {
+                        value
+                    } else {
+                        (core.read_word_32(write)? & !mask) | (value & mask)
+                    };
+                    core.write_word_32(write, value)?;
+                }
+                Step::Expect(Expect {
+                    read,
+                    expect,
+                    mask,
+                    timeout_ms,
+                }) => {
+                    let timeout = Duration::from_millis(timeout_ms);
+                    let start = Instant::now();
+                    loop {
+                        let value = core.read_word_32(read)?;
+                        if value & mask == expect {
+                            break;
+                        }
+                        if start.elapsed() >= timeout {
+                            bail!(
+                                "{} flash hook: 0x{:08X} reads 0x{:08X}, expected 0x{:08X} \
+                                (mask 0x{:08X})",
+                                point.name(),
+                                read,
+                                value,
+                                expect,
+                                mask
+                            );
+                        }
+                        thread::sleep(Duration::from_millis(1));
+                    }
+                }
+                Step::Delay(Delay { delay_ms }) => thread::sleep(Duration::from_millis(delay_ms)),
+            }
+        }
+    }
+    Ok(())
+}
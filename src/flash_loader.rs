@@ -0,0 +1,81 @@
+//! Selection of the flash algorithms (loaders) that program each flash region
+//!
+//! probe-rs picks the algorithm of a flash region by its address range, preferring the one marked
+//! as the default. Chips with external (QSPI/OSPI) flash may come with several algorithms for the
+//! same range; `--flash-loader` picks one of them by name.
+
+use std::ops::Range;
+
+use anyhow::{anyhow, bail};
+use probe_rs::{config::MemoryRegion, Target};
+
+use crate::flash;
+
+/// Makes the algorithms named in `names` the default for the address range they cover
+pub fn select(target: &mut Target, names: &[String]) -> anyhow::Result<()> {
+    for name in names {
+        let range = target
+            .flash_algorithms
+            .iter()
+            .find(|algorithm| &algorithm.name == name)
+            .map(|algorithm| algorithm.flash_properties.address_range.clone())
+            .ok_or_else(|| {
+                let available = target
+                    .flash_algorithms
+                    .iter()
+                    .map(|algorithm| algorithm.name.as_str())
+                    .collect::<Vec<_>>();
+                anyhow!(
+                    "flash loader `{}` not found; {} has: {}",
+                    name,
+                    target.name,
+                    available.join(", ")
+                )
+            })?;
+
+        for algorithm in &mut target.flash_algorithms {
+            if overlap(&algorithm.flash_properties.address_range, &range) {
+                algorithm.default = &algorithm.name == name;
+            }
+        }
+        log::debug!(
+            "flash loader `{}` programs 0x{:08X}-0x{:08X}",
+            name,
+            range.start,
+            range.end - 1
+        );
+    }
+    Ok(())
+}
+
+/// Fails if a loadable segment of the ELF file lies outside of the chip's memory regions, e.g. in
+/// external flash that the chip description doesn't cover
+pub fn check_segments(target: &Target, elf_bytes: &[u8]) -> anyhow::Result<()> {
+    for segment in flash::loadable_segments(elf_bytes)? {
+        let start = u64::from(segment.address);
+        let end = start + segment.data.len() as u64;
+        let covered = target.memory_map.iter().any(|region| {
+            let range = match region {
+                MemoryRegion::Nvm(region) => &region.range,
+                MemoryRegion::Ram(region) => &region.range,
+                MemoryRegion::Generic(region) => &region.range,
+            };
+            u64::from(range.start) <= start && end <= u64::from(range.end)
+        });
+        if !covered {
+            bail!(
+                "segment at 0x{:08X}-0x{:08X} is outside of {}'s memory regions; if it's external \
+                flash, describe the flash region and its flash loader in a chip description \
+                (`--chip-description`)",
+                start,
+                end - 1,
+                target.name
+            );
+        }
+    }
+    Ok(())
+}
+
+fn overlap(a: &Range<u32>, b: &Range<u32>) -> bool {
+    a.start < b.end && b.start < a.end
+}
@@ -0,0 +1,126 @@
+//! Runs the program on several probes in parallel (`--probes`)
+//!
+//! Each probe gets a `probe-run` child process with the same arguments plus `--probe`. Their
+//! output is forwarded line by line, tagged with the probe selector.
+
+use std::{
+    env,
+    ffi::OsString,
+    io::{self, BufRead as _, BufReader, Read, Write as _},
+    process::{Command, Stdio},
+    thread,
+};
+
+use anyhow::Context as _;
+
+use crate::{theme, EXIT_FAILURE, EXIT_SUCCESS};
+
+/// Runs a child for each probe and waits for all of them; returns the exit code of the first
+/// probe (in the given order) that failed
+pub fn run(probes: &[String]) -> anyhow::Result<i32> {
+    let exe = env::current_exe()?;
+    let args = child_args(env::args_os().skip(1));
+
+    let mut children = vec![];
+    for probe in probes {
+        let mut child = Command::new(&exe)
+            .arg("--probe")
+            .arg(probe)
+            .args(&args)
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .with_context(|| format!("failed to start probe-run for probe `{}`", probe))?;
+
+        let tag = format!("[{}]", probe);
+        // NOTE(unwrap) both streams were set to `Stdio::piped`
+        let forwarders = vec![
+            forward(child.stdout.take().unwrap(), tag.clone(), false),
+            forward(child.stderr.take().unwrap(), tag, true),
+        ];
+        children.push((probe, child, forwarders));
+    }
+
+    let mut code = EXIT_SUCCESS;
+    let mut results = vec![];
+    for (probe, mut child, forwarders) in children {
+        let status = child.wait()?;
+        for forwarder in forwarders {
+            let _ = forwarder.join();
+        }
+
+        // NOTE `code` is `None` if the child was killed by a signal
+        let child_code = status.code().unwrap_or(EXIT_FAILURE);
+        if code == EXIT_SUCCESS {
+            code = child_code;
+        }
+        results.push((probe, child_code));
+    }
+
+    eprintln!("\n{}", theme::current().secondary.paint("results:"));
+    for (probe, code) in results {
+        let outcome = if code == EXIT_SUCCESS {
+            theme::current().success.paint("ok")
+        } else {
+            theme::current().failure.paint("failed")
+        };
+        eprintln!("  [{}] {} (exit code {})", probe, outcome, code);
+    }
+
+    Ok(code)
+}
+
+/// The arguments of this process, minus `--probes` and `--probe`
+fn child_args(mut args: impl Iterator<Item = OsString>) -> Vec<OsString> {
+    let mut child_args = vec![];
+    while let Some(arg) = args.next() {
+        if arg == "--probes" || arg == "--probe" {
+            // skip the value as well
+            args.next();
+        } else if !is_probe_with_value(&arg.to_string_lossy()) {
+            child_args.push(arg);
+        }
+    }
+    child_args
+}
+
+fn is_probe_with_value(arg: &str) -> bool {
+    arg.starts_with("--probes=") || arg.starts_with("--probe=")
+}
+
+/// Copies `stream` line by line to stdout (or stderr), prefixing each line with `tag`
+fn forward(
+    stream: impl Read + Send + 'static,
+    tag: String,
+    stderr: bool,
+) -> thread::JoinHandle<()> {
+    thread::spawn(move || {
+        let mut reader = BufReader::new(stream);
+        let mut line = vec![];
+        while let Ok(n) = reader.read_until(b'\n', &mut line) {
+            if n == 0 {
+                break;
+            }
+            if !line.ends_with(b"\n") {
+                line.push(b'\n');
+            }
+
+            let result = if stderr {
+                write_tagged(&mut io::stderr().lock(), &tag, &line)
+            } else {
+                write_tagged(&mut io::stdout().lock(), &tag, &line)
+            };
+            if result.is_err() {
+                break;
+            }
+            line.clear();
+        }
+    })
+}
+
+fn write_tagged(out: &mut impl io::Write, tag: &str, line: &[u8]) -> io::Result<()> {
+    write!(out, "{} ", tag)?;
+    out.write_all(line)?;
+    out.flush()
+}
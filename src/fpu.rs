@@ -0,0 +1,182 @@
+//! Floating-point state of a faulted Cortex-M core
+//!
+//! When the faulting code used the FPU, the exception stacked an extended frame: S0–S15 and FPSCR
+//! of the interrupted code follow the basic frame on its stack. The other registers are left
+//! untouched by exception entry and are read from the live core. Printing them makes numeric bugs,
+//! e.g. a NaN propagating through a control loop, visible at crash time.
+
+use probe_rs::{Core, CoreRegisterAddress, MemoryInterface};
+
+use crate::{cortexm, stacked::Stacked, theme};
+
+/// Coprocessor Access Control Register; CP10 and CP11 are the FPU
+const CPACR: u32 = 0xE000_ED88;
+const CPACR_CP10_CP11: u32 = 0xF << 20;
+/// Floating-Point Context Control Register
+const FPCCR: u32 = 0xE000_EF34;
+/// Set if lazy state preservation is pending: the stacked FP registers were not written yet
+const FPCCR_LSPACT: u32 = 1 << 0;
+/// Media and VFP Feature Register 0; bits 11:8 are non-zero if the FPU does double precision
+const MVFR0: u32 = 0xE000_EF40;
+const MVFR0_DOUBLE_PRECISION: u32 = 0xF << 8;
+
+/// EXC_RETURN bit that is set if the exception stacked the registers on the process stack
+const EXC_RETURN_SPSEL: u32 = 1 << 2;
+
+/// DCRSR register selectors
+const MSP: CoreRegisterAddress = CoreRegisterAddress(0x11);
+const PSP: CoreRegisterAddress = CoreRegisterAddress(0x12);
+const FPSCR: CoreRegisterAddress = CoreRegisterAddress(0x21);
+const S0: u16 = 0x40;
+
+/// Names of the cumulative exception flags in FPSCR, by bit
+const FPSCR_EXCEPTIONS: [(u32, &str); 6] = [
+    (0, "invalid operation"),
+    (1, "division by zero"),
+    (2, "overflow"),
+    (3, "underflow"),
+    (4, "inexact"),
+    (7, "input denormal"),
+];
+
+/// Number of registers printed per line
+const PER_LINE: usize = 4;
+
+pub struct FpuState {
+    /// S0–S15 of the interrupted code
+    low: [u32; 16],
+    fpscr: u32,
+    /// Whether `low` was read from the stacked frame, rather than the live core
+    stacked: bool,
+    /// S16–S31 of the live core
+    high: [u32; 16],
+    double_precision: bool,
+}
+
+impl FpuState {
+    /// Reads the floating-point state of the core halted in the fault handler; `None` if the
+    /// exception didn't stack an extended frame, i.e. the interrupted code didn't use the FPU
+    pub fn read(core: &mut Core<'_>) -> anyhow::Result<Option<Self>> {
+        // NOTE the fault handler breakpoint hits before its prologue, so LR holds EXC_RETURN
+        let exc_return = core.read_core_reg(cortexm::LR)?;
+        if exc_return < cortexm::EXC_RETURN_MARKER || exc_return & cortexm::EXC_RETURN_FTYPE != 0 {
+            return Ok(None);
+        }
+        if core.read_word_32(CPACR)? & CPACR_CP10_CP11 == 0 {
+            log::debug!("the FPU is disabled; not reading the floating-point registers");
+            return Ok(None);
+        }
+
+        let sp = if exc_return & EXC_RETURN_SPSEL != 0 {
+            core.read_core_reg(PSP)?
+        } else {
+            core.read_core_reg(MSP)?
+        };
+        // with lazy state preservation the space on the stack is reserved but only written once
+        // the handler uses the FPU; until then the live registers hold the interrupted state
+        let lazy = core.read_word_32(FPCCR)? & FPCCR_LSPACT != 0;
+        let stacked = Stacked::read(core, sp, true)?;
+        let (low, fpscr) = match stacked.fpu_regs() {
+            Some(regs) if !lazy => (regs.s, regs.fpscr),
+            _ => (read_s(core, 0)?, core.read_core_reg(FPSCR)?),
+        };
+
+        Ok(Some(Self {
+            low,
+            fpscr,
+            stacked: !lazy,
+            high: read_s(core, 16)?,
+            double_precision: core.read_word_32(MVFR0)? & MVFR0_DOUBLE_PRECISION != 0,
+        }))
+    }
+
+    pub fn print(&self) {
+        let source = if self.stacked {
+            "stacked on exception entry"
+        } else {
+            "live, stacking was deferred"
+        };
+        println!(
+            "{}",
+            theme::current()
+                .secondary
+                .paint(&format!("floating-point registers ({}):", source))
+        );
+        print_singles(0, &self.low);
+        println!("  fpscr 0x{:08X}{}", self.fpscr, self.explain_fpscr());
+
+        // NOTE M-profile FPUs have 16 double-precision registers, D0–D15, aliasing S0–S31
+        if self.double_precision {
+            println!(
+                "{}",
+                theme::current()
+                    .secondary
+                    .paint("double-precision registers (live):")
+            );
+            let singles = self.low.iter().chain(&self.high).collect::<Vec<_>>();
+            for (line, pairs) in singles.chunks(2 * PER_LINE).enumerate() {
+                let doubles = pairs
+                    .chunks(2)
+                    .enumerate()
+                    .map(|(i, pair)| {
+                        let bits = u64::from(*pair[0]) | u64::from(*pair[1]) << 32;
+                        format!(
+                            "{:>5} {:<14}",
+                            format!("d{}", line * PER_LINE + i),
+                            f64::from_bits(bits)
+                        )
+                    })
+                    .collect::<String>();
+                println!("{}", doubles.trim_end());
+            }
+        } else {
+            println!(
+                "{}",
+                theme::current()
+                    .secondary
+                    .paint("single-precision registers (live):")
+            );
+            print_singles(16, &self.high);
+        }
+    }
+
+    /// The cumulative exception flags set in FPSCR, e.g. ` (invalid operation)`
+    fn explain_fpscr(&self) -> String {
+        let flags = FPSCR_EXCEPTIONS
+            .iter()
+            .filter(|(bit, _)| self.fpscr & (1 << bit) != 0)
+            .map(|(_, name)| *name)
+            .collect::<Vec<_>>();
+        if flags.is_empty() {
+            String::new()
+        } else {
+            format!(" ({})", flags.join(", "))
+        }
+    }
+}
+
+/// Reads 16 single-precision registers of the live core, starting at `first`
+fn read_s(core: &mut Core<'_>, first: u16) -> anyhow::Result<[u32; 16]> {
+    let mut registers = [0; 16];
+    for (i, register) in registers.iter_mut().enumerate() {
+        *register = core.read_core_reg(CoreRegisterAddress(S0 + first + i as u16))?;
+    }
+    Ok(registers)
+}
+
+fn print_singles(first: usize, registers: &[u32; 16]) {
+    for (line, chunk) in registers.chunks(PER_LINE).enumerate() {
+        let singles = chunk
+            .iter()
+            .enumerate()
+            .map(|(i, bits)| {
+                format!(
+                    "{:>5} {:<14}",
+                    format!("s{}", first + line * PER_LINE + i),
+                    f32::from_bits(*bits)
+                )
+            })
+            .collect::<String>();
+        println!("{}", singles.trim_end());
+    }
+}
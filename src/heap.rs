@@ -0,0 +1,435 @@
+//! `--measure-heap`: heap usage and fragmentation
+//!
+//! If the linker script defines the heap region it is painted before the program starts, like the
+//! stack; the highest overwritten byte shows how far the heap has grown. The current usage and the
+//! fragmentation come from the state of a `linked_list_allocator` heap (the allocator behind
+//! `embedded-alloc` and `alloc-cortex-m`), which is found in the program's `static`s through the
+//! DWARF info.
+
+use std::{
+    ops::Range,
+    time::{Duration, Instant},
+};
+
+use gimli::{AttributeValue, Unit, UnitOffset};
+use object::read::File as ElfFile;
+use probe_rs::{Core, MemoryInterface};
+
+use crate::{
+    dwarf::{self, Reader},
+    target_info::TargetInfo,
+};
+
+const PAINT_VALUE: u8 = 0xAA;
+
+/// Stop walking the free list after this many blocks; it may be corrupted
+const MAX_FREE_BLOCKS: u32 = 10_000;
+
+/// How deep to look into the type of a `static` for the allocator
+const MAX_DEPTH: usize = 8;
+
+pub struct Monitor {
+    /// The heap region, painted before the program starts
+    range: Option<Range<u32>>,
+    painted: bool,
+    allocator: Option<Allocator>,
+    /// How often the allocator's state is printed while the program runs
+    interval: Option<Duration>,
+    last_sample: Option<Instant>,
+    /// Highest usage seen in the samples of the allocator's state
+    peak_sampled: u32,
+}
+
+/// Result of measuring the heap when the program ended
+pub struct HeapUsage {
+    /// The most heap memory the program has used, in bytes, if known
+    pub peak_usage: Option<u32>,
+    /// Bytes allocated when the program ended
+    pub used: Option<u32>,
+    /// Size of the heap, in bytes
+    pub size: Option<u32>,
+    /// How much of the free memory is not part of the largest free block, in percent
+    pub fragmentation: Option<f64>,
+}
+
+impl Monitor {
+    /// Finds the heap region and the allocator's state; `None` if neither was found
+    pub fn new(
+        elf: &ElfFile,
+        target_info: &TargetInfo,
+        interval: Option<Duration>,
+    ) -> anyhow::Result<Option<Self>> {
+        let allocator = find_allocator(elf)?;
+        log::debug!("allocator: {:x?}", allocator);
+
+        let range = target_info.heap_range.clone();
+        if range.is_none() && allocator.is_none() {
+            log::warn!(
+                "could not find the heap; `--measure-heap` needs `__sheap` and `__eheap` symbols \
+                or a `linked_list_allocator` heap"
+            );
+            return Ok(None);
+        }
+        if interval.is_some() && allocator.is_none() {
+            log::warn!("the allocator's state is unknown; `--heap-interval` has no effect");
+        }
+
+        Ok(Some(Self {
+            range,
+            painted: false,
+            allocator,
+            interval,
+            last_sample: None,
+            peak_sampled: 0,
+        }))
+    }
+
+    /// Paints the heap region so that its peak usage can be measured
+    pub fn paint(&mut self, core: &mut Core<'_>) -> anyhow::Result<()> {
+        if let Some(range) = &self.range {
+            log::debug!(
+                "painting {} bytes of heap (0x{:08X}-0x{:08X})",
+                range.end - range.start,
+                range.start,
+                range.end - 1
+            );
+            core.write_8(
+                range.start,
+                &vec![PAINT_VALUE; (range.end - range.start) as usize],
+            )?;
+            self.painted = true;
+        }
+        Ok(())
+    }
+
+    /// Prints the state of the allocator if `--heap-interval` has passed since the last time
+    pub fn poll(&mut self, core: &mut Core<'_>) -> anyhow::Result<()> {
+        let (interval, allocator) = match (self.interval, &self.allocator) {
+            (Some(interval), Some(allocator)) => (interval, allocator),
+            _ => return Ok(()),
+        };
+        if let Some(last_sample) = self.last_sample {
+            if last_sample.elapsed() < interval {
+                return Ok(());
+            }
+        }
+        self.last_sample = Some(Instant::now());
+
+        let state = allocator.read(core)?;
+        self.peak_sampled = self.peak_sampled.max(state.used);
+        log::info!(
+            "heap: {} of {} bytes in use, {} free blocks, {:.1}% fragmentation",
+            state.used,
+            state.size,
+            state.free_blocks,
+            state.fragmentation()
+        );
+        Ok(())
+    }
+
+    /// Reads back the painted region and the allocator's state and reports the heap usage
+    pub fn read_back(&self, core: &mut Core<'_>) -> anyhow::Result<HeapUsage> {
+        let state = match &self.allocator {
+            Some(allocator) => Some(allocator.read(core)?),
+            None => None,
+        };
+
+        let grown_to = match (&self.range, self.painted) {
+            (Some(range), true) => {
+                let mut buf = vec![0; (range.end - range.start) as usize];
+                core.read_8(range.start, &mut buf)?;
+                let grown_to = buf
+                    .iter()
+                    .rposition(|b| *b != PAINT_VALUE)
+                    .map_or(0, |pos| pos as u32 + 1);
+                log::info!(
+                    "program has used {} bytes of heap space ({:.1}% of the {} bytes available)",
+                    grown_to,
+                    grown_to as f64 / buf.len() as f64 * 100.0,
+                    buf.len()
+                );
+                Some(grown_to)
+            }
+            _ => None,
+        };
+
+        if let Some(state) = &state {
+            log::info!(
+                "heap at exit: {} of {} bytes in use; {} bytes free in {} blocks, the largest \
+                is {} bytes ({:.1}% fragmentation)",
+                state.used,
+                state.size,
+                state.free,
+                state.free_blocks,
+                state.largest_free_block,
+                state.fragmentation()
+            );
+        }
+
+        let sampled = state
+            .as_ref()
+            .map(|state| state.used.max(self.peak_sampled));
+        let peak_usage = match (grown_to, sampled) {
+            (Some(grown_to), Some(sampled)) => Some(grown_to.max(sampled)),
+            (grown_to, sampled) => grown_to.or(sampled),
+        };
+        if let (None, Some(peak)) = (grown_to, peak_usage) {
+            log::info!("peak heap usage seen while sampling: {} bytes", peak);
+        }
+
+        Ok(HeapUsage {
+            peak_usage,
+            used: state.as_ref().map(|state| state.used),
+            size: state
+                .as_ref()
+                .map(|state| state.size)
+                .or_else(|| self.range.as_ref().map(|range| range.end - range.start)),
+            fragmentation: state.as_ref().map(State::fragmentation),
+        })
+    }
+}
+
+/// Where the fields of a `linked_list_allocator::Heap` are
+#[derive(Debug)]
+struct Allocator {
+    /// Address of the `Heap`; the other fields are offsets from it
+    address: u32,
+    used: u32,
+    size: Size,
+    /// The `first` hole of the `HoleList`, a dummy whose `next` is the first free block
+    first_hole: u32,
+    /// Offsets of the fields of a `Hole`, i.e. a free block
+    hole_size: u32,
+    hole_next: u32,
+}
+
+/// How the size of the heap is stored
+#[derive(Debug)]
+enum Size {
+    /// `Heap.size` (`linked_list_allocator` v0.9 and older)
+    Field(u32),
+    /// `HoleList.bottom` and `HoleList.top` (v0.10)
+    Bounds { bottom: u32, top: u32 },
+}
+
+/// A snapshot of the allocator's state
+struct State {
+    size: u32,
+    used: u32,
+    free: u32,
+    free_blocks: u32,
+    largest_free_block: u32,
+}
+
+impl State {
+    fn fragmentation(&self) -> f64 {
+        if self.free == 0 {
+            0.0
+        } else {
+            (1.0 - self.largest_free_block as f64 / self.free as f64) * 100.0
+        }
+    }
+}
+
+impl Allocator {
+    fn read(&self, core: &mut Core<'_>) -> anyhow::Result<State> {
+        let used = core.read_word_32(self.address + self.used)?;
+        let size = match self.size {
+            Size::Field(size) => core.read_word_32(self.address + size)?,
+            Size::Bounds { bottom, top } => core
+                .read_word_32(self.address + top)?
+                .wrapping_sub(core.read_word_32(self.address + bottom)?),
+        };
+
+        let (mut free, mut free_blocks, mut largest_free_block) = (0, 0, 0);
+        // NOTE `next` is an `Option` of a pointer; `None` is a null pointer
+        let mut hole = core.read_word_32(self.address + self.first_hole + self.hole_next)?;
+        while hole != 0 {
+            if free_blocks == MAX_FREE_BLOCKS {
+                log::warn!("the heap's free list is too long; it may be corrupted");
+                break;
+            }
+
+            let hole_size = core.read_word_32(hole + self.hole_size)?;
+            free += hole_size;
+            free_blocks += 1;
+            largest_free_block = largest_free_block.max(hole_size);
+            hole = core.read_word_32(hole + self.hole_next)?;
+        }
+
+        Ok(State {
+            size,
+            used,
+            free,
+            free_blocks,
+            largest_free_block,
+        })
+    }
+}
+
+/// Looks for a `static` that is, or contains, a `linked_list_allocator::Heap`
+fn find_allocator(elf: &ElfFile) -> anyhow::Result<Option<Allocator>> {
+    let dwarf = dwarf::load(elf)?;
+    let dwarf = dwarf::borrow(&dwarf);
+
+    let mut units = dwarf.units();
+    while let Some(header) = units.next()? {
+        let unit = dwarf.unit(header)?;
+        let mut entries = unit.entries();
+        while let Some((_, entry)) = entries.next_dfs()? {
+            let address = match dwarf::static_address(&unit, entry)? {
+                Some(address) => address as u32,
+                None => continue,
+            };
+            let ty = match entry.attr_value(gimli::DW_AT_type)? {
+                Some(AttributeValue::UnitRef(ty)) => ty,
+                _ => continue,
+            };
+
+            if let Some(mut allocator) = find_heap(&dwarf, &unit, ty, 0)? {
+                allocator.address += address;
+                return Ok(Some(allocator));
+            }
+        }
+    }
+
+    Ok(None)
+}
+
+/// Looks for a `Heap` in the type at `offset`; the address of the returned allocator is relative
+/// to the start of the type
+fn find_heap(
+    dwarf: &gimli::Dwarf<Reader>,
+    unit: &Unit<Reader>,
+    offset: UnitOffset,
+    depth: usize,
+) -> anyhow::Result<Option<Allocator>> {
+    if depth > MAX_DEPTH {
+        return Ok(None);
+    }
+
+    let members = struct_members(dwarf, unit, offset)?;
+    if let (Some(used), Some(holes)) = (member(&members, "used"), member(&members, "holes")) {
+        return heap_layout(dwarf, unit, &members, used, holes);
+    }
+
+    // e.g. the `Mutex<RefCell<Heap>>` of `embedded-alloc`
+    for member in &members {
+        if let Some(ty) = member.ty {
+            if let Some(mut allocator) = find_heap(dwarf, unit, ty, depth + 1)? {
+                allocator.address += member.offset;
+                return Ok(Some(allocator));
+            }
+        }
+    }
+
+    Ok(None)
+}
+
+fn heap_layout(
+    dwarf: &gimli::Dwarf<Reader>,
+    unit: &Unit<Reader>,
+    heap: &[Member],
+    used: &Member,
+    holes: &Member,
+) -> anyhow::Result<Option<Allocator>> {
+    let hole_list = match holes.ty {
+        Some(ty) => struct_members(dwarf, unit, ty)?,
+        None => vec![],
+    };
+    let first = match member(&hole_list, "first") {
+        Some(first) => first,
+        None => return Ok(None),
+    };
+    let hole = match first.ty {
+        Some(ty) => struct_members(dwarf, unit, ty)?,
+        None => vec![],
+    };
+    let (hole_size, hole_next) = match (member(&hole, "size"), member(&hole, "next")) {
+        (Some(size), Some(next)) => (size.offset, next.offset),
+        _ => return Ok(None),
+    };
+
+    let size = match (
+        member(heap, "size"),
+        member(&hole_list, "bottom"),
+        member(&hole_list, "top"),
+    ) {
+        (Some(size), _, _) => Size::Field(size.offset),
+        (None, Some(bottom), Some(top)) => Size::Bounds {
+            bottom: holes.offset + bottom.offset,
+            top: holes.offset + top.offset,
+        },
+        _ => return Ok(None),
+    };
+
+    Ok(Some(Allocator {
+        address: 0,
+        used: used.offset,
+        size,
+        first_hole: holes.offset + first.offset,
+        hole_size,
+        hole_next,
+    }))
+}
+
+struct Member {
+    name: String,
+    ty: Option<UnitOffset>,
+    /// Offset from the start of the struct
+    offset: u32,
+}
+
+fn member<'a>(members: &'a [Member], name: &str) -> Option<&'a Member> {
+    members.iter().find(|member| member.name == name)
+}
+
+/// Returns the fields of the struct at `offset`; empty if the type is not a struct
+fn struct_members(
+    dwarf: &gimli::Dwarf<Reader>,
+    unit: &Unit<Reader>,
+    mut offset: UnitOffset,
+) -> anyhow::Result<Vec<Member>> {
+    loop {
+        let entry = unit.entry(offset)?;
+        match entry.tag() {
+            gimli::DW_TAG_typedef | gimli::DW_TAG_volatile_type | gimli::DW_TAG_const_type => {
+                match entry.attr_value(gimli::DW_AT_type)? {
+                    Some(AttributeValue::UnitRef(next)) => offset = next,
+                    _ => return Ok(vec![]),
+                }
+            }
+            gimli::DW_TAG_structure_type => break,
+            _ => return Ok(vec![]),
+        }
+    }
+
+    let mut tree = unit.entries_tree(Some(offset))?;
+    let root = tree.root()?;
+    let mut children = root.children();
+    let mut members = vec![];
+    while let Some(child) = children.next()? {
+        let entry = child.entry();
+        if entry.tag() != gimli::DW_TAG_member {
+            continue;
+        }
+
+        let name = match entry.attr_value(gimli::DW_AT_name)? {
+            Some(name) => dwarf
+                .attr_string(unit, name)?
+                .to_string_lossy()
+                .into_owned(),
+            None => continue,
+        };
+        let ty = match entry.attr_value(gimli::DW_AT_type)? {
+            Some(AttributeValue::UnitRef(ty)) => Some(ty),
+            _ => None,
+        };
+        let offset = entry
+            .attr_value(gimli::DW_AT_data_member_location)?
+            .and_then(|location| location.udata_value())
+            .unwrap_or(0) as u32;
+        members.push(Member { name, ty, offset });
+    }
+    Ok(members)
+}
@@ -0,0 +1,52 @@
+//! Delivers the records of a run to the `--message-format=json` output and the [`Runner`] hooks
+//!
+//! [`Runner`]: crate::Runner
+
+use crate::{
+    json::{self, Record},
+    serve::Server,
+};
+
+type Hook = Box<dyn Fn(&Record)>;
+
+#[derive(Default)]
+pub struct Hooks {
+    /// Print each record on stdout, as `--message-format=json` does
+    pub json: bool,
+    pub on_log: Option<Hook>,
+    pub on_backtrace: Option<Hook>,
+    pub on_exit: Option<Hook>,
+    /// Broadcast the defmt frames to the clients of `--serve`
+    pub server: Option<Server>,
+}
+
+impl Hooks {
+    pub fn emit(&self, record: &Record) {
+        if self.json {
+            json::emit(record);
+        }
+        if let (Some(server), Record::Log { .. }) = (&self.server, record) {
+            server.frame(record);
+        }
+
+        let hook = match record {
+            Record::Log { .. }
+            | Record::Output { .. }
+            | Record::Itm { .. }
+            | Record::Variable { .. }
+            | Record::Reset { .. } => &self.on_log,
+            Record::BacktraceFrame { .. }
+            | Record::ExceptionEntry { .. }
+            | Record::SecurityTransition
+            | Record::BacktraceError { .. } => &self.on_backtrace,
+            Record::Stack { .. }
+            | Record::Heap { .. }
+            | Record::Bench { .. }
+            | Record::RunStats { .. }
+            | Record::Exit { .. } => &self.on_exit,
+        };
+        if let Some(hook) = hook {
+            hook(record);
+        }
+    }
+}
@@ -0,0 +1,50 @@
+//! `--hyperlinks`: turns the source locations of defmt frames and backtrace frames into OSC 8
+//! hyperlinks, which terminals that support them open on click
+//!
+//! The link target comes from `--hyperlink-url`, e.g. `vscode://file/{path}:{line}` to open the
+//! file in an editor; it defaults to a `file://` URL. Links are only emitted when stdout is a
+//! terminal that isn't `dumb`, as other consumers would see the escape sequences verbatim.
+
+use std::{env, path::Path};
+
+/// The default `--hyperlink-url`
+const FILE_URL: &str = "file://{path}";
+
+pub struct Hyperlinks {
+    template: String,
+}
+
+impl Hyperlinks {
+    /// `Some` if `enabled` and the terminal can show hyperlinks; `template` may use `{path}`, the
+    /// absolute path of the file, and `{line}`
+    pub fn detect(enabled: bool, template: Option<&str>) -> Option<Self> {
+        if !enabled {
+            return None;
+        }
+        let dumb = env::var("TERM").map_or(false, |term| term == "dumb");
+        if dumb || !atty::is(atty::Stream::Stdout) {
+            log::debug!("stdout is not a terminal that shows hyperlinks; not emitting them");
+            return None;
+        }
+        Some(Self {
+            template: template.unwrap_or(FILE_URL).to_string(),
+        })
+    }
+
+    /// `text`, linked to `line` of the file at `path`; relative paths are resolved against
+    /// `current_dir`
+    pub fn link(&self, text: &str, path: &Path, line: u64, current_dir: &Path) -> String {
+        let path = current_dir.join(path);
+        // NOTE only the characters that break a URL in practice are escaped
+        let path = path
+            .display()
+            .to_string()
+            .replace('%', "%25")
+            .replace(' ', "%20");
+        let url = self
+            .template
+            .replace("{path}", &path)
+            .replace("{line}", &line.to_string());
+        format!("\x1b]8;;{}\x1b\\{}\x1b]8;;\x1b\\", url, text)
+    }
+}
@@ -0,0 +1,119 @@
+//! Firmware images in formats other than ELF: plain binaries and Intel HEX files
+
+use std::{
+    ops::Range,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use anyhow::{anyhow, bail};
+use probe_rs::{
+    flashing::{self, BinOptions, Format},
+    Session,
+};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ImageFormat {
+    Elf,
+    Hex,
+    Bin,
+}
+
+impl ImageFormat {
+    /// Guesses the format of the image at `path` from its extension; defaults to ELF
+    pub fn of(path: &Path) -> Self {
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some("hex") | Some("ihex") => Self::Hex,
+            Some("bin") => Self::Bin,
+            _ => Self::Elf,
+        }
+    }
+}
+
+/// An image passed to `--preflash`; it's flashed before the program
+#[derive(Debug)]
+pub struct Preflash {
+    path: PathBuf,
+    format: ImageFormat,
+    /// Where a plain binary is flashed to
+    address: Option<u32>,
+}
+
+impl FromStr for Preflash {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (path, address) = match s.rfind('@') {
+            Some(pos) => (&s[..pos], Some(parse_address(&s[pos + 1..])?)),
+            None => (s, None),
+        };
+
+        let path = PathBuf::from(path);
+        let format = ImageFormat::of(&path);
+        match (format, address) {
+            (ImageFormat::Bin, None) => {
+                bail!(
+                    "binary image `{}` needs a flash address; use `<file>@<address>`",
+                    s
+                )
+            }
+            (ImageFormat::Hex, Some(_)) | (ImageFormat::Elf, Some(_)) => {
+                bail!("only binary images take a flash address; found `{}`", s)
+            }
+            _ => {}
+        }
+
+        Ok(Self {
+            path,
+            format,
+            address,
+        })
+    }
+}
+
+impl Preflash {
+    pub fn download(&self, sess: &mut Session) -> anyhow::Result<()> {
+        log::info!("flashing {}", self.path.display());
+        let format = match self.format {
+            ImageFormat::Elf => Format::Elf,
+            ImageFormat::Hex => Format::Hex,
+            ImageFormat::Bin => Format::Bin(BinOptions {
+                base_address: self.address,
+                skip: 0,
+            }),
+        };
+        flashing::download_file(sess, &self.path, format)?;
+        Ok(())
+    }
+}
+
+pub fn parse_address(s: &str) -> anyhow::Result<u32> {
+    let address = if let Some(hex) = s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+        u32::from_str_radix(hex, 16)
+    } else {
+        s.parse()
+    };
+    address.map_err(|_| anyhow!("invalid flash address `{}`", s))
+}
+
+/// Parses an address range given as `START..END` or `START+LENGTH`
+pub fn parse_range(s: &str) -> anyhow::Result<Range<u32>> {
+    let (start, end) = if let Some(pos) = s.find("..") {
+        let start = parse_address(&s[..pos])?;
+        (start, parse_address(&s[pos + 2..])?)
+    } else if let Some(pos) = s.find('+') {
+        let start = parse_address(&s[..pos])?;
+        let len = parse_address(&s[pos + 1..])?;
+        let end = start
+            .checked_add(len)
+            .ok_or_else(|| anyhow!("region `{}` overflows the address space", s))?;
+        (start, end)
+    } else {
+        bail!("invalid region `{}`; use `START..END` or `START+LENGTH`", s)
+    };
+
+    if start >= end {
+        bail!("region `{}` is empty", s);
+    }
+    Ok(start..end)
+}
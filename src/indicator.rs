@@ -0,0 +1,88 @@
+//! `--run-indicator`: the probe's TRST pin as a physical pass/fail indicator, e.g. an LED
+//!
+//! SWD doesn't use TRST, so J-Links and CMSIS-DAP probes can drive it freely: it's high while the
+//! run is in progress and pulses when the run failed. Like `--power-cycle` the probe is talked
+//! to directly, before probe-rs opens it and after it closed it; the pin keeps its level in
+//! between.
+
+use std::{thread, time::Duration};
+
+use anyhow::{anyhow, bail, Context as _};
+use hidapi::HidApi;
+use jaylink::JayLink;
+use probe_rs::{DebugProbeInfo, DebugProbeType};
+
+/// How many times the pin pulses when the run failed
+const FAILURE_PULSES: u32 = 10;
+const PULSE_TIME: Duration = Duration::from_millis(250);
+
+/// Drives the pin high for the duration of the run
+pub fn running(probe_info: &DebugProbeInfo) -> anyhow::Result<()> {
+    set(probe_info, true)
+}
+
+/// Drives the pin low at the end of the run, after pulsing it if the run failed
+pub fn finished(probe_info: &DebugProbeInfo, success: bool) -> anyhow::Result<()> {
+    if !success {
+        for _ in 0..FAILURE_PULSES {
+            set(probe_info, false)?;
+            thread::sleep(PULSE_TIME);
+            set(probe_info, true)?;
+            thread::sleep(PULSE_TIME);
+        }
+    }
+    set(probe_info, false)
+}
+
+fn set(probe_info: &DebugProbeInfo, high: bool) -> anyhow::Result<()> {
+    match probe_info.probe_type {
+        DebugProbeType::JLink => JayLink::open_by_serial(probe_info.serial_number.as_deref())
+            .context("failed to open the J-Link")?
+            .set_trst(high)
+            .context("the J-Link can't drive its TRST pin"),
+        DebugProbeType::CmsisDap => cmsis_dap::set_trst(probe_info, high),
+        _ => bail!(
+            "`--run-indicator` is not supported by {}; it needs a J-Link or a CMSIS-DAP probe",
+            probe_info.identifier
+        ),
+    }
+}
+
+mod cmsis_dap {
+    use super::*;
+
+    const DAP_SWJ_PINS: u8 = 0x10;
+    const PIN_NTRST: u8 = 1 << 5;
+    /// HID report size of CMSIS-DAP v1 probes
+    const PACKET_SIZE: usize = 64;
+    const TIMEOUT_MS: i32 = 1000;
+
+    /// NOTE only CMSIS-DAP v1 probes, which use HID reports, are supported
+    pub fn set_trst(probe_info: &DebugProbeInfo, high: bool) -> anyhow::Result<()> {
+        let api = HidApi::new()?;
+        let info = api
+            .device_list()
+            .find(|info| {
+                info.vendor_id() == probe_info.vendor_id
+                    && info.product_id() == probe_info.product_id
+                    && (probe_info.serial_number.is_none()
+                        || info.serial_number() == probe_info.serial_number.as_deref())
+            })
+            .ok_or_else(|| anyhow!("`--run-indicator` supports CMSIS-DAP v1 (HID) probes only"))?;
+        let device = info.open_device(&api)?;
+
+        // the first byte is the HID report ID; the wait time (last 4 bytes) is zero
+        let mut request = [0; PACKET_SIZE + 1];
+        request[1] = DAP_SWJ_PINS;
+        request[2] = if high { PIN_NTRST } else { 0 };
+        request[3] = PIN_NTRST;
+        device.write(&request)?;
+
+        let mut response = [0; PACKET_SIZE];
+        let len = device.read_timeout(&mut response, TIMEOUT_MS)?;
+        if len == 0 || response[0] != DAP_SWJ_PINS {
+            bail!("unexpected response to DAP_SWJ_Pins");
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,111 @@
+//! Forwards host input (stdin or a file) to the target's RTT down channel 0
+
+use std::{
+    fs::File,
+    io::{self, Read},
+    path::Path,
+    sync::mpsc::{self, Receiver, Sender},
+    thread,
+};
+
+use probe_rs_rtt::DownChannel;
+
+pub struct Forwarder {
+    channel: DownChannel,
+    rx: Receiver<Vec<u8>>,
+    /// Input that didn't fit into the down channel's buffer yet
+    pending: Vec<u8>,
+}
+
+impl Forwarder {
+    /// Starts reading from `input_file`, or stdin if `None`, in a background thread
+    pub fn new(channel: DownChannel, input_file: Option<&Path>) -> anyhow::Result<Self> {
+        let reader: Box<dyn Read + Send> = match input_file {
+            Some(path) => Box::new(File::open(path)?),
+            None => Box::new(io::stdin()),
+        };
+
+        let (tx, rx) = mpsc::channel();
+        thread::spawn(move || read_input(reader, tx));
+
+        Ok(Self {
+            channel,
+            rx,
+            pending: vec![],
+        })
+    }
+
+    /// Writes as much of the input received so far to the down channel as its buffer can take
+    pub fn poll(&mut self) -> anyhow::Result<()> {
+        while let Ok(data) = self.rx.try_recv() {
+            self.pending.extend_from_slice(&data);
+        }
+
+        if !self.pending.is_empty() {
+            let num_bytes_written = self.channel.write(&self.pending)?;
+            self.pending.drain(..num_bytes_written);
+        }
+
+        Ok(())
+    }
+}
+
+fn read_input(mut reader: impl Read, tx: Sender<Vec<u8>>) {
+    let mut buf = [0; 256];
+    loop {
+        match reader.read(&mut buf) {
+            Ok(0) => break,
+            Ok(n) => {
+                if tx.send(buf[..n].to_vec()).is_err() {
+                    break;
+                }
+            }
+            Err(e) => {
+                log::warn!("failed to read input: {}", e);
+                break;
+            }
+        }
+    }
+}
+
+/// Disables line buffering and echo of the terminal so keystrokes reach the target immediately
+///
+/// The original terminal settings are restored when this is dropped.
+#[cfg(unix)]
+pub struct RawTerminal {
+    original: termios::Termios,
+}
+
+#[cfg(unix)]
+impl RawTerminal {
+    const STDIN: i32 = 0;
+
+    pub fn enable() -> anyhow::Result<Self> {
+        use termios::{tcsetattr, Termios, ECHO, ICANON, TCSANOW};
+
+        let original = Termios::from_fd(Self::STDIN)?;
+        let mut raw = original;
+        // NOTE `ISIG` stays set so Ctrl+C still stops probe-run
+        raw.c_lflag &= !(ICANON | ECHO);
+        tcsetattr(Self::STDIN, TCSANOW, &raw)?;
+
+        Ok(Self { original })
+    }
+}
+
+#[cfg(unix)]
+impl Drop for RawTerminal {
+    fn drop(&mut self) {
+        let _ = termios::tcsetattr(Self::STDIN, termios::TCSANOW, &self.original);
+    }
+}
+
+#[cfg(not(unix))]
+pub struct RawTerminal;
+
+#[cfg(not(unix))]
+impl RawTerminal {
+    pub fn enable() -> anyhow::Result<Self> {
+        anyhow::bail!("`--interactive` is only supported on Unix-like systems")
+    }
+}
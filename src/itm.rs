@@ -0,0 +1,379 @@
+//! ITM trace collection over SWO
+//!
+//! The output of the ITM stimulus ports is printed like the output of a text RTT channel. DWT
+//! program counter samples are collected into a profile that's printed when the program halts.
+//! With `--irq-profile` the DWT exception trace, timed by the ITM's local timestamps, is collected
+//! into statistics of how often and how long each exception handler ran.
+
+use std::{collections::BTreeMap, mem};
+
+use anyhow::bail;
+use object::{read::File as ElfFile, Object as _, SymbolMap, SymbolMapName};
+use probe_rs::{architecture::arm::swo::SwoConfig, MemoryInterface, Session};
+
+use crate::{arch::Arch, cortexm, rtt::Printer};
+
+/// Debug Watchpoint and Trace Control Register
+const DWT_CTRL: u32 = 0xE000_1000;
+const DWT_CTRL_CYCCNTENA: u32 = 1 << 0;
+/// Maximum reload value for the down counter that paces the PC samples
+const DWT_CTRL_POSTPRESET: u32 = 0xF << 1;
+/// Tap the cycle counter at bit 10 instead of bit 6
+const DWT_CTRL_CYCTAP: u32 = 1 << 9;
+const DWT_CTRL_PCSAMPLENA: u32 = 1 << 12;
+const DWT_CTRL_EXCTRCENA: u32 = 1 << 16;
+
+/// ITM Trace Control Register
+const ITM_TCR: u32 = 0xE000_0E80;
+/// Emit local timestamps, counted in core clock cycles
+const ITM_TCR_TSENA: u32 = 1 << 1;
+/// Forward the DWT packets to the TPIU
+const ITM_TCR_DWTENA: u32 = 1 << 3;
+
+/// Number of functions listed in the profile
+const PROFILE_LEN: usize = 10;
+
+/// Configures the TPIU for SWO output and enables DWT PC sampling, plus the exception trace if
+/// `irq_profile` is set
+pub fn setup(
+    sess: &mut Session,
+    arch: Arch,
+    core_freq: u32,
+    baud: u32,
+    irq_profile: bool,
+) -> anyhow::Result<()> {
+    if arch != Arch::CortexM {
+        bail!("`--itm` is only supported on Cortex-M targets");
+    }
+
+    sess.setup_swv(&SwoConfig::new(core_freq).set_baud(baud))?;
+
+    let mut core = sess.core(0)?;
+    let mut tcr = core.read_word_32(ITM_TCR)? | ITM_TCR_DWTENA;
+    let mut ctrl = core.read_word_32(DWT_CTRL)?
+        | DWT_CTRL_CYCCNTENA
+        | DWT_CTRL_POSTPRESET
+        | DWT_CTRL_CYCTAP
+        | DWT_CTRL_PCSAMPLENA;
+    if irq_profile {
+        tcr |= ITM_TCR_TSENA;
+        ctrl |= DWT_CTRL_EXCTRCENA;
+    }
+    core.write_word_32(ITM_TCR, tcr)?;
+    core.write_word_32(DWT_CTRL, ctrl)?;
+
+    log::debug!(
+        "SWO configured for a {} Hz core clock at {} baud",
+        core_freq,
+        baud
+    );
+    Ok(())
+}
+
+enum Packet<'a> {
+    /// Data written to a stimulus port
+    Instrumentation { port: u8, payload: &'a [u8] },
+    /// A DWT program counter sample; `None` if the core was sleeping
+    PcSample(Option<u32>),
+    /// The core entered, exited or returned to exception `number`
+    ExceptionTrace { number: u32, function: Function },
+    /// Core clock cycles since the previous local timestamp
+    LocalTimestamp(u32),
+    /// Synchronization, overflow, timestamp and the hardware packets we don't use
+    Other,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Function {
+    Entered,
+    Exited,
+    /// The exception (or thread mode, `0`) resumed after a handler that preempted it returned
+    Returned,
+}
+
+/// Decodes the ITM packets read from SWO
+pub struct Decoder {
+    /// Holds the bytes of an incomplete packet
+    buf: Vec<u8>,
+    /// Number of samples taken at each program counter value
+    pc_samples: BTreeMap<u32, u32>,
+    sleep_samples: u32,
+    /// Unset unless `--irq-profile` was given
+    irq_profile: Option<IrqProfile>,
+}
+
+impl Decoder {
+    /// `core_freq` converts the timestamps of the exception trace into seconds
+    pub fn new(irq_profile: bool, core_freq: u32) -> Self {
+        Self {
+            buf: vec![],
+            pc_samples: BTreeMap::new(),
+            sleep_samples: 0,
+            irq_profile: if irq_profile {
+                Some(IrqProfile::new(core_freq))
+            } else {
+                None
+            },
+        }
+    }
+
+    /// Decodes the complete packets in `data` (plus the leftovers of the previous call) and prints
+    /// the stimulus port output
+    pub fn feed(&mut self, data: &[u8], printer: &Printer) -> anyhow::Result<()> {
+        self.buf.extend_from_slice(data);
+
+        let mut consumed = 0;
+        while let Some((packet, len)) = parse(&self.buf[consumed..]) {
+            match packet {
+                Packet::Instrumentation { port, payload } => printer.itm(port, payload)?,
+                Packet::PcSample(Some(pc)) => *self.pc_samples.entry(pc).or_default() += 1,
+                Packet::PcSample(None) => self.sleep_samples += 1,
+                Packet::ExceptionTrace { number, function } => {
+                    if let Some(irq_profile) = &mut self.irq_profile {
+                        irq_profile.pending.push((number, function));
+                    }
+                }
+                Packet::LocalTimestamp(cycles) => {
+                    if let Some(irq_profile) = &mut self.irq_profile {
+                        irq_profile.advance(cycles);
+                    }
+                }
+                Packet::Other => {}
+            }
+            consumed += len;
+        }
+
+        self.buf.drain(..consumed);
+        Ok(())
+    }
+
+    /// Prints the exception handlers that took the most time, if `--irq-profile` was given
+    pub fn print_irq_profile(&self, elf: &ElfFile) {
+        if let Some(irq_profile) = &self.irq_profile {
+            irq_profile.print(elf);
+        }
+    }
+
+    /// Prints the functions in which the most PC samples were taken
+    pub fn print_profile(&self, elf: &ElfFile) {
+        let num_samples = self.pc_samples.values().sum::<u32>() + self.sleep_samples;
+        if num_samples == 0 {
+            log::debug!("no PC samples were collected");
+            return;
+        }
+
+        let symbols = elf.symbol_map();
+        let mut functions = BTreeMap::<&str, u32>::new();
+        for (pc, count) in &self.pc_samples {
+            *functions.entry(function_name(&symbols, *pc)).or_default() += count;
+        }
+        let mut functions = functions.into_iter().collect::<Vec<_>>();
+        functions.sort_by(|a, b| b.1.cmp(&a.1));
+
+        let percentage = |count: u32| count as f64 / num_samples as f64 * 100.0;
+        println!(
+            "PC sampling profile ({} samples, {:.1}% sleeping)",
+            num_samples,
+            percentage(self.sleep_samples)
+        );
+        for (name, count) in functions.iter().take(PROFILE_LEN) {
+            println!("{:>7.1}%  {}", percentage(*count), name);
+        }
+    }
+}
+
+/// How often and how long each exception handler ran, from the DWT exception trace
+struct IrqProfile {
+    core_freq: u32,
+    /// Core clock cycles since tracing started, summed from the local timestamps
+    now: u64,
+    /// Events received since the last timestamp; the next timestamp tells when they happened
+    pending: Vec<(u32, Function)>,
+    /// The handlers that were entered and haven't exited yet, with their entry time; innermost last
+    active: Vec<(u32, u64)>,
+    stats: BTreeMap<u32, IrqStats>,
+}
+
+#[derive(Default)]
+struct IrqStats {
+    entries: u32,
+    /// Cycles spent in the handler, including the handlers that preempted it
+    total: u64,
+    /// Cycles of the longest single run of the handler; the latency it adds to the code it
+    /// interrupts
+    max: u64,
+}
+
+impl IrqProfile {
+    fn new(core_freq: u32) -> Self {
+        Self {
+            core_freq,
+            now: 0,
+            pending: vec![],
+            active: vec![],
+            stats: BTreeMap::new(),
+        }
+    }
+
+    /// Moves time forward and records the events that happened up to then
+    fn advance(&mut self, cycles: u32) {
+        self.now += u64::from(cycles);
+        for (number, function) in mem::take(&mut self.pending) {
+            match function {
+                Function::Entered => {
+                    self.stats.entry(number).or_default().entries += 1;
+                    self.active.push((number, self.now));
+                }
+                Function::Exited => {
+                    // NOTE exits of handlers nested inside of this one may have been lost when the
+                    // ITM FIFO overflowed; drop them as well
+                    if let Some(index) = self.active.iter().rposition(|(n, _)| *n == number) {
+                        let entered = self.active[index].1;
+                        self.active.truncate(index);
+                        let stats = self.stats.entry(number).or_default();
+                        let duration = self.now - entered;
+                        stats.total += duration;
+                        stats.max = stats.max.max(duration);
+                    }
+                }
+                Function::Returned => {}
+            }
+        }
+    }
+
+    fn print(&self, elf: &ElfFile) {
+        if self.stats.is_empty() {
+            log::debug!("no exception trace packets were collected");
+            return;
+        }
+
+        let mut stats = self.stats.iter().collect::<Vec<_>>();
+        stats.sort_by(|a, b| b.1.total.cmp(&a.1.total));
+
+        let micros = |cycles: u64| cycles as f64 / f64::from(self.core_freq) * 1e6;
+        println!(
+            "exception profile ({:.1} ms traced)",
+            micros(self.now) / 1e3
+        );
+        println!(
+            "{:<32} {:>9} {:>12} {:>8} {:>11}",
+            "exception", "entries", "total", "share", "max"
+        );
+        for (number, stats) in stats {
+            let name =
+                cortexm::describe_exception(*number, cortexm::irq_name(elf, *number).as_deref());
+            println!(
+                "{:<32} {:>9} {:>9.0} µs {:>7.1}% {:>8.1} µs",
+                name,
+                stats.entries,
+                micros(stats.total),
+                stats.total as f64 / self.now.max(1) as f64 * 100.0,
+                micros(stats.max)
+            );
+        }
+    }
+}
+
+fn function_name<'a>(symbols: &'a SymbolMap<SymbolMapName<'_>>, pc: u32) -> &'a str {
+    // NOTE the addresses of Thumb functions in the symbol table have their thumb bit set
+    symbols
+        .get(cortexm::set_thumb_bit(pc) as u64)
+        .map_or("<unknown>", |symbol| symbol.name())
+}
+
+/// Parses the packet at the start of `buf`; returns `None` if `buf` holds an incomplete packet
+fn parse(buf: &[u8]) -> Option<(Packet<'_>, usize)> {
+    let header = *buf.first()?;
+
+    // the payload size of source packets is encoded in the header's 2 lowest bits
+    let size = match header & 0b11 {
+        0b01 => 1,
+        0b10 => 2,
+        0b11 => 4,
+        _ => return parse_protocol_packet(buf),
+    };
+    let payload = buf.get(1..1 + size)?;
+
+    let packet = if header & 0b100 == 0 {
+        Packet::Instrumentation {
+            port: header >> 3,
+            payload,
+        }
+    } else {
+        const EXCEPTION_TRACE: u8 = 1;
+        const PC_SAMPLE: u8 = 2;
+        match (header >> 3, payload) {
+            (EXCEPTION_TRACE, [a, b]) => {
+                let function = match (b >> 4) & 0b11 {
+                    1 => Function::Entered,
+                    2 => Function::Exited,
+                    3 => Function::Returned,
+                    _ => return Some((Packet::Other, 1 + size)),
+                };
+                Packet::ExceptionTrace {
+                    number: u32::from(*a) | u32::from(b & 1) << 8,
+                    function,
+                }
+            }
+            (PC_SAMPLE, [_]) => Packet::PcSample(None),
+            (PC_SAMPLE, [a, b, c, d]) => {
+                Packet::PcSample(Some(u32::from_le_bytes([*a, *b, *c, *d])))
+            }
+            _ => Packet::Other,
+        }
+    };
+    Some((packet, 1 + size))
+}
+
+/// Parses the synchronization, overflow, timestamp and extension packets
+fn parse_protocol_packet(buf: &[u8]) -> Option<(Packet<'_>, usize)> {
+    const SYNC: u8 = 0x00;
+    const OVERFLOW: u8 = 0x70;
+    const GLOBAL_TIMESTAMP_1: u8 = 0x94;
+    const GLOBAL_TIMESTAMP_2: u8 = 0xB4;
+    const CONTINUATION: u8 = 0x80;
+
+    let header = buf[0];
+    // local timestamp format 2: `0b0TTT0000`, with the time in the header
+    if header & 0x8F == 0 && header != SYNC && header != OVERFLOW {
+        return Some((Packet::LocalTimestamp(u32::from(header >> 4)), 1));
+    }
+    // local timestamp format 1: `0b11TC0000`, with the time in up to 4 payload bytes of 7 bits
+    if header & 0xCF == 0xC0 {
+        let len = continued_len(buf)?;
+        let cycles = buf[1..len]
+            .iter()
+            .enumerate()
+            .fold(0, |cycles, (index, byte)| {
+                cycles | u32::from(byte & 0x7F) << (7 * index)
+            });
+        return Some((Packet::LocalTimestamp(cycles), len));
+    }
+
+    let len = match header {
+        // at least 47 zero bits followed by a one bit
+        SYNC => {
+            let zeros = buf.iter().take_while(|byte| **byte == 0).count();
+            match buf.get(zeros) {
+                Some(&CONTINUATION) => zeros + 1,
+                Some(_) => zeros,
+                None => return None,
+            }
+        }
+        OVERFLOW => 1,
+        GLOBAL_TIMESTAMP_1 | GLOBAL_TIMESTAMP_2 => continued_len(buf)?,
+        // local timestamps (`0bCTTT0000`) and extension packets (`0bCXXX1X00`) are followed by
+        // payload bytes as long as the continuation bit is set
+        _ if header & CONTINUATION == 0 => 1,
+        _ => continued_len(buf)?,
+    };
+    Some((Packet::Other, len))
+}
+
+/// Length of a packet whose payload bytes end with the first byte with a clear continuation bit
+fn continued_len(buf: &[u8]) -> Option<usize> {
+    buf[1..]
+        .iter()
+        .position(|byte| byte & 0x80 == 0)
+        .map(|pos| pos + 2)
+}
@@ -0,0 +1,126 @@
+//! Newline-delimited JSON records emitted with `--message-format=json`
+
+use serde::Serialize;
+
+#[derive(Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Record<'a> {
+    /// A decoded defmt frame
+    Log {
+        level: &'static str,
+        timestamp: Option<String>,
+        /// When the host received the frame (RFC 3339); set with `--timestamp host` or `both`
+        host_timestamp: Option<String>,
+        /// How far the target's clock has drifted from the host's, in seconds
+        drift: Option<f64>,
+        module: Option<&'a str>,
+        file: Option<&'a str>,
+        line: Option<u32>,
+        message: String,
+    },
+    /// Output of an RTT channel that doesn't carry defmt data
+    Output {
+        data: &'a str,
+    },
+    /// Data written to an ITM stimulus port
+    Itm {
+        port: u8,
+        data: &'a str,
+    },
+    /// New value of a variable watched with `--watch-var`
+    Variable {
+        name: &'a str,
+        value: &'a str,
+    },
+    BacktraceFrame {
+        index: u32,
+        pc: u32,
+        function: &'a str,
+        file: Option<String>,
+        line: Option<u64>,
+        /// The function was inlined into the next frame
+        inline: bool,
+        /// The frame was found by scanning the stack, not with unwind info
+        heuristic: bool,
+    },
+    /// The frames above belong to the handler of `exception`, e.g. `IRQ 37 (USART2)`, which
+    /// interrupted the frames below (e.g. `thread mode`)
+    ExceptionEntry {
+        exception: &'a str,
+        interrupted: &'a str,
+    },
+    /// Secure code called the Non-secure code of the frames above
+    SecurityTransition,
+    BacktraceError {
+        message: &'a str,
+    },
+    /// Result of the stack overflow check
+    Stack {
+        /// `full`, `sentinel` or `none`; `none` if no canary was placed
+        canary_strategy: &'static str,
+        /// Bytes painted with the canary
+        canary_size: u32,
+        canary_touched: bool,
+        /// Lower bound of the stack usage, in bytes; known if the canary was touched or the
+        /// `full` strategy was used
+        min_stack_usage: Option<u32>,
+    },
+    /// Heap usage measured with `--measure-heap`
+    Heap {
+        /// The most heap memory the program has used, in bytes
+        peak_usage: Option<u32>,
+        /// Bytes allocated when the program ended; known for `linked_list_allocator` heaps
+        used: Option<u32>,
+        size: Option<u32>,
+        /// How much of the free memory is not part of the largest free block, in percent
+        fragmentation: Option<f64>,
+    },
+    /// Cycles between `bench_start` and `bench_stop`, measured with `--bench`
+    Bench {
+        runs: usize,
+        min_cycles: u32,
+        mean_cycles: f64,
+        max_cycles: u32,
+        /// The mean in microseconds; known if `--core-freq` is set
+        mean_micros: Option<f64>,
+    },
+    /// How long the phases of the run took, measured with `--stats`; a phase that didn't happen
+    /// (e.g. flashing with `--no-flash`) is `null`
+    RunStats {
+        total_secs: f64,
+        attach_secs: Option<f64>,
+        flash_secs: Option<f64>,
+        /// From the reset until the first log data arrived
+        first_log_secs: Option<f64>,
+        streaming_secs: Option<f64>,
+        defmt_frames: u64,
+    },
+    /// The program reset itself; `count` resets so far
+    Reset {
+        count: u32,
+    },
+    Exit {
+        reason: &'static str,
+        code: i32,
+    },
+}
+
+/// Prints `record` on a line of its own
+pub fn emit(record: &Record) {
+    println!("{}", to_string(record));
+}
+
+pub fn to_string(record: &Record) -> String {
+    // NOTE(expect) all the record types serialize to valid JSON
+    serde_json::to_string(record).expect("failed to serialize record")
+}
+
+pub fn level(level: defmt_decoder::Level) -> &'static str {
+    match level {
+        defmt_decoder::Level::Trace => "trace",
+        defmt_decoder::Level::Debug => "debug",
+        defmt_decoder::Level::Info => "info",
+        defmt_decoder::Level::Warn => "warn",
+        defmt_decoder::Level::Error => "error",
+    }
+}
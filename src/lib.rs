@@ -0,0 +1,1787 @@
+//! Flashes a program onto a microcontroller, runs it, decodes its logs and prints a backtrace when
+//! it halts
+//!
+//! The `probe-run` binary is a thin command line wrapper around [`Runner`]. Decoded log frames are
+//! forwarded to the `log` crate, so a logger should be installed before calling [`Runner::run`]
+//! unless `--message-format=json` output or the hooks are used exclusively.
+
+mod arch;
+mod args;
+pub mod artifact;
+mod backtrace;
+mod bank;
+mod batch;
+mod bench;
+mod bootloader;
+mod breakpoints;
+mod cache;
+mod canary;
+mod catch;
+mod chip_detection;
+pub mod chips;
+pub mod cli;
+pub mod config;
+mod coredump;
+mod cortexa;
+mod cortexm;
+mod decoder;
+mod defmt_test;
+mod dry_run;
+mod dwarf;
+mod exit_code;
+mod external_decoder;
+mod fault_address;
+mod flash;
+mod flash_hooks;
+mod flash_loader;
+pub mod fleet;
+mod fpu;
+mod heap;
+mod hooks;
+mod hyperlink;
+mod image;
+mod indicator;
+mod input;
+mod itm;
+pub mod json;
+mod log_file;
+mod log_filter;
+mod log_level;
+mod log_stats;
+mod memory_usage;
+mod option_bytes;
+mod patterns;
+mod plot;
+mod post_mortem;
+mod power;
+mod preserve;
+pub mod probe;
+mod progress;
+mod protection;
+mod ram_exec;
+pub mod record;
+mod registers;
+mod repl;
+mod reset_cause;
+mod reset_watch;
+mod retry;
+mod riscv;
+mod rtt;
+mod run_stats;
+mod scripts;
+mod semihosting;
+mod serial;
+mod serve;
+mod source;
+mod speed;
+mod spill;
+mod stack_limit;
+mod stacked;
+mod startup;
+mod svd;
+mod symbolizer;
+pub mod tail;
+mod target_info;
+pub mod theme;
+mod timestamp;
+mod unlock;
+mod watch;
+mod watch_var;
+
+use std::{
+    collections::HashSet,
+    ffi::OsString,
+    fs,
+    path::{Path, PathBuf},
+    slice,
+    sync::atomic::{AtomicBool, Ordering},
+    sync::{Arc, Mutex},
+    thread,
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, bail, Context as _};
+use arrayref::array_ref;
+use object::{
+    read::{File as ElfFile, Object as _, ObjectSection as _},
+    ObjectSegment, ObjectSymbol, SymbolSection,
+};
+use probe_rs::{
+    config::registry,
+    flashing::{self, DownloadOptions, Format},
+    Core, DebugProbeInfo, MemoryInterface, Probe, Session, Target,
+};
+use probe_rs_rtt::ScanRegion;
+use signal_hook::consts::signal;
+use structopt::StructOpt as _;
+
+use crate::{
+    arch::{Arch, EntryPoint},
+    args::Args,
+    backtrace::TopException,
+    bank::FlashBank,
+    batch::SessionSlot,
+    bench::Bench,
+    breakpoints::Breakpoints,
+    cache::Cache,
+    canary::{Canary, CanaryStrategy},
+    catch::Catch,
+    cli::{MessageFormat, Opts, ResetType},
+    cortexm::VectorTable,
+    defmt_test::Tests,
+    external_decoder::{Decoder, ExternalDecoder},
+    fault_address::AddressMap,
+    hooks::Hooks,
+    hyperlink::Hyperlinks,
+    image::ImageFormat,
+    json::Record,
+    log_file::LogFile,
+    log_level::LevelSetter,
+    log_stats::LogStats,
+    patterns::Patterns,
+    plot::Plot,
+    progress::Progress,
+    record::Recorder,
+    registers::FaultStatus,
+    reset_watch::ResetWatch,
+    run_stats::RunStats,
+    scripts::Scripts,
+    semihosting::Semihosting,
+    serial::Serial,
+    speed::Speed,
+    spill::Spill,
+    target_info::{RegionOverrides, StackInfo, TargetInfo},
+    timestamp::Timestamps,
+    watch::Watcher,
+    watch_var::Watches,
+};
+
+/// Successfull termination of process.
+pub const EXIT_SUCCESS: i32 = 0;
+pub const EXIT_FAILURE: i32 = 1;
+const TIMEOUT: Duration = Duration::from_secs(1);
+/// How long the program gets to set up its RTT control block again after it reset itself, if
+/// probe-run can't stop it at `main` (`--attach`)
+const RESET_SETTLE_TIME: Duration = Duration::from_millis(100);
+/// Default of `--stack-threshold`, in bytes
+const STACK_THRESHOLD: u32 = 1024;
+
+/// Flashes and runs a program; the library counterpart of the `probe-run` command
+///
+/// ``` no_run
+/// # fn main() -> anyhow::Result<()> {
+/// use probe_run::{json::Record, Runner};
+///
+/// let code = Runner::new("target/thumbv7em-none-eabihf/debug/hello")
+///     .chip("nRF52840_xxAA")
+///     .on_log(|record| {
+///         if let Record::Log { message, .. } = record {
+///             println!("device says: {}", message);
+///         }
+///     })
+///     .run()?;
+/// # Ok(())
+/// # }
+/// ```
+pub struct Runner {
+    opts: Opts,
+    hooks: Hooks,
+}
+
+impl Runner {
+    /// Runs the ELF (or Intel HEX) file at `elf_path` with the default options
+    pub fn new(elf_path: impl Into<PathBuf>) -> Self {
+        let args = vec![
+            OsString::from("probe-run"),
+            elf_path.into().into_os_string(),
+        ];
+        Self::from_opts(Opts::from_iter(args))
+    }
+
+    /// Runs with the given command line options
+    pub fn from_opts(opts: Opts) -> Self {
+        Self {
+            opts,
+            hooks: Hooks::default(),
+        }
+    }
+
+    pub fn chip(mut self, chip: impl Into<String>) -> Self {
+        self.opts.chip = Some(chip.into());
+        self
+    }
+
+    /// Selects the probe like `--probe` does, e.g. `index:1` or `VID:PID:serial`
+    pub fn probe(mut self, probe: impl Into<String>) -> Self {
+        self.opts.probe = Some(probe.into());
+        self
+    }
+
+    /// The probe's speed, in kHz
+    pub fn speed(mut self, speed: u32) -> Self {
+        self.opts.speed = Some(Speed::Khz(speed));
+        self
+    }
+
+    /// Gives access to all the other options
+    pub fn opts_mut(&mut self) -> &mut Opts {
+        &mut self.opts
+    }
+
+    /// Calls `f` with each [`Record::Log`], [`Record::Output`], [`Record::Itm`],
+    /// [`Record::Variable`] and [`Record::Reset`]
+    pub fn on_log(mut self, f: impl Fn(&Record) + 'static) -> Self {
+        self.hooks.on_log = Some(Box::new(f));
+        self
+    }
+
+    /// Calls `f` with each [`Record::BacktraceFrame`], [`Record::ExceptionEntry`],
+    /// [`Record::SecurityTransition`] and [`Record::BacktraceError`]
+    pub fn on_backtrace_frame(mut self, f: impl Fn(&Record) + 'static) -> Self {
+        self.hooks.on_backtrace = Some(Box::new(f));
+        self
+    }
+
+    /// Calls `f` with the [`Record::Stack`] and [`Record::Exit`] records at the end of the run
+    pub fn on_exit(mut self, f: impl Fn(&Record) + 'static) -> Self {
+        self.hooks.on_exit = Some(Box::new(f));
+        self
+    }
+
+    /// Flashes and runs the program until it halts; returns the exit code `probe-run` would use
+    pub fn run(mut self) -> anyhow::Result<i32> {
+        self.hooks.json = self.opts.message_format == MessageFormat::Json;
+        self.hooks.server = serve::Server::bind(&self.opts.serve, self.opts.serve_raw)?;
+        if let Some(port) = self.opts.symbolizer_port {
+            let elf_path = self
+                .opts
+                .symbols
+                .as_ref()
+                .or(self.opts.elf.as_ref())
+                .ok_or_else(|| anyhow!("no ELF file was given"))?;
+            symbolizer::spawn(port, elf_path.clone(), self.opts.source_map.clone())?;
+        }
+        run(&self.opts, &self.hooks)
+    }
+
+    /// Prints the backtrace of the device as it is, without flashing or resetting it; returns the
+    /// exit code `probe-run backtrace` would use
+    pub fn backtrace(mut self) -> anyhow::Result<i32> {
+        self.hooks.json = self.opts.message_format == MessageFormat::Json;
+        let elf_path = self
+            .opts
+            .elf
+            .as_deref()
+            .ok_or_else(|| anyhow!("no ELF file was given"))?;
+        post_mortem::run(&self.opts, elf_path, &self.hooks)
+    }
+
+    /// Runs the programs listed in `manifest` one after the other; returns the exit code
+    /// `probe-run batch` would use
+    pub fn batch(mut self, manifest: &Path, artifacts: Option<&Path>) -> anyhow::Result<i32> {
+        self.hooks.json = self.opts.message_format == MessageFormat::Json;
+        // NOTE the symbolizer serves the symbols of a single ELF file
+        if self.opts.symbolizer_port.is_some() {
+            bail!(
+                "`--symbolizer-port` can't be used with `batch`; each program has its own symbols"
+            );
+        }
+        // NOTE the clients stay connected from one program to the next
+        self.hooks.server = serve::Server::bind(&self.opts.serve, self.opts.serve_raw)?;
+        batch::run(&mut self.opts, manifest, artifacts, &self.hooks)
+    }
+}
+
+/// How a single run of the program ended
+enum Ending {
+    /// The program halted (or `probe-run` stopped waiting for it); holds the exit code
+    Halted(i32),
+    /// Ctrl+C was pressed
+    Interrupted(i32),
+    /// `--watch` noticed a new build of the program
+    ElfChanged,
+}
+
+fn run(opts: &Opts, hooks: &Hooks) -> anyhow::Result<i32> {
+    let elf_path = opts
+        .elf
+        .as_deref()
+        .ok_or_else(|| anyhow!("no ELF file was given"))?;
+
+    // NOTE the registry is global; load the descriptions only once, not on every `--watch` run
+    target_info::load_chip_descriptions(&opts.chip_description)?;
+
+    let indicator_probe = if opts.run_indicator && !opts.dry_run {
+        let probe_info = probe::select(opts.probe.as_deref())?;
+        indicator::running(&probe_info)?;
+        Some(probe_info)
+    } else {
+        None
+    };
+
+    let scripts = Scripts::new(opts, elf_path);
+    let result = run_until_exit(opts, elf_path, hooks, &scripts);
+    if !opts.dry_run {
+        scripts.post_run(*result.as_ref().unwrap_or(&EXIT_FAILURE));
+    }
+    if let Some(probe_info) = &indicator_probe {
+        let success = matches!(result, Ok(EXIT_SUCCESS));
+        // NOTE the run's result matters more than the indicator
+        if let Err(e) = indicator::finished(probe_info, success) {
+            log::warn!("failed to update the run indicator: {:?}", e);
+        }
+    }
+    result
+}
+
+fn run_until_exit(
+    opts: &Opts,
+    elf_path: &Path,
+    hooks: &Hooks,
+    scripts: &Scripts,
+) -> anyhow::Result<i32> {
+    if !opts.watch {
+        return match retry::retry(opts.retries, || {
+            run_once(opts, elf_path, hooks, scripts, None, None)
+        })? {
+            Ending::Halted(code) | Ending::Interrupted(code) => Ok(code),
+            Ending::ElfChanged => unreachable!(),
+        };
+    }
+
+    let watcher = Watcher::new(elf_path);
+    loop {
+        match retry::retry(opts.retries, || {
+            run_once(opts, elf_path, hooks, scripts, Some(&watcher), None)
+        })? {
+            Ending::Interrupted(code) => return Ok(code),
+            Ending::Halted(code) => {
+                if !watcher.wait()? {
+                    return Ok(code);
+                }
+            }
+            Ending::ElfChanged => {}
+        }
+        log::info!("{} changed; restarting", elf_path.display());
+    }
+}
+
+fn run_once(
+    opts: &Opts,
+    elf_path: &Path,
+    hooks: &Hooks,
+    scripts: &Scripts,
+    watcher: Option<&Watcher>,
+    shared_session: Option<&SessionSlot>,
+) -> anyhow::Result<Ending> {
+    if ImageFormat::of(elf_path) == ImageFormat::Hex {
+        if let Some(slot) = shared_session {
+            slot.close();
+        }
+        return run_hex(opts, elf_path, hooks, scripts).map(Ending::Halted);
+    }
+
+    let json = hooks.json;
+    let mut run_stats = RunStats::start();
+    let bytes = fs::read(&elf_path)?;
+    // NOTE a stripped program is flashed as is; the symbols and debug info come from `--symbols`
+    let symbol_bytes = match &opts.symbols {
+        Some(path) => {
+            let symbol_bytes = fs::read(path)?;
+            if !flash::same_segments(&bytes, &symbol_bytes)? {
+                bail!(
+                    "{} doesn't match {}; were they built from the same sources?",
+                    path.display(),
+                    elf_path.display()
+                );
+            }
+            Some(symbol_bytes)
+        }
+        None => None,
+    };
+    let debug_bytes = symbol_bytes.as_deref().unwrap_or(&bytes);
+    let elf = ElfFile::parse(debug_bytes)?;
+    let arch = Arch::from_elf(&elf)?;
+    log::debug!("architecture: {:?}", arch);
+
+    // NOTE we want to raise the linking error before calling `decoder::parse`
+    let text = elf
+        .section_by_name(".text")
+        .map(|section| section.index())
+        .ok_or_else(|| {
+            anyhow!(
+                "`.text` section is missing, please make sure that the linker script was passed \
+                to the linker (check `.cargo/config.toml` and the `RUSTFLAGS` variable)"
+            )
+        })?;
+
+    let (table, locs) = decoder::parse(debug_bytes)?;
+
+    // sections used in cortex-m-rt
+    // NOTE we won't load `.uninit` so it is not included here
+    // NOTE we don't load `.bss` because the app (cortex-m-rt) will zero it
+    let candidates = [".vector_table", ".text", ".rodata", ".data"];
+
+    let mut debug_frame = None;
+    let mut sections = vec![];
+    let mut vector_table = None;
+    for sect in elf.sections() {
+        if let Ok(name) = sect.name() {
+            if name == ".debug_frame" {
+                debug_frame = Some(sect.data()?);
+                continue;
+            }
+
+            let size = sect.size();
+            // skip empty sections
+            if candidates.contains(&name) && size != 0 {
+                let start = sect.address();
+                if size % 4 != 0 || start % 4 != 0 {
+                    // we could support unaligned sections but let's not do that now
+                    bail!("section `{}` is not 4-byte aligned", name);
+                }
+
+                let start = target_info::address(start)?;
+                let data = sect
+                    .data()?
+                    .chunks_exact(4)
+                    .map(|chunk| u32::from_le_bytes(*array_ref!(chunk, 0, 4)))
+                    .collect::<Vec<_>>();
+
+                if name == ".vector_table" {
+                    vector_table = Some(VectorTable::parse(start, &data)?);
+                }
+
+                sections.push(Section { start, data });
+            }
+        }
+    }
+    let (debug_frame, vector_table) = (debug_frame, vector_table);
+
+    let live_functions = elf
+        .symbols()
+        .filter_map(|sym| {
+            if sym.section() == SymbolSection::Section(text) {
+                Some(sym.name())
+            } else {
+                None
+            }
+        })
+        .collect::<Result<HashSet<_>, _>>()?;
+
+    let (rtt_addr, uses_heap, main) = get_rtt_heap_main_from(&elf, arch)?;
+    let mut watches = Watches::new(&opts.watch_var, &elf)?;
+    let breakpoints = Breakpoints::new(&opts.break_on, opts.break_on_panic, &elf, arch)?;
+    let catch = Catch::new(&opts.catch, arch)?;
+    let args = Args::new(&opts.args, &opts.args_symbol, &elf)?;
+    let test_filter = match &opts.test_filter {
+        Some(filter) => Args::new(slice::from_ref(filter), defmt_test::FILTER_SYMBOL, &elf)
+            .context("`--test-filter` needs a `defmt-test` version that supports filtering")?,
+        None => None,
+    };
+    let mut bench = if opts.bench {
+        Some(Bench::new(&elf, arch)?)
+    } else {
+        None
+    };
+    if (args.is_some() || test_filter.is_some()) && opts.attach {
+        bail!("arguments can't be passed to a program that is already running");
+    }
+    let peripherals = match (&opts.svd, opts.dump_peripherals.is_empty()) {
+        (Some(svd), false) => Some(svd::Peripherals::load(svd, &opts.dump_peripherals)?),
+        (None, false) => {
+            bail!("`--dump-peripherals` needs the chip's SVD file; pass it with `--svd`")
+        }
+        (_, true) => None,
+    };
+
+    let entry_point: EntryPoint = match arch {
+        Arch::CortexM => {
+            let vector_table =
+                vector_table.ok_or_else(|| anyhow!("`.vector_table` section is missing"))?;
+            log::debug!("vector table: {:x?}", vector_table);
+            vector_table.into()
+        }
+        Arch::CortexAR | Arch::Aarch64 => cortexa::entry_point(&elf)?,
+        Arch::Riscv32 => riscv::entry_point(&elf)?,
+    };
+    log::debug!("entry point: {:x?}", entry_point);
+
+    // NOTE `--dry-run` goes as far as it can without a probe
+    let probe_info = if opts.dry_run {
+        None
+    } else {
+        Some(probe::select(opts.probe.as_deref())?)
+    };
+
+    let layout = bootloader::Layout::new(&opts.skip_region, opts.app_offset, &elf, &entry_point)?;
+    let mut target_info = TargetInfo::new(
+        opts.chip.as_deref(),
+        &elf,
+        &entry_point,
+        probe_info.as_ref(),
+        layout.skipped(),
+        &RegionOverrides {
+            ram: &opts.ram_region,
+            flash: &opts.flash_region,
+        },
+    )?;
+    flash_loader::select(&mut target_info.probe_target, &opts.flash_loader)?;
+    let target_info = target_info;
+    if let Some(stack_info) = &target_info.stack_info {
+        log::debug!(
+            "{} bytes of RAM are left for the stack (0x{:08X}-0x{:08X})",
+            stack_info.size(),
+            stack_info.range.start(),
+            stack_info.range.end()
+        );
+    }
+    if let Some(min_stack) = opts.min_stack {
+        check_stack_size(target_info.stack_info.as_ref(), min_stack)?;
+    }
+    check_ram_budget(
+        &target_info,
+        opts.stack_threshold.unwrap_or(STACK_THRESHOLD),
+        opts.strict_memory,
+    )?;
+    let probe_info = match &probe_info {
+        Some(probe_info) => probe_info,
+        None => {
+            return dry_run::report(&elf, &bytes, debug_bytes, &target_info, opts.ram_exec)
+                .map(Ending::Halted)
+        }
+    };
+    let cache = Cache::new(probe_info, &target_info.probe_target.name, !opts.no_cache);
+    // NOTE in a batch the previous program may have left the session open
+    let reused_session = shared_session.and_then(|slot| slot.take(&target_info.probe_target.name));
+    scripts.pre_run(probe_info, &target_info.probe_target.name)?;
+    if reused_session.is_none() {
+        power::prepare(probe_info, opts.power_cycle)?;
+    }
+
+    let chip = &target_info.probe_target.name;
+    // NOTE some locked chips refuse the attach, so they are unlocked before it
+    let unlocked = opts.chip_unlock
+        && reused_session.is_none()
+        && unlock::unlock_before_attach(probe_info, chip, opts)?;
+
+    let attach_start = Instant::now();
+    let mut sess = match reused_session {
+        Some(sess) => {
+            log::debug!("reusing the session of the previous program");
+            sess
+        }
+        None => open_session(
+            probe_info,
+            target_info.probe_target.clone(),
+            opts,
+            opts.connect_under_reset,
+        )
+        .map_err(|e| unlock::explain_attach_error(probe_info, chip, opts, e))?,
+    };
+    run_stats.attached(attach_start.elapsed());
+    reset_cause::report(&mut sess, &target_info.probe_target.name);
+
+    if opts.print_memory_usage && !json {
+        memory_usage::print(&elf, &bytes, &target_info.probe_target)?;
+    }
+
+    if opts.chip_unlock {
+        if !unlocked {
+            unlock::unlock(&mut sess, chip)?;
+        }
+        cache.update(|state| state.elf_hash = None);
+    }
+
+    if !opts.attach && !opts.no_flash && !opts.ram_exec {
+        let ranges = flash::loadable_segments(&bytes)?
+            .iter()
+            .map(|segment| segment.address..segment.address + segment.data.len() as u32)
+            .collect::<Vec<_>>();
+        protection::check(&mut sess, &target_info.probe_target.name, &ranges)?;
+    }
+
+    let preserved_ranges = preserve::ranges(&opts.preserve, &elf)?;
+    let flash_start = Instant::now();
+    if opts.attach {
+        log::info!("attaching to the running program; skipped flashing");
+    } else if opts.no_flash {
+        log::info!("skipped flashing");
+    } else if opts.ram_exec {
+        ram_exec::load(&mut sess, opts.core, &bytes, &target_info.probe_target)?;
+        log::info!("success!");
+    } else if opts.flash_bank == Some(FlashBank::Inactive) {
+        cache.update(|state| state.elf_hash = None);
+        flash_hooks::run(&mut sess, &opts.flash_hooks, flash_hooks::Point::PreErase)?;
+        let progress = Progress::new(!json);
+        let flash_progress = progress.flash_progress();
+        let reset = bank::flash_inactive(
+            &mut sess,
+            &target_info.probe_target,
+            &bytes,
+            Some(&flash_progress),
+        )?;
+        if reset {
+            log::debug!("attaching again after the reset that loaded the option bytes");
+            drop(sess);
+            sess = open_session(probe_info, target_info.probe_target.clone(), opts, false)?;
+        }
+        flash_hooks::run(
+            &mut sess,
+            &opts.flash_hooks,
+            flash_hooks::Point::PostProgram,
+        )?;
+        log::info!("success!");
+        if opts.timings {
+            progress.print_timings();
+        }
+    } else if (opts.skip_unchanged || opts.watch)
+        && cache.state().elf_hash == Some(cache::hash(&bytes)?)
+        && flash::spot_check(&mut sess, opts.core, &bytes, &preserved_ranges)?
+    {
+        // NOTE the cache can't tell if something else flashed the device since; the spot check
+        // catches another program, whose vector table differs, without reading back all of it
+        log::info!("the previous run flashed this program; skipped flashing");
+    } else if (opts.skip_unchanged || opts.watch)
+        && flash::is_up_to_date(&mut sess, opts.core, &bytes, &preserved_ranges)?
+    {
+        log::info!("program is already on the device; skipped flashing");
+        let elf_hash = cache::hash(&bytes)?;
+        cache.update(|state| state.elf_hash = Some(elf_hash));
+    } else {
+        let preserved = preserve::save(&mut sess, &preserved_ranges)?;
+        // NOTE if flashing fails half way the next run must not skip it
+        cache.update(|state| state.elf_hash = None);
+        flash_hooks::run(&mut sess, &opts.flash_hooks, flash_hooks::Point::PreErase)?;
+        if opts.erase_all {
+            log::info!("erasing the whole flash");
+            flashing::erase_all(&mut sess)?;
+        }
+        for preflash in &opts.preflash {
+            preflash.download(&mut sess)?;
+        }
+
+        // program lives in Flash
+        flash_loader::check_segments(&target_info.probe_target, &bytes)?;
+        layout.check_segments(&bytes)?;
+        let size = program_size_of(&elf);
+        log::info!("flashing program ({:.02} KiB)", size as f64 / 1024.0);
+        let progress = Progress::new(!json);
+        let flash_progress = progress.flash_progress();
+        let mut download_options = preserve::download_options(&preserved, Some(&flash_progress));
+        // NOTE the sectors the program shares with a skipped region must keep its contents
+        download_options.keep_unwritten_bytes |= !layout.skipped().is_empty();
+        flashing::download_file_with_options(&mut sess, &elf_path, Format::Elf, download_options)?;
+        preserve::restore(&mut sess, &preserved)?;
+        flash_hooks::run(
+            &mut sess,
+            &opts.flash_hooks,
+            flash_hooks::Point::PostProgram,
+        )?;
+        if opts.verify {
+            flash::verify(&mut sess, opts.core, &bytes, &preserved_ranges, &progress)?;
+        }
+        let elf_hash = cache::hash(&bytes)?;
+        cache.update(|state| state.elf_hash = Some(elf_hash));
+        log::info!("success!");
+        if opts.timings {
+            progress.print_timings();
+        }
+    }
+    if !opts.attach && !opts.no_flash {
+        run_stats.flashed(flash_start.elapsed());
+    }
+    if !opts.attach && !opts.write_uicr.is_empty() {
+        option_bytes::write(
+            &mut sess,
+            &target_info.probe_target.name,
+            &opts.write_uicr,
+            opts.allow_permanent_lock,
+        )?;
+    }
+
+    // NOTE a reset would start the program in flash; the RAM image is started in place instead
+    let reset_type = if opts.ram_exec {
+        ResetType::None
+    } else {
+        opts.reset_type.unwrap_or_default()
+    };
+    if !opts.attach && reset_type == ResetType::Hardware {
+        // pulse NRST: reconnecting under reset asserts it until the session is up
+        log::debug!("resetting the device through NRST");
+        drop(sess);
+        sess = open_session(probe_info, target_info.probe_target.clone(), opts, true)?;
+    }
+
+    let mut itm = None;
+    if let (true, Some(core_freq)) = (opts.itm, opts.core_freq) {
+        itm::setup(&mut sess, arch, core_freq, opts.swo_baud, opts.irq_profile)?;
+        itm = Some(itm::Decoder::new(opts.irq_profile, core_freq));
+    }
+
+    // the core that runs the program; with `--all-cores` the other cores are monitored as well
+    let secondary_cores = if opts.all_cores {
+        sess.list_cores()
+            .into_iter()
+            .map(|(index, _)| index)
+            .filter(|index| *index != opts.core)
+            .collect()
+    } else {
+        vec![]
+    };
+
+    let mut heap = if opts.measure_heap {
+        if uses_heap {
+            heap::Monitor::new(&elf, &target_info, opts.heap_interval)?
+        } else {
+            log::info!("the program doesn't use a heap; nothing to measure");
+            None
+        }
+    } else {
+        None
+    };
+
+    let handshake = startup::Handshake {
+        rtt: rtt_addr,
+        args: args.as_ref(),
+        test_filter: test_filter.as_ref(),
+        grace_period: opts.startup_grace_period,
+    };
+    let locator = startup::Locator {
+        arch,
+        main,
+        elf: &elf,
+        live_functions: &live_functions,
+        source_map: &opts.source_map,
+    };
+    // NOTE the program sets its RTT control block up anew after resetting itself
+    let handshake_on_reset = arch == Arch::CortexM && !opts.attach && handshake.is_needed();
+
+    // NOTE `--timeout` covers the whole run, the way to `main` included
+    let deadline = opts.timeout.map(|timeout| Instant::now() + timeout);
+
+    let mut canary = None;
+    {
+        let mut core = sess.core(opts.core)?;
+        run_stats.reset();
+        if opts.attach {
+            // halt only long enough to set up the breakpoints; the program keeps its state
+            core.halt(TIMEOUT)?;
+        } else if reset_type == ResetType::Software {
+            core.reset_and_halt(TIMEOUT)?;
+            // NOTE the core comes out of reset in the bootloader; start the application instead
+            if layout.starts_app() {
+                arch.restart(&mut core, &entry_point)?;
+            }
+        } else {
+            // the core is out of reset already (hardware) or is not reset at all (none)
+            core.halt(TIMEOUT)?;
+            arch.restart(&mut core, &entry_point)?;
+        }
+
+        // NOTE the stack of an already running program is in use and must not be painted over
+        if !opts.attach {
+            let stack_limit = if arch == Arch::CortexM {
+                stack_limit::set(&mut core, &target_info, uses_heap)?
+            } else {
+                None
+            };
+            // NOTE with MSPLIM in place, the canary is only needed to measure the stack usage
+            let strategy = opts.canary_strategy();
+            if stack_limit.is_none() || strategy == CanaryStrategy::Full {
+                canary = Canary::install(
+                    &mut core,
+                    &target_info,
+                    uses_heap,
+                    strategy,
+                    opts.canary_size,
+                )?;
+            }
+            if let Some(heap) = &mut heap {
+                heap.paint(&mut core)?;
+            }
+        }
+
+        log::debug!("starting device");
+        if core.get_available_breakpoint_units()? == 0 {
+            if rtt_addr.is_some() {
+                bail!("RTT not supported on device without HW breakpoints");
+            } else {
+                log::warn!("device doesn't support HW breakpoints; HardFault will NOT make `probe-run` exit with an error code");
+            }
+        }
+
+        // NOTE the RTT control block and the arguments are set up once the program has
+        // initialized its static variables, i.e. when it reaches `main`
+        if !opts.attach && handshake.is_needed() {
+            handshake.perform(&mut core, deadline, &locator)?;
+        }
+
+        if let Some(fault_handler) = entry_point.fault_handler {
+            core.set_hw_breakpoint(arch.instruction_address(fault_handler))?;
+        }
+        let mut num_in_use = entry_point.fault_handler.is_some() as u32;
+        num_in_use += catch.set(&mut core, &entry_point, num_in_use)?;
+        if let Some(bench) = &bench {
+            num_in_use += bench.set(&mut core)?;
+        }
+        breakpoints.set(&mut core, num_in_use)?;
+        core.run()?;
+    }
+    for index in &secondary_cores {
+        let mut core = sess.core(*index)?;
+        core.halt(TIMEOUT)?;
+        if let Some(fault_handler) = entry_point.fault_handler {
+            core.set_hw_breakpoint(arch.instruction_address(fault_handler))?;
+        }
+        let mut num_in_use = entry_point.fault_handler.is_some() as u32;
+        num_in_use += catch.set(&mut core, &entry_point, num_in_use)?;
+        breakpoints.set(&mut core, num_in_use)?;
+        core.run()?;
+    }
+    let canary = canary;
+
+    // Register a signal handler that sets `exit` to `true` on Ctrl+C. On the second Ctrl+C, the
+    // signal's default action will be run.
+    let exit = Arc::new(AtomicBool::new(false));
+    let sigid = signal_hook::flag::register(signal::SIGINT, exit.clone())?;
+
+    let scan_region = rtt_addr.map(|rtt_addr| {
+        if opts.attach {
+            // we can't be sure the running program was built from this exact ELF; rather than
+            // trusting the symbol address scan the stack's RAM region for the control block
+            target_info
+                .active_ram_region
+                .as_ref()
+                .map_or(ScanRegion::Ram, |ram| ScanRegion::Range(ram.range.clone()))
+        } else {
+            ScanRegion::Exact(rtt_addr)
+        }
+    });
+
+    let sess = Arc::new(Mutex::new(sess));
+    if let Some(slot) = shared_session {
+        slot.keep(&target_info.probe_target.name, &sess);
+    }
+    let (mut channels, mut level_setter, down_channel) = if let Some(scan_region) = &scan_region {
+        // NOTE scanning the RAM is slow; first look where the last run found the control block
+        let cached = match (opts.attach, cache.state().rtt_address) {
+            (true, Some(address)) => rtt::attach_at(address, sess.clone()),
+            _ => None,
+        };
+        let mut rtt = match cached {
+            Some(rtt) => rtt,
+            None => rtt::attach(scan_region, sess.clone())?,
+        };
+        if opts.attach {
+            let address = rtt.ptr();
+            cache.update(|state| state.rtt_address = Some(address));
+        }
+        rtt::log_channels(&mut rtt);
+        let channels = rtt::Channel::take_all(&mut rtt, &sess, &opts.rtt_channel, &opts.rtt_map)?;
+        let level_setter = opts
+            .set_log_level
+            .map(|level| LevelSetter::new(&mut rtt, level, &elf))
+            .transpose()?;
+        (channels, level_setter, rtt.down_channels().take(0))
+    } else {
+        if opts.serial.is_none() {
+            eprintln!("RTT logs not available; blocking until the device halts..");
+        }
+        if opts.set_log_level.is_some() {
+            log::warn!("RTT is not available; `--set-log-level` has no effect");
+        }
+        (vec![], None, None)
+    };
+    let mut serial = opts
+        .serial
+        .clone()
+        .map(|spec| Serial::new(spec, opts.serial_format));
+
+    let mut input = match down_channel {
+        // NOTE the REPL reads its commands from stdin
+        Some(_) if opts.repl && opts.input_file.is_none() => None,
+        Some(down_channel) => Some(input::Forwarder::new(
+            down_channel,
+            opts.input_file.as_deref(),
+        )?),
+        None => {
+            if opts.input_file.is_some() || opts.interactive {
+                log::warn!("RTT down channel 0 not found; input will not be forwarded");
+            }
+            None
+        }
+    };
+    let _raw_terminal = if opts.interactive && input.is_some() {
+        Some(input::RawTerminal::enable()?)
+    } else {
+        None
+    };
+
+    let external_decoder = match &opts.decoder {
+        Some(Decoder::External(command)) => Some(ExternalDecoder::spawn(command, elf_path)?),
+        _ => None,
+    };
+
+    let use_defmt = channels.iter().any(|channel| channel.uses_defmt())
+        || serial.as_ref().map_or(false, Serial::uses_defmt);
+
+    if use_defmt && table.is_none() && external_decoder.is_none() {
+        bail!("\"defmt\" RTT channel is in use, but the firmware binary contains no defmt data");
+    }
+    // NOTE the frames of another build of the program decode to garbage with this ELF's table
+    if use_defmt && (opts.no_flash || opts.attach) {
+        log::debug!("comparing the flash contents against the ELF file");
+        let mut sess = sess.lock().unwrap();
+        if !flash::is_up_to_date(&mut sess, opts.core, &bytes, &preserved_ranges)? {
+            if opts.force_decode {
+                log::warn!(
+                    "the firmware on the device doesn't match the ELF file; the logs may be garbled"
+                );
+            } else {
+                bail!(
+                    "firmware/ELF mismatch -- the device runs a different build than {}; \
+                    reflash it (drop `--no-flash`/`--attach`) or pass `--force-decode`",
+                    elf_path.display()
+                );
+            }
+        }
+    }
+
+    if !json {
+        print_separator();
+    }
+
+    let patterns = Patterns::new(opts.success_pattern.clone(), opts.failure_pattern.clone());
+    let tests = Tests::default();
+    let log_stats = LogStats::default();
+    let plot = match &opts.plot_out {
+        Some(path) => Some(Plot::new(path)?),
+        None => None,
+    };
+    let log_file = match &opts.log_file {
+        Some(path) => Some(LogFile::create(
+            path,
+            opts.log_file_max_size,
+            opts.log_file_rotate,
+        )?),
+        None => None,
+    };
+    if plot.is_none() && channels.iter().any(rtt::Channel::uses_plot) {
+        bail!("an RTT channel is mapped to `plot`; pass `--plot-out <path>` to write its samples");
+    }
+
+    // wait for breakpoint
+    // NOTE large enough to drain any channel in one read, so that full buffers can be noticed
+    let read_buf_size = channels
+        .iter()
+        .map(rtt::Channel::buffer_size)
+        .max()
+        .unwrap_or(0)
+        .max(1024);
+    let mut read_buf = vec![0; read_buf_size];
+    let mut was_halted = false;
+    let current_dir = std::env::current_dir()?;
+    let hyperlinks = Hyperlinks::detect(opts.hyperlinks, opts.hyperlink_url.as_deref());
+    let timestamps = Timestamps::new(opts.timestamp);
+    // TODO strip prefix from crates-io paths (?)
+    let printer = rtt::Printer {
+        table: table.as_ref(),
+        locs: locs.as_ref(),
+        current_dir: &current_dir,
+        log_filter: opts.log_filter.as_ref(),
+        patterns: patterns.as_ref(),
+        tests: Some(&tests),
+        log_stats: if opts.log_stats || opts.stats {
+            Some(&log_stats)
+        } else {
+            None
+        },
+        plot: plot.as_ref(),
+        log_file: log_file.as_ref(),
+        external_decoder: external_decoder.as_ref(),
+        quiet_stdout: opts.quiet_stdout,
+        timestamps: Some(&timestamps),
+        hyperlinks: hyperlinks.as_ref(),
+        hooks,
+    };
+    let mut spill = if opts.defer_decode {
+        Some(Spill::create()?)
+    } else {
+        None
+    };
+    let mut recorder = match &opts.record {
+        Some(path) => Some(Recorder::create(path, &elf)?),
+        None => None,
+    };
+    let mut semihosting = if arch == Arch::CortexM {
+        Some(Semihosting::new(opts.semihosting_fs.clone()))
+    } else {
+        None
+    };
+    let mut semihosting_exit_code = None;
+    // NOTE the sticky reset bit only exists on Cortex-M
+    let mut reset_watch = if arch == Arch::CortexM {
+        let mut sess = sess.lock().unwrap();
+        Some(ResetWatch::new(
+            &mut sess.core(opts.core)?,
+            opts.max_resets,
+            handshake_on_reset,
+        )?)
+    } else {
+        None
+    };
+    let mut timed_out = false;
+    let mut too_many_resets = false;
+    let mut elf_changed = false;
+    'poll: while !exit.load(Ordering::Relaxed) {
+        if let Some(count) = reset_watch.as_mut().and_then(ResetWatch::take_reset) {
+            printer.reset(count)?;
+            if reset_watch.as_ref().map_or(false, ResetWatch::exceeded) {
+                too_many_resets = true;
+                break;
+            }
+            if handshake_on_reset {
+                // NOTE the reset halted the core at the reset vector; like the first boot, it
+                // runs to `main` without the fault handler breakpoint, which frees a unit for it
+                let mut sess = sess.lock().unwrap();
+                let mut core = sess.core(opts.core)?;
+                let fault_handler = entry_point
+                    .fault_handler
+                    .map(|fault_handler| arch.instruction_address(fault_handler));
+                if let Some(fault_handler) = fault_handler {
+                    core.clear_hw_breakpoint(fault_handler)?;
+                }
+                handshake.perform(&mut core, deadline, &locator)?;
+                if let Some(fault_handler) = fault_handler {
+                    core.set_hw_breakpoint(fault_handler)?;
+                }
+                core.run()?;
+            }
+            if let Some(scan_region) = &scan_region {
+                if !handshake_on_reset {
+                    // NOTE the control block of the previous boot may still be in RAM;
+                    // attaching to it before the start-up code overwrote it would read stale
+                    // buffer offsets
+                    thread::sleep(RESET_SETTLE_TIME);
+                }
+                let mut rtt = rtt::attach(scan_region, sess.clone())?;
+                // NOTE the down channel keeps working: its buffer is at the same address
+                rtt::Channel::reattach_all(&mut channels, &mut rtt)?;
+            }
+            // the program starts over with its default level
+            if let Some(level_setter) = &mut level_setter {
+                level_setter.resend();
+            }
+        }
+
+        if let Some(input) = &mut input {
+            input.poll()?;
+        }
+        if let Some(level_setter) = &mut level_setter {
+            level_setter.poll()?;
+        }
+
+        for channel in &mut channels {
+            let num_bytes_read = match channel.read(&mut read_buf) {
+                Ok(n) => n,
+                // NOTE with `--retries` the program is restarted, or re-attached to
+                Err(e) if opts.retries > 0 => return Err(e.into()),
+                Err(e) => {
+                    eprintln!("RTT error: {}", e);
+                    break 'poll;
+                }
+            };
+
+            if num_bytes_read != 0 {
+                run_stats.data_received();
+                let data = &read_buf[..num_bytes_read];
+                if let Some(recorder) = &mut recorder {
+                    recorder.write(channel.number(), channel.uses_defmt(), data)?;
+                }
+                if let (Some(server), true) = (&hooks.server, channel.uses_defmt()) {
+                    server.raw(data);
+                }
+                match &mut spill {
+                    Some(spill) if channel.uses_defmt() => spill.write(channel.number(), data)?,
+                    _ => channel.forward(data, &printer)?,
+                }
+            }
+        }
+        if let Some(serial) = &mut serial {
+            serial.poll(&mut read_buf, &printer)?;
+        }
+
+        let mut sess = sess.lock().unwrap();
+        if let Some(itm) = &mut itm {
+            let data = sess.read_swo()?;
+            if !data.is_empty() {
+                itm.feed(&data, &printer)?;
+            }
+        }
+
+        let mut core = sess.core(opts.core)?;
+        let mut is_halted = match &mut reset_watch {
+            Some(reset_watch) => reset_watch.poll(&mut core)?,
+            None => core.core_halted()?,
+        };
+        if !is_halted {
+            if let Some(level_setter) = &mut level_setter {
+                level_setter.check(&mut core)?;
+            }
+            watches.poll(&mut core, &printer)?;
+            if let Some(heap) = &mut heap {
+                heap.poll(&mut core)?;
+            }
+        }
+
+        if let (true, false, Some(semihosting)) = (is_halted, was_halted, &mut semihosting) {
+            match semihosting.handle(&mut core, &printer)? {
+                Some(semihosting::Outcome::Resumed) => continue,
+                Some(semihosting::Outcome::Exited(code)) => semihosting_exit_code = Some(code),
+                None => {}
+            }
+        }
+        if let (true, false, Some(bench)) = (is_halted, was_halted, &mut bench) {
+            if bench.handle(&mut core)? {
+                continue;
+            }
+        }
+        drop(core);
+
+        for index in &secondary_cores {
+            is_halted |= sess.core(*index)?.core_halted()?;
+        }
+
+        if is_halted && was_halted {
+            break;
+        }
+        was_halted = is_halted;
+
+        if patterns.as_ref().and_then(Patterns::outcome).is_some() {
+            break;
+        }
+        if deadline.map_or(false, |deadline| Instant::now() >= deadline) {
+            timed_out = true;
+            break;
+        }
+        if watcher.map_or(false, Watcher::changed) {
+            elf_changed = true;
+            break;
+        }
+    }
+
+    if let Some(spill) = spill {
+        spill.decode(&printer)?;
+    }
+    if let Some(external_decoder) = &external_decoder {
+        external_decoder.finish()?;
+    }
+
+    // Make any incoming SIGINT terminate the process.
+    // Due to https://github.com/vorner/signal-hook/issues/97, this will result in SIGABRT, but you
+    // only need to Ctrl+C here if the backtrace hangs, so that should be fine.
+    signal_hook::low_level::unregister(sigid);
+    signal_hook::flag::register_conditional_default(signal::SIGINT, exit.clone())?;
+
+    if !json && (opts.rtt_stats || channels.iter().any(rtt::Channel::had_overruns)) {
+        rtt::print_stats(&channels);
+    }
+    if !json && opts.log_stats {
+        log_stats.print();
+    }
+    if opts.stats {
+        run_stats.report(log_stats.frames(), hooks);
+    }
+    if let Some(bench) = &bench {
+        bench.report(opts.core_freq, hooks);
+    }
+
+    if let Some(outcome) = patterns.as_ref().and_then(Patterns::outcome) {
+        // NOTE the device is left running; dropping the session detaches from it
+        let reason = match outcome {
+            patterns::Outcome::Success => {
+                log::info!("log output matched the success pattern");
+                "success_pattern"
+            }
+            patterns::Outcome::Failure => {
+                log::error!("log output matched the failure pattern");
+                "failure_pattern"
+            }
+        };
+        let code = exit_code::code(&opts.exit_code_map, reason);
+        hooks.emit(&Record::Exit { reason, code });
+        return Ok(Ending::Halted(code));
+    }
+
+    if elf_changed {
+        // NOTE the next run halts and resets the device
+        return Ok(Ending::ElfChanged);
+    }
+
+    let mut session = sess.lock().unwrap();
+
+    if let (Some(itm), false) = (&itm, json) {
+        print_separator();
+        itm.print_profile(&elf);
+        itm.print_irq_profile(&elf);
+    }
+
+    // stop the cores that are still running so that their state can be inspected
+    for index in &secondary_cores {
+        session.core(*index)?.halt(TIMEOUT)?;
+    }
+
+    let mut core = session.core(opts.core)?;
+
+    let interrupted = exit.load(Ordering::Relaxed);
+    if interrupted {
+        // Ctrl-C was pressed; stop the microcontroller and report where it was
+        core.halt(TIMEOUT)?;
+        let pc = core.read_core_reg(arch.program_counter())?;
+        log::info!(
+            "interrupted; the program was at 0x{:08X}",
+            arch.instruction_address(pc)
+        );
+    } else if timed_out {
+        log::error!(
+            "the program did not halt within {:?}",
+            opts.timeout.unwrap()
+        );
+        core.halt(TIMEOUT)?;
+    } else if too_many_resets {
+        log::error!(
+            "the program reset {} times; more than `--max-resets` allows",
+            reset_watch.as_ref().map_or(0, ResetWatch::count)
+        );
+        core.halt(TIMEOUT)?;
+    }
+
+    if let (Arch::CortexM, Some(secure)) = (arch, opts.security_state()) {
+        // unwind the stack of the selected security state
+        cortexm::select_security_state(&mut core, secure)?;
+    }
+
+    let (min_stack_usage, canary_touched) = match &canary {
+        Some(canary) => {
+            let stack_usage = canary.read_back(&mut core)?;
+            (stack_usage.min_stack_usage, stack_usage.canary_touched)
+        }
+        None => (None, false),
+    };
+    if interrupted && !canary_touched {
+        if let Some(canary) = &canary {
+            log::info!(
+                "the stack has not overflowed so far ({} canary, {} bytes)",
+                canary.strategy().name(),
+                canary.size()
+            );
+        }
+    }
+    hooks.emit(&Record::Stack {
+        canary_strategy: canary
+            .as_ref()
+            .map_or(CanaryStrategy::None, |canary| canary.strategy())
+            .name(),
+        canary_size: canary.as_ref().map_or(0, |canary| canary.size()),
+        canary_touched,
+        min_stack_usage,
+    });
+    if let Some(heap) = &heap {
+        let heap_usage = heap.read_back(&mut core)?;
+        hooks.emit(&Record::Heap {
+            peak_usage: heap_usage.peak_usage,
+            used: heap_usage.used,
+            size: heap_usage.size,
+            fragmentation: heap_usage.fragmentation,
+        });
+    }
+
+    let breakpoint_hit = breakpoints.hit(&mut core, arch)?;
+    let caught = catch.hit(&mut core)?;
+    drop(core);
+
+    // NOTE only needed to explain faults; built after the run to not delay start-up
+    let address_map = AddressMap::new(&elf, &target_info.probe_target, opts.svd.as_deref());
+
+    let top_exception = if arch.supports_backtrace() {
+        let mut core = session.core(opts.core)?;
+        let pc = core.read_core_reg(arch.program_counter())?;
+
+        let debug_frame = debug_frame.ok_or_else(|| anyhow!("`.debug_frame` section not found"))?;
+
+        if !json {
+            print_separator();
+            if let Some(exception) = caught.filter(|exception| exception.is_fault()) {
+                log::error!("caught {}", exception.description());
+                FaultStatus::read(&mut core)?.print(&address_map);
+            }
+        }
+
+        let settings = backtrace::Settings {
+            current_dir: &current_dir,
+            // TODO any other cases in which we should force a backtrace?
+            force_backtrace: opts.force_backtrace
+                || canary_touched
+                || timed_out
+                || interrupted
+                || breakpoint_hit.is_some()
+                || caught.is_some(),
+            max_backtrace_len: opts.max_backtrace_len,
+            backtrace_limit: opts.backtrace_limit,
+            message_format: opts.message_format,
+            backtrace_format: opts.backtrace_format,
+            backtrace_context: opts.backtrace_context,
+            disassemble: opts.disassemble,
+            source_map: &opts.source_map,
+            hyperlinks: hyperlinks.as_ref(),
+            address_map: &address_map,
+            hooks,
+        };
+        if !secondary_cores.is_empty() && !json {
+            println!("core {}", opts.core);
+        }
+        let mut top_exception = backtrace::construct(
+            &mut core,
+            arch,
+            pc,
+            debug_frame,
+            &elf,
+            &entry_point,
+            &target_info.active_ram_region,
+            &live_functions,
+            &settings,
+        )?;
+        drop(core);
+
+        for index in &secondary_cores {
+            let mut core = session.core(*index)?;
+            let pc = core.read_core_reg(arch.program_counter())?;
+            if !json {
+                println!("\ncore {}", index);
+            }
+            let core_exception = backtrace::construct(
+                &mut core,
+                arch,
+                pc,
+                debug_frame,
+                &elf,
+                &entry_point,
+                &target_info.active_ram_region,
+                &live_functions,
+                &settings,
+            )?;
+            top_exception = top_exception.or(core_exception);
+        }
+        top_exception
+    } else {
+        log::info!("backtraces are not supported on {:?} targets yet", arch);
+        None
+    };
+
+    // NOTE hardware breakpoints survive a reset and would trip up the next run
+    for index in &secondary_cores {
+        let mut core = session.core(*index)?;
+        core.clear_all_hw_breakpoints()?;
+        catch.clear(&mut core)?;
+    }
+    let mut core = session.core(opts.core)?;
+    catch.clear(&mut core)?;
+    if let Some(reset_watch) = &reset_watch {
+        reset_watch.clear(&mut core)?;
+    }
+
+    let crashed = top_exception.is_some() || caught.map_or(false, |exception| exception.is_fault());
+    if let (Some(peripherals), true, false) = (&peripherals, crashed, json) {
+        println!();
+        peripherals.dump(&mut core)?;
+    }
+
+    if let (Some(path), Some(_)) = (&opts.core_dump, &top_exception) {
+        coredump::write(path, &mut core, arch, &target_info)?;
+        log::info!("core dump written to {}", path.display());
+    }
+
+    let repl_outcome = if opts.repl && !json {
+        let settings = backtrace::Settings {
+            current_dir: &current_dir,
+            force_backtrace: true,
+            max_backtrace_len: opts.max_backtrace_len,
+            backtrace_limit: opts.backtrace_limit,
+            message_format: opts.message_format,
+            backtrace_format: opts.backtrace_format,
+            backtrace_context: opts.backtrace_context,
+            disassemble: opts.disassemble,
+            source_map: &opts.source_map,
+            hyperlinks: hyperlinks.as_ref(),
+            address_map: &address_map,
+            hooks,
+        };
+        let mut print_backtrace = |core: &mut Core<'_>| -> anyhow::Result<()> {
+            let debug_frame = match debug_frame {
+                Some(debug_frame) if arch.supports_backtrace() => debug_frame,
+                _ => bail!("backtraces are not available for this program"),
+            };
+            let pc = core.read_core_reg(arch.program_counter())?;
+            backtrace::construct(
+                core,
+                arch,
+                pc,
+                debug_frame,
+                &elf,
+                &entry_point,
+                &target_info.active_ram_region,
+                &live_functions,
+                &settings,
+            )?;
+            Ok(())
+        };
+        repl::run(&mut core, arch, &elf, &mut print_backtrace)?
+    } else {
+        repl::Outcome::Quit
+    };
+
+    let gdb_handoff = opts.gdb_on_crash && top_exception.is_some();
+    if !gdb_handoff && repl_outcome == repl::Outcome::Quit {
+        core.clear_all_hw_breakpoints()?;
+        if opts.reset_on_exit {
+            // leave the program running from a clean state
+            core.reset()?;
+        } else if reset_type != ResetType::None {
+            core.reset_and_halt(TIMEOUT)?;
+        }
+    }
+
+    let mapped = |reason| (reason, exit_code::code(&opts.exit_code_map, reason));
+    let (reason, code) = match (top_exception, caught, breakpoint_hit) {
+        (Some(TopException::StackOverflow), _, _) => {
+            log::error!("the program has overflowed its stack");
+            mapped("stack_overflow")
+        }
+        (Some(TopException::HardFault), _, _) => {
+            log::error!("the program panicked");
+            mapped("hard_fault")
+        }
+        (None, Some(exception), _) => {
+            // NOTE faults were reported along with the fault status already
+            if !exception.is_fault() || json {
+                log::error!("caught {}", exception.description());
+            }
+            mapped("caught_exception")
+        }
+        (None, None, Some(breakpoints::PANIC_HANDLER)) => {
+            log::error!("the program panicked");
+            mapped("panic")
+        }
+        (None, None, Some(symbol)) => {
+            log::error!("the program reached `{}`", symbol);
+            mapped("breakpoint")
+        }
+        (None, None, None) if timed_out => mapped("timeout"),
+        (None, None, None) if too_many_resets => mapped("too_many_resets"),
+        (None, None, None) => match semihosting_exit_code {
+            Some(code) => {
+                log::info!("program exited with code {}", code);
+                ("exited", code)
+            }
+            None => {
+                log::info!("device halted without error");
+                mapped("halted")
+            }
+        },
+    };
+
+    tests.finish();
+    if tests.found() {
+        if !json {
+            tests.print_summary();
+        }
+        if let Some(path) = &opts.junit {
+            // NOTE(unwrap) the ELF file was read so its path ends in a file name
+            let suite = elf_path.file_stem().unwrap().to_string_lossy();
+            tests.write_junit(path, &suite)?;
+            log::info!("test report written to {}", path.display());
+        }
+    } else if opts.junit.is_some() {
+        log::warn!("no `defmt-test` tests were run; not writing a JUnit report");
+    }
+
+    hooks.emit(&Record::Exit { reason, code });
+
+    if gdb_handoff {
+        drop(core);
+        drop(session);
+        // NOTE the RTT channels share the session, which the GDB server takes over
+        drop((channels, level_setter, input));
+        if let Some(slot) = shared_session {
+            slot.close();
+        }
+        let sess = Arc::try_unwrap(sess)
+            .map_err(|_| anyhow!("the session is still in use; can't hand it over to GDB"))?
+            .into_inner()
+            .unwrap();
+
+        // the GDB server runs until Ctrl+C is pressed; let that terminate the process
+        exit.store(true, Ordering::Relaxed);
+        log::info!(
+            "the core was left in its crashed state; connect GDB to {} (press Ctrl+C to exit)",
+            opts.gdb_connection_string
+        );
+        probe_rs_gdb_server::run(Some(&opts.gdb_connection_string), sess)
+            .map_err(|e| anyhow!(e))?;
+    }
+
+    if exit.load(Ordering::Relaxed) {
+        Ok(Ending::Interrupted(code))
+    } else {
+        Ok(Ending::Halted(code))
+    }
+}
+
+fn open_session(
+    probe_info: &DebugProbeInfo,
+    target: Target,
+    opts: &Opts,
+    under_reset: bool,
+) -> anyhow::Result<Session> {
+    if opts.speed == Some(Speed::Auto) {
+        let cache = Cache::new(probe_info, &target.name, !opts.no_cache);
+        let sess = speed::negotiate(probe_info, &target, under_reset, &cache)?;
+        log::debug!("started session");
+        return Ok(sess);
+    }
+
+    let mut probe = probe_info.open()?;
+    log::debug!("opened probe");
+
+    if let Some(Speed::Khz(speed)) = opts.speed {
+        probe.set_speed(speed)?;
+    }
+
+    let sess = if under_reset {
+        probe.attach_under_reset(target)?
+    } else {
+        probe.attach(target)?
+    };
+    log::debug!("started session");
+    Ok(sess)
+}
+
+/// Flashes and runs an Intel HEX file
+///
+/// HEX files hold no symbols or debug info, so there are no logs or backtraces; `probe-run` waits
+/// until the device halts (or Ctrl+C is pressed).
+fn run_hex(opts: &Opts, path: &Path, hooks: &Hooks, scripts: &Scripts) -> anyhow::Result<i32> {
+    let chip = opts
+        .chip
+        .as_deref()
+        .ok_or_else(|| anyhow!("`--chip` must be specified to run a HEX file"))?;
+    if opts.dry_run {
+        bail!("`--dry-run` is not supported with HEX files");
+    }
+    let mut target = registry::get_target_by_name(chip)?;
+    flash_loader::select(&mut target, &opts.flash_loader)?;
+    let probe_info = probe::select(opts.probe.as_deref())?;
+    if opts.ram_exec {
+        bail!("`--ram-exec` is not supported with HEX files; their entry point is unknown");
+    }
+
+    if opts.flash_bank == Some(FlashBank::Inactive) {
+        bail!("`--flash-bank inactive` is not supported with HEX files");
+    }
+    let reset_type = opts.reset_type.unwrap_or_default();
+    if reset_type == ResetType::None {
+        bail!("`--reset-type none` is not supported with HEX files; their entry point is unknown");
+    }
+    scripts.pre_run(&probe_info, chip)?;
+    power::prepare(&probe_info, opts.power_cycle)?;
+    let unlocked = opts.chip_unlock && unlock::unlock_before_attach(&probe_info, chip, opts)?;
+    let mut sess = open_session(&probe_info, target.clone(), opts, opts.connect_under_reset)
+        .map_err(|e| unlock::explain_attach_error(&probe_info, chip, opts, e))?;
+    reset_cause::report(&mut sess, chip);
+    // NOTE whatever the cache says was flashed last is gone once the HEX file is flashed
+    let cache = Cache::new(&probe_info, chip, !opts.no_cache);
+    if opts.chip_unlock {
+        if !unlocked {
+            unlock::unlock(&mut sess, chip)?;
+        }
+        cache.update(|state| state.elf_hash = None);
+    }
+
+    if opts.no_flash {
+        log::info!("skipped flashing");
+    } else {
+        // NOTE the HEX file's ranges aren't known here; only read protection is checked
+        protection::check(&mut sess, chip, &[])?;
+        cache.update(|state| state.elf_hash = None);
+        flash_hooks::run(&mut sess, &opts.flash_hooks, flash_hooks::Point::PreErase)?;
+        if opts.erase_all {
+            log::info!("erasing the whole flash");
+            flashing::erase_all(&mut sess)?;
+        }
+        for preflash in &opts.preflash {
+            preflash.download(&mut sess)?;
+        }
+        log::info!("flashing {}", path.display());
+        let progress = Progress::new(!hooks.json);
+        let flash_progress = progress.flash_progress();
+        flashing::download_file_with_options(
+            &mut sess,
+            path,
+            Format::Hex,
+            DownloadOptions {
+                progress: Some(&flash_progress),
+                ..DownloadOptions::default()
+            },
+        )?;
+        flash_hooks::run(
+            &mut sess,
+            &opts.flash_hooks,
+            flash_hooks::Point::PostProgram,
+        )?;
+        log::info!("success!");
+        if opts.timings {
+            progress.print_timings();
+        }
+    }
+    if !opts.write_uicr.is_empty() {
+        option_bytes::write(&mut sess, chip, &opts.write_uicr, opts.allow_permanent_lock)?;
+    }
+    log::warn!("the program is a HEX file; logs and backtraces are not available");
+
+    let exit = Arc::new(AtomicBool::new(false));
+    signal_hook::flag::register(signal::SIGINT, exit.clone())?;
+
+    if reset_type == ResetType::Hardware {
+        // the device starts running the program once NRST is released
+        drop(sess);
+        sess = open_session(&probe_info, target, opts, true)?;
+    }
+    let mut core = sess.core(opts.core)?;
+    if reset_type == ResetType::Software {
+        core.reset()?;
+    }
+    while !exit.load(Ordering::Relaxed) && !core.core_halted()? {
+        thread::sleep(Duration::from_millis(100));
+    }
+    core.reset_and_halt(TIMEOUT)?;
+
+    log::info!("device halted");
+    let code = exit_code::code(&opts.exit_code_map, "halted");
+    hooks.emit(&Record::Exit {
+        reason: "halted",
+        code,
+    });
+    Ok(code)
+}
+
+fn program_size_of(file: &ElfFile) -> u64 {
+    // `segments` iterates only over *loadable* segments,
+    // which are the segments that will be loaded to Flash by probe-rs
+    file.segments().map(|segment| segment.size()).sum()
+}
+
+/// Fails if the stack can grow to less than `min_stack` bytes
+fn check_stack_size(stack_info: Option<&StackInfo>, min_stack: u32) -> anyhow::Result<()> {
+    let stack_info = match stack_info {
+        Some(stack_info) => stack_info,
+        None => {
+            log::warn!("the stack's location is unknown; `--min-stack` was not checked");
+            return Ok(());
+        }
+    };
+
+    if stack_info.size() < min_stack {
+        bail!(
+            "only {} bytes of RAM are left for the stack (0x{:08X}-0x{:08X}) but `--min-stack` \
+            requires {} bytes; shrink the program's static data (`.data` and `.bss`)",
+            stack_info.size(),
+            stack_info.range.start(),
+            stack_info.range.end(),
+            min_stack
+        );
+    }
+    Ok(())
+}
+
+/// Warns (or fails, with `--strict-memory`) if the static data and the heap region leave less than
+/// `threshold` bytes of the stack's RAM region for the stack
+fn check_ram_budget(target_info: &TargetInfo, threshold: u32, strict: bool) -> anyhow::Result<()> {
+    let (ram, stack_info) = match (&target_info.active_ram_region, &target_info.stack_info) {
+        (Some(ram), Some(stack_info)) => (ram, stack_info),
+        _ => return Ok(()),
+    };
+
+    // NOTE a heap region defined by the linker script (rather than a `static` in `.bss`) lies
+    // between the static data and the stack
+    let heap = match &target_info.heap_range {
+        Some(heap) if stack_info.range.contains(&heap.end) => heap.end - stack_info.range.start(),
+        _ => 0,
+    };
+    let stack = stack_info.size().saturating_sub(heap);
+    if stack >= threshold {
+        return Ok(());
+    }
+
+    let message = format!(
+        "{} bytes of static data (`.data` and `.bss`) and {} bytes of heap leave {} bytes of the \
+        {} byte RAM region for the stack, less than the {} bytes of `--stack-threshold`",
+        stack_info.static_data,
+        heap,
+        stack,
+        ram.range.end - ram.range.start,
+        threshold
+    );
+    if strict {
+        bail!("{}", message);
+    }
+    log::warn!("{}", message);
+    Ok(())
+}
+
+/// Print a line to separate different execution stages.
+fn print_separator() {
+    let theme = theme::current();
+    println!("{}", theme.secondary.paint(&theme.separator.repeat(80)));
+}
+
+fn get_rtt_heap_main_from(
+    elf: &ElfFile,
+    arch: Arch,
+) -> anyhow::Result<(Option<u32>, /* uses heap: */ bool, u32)> {
+    let mut rtt = None;
+    let mut uses_heap = false;
+    let mut main = None;
+
+    for symbol in elf.symbols() {
+        let name = match symbol.name() {
+            Ok(name) => name,
+            Err(_) => continue,
+        };
+
+        match name {
+            "main" => {
+                main = Some(arch.instruction_address(target_info::address(symbol.address())?))
+            }
+            "_SEGGER_RTT" => rtt = Some(target_info::address(symbol.address())?),
+            "__rust_alloc" | "__rg_alloc" | "__rdl_alloc" | "malloc" if !uses_heap => {
+                log::debug!("symbol `{}` indicates heap is in use", name);
+                uses_heap = true;
+            }
+            _ => {}
+        }
+    }
+
+    Ok((
+        rtt,
+        uses_heap,
+        main.ok_or_else(|| anyhow!("`main` symbol not found"))?,
+    ))
+}
+
+/// ELF section to be loaded onto the target
+#[derive(Debug)]
+struct Section {
+    start: u32,
+    data: Vec<u32>,
+}
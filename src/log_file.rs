@@ -0,0 +1,194 @@
+//! `--log-file`: the program's output, written to a file as well as (or instead of) stdout
+//!
+//! The decoded defmt frames and the data of text channels are written without colors or other
+//! ANSI escape sequences. For long soak tests the file can be rotated once it reaches a size
+//! (`--log-file-max-size`) or age (`--log-file-rotate`): the full file is renamed to
+//! `<path>.<n>`, with `n` counting up, and a new one started.
+
+use std::{
+    cell::RefCell,
+    fs::{self, File},
+    io::{BufWriter, Write as _},
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+
+use anyhow::Context as _;
+
+use crate::{decoder::Frame, json};
+
+const ESC: u8 = 0x1B;
+const BEL: u8 = 0x07;
+
+pub struct LogFile {
+    path: PathBuf,
+    max_size: Option<u64>,
+    interval: Option<Duration>,
+    state: RefCell<State>,
+}
+
+struct State {
+    writer: BufWriter<File>,
+    /// Bytes written to the current file
+    size: u64,
+    opened: Instant,
+    /// Suffix of the next rotated file
+    next_suffix: u32,
+    /// Escape sequences may be split across writes
+    escape: Escape,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Escape {
+    None,
+    /// After `ESC`
+    Start,
+    /// Inside `ESC [`; ends with a byte in `@`..=`~`
+    Csi,
+    /// Inside `ESC ]`; ends with `BEL` or `ESC`
+    Osc,
+}
+
+impl LogFile {
+    pub fn create(
+        path: &Path,
+        max_size: Option<u64>,
+        interval: Option<Duration>,
+    ) -> anyhow::Result<Self> {
+        // NOTE keep the files rotated by earlier runs
+        let mut next_suffix = 1;
+        while rotated_path(path, next_suffix).exists() {
+            next_suffix += 1;
+        }
+
+        Ok(Self {
+            path: path.to_owned(),
+            max_size,
+            interval,
+            state: RefCell::new(State {
+                writer: open(path)?,
+                size: 0,
+                opened: Instant::now(),
+                next_suffix,
+                escape: Escape::None,
+            }),
+        })
+    }
+
+    /// Writes a decoded defmt frame the way it's printed, minus the colors
+    pub fn frame(
+        &self,
+        prefix: Option<&str>,
+        frame: &Frame,
+        file: Option<&str>,
+        line: Option<u32>,
+        mod_path: Option<&str>,
+    ) -> anyhow::Result<()> {
+        let mut text = String::new();
+        if let Some(prefix) = prefix {
+            text.push_str(prefix);
+            text.push(' ');
+        }
+        if let Some(timestamp) = frame.timestamp() {
+            text.push_str(&timestamp);
+            text.push(' ');
+        }
+        text.push_str(&format!(
+            "{:<5} {}\n",
+            json::level(frame.level()).to_uppercase(),
+            frame.message()
+        ));
+        if let (Some(file), Some(line)) = (file, line) {
+            text.push_str(&format!(
+                "└─ {} @ {}:{}\n",
+                mod_path.unwrap_or(""),
+                file,
+                line
+            ));
+        }
+        self.write(text.as_bytes())
+    }
+
+    /// Writes the data of a text channel
+    pub fn text(&self, data: &[u8]) -> anyhow::Result<()> {
+        self.write(data)
+    }
+
+    fn write(&self, data: &[u8]) -> anyhow::Result<()> {
+        let mut state = self.state.borrow_mut();
+        let data = strip_ansi(&mut state.escape, data);
+
+        let full = self
+            .max_size
+            .map_or(false, |max_size| state.size + data.len() as u64 > max_size);
+        let old = self
+            .interval
+            .map_or(false, |interval| state.opened.elapsed() >= interval);
+        if state.size != 0 && (full || old) {
+            self.rotate(&mut state)?;
+        }
+
+        state.writer.write_all(&data)?;
+        // NOTE flush every write so that `tail -f` sees the output right away
+        state.writer.flush()?;
+        state.size += data.len() as u64;
+        Ok(())
+    }
+
+    fn rotate(&self, state: &mut State) -> anyhow::Result<()> {
+        state.writer.flush()?;
+        let rotated = rotated_path(&self.path, state.next_suffix);
+        fs::rename(&self.path, &rotated).with_context(|| {
+            format!(
+                "failed to rotate {} to {}",
+                self.path.display(),
+                rotated.display()
+            )
+        })?;
+        log::debug!("rotated the log file to {}", rotated.display());
+
+        state.writer = open(&self.path)?;
+        state.size = 0;
+        state.opened = Instant::now();
+        state.next_suffix += 1;
+        Ok(())
+    }
+}
+
+fn open(path: &Path) -> anyhow::Result<BufWriter<File>> {
+    let file =
+        File::create(path).with_context(|| format!("failed to create {}", path.display()))?;
+    Ok(BufWriter::new(file))
+}
+
+fn rotated_path(path: &Path, suffix: u32) -> PathBuf {
+    let mut rotated = path.as_os_str().to_owned();
+    rotated.push(format!(".{}", suffix));
+    rotated.into()
+}
+
+/// Removes the ANSI escape sequences from `data`; `escape` carries an unfinished one over to the
+/// next call
+fn strip_ansi(escape: &mut Escape, data: &[u8]) -> Vec<u8> {
+    let mut stripped = Vec::with_capacity(data.len());
+    for &byte in data {
+        *escape = match (*escape, byte) {
+            (Escape::None, ESC) => Escape::Start,
+            (Escape::None, _) => {
+                stripped.push(byte);
+                Escape::None
+            }
+            (Escape::Start, b'[') => Escape::Csi,
+            (Escape::Start, b']') => Escape::Osc,
+            // two-byte sequences, e.g. `ESC c`
+            (Escape::Start, _) => Escape::None,
+            (Escape::Csi, b'@'..=b'~') => Escape::None,
+            (Escape::Csi, _) => Escape::Csi,
+            (Escape::Osc, BEL) => Escape::None,
+            // NOTE `ESC \` terminates the OSC sequence; the `\` ends the new sequence
+            (Escape::Osc, ESC) => Escape::Start,
+            (Escape::Osc, _) => Escape::Osc,
+        };
+    }
+    stripped
+}
@@ -0,0 +1,106 @@
+//! Host-side filtering of defmt frames by module path and level
+//!
+//! A filter is a comma-separated list of directives, like `my_crate::radio=trace,warn`. A
+//! directive is either a level, which applies to all modules, or `<module path>=<level>`. A `*` in
+//! a module path matches any sequence of characters within a single path segment. When several
+//! directives match a frame's module, the one with the most specific module path wins.
+
+use std::str::FromStr;
+
+use anyhow::anyhow;
+use log::LevelFilter;
+
+#[derive(Debug)]
+pub struct LogFilter {
+    /// Level of the frames whose module matches none of the `directives`
+    default: LevelFilter,
+    directives: Vec<Directive>,
+}
+
+#[derive(Debug)]
+struct Directive {
+    /// The segments of the module path
+    module: Vec<String>,
+    level: LevelFilter,
+}
+
+impl LogFilter {
+    /// Whether a frame of `level`, logged from `module`, should be printed
+    pub fn enabled(&self, module: Option<&str>, level: defmt_decoder::Level) -> bool {
+        let level = match level {
+            defmt_decoder::Level::Trace => log::Level::Trace,
+            defmt_decoder::Level::Debug => log::Level::Debug,
+            defmt_decoder::Level::Info => log::Level::Info,
+            defmt_decoder::Level::Warn => log::Level::Warn,
+            defmt_decoder::Level::Error => log::Level::Error,
+        };
+
+        let segments = module
+            .map(|module| module.split("::").collect::<Vec<_>>())
+            .unwrap_or_default();
+        // NOTE `max_by_key` returns the last of equally specific directives
+        let threshold = self
+            .directives
+            .iter()
+            .filter(|directive| directive.matches(&segments))
+            .max_by_key(|directive| directive.module.len())
+            .map_or(self.default, |directive| directive.level);
+
+        level <= threshold
+    }
+}
+
+impl Directive {
+    /// Whether this directive's module path is a prefix of `segments`
+    fn matches(&self, segments: &[&str]) -> bool {
+        self.module.len() <= segments.len()
+            && self
+                .module
+                .iter()
+                .zip(segments)
+                .all(|(pattern, segment)| glob_match(pattern, segment))
+    }
+}
+
+impl FromStr for LogFilter {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut filter = Self {
+            default: LevelFilter::Trace,
+            directives: vec![],
+        };
+
+        for directive in s.split(',').map(str::trim).filter(|d| !d.is_empty()) {
+            let parse_level = |level: &str| {
+                level
+                    .parse::<LevelFilter>()
+                    .map_err(|_| anyhow!("invalid log level `{}` in `{}`", level, directive))
+            };
+
+            match directive.find('=') {
+                Some(pos) => filter.directives.push(Directive {
+                    module: directive[..pos].split("::").map(String::from).collect(),
+                    level: parse_level(&directive[pos + 1..])?,
+                }),
+                None => filter.default = parse_level(directive)?,
+            }
+        }
+
+        Ok(filter)
+    }
+}
+
+/// Matches `text` against `pattern`, in which `*` matches any sequence of characters
+pub fn glob_match(pattern: &str, text: &str) -> bool {
+    match pattern.find('*') {
+        None => pattern == text,
+        Some(pos) => {
+            let (prefix, rest) = (&pattern[..pos], &pattern[pos + 1..]);
+            text.starts_with(prefix)
+                && (prefix.len()..=text.len())
+                    .filter(|start| text.is_char_boundary(*start))
+                    .any(|start| glob_match(rest, &text[start..]))
+        }
+    }
+}
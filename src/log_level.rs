@@ -0,0 +1,128 @@
+//! `--set-log-level`: changes the level of defmt's run-time filter in the running program, without
+//! reflashing it
+//!
+//! The program opts in with an RTT down channel named `defmt-level`. probe-run writes the new level
+//! to it as a single byte (0 = trace, 1 = debug, 2 = info, 3 = warn, 4 = error); the program applies
+//! it and acknowledges by storing the same byte in its `#[no_mangle]` `u8` static `DEFMT_LEVEL`,
+//! which probe-run polls.
+
+use std::time::{Duration, Instant};
+
+use anyhow::anyhow;
+use log::Level;
+use object::read::{File as ElfFile, Object as _, ObjectSymbol as _};
+use probe_rs::{Core, MemoryInterface};
+use probe_rs_rtt::{DownChannel, Rtt};
+
+use crate::target_info;
+
+const CHANNEL_NAME: &str = "defmt-level";
+const ACK_SYMBOL: &str = "DEFMT_LEVEL";
+/// How long the program gets to acknowledge the new level
+const ACK_TIMEOUT: Duration = Duration::from_secs(1);
+
+pub struct LevelSetter {
+    channel: DownChannel,
+    level: Level,
+    ack_address: Option<u32>,
+    state: State,
+}
+
+enum State {
+    /// The request hasn't been written to the down channel yet
+    Pending,
+    Sent(Instant),
+    Done,
+}
+
+impl LevelSetter {
+    /// Takes the `defmt-level` down channel; call this before taking the input channel, which may
+    /// be the same one
+    pub fn new(rtt: &mut Rtt, level: Level, elf: &ElfFile) -> anyhow::Result<Self> {
+        let channels = rtt.down_channels();
+        let number = (0..channels.len())
+            .find(|number| {
+                channels
+                    .get(*number)
+                    .map_or(false, |channel| channel.name() == Some(CHANNEL_NAME))
+            })
+            .ok_or_else(|| {
+                anyhow!(
+                    "`--set-log-level` needs an RTT down channel named `{}` in the program",
+                    CHANNEL_NAME
+                )
+            })?;
+        // NOTE(unwrap) the channel was found above
+        let channel = channels.take(number).unwrap();
+
+        let ack_address = elf
+            .symbols()
+            .find(|symbol| symbol.name().map_or(false, |name| name == ACK_SYMBOL))
+            .map(|symbol| target_info::address(symbol.address()))
+            .transpose()?;
+        if ack_address.is_none() {
+            log::warn!(
+                "the program has no `{}` static; the new log level won't be acknowledged",
+                ACK_SYMBOL
+            );
+        }
+
+        Ok(Self {
+            channel,
+            level,
+            ack_address,
+            state: State::Pending,
+        })
+    }
+
+    /// Sends the level again, e.g. after the program reset itself and started with its default
+    pub fn resend(&mut self) {
+        self.state = State::Pending;
+    }
+
+    /// Writes the request to the down channel, once it has room for it
+    ///
+    /// NOTE writing to the channel locks the session, so call this while it's not locked
+    pub fn poll(&mut self) -> anyhow::Result<()> {
+        if let State::Pending = self.state {
+            if self.channel.write(&[encode(self.level)])? == 1 {
+                log::debug!("requested log level `{}`", self.level);
+                self.state = match self.ack_address {
+                    Some(_) => State::Sent(Instant::now()),
+                    None => State::Done,
+                };
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks whether the program acknowledged the request
+    pub fn check(&mut self, core: &mut Core<'_>) -> anyhow::Result<()> {
+        if let (State::Sent(sent), Some(ack_address)) = (&self.state, self.ack_address) {
+            let mut ack = [0];
+            core.read_8(ack_address, &mut ack)?;
+            if ack[0] == encode(self.level) {
+                log::info!("the program switched to log level `{}`", self.level);
+                self.state = State::Done;
+            } else if sent.elapsed() > ACK_TIMEOUT {
+                log::warn!(
+                    "the program didn't acknowledge log level `{}` within {:?}",
+                    self.level,
+                    ACK_TIMEOUT
+                );
+                self.state = State::Done;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn encode(level: Level) -> u8 {
+    match level {
+        Level::Trace => 0,
+        Level::Debug => 1,
+        Level::Info => 2,
+        Level::Warn => 3,
+        Level::Error => 4,
+    }
+}
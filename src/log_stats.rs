@@ -0,0 +1,100 @@
+//! `--log-stats`: a summary of the defmt frames the program logged
+//!
+//! Shows which levels, modules and call sites produce the most frames, to find the chatty code
+//! paths that use up the RTT bandwidth.
+
+use std::{
+    cell::RefCell,
+    collections::{BTreeMap, HashMap},
+};
+
+use crate::theme;
+
+/// Number of modules and call sites listed
+const TOP_LEN: usize = 10;
+
+#[derive(Default)]
+pub struct LogStats {
+    state: RefCell<State>,
+}
+
+#[derive(Default)]
+struct State {
+    frames: u64,
+    bytes: u64,
+    levels: BTreeMap<&'static str, u64>,
+    modules: HashMap<String, u64>,
+    /// Keyed by `file:line`
+    call_sites: HashMap<String, u64>,
+}
+
+impl LogStats {
+    /// Counts a decoded frame that took `num_bytes` of the defmt stream
+    pub fn observe(
+        &self,
+        level: &'static str,
+        module: Option<&str>,
+        location: Option<(&str, u32)>,
+        num_bytes: usize,
+    ) {
+        let mut state = self.state.borrow_mut();
+        state.frames += 1;
+        state.bytes += num_bytes as u64;
+        *state.levels.entry(level).or_default() += 1;
+        *state
+            .modules
+            .entry(module.unwrap_or("<unknown>").to_string())
+            .or_default() += 1;
+        let call_site = match location {
+            Some((file, line)) => format!("{}:{}", file, line),
+            None => "<unknown>".to_string(),
+        };
+        *state.call_sites.entry(call_site).or_default() += 1;
+    }
+
+    /// Number of frames counted so far
+    pub fn frames(&self) -> u64 {
+        self.state.borrow().frames
+    }
+
+    pub fn print(&self) {
+        let state = self.state.borrow();
+        println!(
+            "{} {} frames, {} bytes",
+            theme::current().secondary.paint("defmt log statistics:"),
+            state.frames,
+            state.bytes
+        );
+        if state.frames == 0 {
+            return;
+        }
+
+        let levels = state
+            .levels
+            .iter()
+            .map(|(level, count)| format!("{} {}", level, count))
+            .collect::<Vec<_>>();
+        println!("  by level: {}", levels.join(", "));
+
+        println!("  busiest modules:");
+        for (module, count) in top(&state.modules) {
+            println!("  {:>8}  {}", count, module);
+        }
+        println!("  busiest call sites:");
+        for (call_site, count) in top(&state.call_sites) {
+            println!("  {:>8}  {}", count, call_site);
+        }
+    }
+}
+
+/// The `TOP_LEN` entries with the highest counts, highest first
+fn top(counts: &HashMap<String, u64>) -> Vec<(&str, u64)> {
+    let mut entries = counts
+        .iter()
+        .map(|(name, count)| (name.as_str(), *count))
+        .collect::<Vec<_>>();
+    // NOTE ties are broken by name so that the output is deterministic
+    entries.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(b.0)));
+    entries.truncate(TOP_LEN);
+    entries
+}
@@ -0,0 +1,75 @@
+//! `--print-memory-usage`: how much of each flash and RAM region the program occupies, like GNU
+//! ld's option of the same name
+
+use std::ops::Range;
+
+use object::{
+    read::{File as ElfFile, Object as _, ObjectSection as _},
+    SectionKind,
+};
+use probe_rs::{config::MemoryRegion, Target};
+
+use crate::flash;
+
+/// Prints the used and total size of the flash and RAM regions in the memory map of `target`
+pub fn print(elf: &ElfFile, elf_bytes: &[u8], target: &Target) -> anyhow::Result<()> {
+    // flash holds the loadable segments at their load addresses; this includes the initial values
+    // of `.data`
+    let flash_contents = flash::loadable_segments(elf_bytes)?
+        .iter()
+        .map(|segment| {
+            let start = u64::from(segment.address);
+            start..start + segment.data.len() as u64
+        })
+        .collect::<Vec<_>>();
+    // RAM holds the sections at their run addresses, including the zero-initialized ones
+    let ram_contents = elf
+        .sections()
+        .filter(|sect| {
+            sect.size() != 0
+                && matches!(
+                    sect.kind(),
+                    SectionKind::Text
+                        | SectionKind::Data
+                        | SectionKind::ReadOnlyData
+                        | SectionKind::UninitializedData
+                )
+        })
+        .map(|sect| sect.address()..sect.address() + sect.size())
+        .collect::<Vec<_>>();
+
+    println!(
+        "{:<29} {:>12} {:>12} {:>9}",
+        "memory region", "used", "size", "usage"
+    );
+    for region in &target.memory_map {
+        let (name, range, contents) = match region {
+            MemoryRegion::Nvm(region) => ("FLASH", &region.range, &flash_contents),
+            MemoryRegion::Ram(region) => ("RAM", &region.range, &ram_contents),
+            _ => continue,
+        };
+
+        let range = u64::from(range.start)..u64::from(range.end);
+        let used = contents
+            .iter()
+            .map(|content| overlap(content, &range))
+            .sum::<u64>();
+        let size = range.end - range.start;
+        println!(
+            "{:<5} 0x{:08X}-0x{:08X}   {:>8.02} KiB {:>8.02} KiB {:>8.02}%",
+            name,
+            range.start,
+            range.end - 1,
+            used as f64 / 1024.0,
+            size as f64 / 1024.0,
+            used as f64 / size as f64 * 100.0
+        );
+    }
+
+    Ok(())
+}
+
+/// Number of bytes `a` and `b` have in common
+fn overlap(a: &Range<u64>, b: &Range<u64>) -> u64 {
+    a.end.min(b.end).saturating_sub(a.start.max(b.start))
+}
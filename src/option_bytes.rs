@@ -0,0 +1,269 @@
+//! `--write-uicr`: programs nRF UICR registers and STM32 option bytes while flashing
+//!
+//! These live in dedicated non-volatile memory that the flash algorithms don't touch; each family
+//! has its own sequence for unlocking, erasing and writing them.
+
+use std::{
+    str::FromStr,
+    thread,
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, bail};
+use probe_rs::{Core, MemoryInterface, Session};
+
+use crate::image;
+
+const TIMEOUT: Duration = Duration::from_secs(1);
+/// Interval at which the flash controllers' busy flags are polled
+const POLL_INTERVAL: Duration = Duration::from_millis(1);
+
+/// A word to write: `ADDRESS=VALUE`
+#[derive(Clone, Copy, Debug)]
+pub struct OptionWord {
+    pub address: u32,
+    pub value: u32,
+}
+
+impl FromStr for OptionWord {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let pos = s
+            .find('=')
+            .ok_or_else(|| anyhow!("expected `ADDRESS=VALUE`, found `{}`", s))?;
+        let value = &s[pos + 1..];
+        Ok(Self {
+            address: image::parse_address(&s[..pos])?,
+            value: image::parse_address(value)
+                .map_err(|_| anyhow!("invalid option byte value `{}`", value))?,
+        })
+    }
+}
+
+/// How the option bytes of a chip are programmed
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Family {
+    /// nRF51 and nRF52: UICR, programmed through the NVMC
+    Nrf,
+    /// STM32F4: option bytes, programmed through `FLASH_OPTCR`
+    Stm32f4,
+}
+
+impl Family {
+    fn of(chip: &str) -> Option<Self> {
+        let chip = chip.to_ascii_lowercase();
+        if chip.starts_with("nrf51") || chip.starts_with("nrf52") {
+            Some(Family::Nrf)
+        } else if chip.starts_with("stm32f4") {
+            Some(Family::Stm32f4)
+        } else {
+            None
+        }
+    }
+}
+
+/// Writes `words` into the option bytes of `chip`; words that already hold their value are skipped
+///
+/// Words that lock the chip for good, i.e. read protection level 2, are refused unless
+/// `allow_permanent_lock` is set.
+pub fn write(
+    sess: &mut Session,
+    chip: &str,
+    words: &[OptionWord],
+    allow_permanent_lock: bool,
+) -> anyhow::Result<()> {
+    let family = Family::of(chip).ok_or_else(|| {
+        anyhow!(
+            "writing option bytes is not supported on `{}`; only nRF51, nRF52 and STM32F4 chips \
+            are supported",
+            chip
+        )
+    })?;
+
+    let mut core = sess.core(0)?;
+    core.halt(TIMEOUT)?;
+    match family {
+        Family::Nrf => nrf::write(&mut core, words),
+        Family::Stm32f4 => stm32f4::write(&mut core, words, allow_permanent_lock),
+    }
+}
+
+/// Sets the read protection of an STM32F4 back to level 0; this mass erases the flash
+pub fn regress_stm32f4_rdp(sess: &mut Session) -> anyhow::Result<()> {
+    let mut core = sess.core(0)?;
+    core.halt(TIMEOUT)?;
+    stm32f4::regress_rdp(&mut core)
+}
+
+mod nrf {
+    use super::*;
+
+    const UICR: u32 = 0x1000_1000;
+    const UICR_SIZE: u32 = 0x400;
+
+    const NVMC_READY: u32 = 0x4001_E400;
+    const NVMC_CONFIG: u32 = 0x4001_E504;
+    const NVMC_ERASEUICR: u32 = 0x4001_E514;
+    const CONFIG_REN: u32 = 0;
+    const CONFIG_WEN: u32 = 1;
+    const CONFIG_EEN: u32 = 2;
+
+    pub fn write(core: &mut Core<'_>, words: &[OptionWord]) -> anyhow::Result<()> {
+        for word in words {
+            if !(UICR..UICR + UICR_SIZE).contains(&word.address) || word.address % 4 != 0 {
+                bail!("0x{:08X} is not a UICR register", word.address);
+            }
+        }
+
+        let mut uicr = vec![0; (UICR_SIZE / 4) as usize];
+        core.read_32(UICR, &mut uicr)?;
+        let mut new_uicr = uicr.clone();
+        for word in words {
+            new_uicr[((word.address - UICR) / 4) as usize] = word.value;
+        }
+        if new_uicr == uicr {
+            log::info!("UICR is up to date");
+            return Ok(());
+        }
+
+        // NOTE flash bits can only be cleared by writing; setting any bit takes an erase of UICR
+        let needs_erase = uicr.iter().zip(&new_uicr).any(|(old, new)| new & !old != 0);
+        if needs_erase {
+            log::debug!("erasing UICR");
+            core.write_word_32(NVMC_CONFIG, CONFIG_EEN)?;
+            core.write_word_32(NVMC_ERASEUICR, 1)?;
+            wait_ready(core)?;
+            uicr.iter_mut().for_each(|word| *word = !0);
+        }
+
+        core.write_word_32(NVMC_CONFIG, CONFIG_WEN)?;
+        for (index, (old, new)) in uicr.iter().zip(&new_uicr).enumerate() {
+            if old != new {
+                let address = UICR + index as u32 * 4;
+                log::debug!("writing 0x{:08X} to UICR register 0x{:08X}", new, address);
+                core.write_word_32(address, *new)?;
+                wait_ready(core)?;
+            }
+        }
+        core.write_word_32(NVMC_CONFIG, CONFIG_REN)?;
+
+        log::info!("UICR written; the new values take effect after a reset");
+        Ok(())
+    }
+
+    fn wait_ready(core: &mut Core<'_>) -> anyhow::Result<()> {
+        let start = Instant::now();
+        while core.read_word_32(NVMC_READY)? & 1 == 0 {
+            if start.elapsed() > TIMEOUT {
+                bail!("timed out waiting for the NVMC");
+            }
+            thread::sleep(POLL_INTERVAL);
+        }
+        Ok(())
+    }
+}
+
+mod stm32f4 {
+    use super::*;
+
+    /// The option bytes as they appear in memory: USER and RDP, then nWRP
+    const OPTION_BYTES_USER_RDP: u32 = 0x1FFF_C000;
+    const OPTION_BYTES_NWRP: u32 = 0x1FFF_C008;
+
+    const FLASH_OPTKEYR: u32 = 0x4002_3C08;
+    const FLASH_SR: u32 = 0x4002_3C0C;
+    const FLASH_OPTCR: u32 = 0x4002_3C14;
+    const OPTKEY1: u32 = 0x0819_2A3B;
+    const OPTKEY2: u32 = 0x4C5D_6E7F;
+    const SR_BSY: u32 = 1 << 16;
+    const OPTCR_OPTLOCK: u32 = 1 << 0;
+    const OPTCR_OPTSTRT: u32 = 1 << 1;
+    /// BOR_LEV, USER and RDP; mirrors the low half-word at `OPTION_BYTES_USER_RDP`
+    const OPTCR_USER_RDP_MASK: u32 = 0xFFFC;
+    /// nWRP; mirrors the low half-word at `OPTION_BYTES_NWRP`
+    const OPTCR_NWRP_MASK: u32 = 0x0FFF_0000;
+    const OPTCR_RDP_MASK: u32 = 0xFF00;
+    const RDP_LEVEL_0: u32 = 0xAA << 8;
+    /// Disables the debug port and the option byte writes for good
+    const RDP_LEVEL_2: u32 = 0xCC << 8;
+    /// Regressing RDP to level 0 mass erases the flash, which takes up to 32 s on the 2 MiB parts
+    const BUSY_TIMEOUT: Duration = Duration::from_secs(40);
+
+    pub fn regress_rdp(core: &mut Core<'_>) -> anyhow::Result<()> {
+        let optcr = core.read_word_32(FLASH_OPTCR)?;
+        let value = (optcr & OPTCR_USER_RDP_MASK & !OPTCR_RDP_MASK) | RDP_LEVEL_0;
+        write(
+            core,
+            &[OptionWord {
+                address: OPTION_BYTES_USER_RDP,
+                value,
+            }],
+            false,
+        )
+    }
+
+    pub fn write(
+        core: &mut Core<'_>,
+        words: &[OptionWord],
+        allow_permanent_lock: bool,
+    ) -> anyhow::Result<()> {
+        let old_optcr = core.read_word_32(FLASH_OPTCR)?;
+        let mut optcr = old_optcr;
+        for word in words {
+            match word.address {
+                OPTION_BYTES_USER_RDP => {
+                    optcr = (optcr & !OPTCR_USER_RDP_MASK) | (word.value & OPTCR_USER_RDP_MASK)
+                }
+                OPTION_BYTES_NWRP => {
+                    optcr = (optcr & !OPTCR_NWRP_MASK) | ((word.value << 16) & OPTCR_NWRP_MASK)
+                }
+                address => bail!(
+                    "0x{:08X} is not an option byte address; use 0x{:08X} (USER, RDP) or \
+                    0x{:08X} (nWRP)",
+                    address,
+                    OPTION_BYTES_USER_RDP,
+                    OPTION_BYTES_NWRP
+                ),
+            }
+        }
+        if optcr == old_optcr {
+            log::info!("option bytes are up to date");
+            return Ok(());
+        }
+        if optcr & OPTCR_RDP_MASK == RDP_LEVEL_2 && !allow_permanent_lock {
+            bail!(
+                "RDP = 0xCC sets read protection level 2, which can't be undone: the chip can \
+                never be debugged or reprogrammed again; pass `--allow-permanent-lock` to do it \
+                anyway"
+            );
+        }
+
+        // the option bytes are erased and programmed as a whole when OPTSTRT is set
+        if old_optcr & OPTCR_OPTLOCK != 0 {
+            core.write_word_32(FLASH_OPTKEYR, OPTKEY1)?;
+            core.write_word_32(FLASH_OPTKEYR, OPTKEY2)?;
+        }
+        wait_not_busy(core)?;
+        let optcr = optcr & !(OPTCR_OPTLOCK | OPTCR_OPTSTRT);
+        log::debug!("writing 0x{:08X} to FLASH_OPTCR", optcr);
+        core.write_word_32(FLASH_OPTCR, optcr)?;
+        core.write_word_32(FLASH_OPTCR, optcr | OPTCR_OPTSTRT)?;
+        wait_not_busy(core)?;
+        core.write_word_32(FLASH_OPTCR, optcr | OPTCR_OPTLOCK)?;
+
+        log::info!("option bytes written; the new values take effect after a reset");
+        Ok(())
+    }
+
+    fn wait_not_busy(core: &mut Core<'_>) -> anyhow::Result<()> {
+        let start = Instant::now();
+        while core.read_word_32(FLASH_SR)? & SR_BSY != 0 {
+            if start.elapsed() > BUSY_TIMEOUT {
+                bail!("timed out waiting for the flash interface");
+            }
+            thread::sleep(POLL_INTERVAL);
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,70 @@
+//! Ends the run when the program's log output matches `--success-pattern` or `--failure-pattern`
+
+use std::cell::{Cell, RefCell};
+
+use regex::Regex;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Outcome {
+    Success,
+    Failure,
+}
+
+pub struct Patterns {
+    success: Option<Regex>,
+    failure: Option<Regex>,
+    /// Holds the text output that's not terminated by a newline yet
+    partial_line: RefCell<String>,
+    outcome: Cell<Option<Outcome>>,
+}
+
+impl Patterns {
+    /// Returns `None` if neither pattern was given
+    pub fn new(success: Option<Regex>, failure: Option<Regex>) -> Option<Self> {
+        if success.is_none() && failure.is_none() {
+            return None;
+        }
+
+        Some(Self {
+            success,
+            failure,
+            partial_line: RefCell::new(String::new()),
+            outcome: Cell::new(None),
+        })
+    }
+
+    /// The outcome of the first line that matched a pattern
+    pub fn outcome(&self) -> Option<Outcome> {
+        self.outcome.get()
+    }
+
+    /// Checks a line of log output, e.g. the message of a defmt frame
+    pub fn check_line(&self, line: &str) {
+        if self.outcome.get().is_some() {
+            return;
+        }
+
+        // NOTE a line that matches both patterns counts as a failure
+        let is_match =
+            |pattern: &Option<Regex>| pattern.as_ref().map_or(false, |re| re.is_match(line));
+        if is_match(&self.failure) {
+            log::debug!("`{}` matched the failure pattern", line);
+            self.outcome.set(Some(Outcome::Failure));
+        } else if is_match(&self.success) {
+            log::debug!("`{}` matched the success pattern", line);
+            self.outcome.set(Some(Outcome::Success));
+        }
+    }
+
+    /// Checks the complete lines of text output
+    pub fn check_text(&self, data: &[u8]) {
+        let mut partial_line = self.partial_line.borrow_mut();
+        partial_line.push_str(&String::from_utf8_lossy(data));
+
+        while let Some(pos) = partial_line.find('\n') {
+            let line = partial_line[..pos].trim_end_matches('\r').to_string();
+            partial_line.drain(..=pos);
+            self.check_line(&line);
+        }
+    }
+}
@@ -0,0 +1,166 @@
+//! `--plot-out`: numeric samples the program logs, written to a CSV or NDJSON file
+//!
+//! Samples are `name=value` pairs, separated by spaces or commas, e.g. `temp=21.5 rpm=1200`. They
+//! come from defmt frames whose message starts with `@plot` and from RTT channels mapped to `plot`
+//! (`--rtt-map 1=plot`), one sample per line. Each sample is written, with host timestamps, as
+//! soon as it arrives, so external tools can graph the file while the program runs.
+
+use std::{
+    cell::RefCell,
+    fs::File,
+    io::{BufWriter, Write as _},
+    path::Path,
+    time::{Instant, SystemTime},
+};
+
+use anyhow::Context as _;
+
+/// Marks the defmt frames that carry samples
+const MARKER: &str = "@plot";
+
+#[derive(Clone, Copy, PartialEq)]
+enum Format {
+    /// One column per name; the columns are those of the first sample
+    Csv,
+    /// One JSON object per line
+    Ndjson,
+}
+
+pub struct Plot {
+    format: Format,
+    start: Instant,
+    state: RefCell<State>,
+}
+
+struct State {
+    writer: BufWriter<File>,
+    /// CSV columns after the timestamps; `None` until the first sample
+    columns: Option<Vec<String>>,
+    /// Names already warned about for not having a CSV column
+    dropped: Vec<String>,
+}
+
+impl Plot {
+    /// Writes CSV if `path` ends in `.csv` and NDJSON otherwise
+    pub fn new(path: &Path) -> anyhow::Result<Self> {
+        let format = match path.extension() {
+            Some(extension) if extension == "csv" => Format::Csv,
+            _ => Format::Ndjson,
+        };
+        let file =
+            File::create(path).with_context(|| format!("failed to create {}", path.display()))?;
+        Ok(Self {
+            format,
+            start: Instant::now(),
+            state: RefCell::new(State {
+                writer: BufWriter::new(file),
+                columns: None,
+                dropped: vec![],
+            }),
+        })
+    }
+
+    /// Records the sample in a defmt log message; returns `false` if it's not a sample
+    pub fn message(&self, message: &str) -> anyhow::Result<bool> {
+        if !message.starts_with(MARKER) {
+            return Ok(false);
+        }
+        self.sample(&message[MARKER.len()..])?;
+        Ok(true)
+    }
+
+    /// Records the samples in the complete lines of `data` read from a `plot` channel, removing
+    /// them from it
+    pub fn lines(&self, data: &mut Vec<u8>) -> anyhow::Result<()> {
+        while let Some(pos) = data.iter().position(|byte| *byte == b'\n') {
+            let line = data.drain(..=pos).collect::<Vec<_>>();
+            self.sample(&String::from_utf8_lossy(&line))?;
+        }
+        Ok(())
+    }
+
+    fn sample(&self, text: &str) -> anyhow::Result<()> {
+        let values = parse(text);
+        if values.is_empty() {
+            return Ok(());
+        }
+
+        let host_time = humantime::format_rfc3339_micros(SystemTime::now()).to_string();
+        let elapsed = self.start.elapsed().as_secs_f64();
+        let mut state = self.state.borrow_mut();
+        let state = &mut *state;
+        match self.format {
+            Format::Csv => {
+                if state.columns.is_none() {
+                    let columns = values
+                        .iter()
+                        .map(|(name, _)| name.to_string())
+                        .collect::<Vec<_>>();
+                    writeln!(state.writer, "host_time,elapsed,{}", columns.join(","))?;
+                    state.columns = Some(columns);
+                }
+                // NOTE(unwrap) set above
+                let columns = state.columns.as_ref().unwrap();
+
+                for (name, _) in &values {
+                    if !columns.iter().any(|column| column == name)
+                        && !state.dropped.iter().any(|dropped| dropped == name)
+                    {
+                        log::warn!(
+                            "`{}` was not in the first sample, so it has no CSV column; \
+                            write NDJSON (`--plot-out <path>.ndjson`) to keep it",
+                            name
+                        );
+                        state.dropped.push(name.to_string());
+                    }
+                }
+
+                let row = columns
+                    .iter()
+                    .map(|column| {
+                        values
+                            .iter()
+                            .find(|(name, _)| name == column)
+                            .map_or(String::new(), |(_, value)| value.to_string())
+                    })
+                    .collect::<Vec<_>>();
+                writeln!(
+                    state.writer,
+                    "{},{:.6},{}",
+                    host_time,
+                    elapsed,
+                    row.join(",")
+                )?;
+            }
+            Format::Ndjson => {
+                let mut object = serde_json::Map::new();
+                object.insert("host_time".into(), host_time.into());
+                object.insert("elapsed".into(), elapsed.into());
+                for (name, value) in values {
+                    object.insert(name.into(), value.into());
+                }
+                writeln!(state.writer, "{}", serde_json::Value::Object(object))?;
+            }
+        }
+        // NOTE flush every sample so that tools following the file see it right away
+        state.writer.flush()?;
+        Ok(())
+    }
+}
+
+/// The `name=value` pairs in `text` whose value is a number
+fn parse(text: &str) -> Vec<(&str, f64)> {
+    text.split(|c: char| c.is_whitespace() || c == ',')
+        .filter_map(|pair| {
+            let mut parts = pair.splitn(2, '=');
+            // NOTE(unwrap) `splitn` yields at least one part
+            let name = parts.next().unwrap();
+            let value = parts.next()?.parse().ok()?;
+            if name.is_empty() {
+                None
+            } else {
+                Some((name, value))
+            }
+        })
+        .collect()
+}
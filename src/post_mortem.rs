@@ -0,0 +1,191 @@
+//! `probe-run backtrace`: the crash report of a device that is already wedged
+//!
+//! The device is attached to without flashing or resetting it; the core is halted and unwound with
+//! the given ELF file, as after a crash during a normal run. A core that was running is resumed
+//! afterwards, so the device is left the way it was found.
+
+use std::{collections::HashSet, env, fs, ops::RangeInclusive, path::Path};
+
+use anyhow::{anyhow, bail};
+use object::{
+    read::{File as ElfFile, Object as _, ObjectSection as _, ObjectSymbol as _},
+    SymbolSection,
+};
+
+use crate::{
+    arch::{Arch, EntryPoint},
+    backtrace::{self, TopException},
+    cli::Opts,
+    cortexa,
+    cortexm::{self, VectorTable},
+    exit_code,
+    fault_address::AddressMap,
+    hooks::Hooks,
+    hyperlink::Hyperlinks,
+    json::Record,
+    probe,
+    registers::FaultStatus,
+    riscv, svd,
+    target_info::{self, RegionOverrides, TargetInfo},
+    TIMEOUT,
+};
+
+/// Exception numbers of the Cortex-M fault handlers: HardFault to SecureFault
+const FAULTS: RangeInclusive<u32> = 3..=7;
+
+pub fn run(opts: &Opts, elf_path: &Path, hooks: &Hooks) -> anyhow::Result<i32> {
+    let json = hooks.json;
+    let bytes = match &opts.symbols {
+        Some(path) => fs::read(path)?,
+        None => fs::read(elf_path)?,
+    };
+    let elf = ElfFile::parse(&bytes)?;
+    let arch = Arch::from_elf(&elf)?;
+    if !arch.supports_backtrace() {
+        bail!("backtraces are not supported for {:?} programs", arch);
+    }
+
+    let debug_frame = elf
+        .section_by_name(".debug_frame")
+        .ok_or_else(|| anyhow!("`.debug_frame` section not found"))?
+        .data()?;
+    let text = elf
+        .section_by_name(".text")
+        .map(|section| section.index())
+        .ok_or_else(|| anyhow!("`.text` section is missing"))?;
+    let live_functions = elf
+        .symbols()
+        .filter(|symbol| symbol.section() == SymbolSection::Section(text))
+        .map(|symbol| symbol.name())
+        .collect::<Result<HashSet<_>, _>>()?;
+
+    let entry_point: EntryPoint = match arch {
+        Arch::CortexM => {
+            let section = elf
+                .section_by_name(".vector_table")
+                .ok_or_else(|| anyhow!("`.vector_table` section is missing"))?;
+            let words = section
+                .data()?
+                .chunks_exact(4)
+                .map(|chunk| u32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]))
+                .collect::<Vec<_>>();
+            VectorTable::parse(target_info::address(section.address())?, &words)?.into()
+        }
+        Arch::CortexAR | Arch::Aarch64 => cortexa::entry_point(&elf)?,
+        Arch::Riscv32 => riscv::entry_point(&elf)?,
+    };
+
+    target_info::load_chip_descriptions(&opts.chip_description)?;
+    let probe_info = &probe::select(opts.probe.as_deref())?;
+    let overrides = RegionOverrides {
+        ram: &opts.ram_region,
+        flash: &opts.flash_region,
+    };
+    let target_info = TargetInfo::new(
+        opts.chip.as_deref(),
+        &elf,
+        &entry_point,
+        Some(probe_info),
+        &[],
+        &overrides,
+    )?;
+    let peripherals = match (&opts.svd, opts.dump_peripherals.is_empty()) {
+        (Some(svd), false) => Some(svd::Peripherals::load(svd, &opts.dump_peripherals)?),
+        (None, false) => {
+            bail!("`--dump-peripherals` needs the chip's SVD file; pass it with `--svd`")
+        }
+        (_, true) => None,
+    };
+
+    // NOTE attaching under reset would destroy the state that is to be inspected
+    let mut sess = crate::open_session(probe_info, target_info.probe_target.clone(), opts, false)?;
+    let mut core = sess.core(opts.core)?;
+    let was_halted = core.core_halted()?;
+    if !was_halted {
+        core.halt(TIMEOUT)?;
+    }
+    let pc = core.read_core_reg(arch.program_counter())?;
+    log::info!(
+        "the core {} at 0x{:08X}",
+        if was_halted { "was halted" } else { "halted" },
+        arch.instruction_address(pc)
+    );
+
+    if let (Arch::CortexM, Some(secure)) = (arch, opts.security_state()) {
+        cortexm::select_security_state(&mut core, secure)?;
+    }
+
+    let address_map = AddressMap::new(&elf, &target_info.probe_target, opts.svd.as_deref());
+    if !json {
+        crate::print_separator();
+    }
+    // NOTE `backtrace::construct` explains the fault only if the core stopped at the entry of the
+    // fault handler; a wedged program usually spins inside of it
+    let mut in_fault_handler = false;
+    if arch == Arch::CortexM {
+        let exception = core.read_core_reg(cortexm::XPSR)? & cortexm::IPSR_MASK;
+        let at_fault_handler_entry = entry_point
+            .fault_handler
+            .map(|fault_handler| arch.instruction_address(fault_handler))
+            == Some(arch.instruction_address(pc));
+        in_fault_handler = FAULTS.contains(&exception);
+        if in_fault_handler && !at_fault_handler_entry && !json {
+            log::error!(
+                "the core is in the {} handler",
+                cortexm::describe_exception(exception, None)
+            );
+            FaultStatus::read(&mut core)?.print(&address_map);
+        }
+    }
+
+    let current_dir = env::current_dir()?;
+    let hyperlinks = Hyperlinks::detect(opts.hyperlinks, opts.hyperlink_url.as_deref());
+    let settings = backtrace::Settings {
+        current_dir: &current_dir,
+        force_backtrace: true,
+        max_backtrace_len: opts.max_backtrace_len,
+        backtrace_limit: opts.backtrace_limit,
+        message_format: opts.message_format,
+        backtrace_format: opts.backtrace_format,
+        backtrace_context: opts.backtrace_context,
+        disassemble: opts.disassemble,
+        source_map: &opts.source_map,
+        hyperlinks: hyperlinks.as_ref(),
+        address_map: &address_map,
+        hooks,
+    };
+    let top_exception = backtrace::construct(
+        &mut core,
+        arch,
+        pc,
+        debug_frame,
+        &elf,
+        &entry_point,
+        &target_info.active_ram_region,
+        &live_functions,
+        &settings,
+    )?;
+
+    if let (Some(peripherals), false) = (&peripherals, json) {
+        crate::print_separator();
+        peripherals.dump(&mut core)?;
+    }
+
+    if !was_halted {
+        core.run()?;
+        log::debug!("resumed the core");
+    }
+
+    let reason = match top_exception {
+        Some(TopException::StackOverflow) => {
+            log::error!("the program has overflowed its stack");
+            "stack_overflow"
+        }
+        Some(TopException::HardFault) => "hard_fault",
+        None if in_fault_handler => "hard_fault",
+        None => "halted",
+    };
+    let code = exit_code::code(&opts.exit_code_map, reason);
+    hooks.emit(&Record::Exit { reason, code });
+    Ok(code)
+}
@@ -0,0 +1,144 @@
+//! Target voltage reporting and `--power-cycle`
+//!
+//! probe-rs doesn't expose these probe features, so the probe is talked to directly, before
+//! probe-rs opens it. ST-LINKs measure the target's VDD; J-Links measure it as well and can
+//! switch the 5 V supply on pin 19 of their connector, which powers many evaluation boards.
+
+use std::{thread, time::Duration};
+
+use anyhow::{anyhow, bail, Context as _};
+use jaylink::JayLink;
+use probe_rs::DebugProbeInfo;
+use rusb::UsbContext as _;
+
+const VID_STMICRO: u16 = 0x0483;
+const VID_SEGGER: u16 = 0x1366;
+
+/// Below this the target is likely unpowered or browning out
+const LOW_VOLTAGE: f32 = 1.6;
+
+/// How long the target stays unpowered during a power cycle
+const POWER_OFF_TIME: Duration = Duration::from_millis(500);
+/// How long the target gets to start up after power was restored
+const POWER_ON_TIME: Duration = Duration::from_millis(100);
+
+/// Power cycles the target if requested, then reports its voltage if the probe measures it
+///
+/// NOTE must be called while probe-rs doesn't have the probe open
+pub fn prepare(probe_info: &DebugProbeInfo, power_cycle: bool) -> anyhow::Result<()> {
+    if power_cycle {
+        match probe_info.vendor_id {
+            VID_SEGGER => jlink::power_cycle(probe_info)?,
+            _ => bail!(
+                "`--power-cycle` is not supported by {}; only J-Links can switch the target's power",
+                probe_info.identifier
+            ),
+        }
+        log::info!("power cycled the target");
+    }
+
+    let voltage = match probe_info.vendor_id {
+        VID_SEGGER => jlink::target_voltage(probe_info),
+        VID_STMICRO => stlink::target_voltage(probe_info),
+        _ => return Ok(()),
+    };
+    match voltage {
+        Ok(voltage) if voltage < LOW_VOLTAGE => log::warn!(
+            "the target voltage is only {:.2} V; is the target powered?",
+            voltage
+        ),
+        Ok(voltage) => log::info!("target voltage: {:.2} V", voltage),
+        // NOTE not all probe firmware versions support the measurement
+        Err(e) => log::debug!("failed to read the target voltage: {:?}", e),
+    }
+    Ok(())
+}
+
+mod jlink {
+    use super::*;
+
+    fn open(probe_info: &DebugProbeInfo) -> anyhow::Result<JayLink> {
+        JayLink::open_by_serial(probe_info.serial_number.as_deref())
+            .context("failed to open the J-Link")
+    }
+
+    pub fn target_voltage(probe_info: &DebugProbeInfo) -> anyhow::Result<f32> {
+        let millivolts = open(probe_info)?.read_target_voltage()?;
+        Ok(f32::from(millivolts) / 1000.0)
+    }
+
+    pub fn power_cycle(probe_info: &DebugProbeInfo) -> anyhow::Result<()> {
+        let mut jlink = open(probe_info)?;
+        jlink
+            .set_kickstart_power(false)
+            .context("the J-Link can't switch the target's power")?;
+        thread::sleep(POWER_OFF_TIME);
+        jlink.set_kickstart_power(true)?;
+        thread::sleep(POWER_ON_TIME);
+        Ok(())
+    }
+}
+
+mod stlink {
+    use super::*;
+
+    /// The original ST-LINK/V2 uses a different OUT endpoint than the later versions
+    const PID_V2: u16 = 0x3748;
+    const ENDPOINT_OUT_V2: u8 = 0x02;
+    const ENDPOINT_OUT: u8 = 0x01;
+    const ENDPOINT_IN: u8 = 0x81;
+    const GET_TARGET_VOLTAGE: u8 = 0xF7;
+    const COMMAND_SIZE: usize = 16;
+    const TIMEOUT: Duration = Duration::from_secs(1);
+
+    pub fn target_voltage(probe_info: &DebugProbeInfo) -> anyhow::Result<f32> {
+        let context = rusb::Context::new()?;
+        let device = context
+            .devices()?
+            .iter()
+            .find(|device| {
+                let descriptor = match device.device_descriptor() {
+                    Ok(descriptor) => descriptor,
+                    Err(_) => return false,
+                };
+                if descriptor.vendor_id() != probe_info.vendor_id
+                    || descriptor.product_id() != probe_info.product_id
+                {
+                    return false;
+                }
+                match &probe_info.serial_number {
+                    Some(serial) => device
+                        .open()
+                        .and_then(|handle| handle.read_serial_number_string_ascii(&descriptor))
+                        .map_or(false, |actual| actual == *serial),
+                    None => true,
+                }
+            })
+            .ok_or_else(|| anyhow!("ST-LINK not found"))?;
+
+        let mut handle = device.open()?;
+        handle.claim_interface(0)?;
+        let endpoint_out = if probe_info.product_id == PID_V2 {
+            ENDPOINT_OUT_V2
+        } else {
+            ENDPOINT_OUT
+        };
+        let mut command = [0; COMMAND_SIZE];
+        command[0] = GET_TARGET_VOLTAGE;
+        handle.write_bulk(endpoint_out, &command, TIMEOUT)?;
+        let mut response = [0; 8];
+        let len = handle.read_bulk(ENDPOINT_IN, &mut response, TIMEOUT)?;
+        handle.release_interface(0)?;
+        if len != response.len() {
+            bail!("unexpected response to the voltage request ({} bytes)", len);
+        }
+
+        // the ADC measures VDD through a divider by 2, against the internal 1.2 V reference
+        let reference = u32::from_le_bytes([response[0], response[1], response[2], response[3]]);
+        let vdd = u32::from_le_bytes([response[4], response[5], response[6], response[7]]);
+        if reference == 0 {
+            bail!("the ST-LINK returned no voltage reference");
+        }
+        Ok(2.0 * vdd as f32 * 1.2 / reference as f32)
+    }
+}
@@ -0,0 +1,129 @@
+//! `--preserve`: keeps flash regions such as calibration data or settings pages across flashing
+
+use std::{env, fs, ops::Range, process, str::FromStr};
+
+use anyhow::{anyhow, Context as _};
+use object::read::{File as ElfFile, Object as _, ObjectSection as _};
+use probe_rs::{
+    flashing::{self, BinOptions, DownloadOptions, FlashProgress, Format},
+    MemoryInterface, Session,
+};
+
+use crate::image;
+
+/// A region passed to `--preserve`
+#[derive(Debug)]
+pub enum Region {
+    /// `START..END` or `START+LENGTH`
+    Range { start: u32, end: u32 },
+    /// The address range of a linker section, e.g. `.nvm`
+    Section(String),
+}
+
+impl FromStr for Region {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.starts_with('.') {
+            return Ok(Self::Section(s.to_string()));
+        }
+
+        let range = image::parse_range(s)?;
+        Ok(Self::Range {
+            start: range.start,
+            end: range.end,
+        })
+    }
+}
+
+/// The contents of a preserved region before flashing
+pub struct Saved {
+    start: u32,
+    data: Vec<u8>,
+}
+
+/// Resolves the address ranges of `regions`
+pub fn ranges(regions: &[Region], elf: &ElfFile) -> anyhow::Result<Vec<Range<u32>>> {
+    regions
+        .iter()
+        .map(|region| match region {
+            Region::Range { start, end } => Ok(*start..*end),
+            Region::Section(name) => {
+                let section = elf
+                    .section_by_name(name)
+                    .ok_or_else(|| anyhow!("section `{}` not found in the ELF file", name))?;
+                let start = section.address() as u32;
+                Ok(start..start + section.size() as u32)
+            }
+        })
+        .collect()
+}
+
+/// Reads the current contents of the preserved `ranges` from the device
+pub fn save(sess: &mut Session, ranges: &[Range<u32>]) -> anyhow::Result<Vec<Saved>> {
+    let mut saved = vec![];
+    let mut core = sess.core(0)?;
+    for range in ranges {
+        let mut data = vec![0; (range.end - range.start) as usize];
+        core.read_8(range.start, &mut data)?;
+        log::debug!("preserving {:#010x}..{:#010x}", range.start, range.end);
+        saved.push(Saved {
+            start: range.start,
+            data,
+        });
+    }
+    Ok(saved)
+}
+
+/// Options for flashing the program without erasing the parts of the sectors it doesn't cover
+pub fn download_options<'a>(
+    saved: &[Saved],
+    progress: Option<&'a FlashProgress>,
+) -> DownloadOptions<'a> {
+    DownloadOptions {
+        keep_unwritten_bytes: !saved.is_empty(),
+        progress,
+        ..DownloadOptions::default()
+    }
+}
+
+/// Writes back the preserved regions that flashing the program changed
+pub fn restore(sess: &mut Session, saved: &[Saved]) -> anyhow::Result<()> {
+    for region in saved {
+        let mut data = vec![0; region.data.len()];
+        sess.core(0)?.read_8(region.start, &mut data)?;
+        if data == region.data {
+            continue;
+        }
+
+        log::info!(
+            "restoring the preserved region at {:#010x} ({} bytes)",
+            region.start,
+            region.data.len()
+        );
+        // NOTE probe-rs flashes images from files; the data is passed through a temporary one
+        let path = env::temp_dir().join(format!(
+            "probe-run-preserve-{}-{:08x}.bin",
+            process::id(),
+            region.start
+        ));
+        fs::write(&path, &region.data)?;
+        let result = flashing::download_file_with_options(
+            sess,
+            &path,
+            Format::Bin(BinOptions {
+                base_address: Some(region.start),
+                skip: 0,
+            }),
+            download_options(saved, None),
+        );
+        let _ = fs::remove_file(&path);
+        result.with_context(|| {
+            format!(
+                "failed to restore the preserved region at {:#010x}",
+                region.start
+            )
+        })?;
+    }
+    Ok(())
+}
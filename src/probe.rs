@@ -0,0 +1,123 @@
+//! Selection of the debug probe to use
+
+use std::{error::Error, fmt, str::FromStr};
+
+use anyhow::{anyhow, bail};
+use probe_rs::{DebugProbeInfo, Probe};
+
+/// No probe matched `--probe`; `--retries` waits for one that's being re-enumerated by the host
+#[derive(Debug)]
+pub struct NotFound;
+
+impl fmt::Display for NotFound {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("no probe was found")
+    }
+}
+
+impl Error for NotFound {}
+
+/// Finds the probe selected with `--probe`; fails unless exactly one probe matches
+pub fn select(probe_opt: Option<&str>) -> anyhow::Result<DebugProbeInfo> {
+    let probes = Probe::list_all();
+    let mut probes = if let Some(probe_opt) = probe_opt {
+        let selector = probe_opt.parse()?;
+        probes_filter(&probes, &selector)
+    } else {
+        probes
+    };
+
+    // ensure exactly one probe is found
+    if probes.is_empty() {
+        return Err(NotFound.into());
+    }
+    log::debug!("found {} probes", probes.len());
+    if probes.len() > 1 {
+        print(probes);
+        bail!("more than one probe found; use --probe to specify which one to use (e.g. `--probe index:1`)");
+    }
+    Ok(probes.remove(0))
+}
+
+pub fn print(probes: Vec<DebugProbeInfo>) {
+    if !probes.is_empty() {
+        println!("The following devices were found:");
+        probes
+            .iter()
+            .enumerate()
+            .for_each(|(num, link)| println!("[{}]: {:?}", num, link));
+    } else {
+        println!("No devices were found.");
+    }
+}
+
+struct ProbeFilter {
+    /// Position in the list printed by `--list-probes`
+    index: Option<usize>,
+    vid_pid: Option<(u16, u16)>,
+    serial: Option<String>,
+}
+
+impl FromStr for ProbeFilter {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parts = s.split(':').collect::<Vec<_>>();
+        match &*parts {
+            ["index", index] => Ok(Self {
+                index: Some(
+                    index
+                        .parse()
+                        .map_err(|_| anyhow!("invalid probe index `{}`", index))?,
+                ),
+                vid_pid: None,
+                serial: None,
+            }),
+            [serial] => Ok(Self {
+                index: None,
+                vid_pid: None,
+                serial: Some(serial.to_string()),
+            }),
+            [vid, pid] => Ok(Self {
+                index: None,
+                vid_pid: Some((u16::from_str_radix(vid, 16)?, u16::from_str_radix(pid, 16)?)),
+                serial: None,
+            }),
+            [vid, pid, serial] => Ok(Self {
+                index: None,
+                vid_pid: Some((u16::from_str_radix(vid, 16)?, u16::from_str_radix(pid, 16)?)),
+                serial: Some(serial.to_string()),
+            }),
+            _ => Err(anyhow!("invalid probe filter")),
+        }
+    }
+}
+
+fn probes_filter(probes: &[DebugProbeInfo], selector: &ProbeFilter) -> Vec<DebugProbeInfo> {
+    probes
+        .iter()
+        .enumerate()
+        .filter(|(index, p)| {
+            if let Some(selected) = selector.index {
+                if *index != selected {
+                    return false;
+                }
+            }
+
+            if let Some((vid, pid)) = selector.vid_pid {
+                if p.vendor_id != vid || p.product_id != pid {
+                    return false;
+                }
+            }
+
+            if let Some(serial) = &selector.serial {
+                if p.serial_number.as_deref() != Some(serial) {
+                    return false;
+                }
+            }
+
+            true
+        })
+        .map(|(_, p)| p.clone())
+        .collect()
+}
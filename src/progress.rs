@@ -0,0 +1,165 @@
+//! Progress bars for the phases of flashing and the `--timings` summary
+//!
+//! probe-rs reports every erased sector and programmed page; each phase gets a progress bar of
+//! its own and its duration is recorded, so that slow probes or a too low SWD clock stand out.
+
+use std::{
+    cell::RefCell,
+    rc::Rc,
+    time::{Duration, Instant},
+};
+
+use indicatif::{ProgressBar, ProgressStyle};
+use probe_rs::flashing::{FlashProgress, ProgressEvent};
+
+const TEMPLATE: &str = "{msg:>9} [{bar:40}] {bytes:>10}/{total_bytes:<10} {elapsed:>3}/ETA {eta}";
+
+/// Shared between the flash loader's progress handler and `probe-run`'s own phases (verify)
+#[derive(Clone)]
+pub struct Progress {
+    show_bars: bool,
+    state: Rc<RefCell<State>>,
+}
+
+#[derive(Default)]
+struct State {
+    /// Bytes the flash loader is going to fill, erase and program, in that order
+    totals: (u64, u64, u64),
+    current: Option<(Phase, ProgressBar)>,
+    finished: Vec<Phase>,
+}
+
+struct Phase {
+    name: &'static str,
+    bytes: u64,
+    started: Instant,
+    duration: Duration,
+}
+
+impl Progress {
+    /// With `show_bars` false only the timings are recorded
+    pub fn new(show_bars: bool) -> Self {
+        Self {
+            show_bars,
+            state: Rc::default(),
+        }
+    }
+
+    /// The handler to pass to the flash loader through its `DownloadOptions`
+    pub fn flash_progress(&self) -> FlashProgress {
+        let progress = self.clone();
+        FlashProgress::new(move |event| progress.on_event(event))
+    }
+
+    fn on_event(&self, event: ProgressEvent) {
+        match event {
+            ProgressEvent::Initialized { flash_layout } => {
+                let fill = flash_layout
+                    .fills()
+                    .iter()
+                    .map(|fill| fill.size())
+                    .sum::<u32>();
+                let erase = flash_layout
+                    .sectors()
+                    .iter()
+                    .map(|sect| sect.size())
+                    .sum::<u32>();
+                let program = flash_layout
+                    .pages()
+                    .iter()
+                    .map(|page| page.size())
+                    .sum::<u32>();
+                self.state.borrow_mut().totals = (fill.into(), erase.into(), program.into());
+            }
+            ProgressEvent::StartedFilling => {
+                let total = self.state.borrow().totals.0;
+                self.start("fill", total);
+            }
+            ProgressEvent::StartedErasing => {
+                let total = self.state.borrow().totals.1;
+                self.start("erase", total);
+            }
+            ProgressEvent::StartedProgramming => {
+                let total = self.state.borrow().totals.2;
+                self.start("program", total);
+            }
+            ProgressEvent::PageFilled { size, .. }
+            | ProgressEvent::SectorErased { size, .. }
+            | ProgressEvent::PageProgrammed { size, .. } => self.advance(size.into()),
+            ProgressEvent::FinishedFilling
+            | ProgressEvent::FinishedErasing
+            | ProgressEvent::FinishedProgramming => self.finish(),
+            ProgressEvent::FailedFilling
+            | ProgressEvent::FailedErasing
+            | ProgressEvent::FailedProgramming => self.abandon(),
+        }
+    }
+
+    /// Starts a phase that processes `total` bytes
+    pub fn start(&self, name: &'static str, total: u64) {
+        let bar = if self.show_bars {
+            let bar = ProgressBar::new(total);
+            bar.set_style(
+                ProgressStyle::default_bar()
+                    .template(TEMPLATE)
+                    .progress_chars("##-"),
+            );
+            bar.set_message(name);
+            bar
+        } else {
+            ProgressBar::hidden()
+        };
+        let phase = Phase {
+            name,
+            bytes: 0,
+            started: Instant::now(),
+            duration: Duration::default(),
+        };
+        self.state.borrow_mut().current = Some((phase, bar));
+    }
+
+    pub fn advance(&self, bytes: u64) {
+        if let Some((phase, bar)) = &mut self.state.borrow_mut().current {
+            phase.bytes += bytes;
+            bar.inc(bytes);
+        }
+    }
+
+    pub fn finish(&self) {
+        let mut state = self.state.borrow_mut();
+        if let Some((mut phase, bar)) = state.current.take() {
+            bar.finish();
+            phase.duration = phase.started.elapsed();
+            state.finished.push(phase);
+        }
+    }
+
+    fn abandon(&self) {
+        if let Some((_, bar)) = self.state.borrow_mut().current.take() {
+            bar.abandon();
+        }
+    }
+
+    /// Logs how long each phase took, e.g. `erase 3.1s, program 9.8s @ 41 KiB/s, verify 2.2s`
+    pub fn print_timings(&self) {
+        let state = self.state.borrow();
+        if state.finished.is_empty() {
+            return;
+        }
+
+        let phases = state
+            .finished
+            .iter()
+            .map(|phase| {
+                let secs = phase.duration.as_secs_f64();
+                if phase.name == "program" && secs > 0. {
+                    let rate = phase.bytes as f64 / 1024. / secs;
+                    format!("{} {:.1}s @ {:.0} KiB/s", phase.name, secs, rate)
+                } else {
+                    format!("{} {:.1}s", phase.name, secs)
+                }
+            })
+            .collect::<Vec<_>>();
+        log::info!("timings: {}", phases.join(", "));
+    }
+}
@@ -0,0 +1,149 @@
+//! Checks the read and write protection of the flash before flashing
+//!
+//! A protected chip otherwise fails deep inside the flash loader with an error that doesn't say
+//! why. Supported are the nRF52 (APPROTECT, BPROT and ACL) and the STM32F4 (RDP and nWRP).
+
+use std::ops::Range;
+
+use anyhow::bail;
+use probe_rs::{MemoryInterface, Session};
+
+use crate::unlock;
+
+/// Fails if the flash is read-protected, or if the address `ranges` about to be flashed are
+/// write-protected
+pub fn check(sess: &mut Session, chip: &str, ranges: &[Range<u32>]) -> anyhow::Result<()> {
+    let lowercase = chip.to_ascii_lowercase();
+    let protected = if lowercase.starts_with("nrf52") {
+        nrf52::check(sess, &lowercase)?
+    } else if lowercase.starts_with("stm32f4") {
+        stm32f4::check(sess)?
+    } else {
+        log::debug!("the flash protection of `{}` can't be checked", chip);
+        return Ok(());
+    };
+
+    for (range, mechanism) in &protected {
+        if let Some(overlap) = ranges
+            .iter()
+            .find(|other| other.start < range.end && range.start < other.end)
+        {
+            bail!(
+                "flash at 0x{:08X}-0x{:08X} is write-protected ({}) but the program is to be \
+                written to 0x{:08X}-0x{:08X}; clear the protection first",
+                range.start,
+                range.end - 1,
+                mechanism,
+                overlap.start,
+                overlap.end - 1
+            );
+        }
+        log::info!(
+            "flash at 0x{:08X}-0x{:08X} is write-protected ({})",
+            range.start,
+            range.end - 1,
+            mechanism
+        );
+    }
+    if protected.is_empty() {
+        log::debug!("the flash is neither read- nor write-protected");
+    }
+    Ok(())
+}
+
+mod nrf52 {
+    use super::*;
+
+    /// The CTRL-AP of the nRF52
+    const CTRL_AP: u16 = 1;
+
+    const PAGE_SIZE: u32 = 4096;
+    /// `BPROT.CONFIG0..3`, one bit per page (nRF52832, nRF52810, nRF52811)
+    const BPROT_CONFIG: [u32; 4] = [0x4000_0600, 0x4000_0604, 0x4000_0610, 0x4000_0614];
+    /// Set if the protection is off while a debugger is attached; the reset value
+    const BPROT_DISABLEINDEBUG: u32 = 0x4000_0608;
+    /// `ACL[0].ADDR`; `SIZE` and `PERM` follow, and the next region after 16 bytes (nRF52833,
+    /// nRF52840)
+    const ACL: u32 = 0x4001_E800;
+    const ACL_REGIONS: u32 = 8;
+    const ACL_PERM_WRITE_DISABLED: u32 = 1 << 1;
+
+    /// Returns the write-protected ranges
+    pub fn check(sess: &mut Session, chip: &str) -> anyhow::Result<Vec<(Range<u32>, String)>> {
+        // NOTE probe-rs usually can't attach to a locked chip in the first place; then
+        // `unlock::explain_attach_error` gives this hint
+        if unlock::is_nrf_locked(&mut **sess.get_arm_interface()?, CTRL_AP)? {
+            bail!("flash is read-protected (APPROTECT): run with `--chip-unlock` to mass-erase it");
+        }
+
+        let mut core = sess.core(0)?;
+        let mut protected = vec![];
+        if chip.starts_with("nrf52840") || chip.starts_with("nrf52833") {
+            for index in 0..ACL_REGIONS {
+                let mut acl = [0; 3];
+                core.read_32(ACL + index * 0x10, &mut acl)?;
+                let [addr, size, perm] = acl;
+                if size != 0 && perm & ACL_PERM_WRITE_DISABLED != 0 {
+                    protected.push((addr..addr + size, format!("ACL[{}]", index)));
+                }
+            }
+        } else if core.read_word_32(BPROT_DISABLEINDEBUG)? & 1 == 0 {
+            for (index, config) in BPROT_CONFIG.iter().enumerate() {
+                let bits = core.read_word_32(*config)?;
+                for bit in 0..32 {
+                    if bits & (1 << bit) != 0 {
+                        let start = (index as u32 * 32 + bit) * PAGE_SIZE;
+                        let end = start + PAGE_SIZE;
+                        // NOTE adjacent pages are reported as one range
+                        match protected.last_mut() {
+                            Some((range, _)) if range.end == start => range.end = end,
+                            _ => protected.push((start..end, "BPROT".to_string())),
+                        }
+                    }
+                }
+            }
+        }
+        Ok(protected)
+    }
+}
+
+mod stm32f4 {
+    use super::*;
+
+    const FLASH_OPTCR: u32 = 0x4002_3C14;
+    const OPTCR_RDP_SHIFT: u32 = 8;
+    const RDP_LEVEL_0: u32 = 0xAA;
+    const RDP_LEVEL_2: u32 = 0xCC;
+    /// nWRP: a clear bit write-protects its sector
+    const OPTCR_NWRP_SHIFT: u32 = 16;
+
+    const FLASH_START: u32 = 0x0800_0000;
+    /// Sizes of the sectors of the first flash bank, in KiB
+    const SECTOR_SIZES: [u32; 12] = [16, 16, 16, 16, 64, 128, 128, 128, 128, 128, 128, 128];
+
+    /// Returns the write-protected ranges
+    pub fn check(sess: &mut Session) -> anyhow::Result<Vec<(Range<u32>, String)>> {
+        let mut core = sess.core(0)?;
+        let optcr = core.read_word_32(FLASH_OPTCR)?;
+        match (optcr >> OPTCR_RDP_SHIFT) & 0xFF {
+            RDP_LEVEL_0 => {}
+            RDP_LEVEL_2 => bail!(
+                "flash is permanently read-protected (RDP level 2); the chip can't be reflashed"
+            ),
+            _ => bail!(
+                "flash is read-protected (RDP level 1): run with `--chip-unlock` to mass-erase it"
+            ),
+        }
+
+        let mut protected = vec![];
+        let mut start = FLASH_START;
+        for (sector, size) in SECTOR_SIZES.iter().enumerate() {
+            let end = start + size * 1024;
+            if optcr & (1 << (OPTCR_NWRP_SHIFT + sector as u32)) == 0 {
+                protected.push((start..end, format!("nWRP sector {}", sector)));
+            }
+            start = end;
+        }
+        Ok(protected)
+    }
+}
@@ -0,0 +1,51 @@
+//! Running the program from RAM (`--ram-exec`), without touching the flash
+
+use anyhow::bail;
+use probe_rs::{config::MemoryRegion, MemoryInterface, Session, Target};
+
+use crate::{flash, TIMEOUT};
+
+/// Halts the core and writes the loadable segments of the ELF file into RAM
+///
+/// The program must be linked to run from RAM; every segment has to lie within a RAM region of
+/// the target. Starting the program is left to the caller.
+pub fn load(
+    sess: &mut Session,
+    core_index: usize,
+    elf_bytes: &[u8],
+    target: &Target,
+) -> anyhow::Result<()> {
+    let segments = flash::loadable_segments(elf_bytes)?;
+    for segment in &segments {
+        let start = u64::from(segment.address);
+        let end = start + segment.data.len() as u64;
+        let in_ram = target.memory_map.iter().any(|region| match region {
+            MemoryRegion::Ram(ram) => {
+                u64::from(ram.range.start) <= start && end <= u64::from(ram.range.end)
+            }
+            _ => false,
+        });
+        if !in_ram {
+            bail!(
+                "segment at 0x{:08X}-0x{:08X} is not in RAM; link the program to run from RAM to use `--ram-exec`",
+                start,
+                end - 1
+            );
+        }
+    }
+
+    let size = segments
+        .iter()
+        .map(|segment| segment.data.len())
+        .sum::<usize>();
+    log::info!(
+        "loading program into RAM ({:.02} KiB)",
+        size as f64 / 1024.0
+    );
+    let mut core = sess.core(core_index)?;
+    core.reset_and_halt(TIMEOUT)?;
+    for segment in &segments {
+        core.write_8(segment.address, segment.data)?;
+    }
+    Ok(())
+}
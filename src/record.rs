@@ -0,0 +1,167 @@
+//! Recording of the RTT data received from the target, and its offline replay
+//!
+//! A recording starts with a header that holds a hash of the ELF file's defmt table, followed by
+//! the chunks of data read from the RTT up channels, in the order they were read.
+
+use std::{
+    collections::BTreeMap,
+    fs::{self, File},
+    io::{self, BufWriter, Read as _, Write as _},
+    path::Path,
+};
+
+use anyhow::{anyhow, bail};
+use arrayref::array_ref;
+use object::{
+    read::File as ElfFile, Object as _, ObjectSection as _, ObjectSymbol as _, SymbolSection,
+};
+
+use crate::{hooks::Hooks, log_filter::LogFilter, rtt::Printer};
+
+const MAGIC: &[u8; 8] = b"PRUNREC\0";
+const VERSION: u8 = 1;
+
+const KIND_TEXT: u8 = 0;
+const KIND_DEFMT: u8 = 1;
+
+/// Writes the data read from the RTT up channels to a file
+pub struct Recorder {
+    file: BufWriter<File>,
+}
+
+impl Recorder {
+    pub fn create(path: &Path, elf: &ElfFile) -> anyhow::Result<Self> {
+        let mut file = BufWriter::new(File::create(path)?);
+        file.write_all(MAGIC)?;
+        file.write_all(&[VERSION])?;
+        file.write_all(&defmt_table_hash(elf).to_le_bytes())?;
+        Ok(Self { file })
+    }
+
+    /// Appends `data`, read from up channel `channel`, to the recording
+    pub fn write(&mut self, channel: usize, uses_defmt: bool, data: &[u8]) -> anyhow::Result<()> {
+        let kind = if uses_defmt { KIND_DEFMT } else { KIND_TEXT };
+        self.file.write_all(&(channel as u32).to_le_bytes())?;
+        self.file.write_all(&[kind])?;
+        self.file.write_all(&(data.len() as u32).to_le_bytes())?;
+        self.file.write_all(data)?;
+        // NOTE flush every chunk; the recording must be usable even if `probe-run` is killed
+        self.file.flush()?;
+        Ok(())
+    }
+}
+
+/// Decodes and prints a recording as if the data came from the target
+pub fn replay(
+    recording: &Path,
+    elf_path: &Path,
+    log_filter: Option<&LogFilter>,
+    json: bool,
+) -> anyhow::Result<()> {
+    let bytes = fs::read(elf_path)?;
+    let elf = ElfFile::parse(&bytes)?;
+    let (table, locs) = crate::decoder::parse(&bytes)?;
+
+    let recording = fs::read(recording)?;
+    let mut reader = &recording[..];
+    let mut header = [0; MAGIC.len() + 1 + 8];
+    reader
+        .read_exact(&mut header)
+        .map_err(|_| anyhow!("not a probe-run recording"))?;
+    if &header[..MAGIC.len()] != MAGIC {
+        bail!("not a probe-run recording");
+    }
+    if header[MAGIC.len()] != VERSION {
+        bail!("unsupported recording version {}", header[MAGIC.len()]);
+    }
+    let hash = u64::from_le_bytes(*array_ref!(header, MAGIC.len() + 1, 8));
+    if hash != defmt_table_hash(&elf) {
+        bail!(
+            "the defmt table of {} does not match the recording; \
+            was the recording made with a different build of the firmware?",
+            elf_path.display()
+        );
+    }
+
+    let current_dir = std::env::current_dir()?;
+    let printer = Printer {
+        table: table.as_ref(),
+        locs: locs.as_ref(),
+        current_dir: &current_dir,
+        log_filter,
+        patterns: None,
+        tests: None,
+        log_stats: None,
+        plot: None,
+        log_file: None,
+        external_decoder: None,
+        quiet_stdout: false,
+        timestamps: None,
+        hyperlinks: None,
+        hooks: &Hooks {
+            json,
+            ..Hooks::default()
+        },
+    };
+
+    // holds the incomplete defmt frames of each channel
+    let mut frames = BTreeMap::<u32, Vec<u8>>::new();
+    while !reader.is_empty() {
+        let mut chunk_header = [0; 9];
+        read_chunk_part(&mut reader, &mut chunk_header)?;
+        let channel = u32::from_le_bytes(*array_ref!(chunk_header, 0, 4));
+        let kind = chunk_header[4];
+        let mut data = vec![0; u32::from_le_bytes(*array_ref!(chunk_header, 5, 4)) as usize];
+        read_chunk_part(&mut reader, &mut data)?;
+
+        if kind == KIND_DEFMT {
+            let frames = frames.entry(channel).or_default();
+            frames.extend_from_slice(&data);
+            printer.defmt(frames)?;
+        } else {
+            printer.text(&data)?;
+        }
+    }
+
+    Ok(())
+}
+
+fn read_chunk_part(reader: &mut &[u8], buf: &mut [u8]) -> anyhow::Result<()> {
+    reader.read_exact(buf).map_err(|e| match e.kind() {
+        io::ErrorKind::UnexpectedEof => anyhow!("the recording is truncated"),
+        _ => e.into(),
+    })
+}
+
+/// FNV-1a hash of the symbols in the `.defmt` section, or 0 if the ELF file has no defmt data
+///
+/// NOTE the defmt table is encoded in the names and addresses of these symbols
+fn defmt_table_hash(elf: &ElfFile) -> u64 {
+    const OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+    const PRIME: u64 = 0x0000_0100_0000_01b3;
+
+    let defmt = match elf.section_by_name(".defmt") {
+        Some(section) => section.index(),
+        None => return 0,
+    };
+
+    let mut symbols = elf
+        .symbols()
+        .filter(|symbol| symbol.section() == SymbolSection::Section(defmt))
+        .filter_map(|symbol| Some((symbol.address(), symbol.name().ok()?)))
+        .collect::<Vec<_>>();
+    symbols.sort_unstable();
+
+    symbols
+        .iter()
+        .flat_map(|(address, name)| {
+            address
+                .to_le_bytes()
+                .to_vec()
+                .into_iter()
+                .chain(name.bytes())
+        })
+        .fold(OFFSET_BASIS, |hash, byte| {
+            (hash ^ u64::from(byte)).wrapping_mul(PRIME)
+        })
+}
@@ -3,24 +3,21 @@ use std::collections::{btree_map, BTreeMap};
 use gimli::{read::CfaRule, EndianSlice, LittleEndian, Register, RegisterRule};
 use probe_rs::{Core, CoreRegisterAddress, MemoryInterface};
 
-pub const LR: CoreRegisterAddress = CoreRegisterAddress(14);
-pub const PC: CoreRegisterAddress = CoreRegisterAddress(15);
-pub const SP: CoreRegisterAddress = CoreRegisterAddress(13);
-
-pub const LR_END: u32 = 0xFFFF_FFFF;
+use crate::{arch::Arch, fault_address::AddressMap, theme};
 
 /// Cache and track the state of CPU registers while the stack is being unwound.
 pub struct Registers<'c, 'probe> {
+    arch: Arch,
     cache: BTreeMap<u16, u32>,
     pub core: &'c mut Core<'probe>,
 }
 
 impl<'c, 'probe> Registers<'c, 'probe> {
-    pub fn new(lr: u32, sp: u32, core: &'c mut Core<'probe>) -> Self {
+    pub fn new(arch: Arch, lr: u32, sp: u32, core: &'c mut Core<'probe>) -> Self {
         let mut cache = BTreeMap::new();
-        cache.insert(LR.0, lr);
-        cache.insert(SP.0, sp);
-        Self { cache, core }
+        cache.insert(arch.return_address().0, lr);
+        cache.insert(arch.stack_pointer().0, sp);
+        Self { arch, cache, core }
     }
 
     pub fn get(&mut self, reg: CoreRegisterAddress) -> anyhow::Result<u32> {
@@ -44,13 +41,15 @@ impl<'c, 'probe> Registers<'c, 'probe> {
     ) -> anyhow::Result</* cfa_changed: */ bool> {
         match rule {
             CfaRule::RegisterAndOffset { register, offset } => {
-                let cfa = (i64::from(self.get(gimli2probe(register))?) + offset) as u32;
-                let old_cfa = self.cache.get(&SP.0);
+                let sp = self.arch.stack_pointer();
+                let cfa =
+                    (i64::from(self.get(self.arch.dwarf_register(*register))?) + offset) as u32;
+                let old_cfa = self.cache.get(&sp.0);
                 let changed = old_cfa != Some(&cfa);
                 if changed {
                     log::debug!("update_cfa: CFA changed {:8x?} -> {:8x}", old_cfa, cfa);
                 }
-                self.cache.insert(SP.0, cfa);
+                self.cache.insert(sp.0, cfa);
                 Ok(changed)
             }
             // NOTE not encountered in practice so far
@@ -63,12 +62,17 @@ impl<'c, 'probe> Registers<'c, 'probe> {
         reg: &Register,
         rule: &RegisterRule<EndianSlice<LittleEndian>>,
     ) -> anyhow::Result<()> {
+        let reg = self.arch.dwarf_register(*reg);
         match rule {
             RegisterRule::Offset(offset) => {
-                let cfa = self.get(SP)?;
+                let cfa = self.get(self.arch.stack_pointer())?;
                 let addr = (cfa as i64 + offset) as u32;
                 self.cache.insert(reg.0, self.core.read_word_32(addr)?);
             }
+            // `riscv-rt` marks the return address of its entry point as undefined
+            RegisterRule::Undefined if reg.0 == self.arch.return_address().0 => {
+                self.cache.insert(reg.0, self.arch.end_of_stack());
+            }
             RegisterRule::Undefined => unreachable!(),
             _ => unimplemented!(),
         }
@@ -76,6 +80,230 @@ impl<'c, 'probe> Registers<'c, 'probe> {
     }
 }
 
-fn gimli2probe(reg: &Register) -> CoreRegisterAddress {
-    CoreRegisterAddress(reg.0)
+/// Configurable Fault Status Register; holds the MemManage, BusFault and UsageFault status
+const CFSR: u32 = 0xE000_ED28;
+/// HardFault Status Register
+const HFSR: u32 = 0xE000_ED2C;
+/// MemManage Fault Address Register
+const MMFAR: u32 = 0xE000_ED34;
+/// BusFault Address Register
+const BFAR: u32 = 0xE000_ED38;
+/// SecureFault Status Register (ARMv8-M with the Security Extension)
+const SFSR: u32 = 0xE000_EDE4;
+/// SecureFault Address Register
+const SFAR: u32 = 0xE000_EDE8;
+
+const MMARVALID: u32 = 1 << 7;
+const BFARVALID: u32 = 1 << 15;
+const SFARVALID: u32 = 1 << 6;
+/// UsageFault: the stack pointer went below its limit register (ARMv8-M)
+const STKOF: u32 = 1 << 20;
+
+/// A data access that faulted, with its address
+#[derive(Clone, Copy, Debug)]
+pub enum FaultingAccess {
+    MemManage(u32),
+    Bus(u32),
+}
+
+impl FaultingAccess {
+    pub fn address(self) -> u32 {
+        match self {
+            FaultingAccess::MemManage(address) | FaultingAccess::Bus(address) => address,
+        }
+    }
+}
+
+/// The fault status registers of a Cortex-M core's System Control Block
+#[derive(Debug)]
+pub struct FaultStatus {
+    cfsr: u32,
+    hfsr: u32,
+    mmfar: u32,
+    bfar: u32,
+    sfsr: u32,
+    sfar: u32,
+}
+
+impl FaultStatus {
+    pub fn read(core: &mut Core<'_>) -> anyhow::Result<Self> {
+        Ok(Self {
+            cfsr: core.read_word_32(CFSR)?,
+            hfsr: core.read_word_32(HFSR)?,
+            mmfar: core.read_word_32(MMFAR)?,
+            bfar: core.read_word_32(BFAR)?,
+            // NOTE reserved on cores without the Security Extension; may fail or read as zero
+            sfsr: core.read_word_32(SFSR).unwrap_or(0),
+            sfar: core.read_word_32(SFAR).unwrap_or(0),
+        })
+    }
+
+    /// Whether the stack pointer went below its limit register
+    pub fn is_stack_overflow(&self) -> bool {
+        self.cfsr & STKOF != 0
+    }
+
+    /// The data access that faulted, if the core recorded its address
+    pub fn faulting_access(&self) -> Option<FaultingAccess> {
+        if self.cfsr & (1 << 1) != 0 && self.cfsr & MMARVALID != 0 {
+            Some(FaultingAccess::MemManage(self.mmfar))
+        } else if self.cfsr & (1 << 9) != 0 && self.cfsr & BFARVALID != 0 {
+            Some(FaultingAccess::Bus(self.bfar))
+        } else {
+            None
+        }
+    }
+
+    /// Human-readable explanations of the fault(s) recorded in the registers
+    pub fn explain(&self) -> Vec<String> {
+        let mut causes = vec![];
+        let mut cause = |mask: u32, msg: &str| {
+            if self.cfsr & mask != 0 {
+                causes.push(msg.to_string());
+            }
+        };
+
+        // MemManage faults
+        cause(
+            1 << 0,
+            "memory management fault: instruction fetch from a non-executable region",
+        );
+        cause(
+            1 << 3,
+            "memory management fault on unstacking for an exception return",
+        );
+        cause(
+            1 << 4,
+            "memory management fault on stacking for exception entry",
+        );
+        cause(
+            1 << 5,
+            "memory management fault during floating-point lazy state preservation",
+        );
+
+        // BusFaults
+        cause(1 << 8, "bus fault on instruction prefetch");
+        cause(
+            1 << 10,
+            "imprecise bus fault (data access; the faulting address is unknown)",
+        );
+        cause(1 << 11, "bus fault on unstacking for an exception return");
+        cause(1 << 12, "bus fault on stacking for exception entry");
+        cause(
+            1 << 13,
+            "bus fault during floating-point lazy state preservation",
+        );
+
+        // UsageFaults
+        cause(1 << 16, "usage fault: undefined instruction");
+        cause(
+            1 << 17,
+            "usage fault: invalid state (attempt to leave Thumb mode)",
+        );
+        cause(
+            1 << 18,
+            "usage fault: invalid EXC_RETURN value loaded into the PC",
+        );
+        cause(
+            1 << 19,
+            "usage fault: coprocessor (e.g. FPU) access while it is disabled",
+        );
+        cause(STKOF, "usage fault: stack overflow (MSPLIM violation)");
+        cause(1 << 24, "usage fault: unaligned memory access");
+        cause(1 << 25, "usage fault: divide by zero");
+
+        // faults that report the faulting address
+        if self.cfsr & (1 << 1) != 0 {
+            causes.push(match self.cfsr & MMARVALID {
+                0 => "memory management fault: data access violation".to_string(),
+                _ => format!(
+                    "memory management fault: data access violation at {:#010x}",
+                    self.mmfar
+                ),
+            });
+        }
+        if self.cfsr & (1 << 9) != 0 {
+            causes.push(match self.cfsr & BFARVALID {
+                0 => "precise bus fault caused by a data access".to_string(),
+                _ => format!(
+                    "precise bus fault at {:#010x} caused by a data access",
+                    self.bfar
+                ),
+            });
+        }
+
+        let mut secure_cause = |mask: u32, msg: &str| {
+            if self.sfsr & mask != 0 {
+                causes.push(format!("secure fault: {}", msg));
+            }
+        };
+        secure_cause(1 << 0, "invalid entry point into the secure state");
+        secure_cause(1 << 1, "invalid integrity signature on exception return");
+        secure_cause(1 << 2, "invalid exception return");
+        secure_cause(
+            1 << 4,
+            "branch into the non-secure state without marking it (BXNS/BLXNS)",
+        );
+        secure_cause(
+            1 << 5,
+            "violation during floating-point lazy state preservation",
+        );
+        secure_cause(1 << 7, "error during floating-point lazy state activation");
+        if self.sfsr & (1 << 3) != 0 {
+            causes.push(match self.sfsr & SFARVALID {
+                0 => "secure fault: non-secure access to secure memory".to_string(),
+                _ => format!(
+                    "secure fault: non-secure access to secure memory at {:#010x}",
+                    self.sfar
+                ),
+            });
+        }
+
+        if self.hfsr & (1 << 1) != 0 {
+            causes.push("hard fault: bus fault while reading the vector table".to_string());
+        }
+        if self.hfsr & (1 << 30) != 0 && causes.is_empty() {
+            causes.push(
+                "hard fault: escalated from a configurable fault that is disabled or could not be handled"
+                    .to_string(),
+            );
+        }
+        if self.hfsr & (1 << 31) != 0 {
+            causes.push("hard fault: debug event".to_string());
+        }
+
+        causes
+    }
+
+    pub fn print(&self, address_map: &AddressMap) {
+        log::debug!(
+            "CFSR={:#010x} HFSR={:#010x} MMFAR={:#010x} BFAR={:#010x} SFSR={:#010x} SFAR={:#010x}",
+            self.cfsr,
+            self.hfsr,
+            self.mmfar,
+            self.bfar,
+            self.sfsr,
+            self.sfar
+        );
+
+        let causes = self.explain();
+        if causes.is_empty() {
+            println!(
+                "{}",
+                theme::current()
+                    .secondary
+                    .paint("fault status registers do not record a cause")
+            );
+        }
+        for cause in causes {
+            println!("{} {}", theme::current().failure.paint("fault:"), cause);
+        }
+        if let Some(access) = self.faulting_access() {
+            println!(
+                "{} {}",
+                theme::current().failure.paint("fault:"),
+                address_map.describe(access)
+            );
+        }
+    }
 }
@@ -0,0 +1,165 @@
+//! `--repl`: an interactive prompt for inspecting the halted device before `probe-run` exits
+//!
+//! It covers the quick questions that would otherwise take a GDB session: memory, registers, the
+//! backtrace and global variables. `resume` and `reset` leave the program running and exit.
+
+use std::io::{self, BufRead as _, Write as _};
+
+use anyhow::anyhow;
+use object::read::File as ElfFile;
+use probe_rs::{Core, CoreRegisterAddress, MemoryInterface};
+
+use crate::{arch::Arch, cortexm, riscv, theme, watch_var};
+
+/// Bytes per line of `read`'s hex dump
+const DUMP_WIDTH: usize = 16;
+
+const HELP: &str = "\
+commands:
+  read <addr> <len>   hex dump <len> bytes of memory at <addr>
+  regs                print the core registers
+  bt                  print the backtrace
+  var <name>[:<type>] print a global variable
+  resume              let the program continue and exit
+  reset               reset the device, let it run and exit
+  quit                exit (also Ctrl+D)";
+
+/// How the prompt was left
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Outcome {
+    /// The device is still halted
+    Quit,
+    /// The program is running again, resumed or reset
+    Running,
+}
+
+/// Prompts for commands until one of them leaves the prompt; `backtrace` prints the backtrace
+pub fn run(
+    core: &mut Core<'_>,
+    arch: Arch,
+    elf: &ElfFile,
+    backtrace: &mut dyn FnMut(&mut Core<'_>) -> anyhow::Result<()>,
+) -> anyhow::Result<Outcome> {
+    println!(
+        "{}",
+        theme::current()
+            .secondary
+            .paint("entering the REPL; type `help` for a list of commands")
+    );
+    let stdin = io::stdin();
+    let mut lines = stdin.lock().lines();
+    loop {
+        print!("{} ", theme::current().emphasis.paint("(probe-run)"));
+        io::stdout().flush()?;
+        let line = match lines.next() {
+            Some(line) => line?,
+            None => {
+                println!();
+                return Ok(Outcome::Quit);
+            }
+        };
+
+        let words = line.split_whitespace().collect::<Vec<_>>();
+        let result = match &words[..] {
+            [] => Ok(()),
+            ["help"] => {
+                println!("{}", HELP);
+                Ok(())
+            }
+            ["read", address, len] => read(core, address, len),
+            ["regs"] => registers(core, arch),
+            ["bt"] => backtrace(core),
+            ["var", spec] => spec
+                .parse()
+                .and_then(|spec| watch_var::read(&spec, elf, core))
+                .map(|value| println!("{} = {}", spec, value)),
+            ["resume"] => {
+                core.clear_all_hw_breakpoints()?;
+                core.run()?;
+                return Ok(Outcome::Running);
+            }
+            ["reset"] => {
+                core.clear_all_hw_breakpoints()?;
+                core.reset()?;
+                return Ok(Outcome::Running);
+            }
+            ["quit"] | ["exit"] => return Ok(Outcome::Quit),
+            _ => Err(anyhow!("unknown command `{}`; type `help`", line.trim())),
+        };
+        // NOTE a bad command shouldn't end the session
+        if let Err(e) = result {
+            println!("{} {}", theme::current().failure.paint("error:"), e);
+        }
+    }
+}
+
+fn read(core: &mut Core<'_>, address: &str, len: &str) -> anyhow::Result<()> {
+    let address = parse_number(address)?;
+    let len = parse_number(len)? as usize;
+    let mut bytes = vec![0; len];
+    core.read_8(address, &mut bytes)?;
+
+    for (index, chunk) in bytes.chunks(DUMP_WIDTH).enumerate() {
+        let hex = chunk
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect::<Vec<_>>()
+            .join(" ");
+        let ascii = chunk
+            .iter()
+            .map(|byte| match byte {
+                0x20..=0x7e => *byte as char,
+                _ => '.',
+            })
+            .collect::<String>();
+        println!(
+            "0x{:08X}  {:<width$}  {}",
+            address as usize + index * DUMP_WIDTH,
+            hex,
+            theme::current().secondary.paint(&ascii),
+            width = DUMP_WIDTH * 3 - 1
+        );
+    }
+    Ok(())
+}
+
+fn registers(core: &mut Core<'_>, arch: Arch) -> anyhow::Result<()> {
+    let registers = match arch {
+        Arch::CortexM => {
+            let mut registers = (0..13)
+                .map(|reg| (format!("r{}", reg), CoreRegisterAddress(reg)))
+                .collect::<Vec<_>>();
+            registers.push(("sp".to_string(), cortexm::SP));
+            registers.push(("lr".to_string(), cortexm::LR));
+            registers.push(("pc".to_string(), cortexm::PC));
+            registers.push(("xpsr".to_string(), cortexm::XPSR));
+            registers
+        }
+        Arch::Riscv32 => {
+            let mut registers = (1..32)
+                .map(|reg| (format!("x{}", reg), riscv::dwarf_register(reg)))
+                .collect::<Vec<_>>();
+            registers.push(("pc".to_string(), riscv::PC));
+            registers
+        }
+        Arch::CortexAR | Arch::Aarch64 => vec![
+            ("sp".to_string(), arch.stack_pointer()),
+            ("lr".to_string(), arch.return_address()),
+            ("pc".to_string(), arch.program_counter()),
+        ],
+    };
+
+    for (name, register) in registers {
+        println!("{:>5}  0x{:08X}", name, core.read_core_reg(register)?);
+    }
+    Ok(())
+}
+
+fn parse_number(s: &str) -> anyhow::Result<u32> {
+    let number = if let Some(hex) = s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+        u32::from_str_radix(hex, 16)
+    } else {
+        s.parse()
+    };
+    number.map_err(|_| anyhow!("invalid number `{}`", s))
+}
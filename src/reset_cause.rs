@@ -0,0 +1,107 @@
+//! Reports why the chip last reset, e.g. a watchdog, right after attaching to it
+//!
+//! The cause is read from the reset reason register of the chip family (nRF `POWER.RESETREAS`,
+//! STM32 `RCC_CSR`), which is then cleared so that the next run only reports new resets.
+
+use probe_rs::{MemoryInterface, Session};
+
+/// A reset reason register and the meaning of its bits
+struct Register {
+    address: u32,
+    causes: &'static [(u32, &'static str)],
+    /// Clears the register when written
+    clear: Clear,
+}
+
+enum Clear {
+    /// Write back the bits that are set (nRF)
+    WriteOnes,
+    /// Set this bit (`RMVF` on STM32)
+    Bit(u32),
+}
+
+const NRF5_RESETREAS: Register = Register {
+    address: 0x4000_0400,
+    causes: &[
+        (0, "reset pin"),
+        (1, "watchdog"),
+        (2, "soft reset"),
+        (3, "CPU lock-up"),
+        (16, "wake-up from System OFF (GPIO)"),
+        (17, "wake-up from System OFF (LPCOMP)"),
+        (18, "wake-up from System OFF (debug interface)"),
+        (19, "wake-up from System OFF (NFC)"),
+    ],
+    clear: Clear::WriteOnes,
+};
+
+const STM32F1_RCC_CSR: Register = Register {
+    address: 0x4002_1024,
+    causes: STM32_CAUSES,
+    clear: Clear::Bit(24),
+};
+
+/// F2, F4 and F7
+const STM32F4_RCC_CSR: Register = Register {
+    address: 0x4002_3874,
+    causes: STM32_CAUSES,
+    clear: Clear::Bit(24),
+};
+
+const STM32_CAUSES: &[(u32, &str)] = &[
+    (25, "brown-out"),
+    (26, "reset pin"),
+    (27, "power-on"),
+    (28, "software reset"),
+    (29, "independent watchdog"),
+    (30, "window watchdog"),
+    (31, "low-power management"),
+];
+
+/// Logs the reset cause of `chip`, if its family is known; failures only show up with `-v`, as the
+/// cause is not needed to run the program
+pub fn report(sess: &mut Session, chip: &str) {
+    let lowercase = chip.to_ascii_lowercase();
+    let register = if lowercase.starts_with("nrf51") || lowercase.starts_with("nrf52") {
+        NRF5_RESETREAS
+    } else if lowercase.starts_with("stm32f1") {
+        STM32F1_RCC_CSR
+    } else if ["stm32f2", "stm32f4", "stm32f7"]
+        .iter()
+        .any(|family| lowercase.starts_with(family))
+    {
+        STM32F4_RCC_CSR
+    } else {
+        log::debug!("the reset cause of `{}` can't be read", chip);
+        return;
+    };
+
+    if let Err(e) = read_and_clear(sess, &register) {
+        // NOTE e.g. a read-protected chip; `--chip-unlock` deals with that later
+        log::debug!("failed to read the reset cause: {}", e);
+    }
+}
+
+fn read_and_clear(sess: &mut Session, register: &Register) -> anyhow::Result<()> {
+    let mut core = sess.core(0)?;
+    let value = core.read_word_32(register.address)?;
+    let causes = register
+        .causes
+        .iter()
+        .filter(|(bit, _)| value & (1 << bit) != 0)
+        .map(|(_, cause)| *cause)
+        .collect::<Vec<_>>();
+
+    match (&register.clear, causes.is_empty()) {
+        // NOTE an empty RESETREAS means the chip was powered on, or browned out
+        (Clear::WriteOnes, true) => log::info!("reset cause: power-on"),
+        (_, true) => log::info!("reset cause: unknown (0x{:08X})", value),
+        _ => log::info!("reset cause: {}", causes.join(", ")),
+    }
+
+    match register.clear {
+        Clear::WriteOnes => core.write_word_32(register.address, value)?,
+        Clear::Bit(bit) => core.write_word_32(register.address, value | 1 << bit)?,
+    }
+    Ok(())
+}
@@ -0,0 +1,94 @@
+//! Resets the program does itself, e.g. a watchdog test or the jump out of a bootloader
+//!
+//! The Cortex-M core sets the sticky `S_RESET_ST` bit of DHCSR when it's reset; reading the
+//! register clears it. The debug logic (and with it the hardware breakpoints) survives a system
+//! reset, but the program sets up its RTT control block anew, so probe-run attaches to it again.
+//! To do that at the right time, the reset vector catch halts the core right after the reset, and
+//! the program is run to `main` like on its first boot.
+
+use probe_rs::{Core, MemoryInterface};
+
+/// Debug Halting Control and Status Register
+const DHCSR: u32 = 0xE000_EDF0;
+const DHCSR_S_HALT: u32 = 1 << 17;
+const DHCSR_S_RESET_ST: u32 = 1 << 25;
+/// Debug Exception and Monitor Control Register
+const DEMCR: u32 = 0xE000_EDFC;
+const DEMCR_VC_CORERESET: u32 = 1 << 0;
+
+pub struct ResetWatch {
+    max_resets: Option<u32>,
+    /// The core halts at the reset vector after a reset
+    catch_reset: bool,
+    /// Resets seen so far
+    count: u32,
+    /// A reset was seen but not handled yet
+    pending: bool,
+}
+
+impl ResetWatch {
+    /// With `catch_reset`, the core halts after every reset until the reset has been handled
+    ///
+    /// NOTE the resets probe-run did itself before this call are not counted
+    pub fn new(
+        core: &mut Core<'_>,
+        max_resets: Option<u32>,
+        catch_reset: bool,
+    ) -> anyhow::Result<Self> {
+        core.read_word_32(DHCSR)?;
+        if catch_reset {
+            let demcr = core.read_word_32(DEMCR)?;
+            core.write_word_32(DEMCR, demcr | DEMCR_VC_CORERESET)?;
+        }
+        Ok(Self {
+            max_resets,
+            catch_reset,
+            count: 0,
+            pending: false,
+        })
+    }
+
+    /// Clears the reset vector catch; unlike breakpoints it survives a system reset
+    pub fn clear(&self, core: &mut Core<'_>) -> anyhow::Result<()> {
+        if self.catch_reset {
+            let demcr = core.read_word_32(DEMCR)?;
+            core.write_word_32(DEMCR, demcr & !DEMCR_VC_CORERESET)?;
+        }
+        Ok(())
+    }
+
+    /// Reads DHCSR; returns whether the core is halted and records whether it was reset
+    ///
+    /// NOTE this replaces `Core::core_halted`, which reads DHCSR as well and would clear the
+    /// sticky reset bit before this sees it
+    pub fn poll(&mut self, core: &mut Core<'_>) -> anyhow::Result<bool> {
+        let dhcsr = core.read_word_32(DHCSR)?;
+        if dhcsr & DHCSR_S_RESET_ST != 0 {
+            self.count += 1;
+            self.pending = true;
+            log::debug!("the core was reset (#{})", self.count);
+        }
+        // NOTE the halt at the reset vector is part of the reset, not the end of the program
+        Ok(dhcsr & DHCSR_S_HALT != 0 && !(self.catch_reset && self.pending))
+    }
+
+    /// The number of the reset seen since the last call, if any
+    pub fn take_reset(&mut self) -> Option<u32> {
+        if self.pending {
+            self.pending = false;
+            Some(self.count)
+        } else {
+            None
+        }
+    }
+
+    pub fn count(&self) -> u32 {
+        self.count
+    }
+
+    /// Whether the program reset more often than `--max-resets` allows
+    pub fn exceeded(&self) -> bool {
+        self.max_resets
+            .map_or(false, |max_resets| self.count > max_resets)
+    }
+}
@@ -0,0 +1,62 @@
+//! `--retries`: recovery from transient probe errors
+//!
+//! Flaky USB connections and SWD protocol errors make the probe fail now and then, which says
+//! nothing about the program. Such errors are retried after a delay that doubles on every attempt;
+//! the probe is enumerated and opened again, so a probe that was re-enumerated by the host is found
+//! again. Everything else, e.g. a missing ELF file or a chip that doesn't match, fails right away.
+
+use std::{thread, time::Duration};
+
+use probe_rs::{architecture::arm::DapError, DebugProbeError};
+
+use crate::probe;
+
+/// Delay before the first retry
+const INITIAL_BACKOFF: Duration = Duration::from_millis(500);
+const MAX_BACKOFF: Duration = Duration::from_secs(8);
+
+/// Calls `f` until it succeeds, fails with an error that isn't a probe error or has been retried
+/// `retries` times
+pub fn retry<T>(retries: u32, mut f: impl FnMut() -> anyhow::Result<T>) -> anyhow::Result<T> {
+    let mut backoff = INITIAL_BACKOFF;
+    for attempt in 1.. {
+        match f() {
+            Err(e) if attempt <= retries && is_retryable(&e) => {
+                log::warn!(
+                    "probe error: {:#}; retrying in {:?} ({}/{})",
+                    e,
+                    backoff,
+                    attempt,
+                    retries
+                );
+                thread::sleep(backoff);
+                backoff = (backoff * 2).min(MAX_BACKOFF);
+            }
+            result => return result,
+        }
+    }
+
+    unreachable!()
+}
+
+/// Whether `e` was caused by the probe or its connection rather than by the program or the options
+fn is_retryable(e: &anyhow::Error) -> bool {
+    // NOTE the source of a `probe_rs::Error` is the `DebugProbeError`, whose source is the
+    // `DapError` or USB error, so each of them shows up in the chain
+    e.chain().any(|cause| {
+        if let Some(e) = cause.downcast_ref::<DebugProbeError>() {
+            matches!(
+                e,
+                DebugProbeError::USB(_)
+                    | DebugProbeError::ProbeCouldNotBeCreated(_)
+                    | DebugProbeError::Timeout
+            )
+        } else if let Some(e) = cause.downcast_ref::<DapError>() {
+            // NOTE a target that doesn't power up its debug domain won't do so on the next try
+            !matches!(e, DapError::TargetPowerUpFailed)
+        } else {
+            // the probe disappeared while the host re-enumerated it
+            cause.is::<rusb::Error>() || cause.is::<probe::NotFound>()
+        }
+    })
+}
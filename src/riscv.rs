@@ -0,0 +1,49 @@
+//! RISC-V (RV32) specific knowledge: register numbering and `riscv-rt` entry point
+
+use anyhow::anyhow;
+use object::{read::File as ElfFile, Object as _, ObjectSymbol as _};
+use probe_rs::CoreRegisterAddress;
+
+use crate::arch::EntryPoint;
+
+/// probe-rs maps the general purpose registers `x0`-`x31` to these addresses
+const GPR_BASE: u16 = 0x1000;
+
+/// Return address (`x1`)
+pub const RA: CoreRegisterAddress = CoreRegisterAddress(GPR_BASE + 1);
+/// Stack pointer (`x2`)
+pub const SP: CoreRegisterAddress = CoreRegisterAddress(GPR_BASE + 2);
+/// Debug Program Counter CSR; holds the PC while the core is halted
+pub const PC: CoreRegisterAddress = CoreRegisterAddress(0x7b1);
+
+/// `riscv-rt` zeroes `ra` before calling into Rust code; marks the end of the stack
+pub const RA_END: u32 = 0;
+
+/// Maps a DWARF register number (`x0`-`x31`) to the address probe-rs uses for it
+pub fn dwarf_register(reg: u16) -> CoreRegisterAddress {
+    CoreRegisterAddress(GPR_BASE + reg)
+}
+
+/// Extracts the entry point from the ELF header and the symbols `riscv-rt` defines
+pub fn entry_point(elf: &ElfFile) -> anyhow::Result<EntryPoint> {
+    let (mut stack_start, mut exception_handler) = (None, None);
+    for symbol in elf.symbols() {
+        match symbol.name() {
+            Ok("_stack_start") => stack_start = Some(symbol.address() as u32),
+            // NOTE not `_start_trap`, which also handles the interrupts
+            Ok("ExceptionHandler") => exception_handler = Some(symbol.address() as u32),
+            _ => {}
+        }
+    }
+
+    Ok(EntryPoint {
+        vector_table: None,
+        initial_stack_pointer: stack_start.ok_or_else(|| {
+            anyhow!("`_stack_start` symbol not found; is this a `riscv-rt` program?")
+        })?,
+        reset: elf.entry() as u32,
+        fault_handler: Some(
+            exception_handler.ok_or_else(|| anyhow!("`ExceptionHandler` symbol not found"))?,
+        ),
+    })
+}
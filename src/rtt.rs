@@ -0,0 +1,724 @@
+//! RTT control block attachment and routing of the up channels' data
+
+use std::{
+    fs::File,
+    io::{self, Write as _},
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::{Arc, Mutex},
+    time::Instant,
+};
+
+use anyhow::{anyhow, bail};
+use defmt_decoder::Locations;
+use probe_rs::{MemoryInterface as _, Session};
+use probe_rs_rtt::{ChannelMode, Rtt, ScanRegion, UpChannel};
+
+use crate::{
+    decoder::{Frame, Table},
+    defmt_test::Tests,
+    external_decoder::ExternalDecoder,
+    hooks::Hooks,
+    hyperlink::Hyperlinks,
+    json::{self, Record},
+    log_file::LogFile,
+    log_filter::LogFilter,
+    log_stats::LogStats,
+    patterns::Patterns,
+    plot::Plot,
+    theme,
+    timestamp::{Mode, Timestamps},
+};
+
+/// Offset of the up channel descriptors in the control block, and the size of one descriptor
+const O_UP_CHANNELS: u32 = 24;
+const CHANNEL_SIZE: u32 = 24;
+/// Offset of the write offset (`WrOff`) in a channel descriptor; the read offset (`RdOff`) follows
+const O_WRITE: u32 = 12;
+
+/// Attaches to the RTT control block, retrying while the target is still initializing it
+pub fn attach(scan_region: &ScanRegion, sess: Arc<Mutex<Session>>) -> anyhow::Result<Rtt> {
+    const NUM_RETRIES: usize = 10; // picked at random, increase if necessary
+
+    for try_index in 0..=NUM_RETRIES {
+        match Rtt::attach_region(sess.clone(), scan_region) {
+            Ok(rtt) => {
+                log::debug!("Successfully attached RTT");
+                return Ok(rtt);
+            }
+            Err(probe_rs_rtt::Error::ControlBlockNotFound) => {
+                if try_index < NUM_RETRIES {
+                    log::trace!("Could not attach because the target's RTT control block isn't initialized (yet). retrying");
+                } else {
+                    log::error!("Max number of RTT attach retries exceeded.");
+                }
+            }
+            Err(e) => return Err(anyhow!(e)),
+        }
+    }
+
+    Err(anyhow!(probe_rs_rtt::Error::ControlBlockNotFound))
+}
+
+/// Attaches to the RTT control block at `address` if there is one there; doesn't retry
+pub fn attach_at(address: u32, sess: Arc<Mutex<Session>>) -> Option<Rtt> {
+    match Rtt::attach_region(sess, &ScanRegion::Exact(address)) {
+        Ok(rtt) => {
+            log::debug!(
+                "found the RTT control block at its cached address 0x{:08X}",
+                address
+            );
+            Some(rtt)
+        }
+        Err(_) => None,
+    }
+}
+
+/// Where the data of an up channel goes
+#[derive(Debug)]
+pub enum Destination {
+    /// Decode the data as defmt frames and print them
+    Defmt,
+    /// Print the data as is
+    Text,
+    /// Write the raw data to a file
+    File(PathBuf),
+    /// Parse each line as a sample for `--plot-out`
+    Plot,
+}
+
+/// An up channel, by its number or by the name the firmware gave it
+#[derive(Clone, Debug)]
+pub enum ChannelSelector {
+    Number(usize),
+    Name(String),
+}
+
+impl ChannelSelector {
+    /// The number of the up channel this selects
+    fn resolve(&self, rtt: &mut Rtt) -> anyhow::Result<usize> {
+        let name = match self {
+            ChannelSelector::Number(number) => return Ok(*number),
+            ChannelSelector::Name(name) => name,
+        };
+        let channels = rtt.up_channels();
+        let names = (0..channels.len())
+            .filter_map(|number| channels.get(number).and_then(|channel| channel.name()))
+            .map(|name| format!("`{}`", name))
+            .collect::<Vec<_>>();
+        (0..channels.len())
+            .find(|number| {
+                channels
+                    .get(*number)
+                    .map_or(false, |channel| channel.name() == Some(name))
+            })
+            .ok_or_else(|| {
+                anyhow!(
+                    "no RTT up channel is named `{}`; the named ones are: {}",
+                    name,
+                    if names.is_empty() {
+                        "(none)".to_string()
+                    } else {
+                        names.join(", ")
+                    }
+                )
+            })
+    }
+}
+
+impl FromStr for ChannelSelector {
+    type Err = anyhow::Error;
+
+    /// `<index>` or `name=<name>`, as passed to `--rtt-channel`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(name) = s.strip_prefix("name=") {
+            return Ok(ChannelSelector::Name(name.to_string()));
+        }
+        s.parse()
+            .map(ChannelSelector::Number)
+            .map_err(|_| anyhow!("expected `<index>` or `name=<name>`, found `{}`", s))
+    }
+}
+
+/// A `<channel>=<destination>` pair passed to `--rtt-map`; the channel is given by its index or its
+/// name
+#[derive(Debug)]
+pub struct ChannelMapping {
+    pub channel: ChannelSelector,
+    pub destination: Destination,
+}
+
+impl FromStr for ChannelMapping {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (channel, destination) = match s.find('=') {
+            Some(pos) => (&s[..pos], &s[pos + 1..]),
+            None => bail!("expected `<channel>=<destination>`, found `{}`", s),
+        };
+
+        let channel = match channel.parse() {
+            Ok(number) => ChannelSelector::Number(number),
+            Err(_) => ChannelSelector::Name(channel.to_string()),
+        };
+        let destination = match destination {
+            "defmt" => Destination::Defmt,
+            "text" => Destination::Text,
+            "plot" => Destination::Plot,
+            _ if destination.starts_with("file:") => {
+                Destination::File(PathBuf::from(&destination["file:".len()..]))
+            }
+            _ => bail!(
+                "unknown destination `{}`; expected `defmt`, `text`, `plot` or `file:<path>`",
+                destination
+            ),
+        };
+
+        Ok(Self {
+            channel,
+            destination,
+        })
+    }
+}
+
+/// Logs the number, name and buffer size of every channel in the control block (with `-v`)
+pub fn log_channels(rtt: &mut Rtt) {
+    let up_channels = rtt.up_channels();
+    for number in 0..up_channels.len() {
+        if let Some(channel) = up_channels.get(number) {
+            log::debug!(
+                "RTT up channel {}: {} ({} bytes)",
+                number,
+                describe_name(channel.name()),
+                channel.buffer_size()
+            );
+        }
+    }
+    let down_channels = rtt.down_channels();
+    for number in 0..down_channels.len() {
+        if let Some(channel) = down_channels.get(number) {
+            log::debug!(
+                "RTT down channel {}: {} ({} bytes)",
+                number,
+                describe_name(channel.name()),
+                channel.buffer_size()
+            );
+        }
+    }
+}
+
+/// Address of the descriptor of up channel `number` in the control block at `control_block`
+fn descriptor(control_block: u32, number: usize) -> u32 {
+    control_block + O_UP_CHANNELS + number as u32 * CHANNEL_SIZE
+}
+
+fn describe_name(name: Option<&str>) -> String {
+    name.map_or("(unnamed)".to_string(), |name| format!("`{}`", name))
+}
+
+/// Whether the target drops data instead of waiting when the buffer of `up_channel` is full
+fn is_non_blocking(up_channel: &UpChannel) -> bool {
+    !matches!(up_channel.mode(), Ok(ChannelMode::BlockIfFull))
+}
+
+/// An up channel together with the state needed to forward its data
+pub struct Channel {
+    up_channel: UpChannel,
+    output: Output,
+    /// The target drops data instead of waiting when the buffer is full
+    non_blocking: bool,
+    /// Address of the channel's descriptor in the control block
+    descriptor: u32,
+    session: Arc<Mutex<Session>>,
+    /// Only tracked for non-blocking channels
+    last_read: Option<LastRead>,
+    /// Bytes per second the target writes, measured between reads that didn't find the buffer full
+    write_rate: Option<f64>,
+    stats: Stats,
+}
+
+struct Stats {
+    start: Instant,
+    bytes: u64,
+    /// Number of reads that found the buffer full
+    overruns: u32,
+    /// Estimate of the bytes the target dropped
+    lost: u64,
+}
+
+/// How full the buffer was right after a read
+#[derive(Clone, Copy)]
+struct LastRead {
+    at: Instant,
+    used: u32,
+}
+
+enum Output {
+    /// Holds the bytes of incomplete defmt frames
+    Defmt(Vec<u8>),
+    Text,
+    File(File),
+    /// Holds the bytes of incomplete lines
+    Plot(Vec<u8>),
+}
+
+impl Channel {
+    /// Takes the up channels the logs are read from
+    ///
+    /// Channel `log_channel` is decoded as defmt if it's named "defmt" (as `defmt-rtt` does) and
+    /// printed as text otherwise, unless `mappings` says differently.
+    pub fn take_all(
+        rtt: &mut Rtt,
+        sess: &Arc<Mutex<Session>>,
+        log_channel: &ChannelSelector,
+        mappings: &[ChannelMapping],
+    ) -> anyhow::Result<Vec<Self>> {
+        // NOTE names are resolved before taking any channel; taken channels can't be looked up
+        let log_channel = log_channel.resolve(rtt)?;
+        let mappings = mappings
+            .iter()
+            .map(|mapping| Ok((mapping.channel.resolve(rtt)?, &mapping.destination)))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        let mut channels = vec![];
+
+        if mappings.iter().all(|(number, _)| *number != log_channel) {
+            let up_channel = rtt
+                .up_channels()
+                .take(log_channel)
+                .ok_or_else(|| anyhow!("RTT up channel {} not found", log_channel))?;
+            let output = if up_channel.name() == Some("defmt") {
+                Output::Defmt(vec![])
+            } else {
+                Output::Text
+            };
+            channels.push(Self::new(up_channel, output, rtt.ptr(), sess.clone()));
+        }
+
+        for (number, destination) in mappings {
+            let up_channel = rtt
+                .up_channels()
+                .take(number)
+                .ok_or_else(|| anyhow!("RTT up channel {} not found", number))?;
+            let output = match destination {
+                Destination::Defmt => Output::Defmt(vec![]),
+                Destination::Text => Output::Text,
+                Destination::File(path) => Output::File(File::create(path)?),
+                Destination::Plot => Output::Plot(vec![]),
+            };
+            channels.push(Self::new(up_channel, output, rtt.ptr(), sess.clone()));
+        }
+
+        Ok(channels)
+    }
+
+    /// Takes the up channels again from the control block the program set up after resetting
+    /// itself; the outputs, e.g. the open `file:` destinations, and the statistics carry over
+    pub fn reattach_all(channels: &mut [Self], rtt: &mut Rtt) -> anyhow::Result<()> {
+        for channel in channels {
+            let number = channel.number();
+            channel.up_channel = rtt
+                .up_channels()
+                .take(number)
+                .ok_or_else(|| anyhow!("RTT up channel {} not found after the reset", number))?;
+            // NOTE the mode is the one of the new boot
+            channel.non_blocking = is_non_blocking(&channel.up_channel);
+            channel.descriptor = descriptor(rtt.ptr(), number);
+            channel.last_read = None;
+            // NOTE the incomplete frame or line of the previous boot is never going to be completed
+            match &mut channel.output {
+                Output::Defmt(bytes) | Output::Plot(bytes) => bytes.clear(),
+                Output::Text | Output::File(_) => {}
+            }
+        }
+        Ok(())
+    }
+
+    fn new(
+        up_channel: UpChannel,
+        output: Output,
+        control_block: u32,
+        session: Arc<Mutex<Session>>,
+    ) -> Self {
+        Self {
+            non_blocking: is_non_blocking(&up_channel),
+            descriptor: descriptor(control_block, up_channel.number()),
+            up_channel,
+            output,
+            session,
+            last_read: None,
+            write_rate: None,
+            stats: Stats {
+                start: Instant::now(),
+                bytes: 0,
+                overruns: 0,
+                lost: 0,
+            },
+        }
+    }
+
+    /// Size of the channel's buffer on the target; a read into a buffer this big drains it
+    pub fn buffer_size(&self) -> usize {
+        self.up_channel.buffer_size()
+    }
+
+    pub fn number(&self) -> usize {
+        self.up_channel.number()
+    }
+
+    pub fn uses_defmt(&self) -> bool {
+        matches!(self.output, Output::Defmt(_))
+    }
+
+    pub fn uses_plot(&self) -> bool {
+        matches!(self.output, Output::Plot(_))
+    }
+
+    pub fn read(&mut self, buf: &mut [u8]) -> Result<usize, probe_rs_rtt::Error> {
+        if !self.non_blocking {
+            let num_bytes_read = self.up_channel.read(buf)?;
+            self.stats.bytes += num_bytes_read as u64;
+            return Ok(num_bytes_read);
+        }
+
+        let used = self.used()?;
+        let num_bytes_read = self.up_channel.read(buf)?;
+        self.stats.bytes += num_bytes_read as u64;
+        let now = Instant::now();
+
+        // NOTE the ring buffer holds at most `buffer_size - 1` bytes
+        let capacity = self.buffer_size() as u32 - 1;
+        match (self.last_read, used >= capacity) {
+            (last_read, true) => {
+                // the target dropped what it wrote beyond the space the previous read left it;
+                // assume it kept writing at the rate it did before
+                let lost = last_read.zip(self.write_rate).map(|(last_read, rate)| {
+                    let secs = now.duration_since(last_read.at).as_secs_f64();
+                    (rate * secs - f64::from(capacity - last_read.used)).max(0.0) as u64
+                });
+                self.report_overrun(lost);
+            }
+            (Some(last_read), false) => {
+                let secs = now.duration_since(last_read.at).as_secs_f64();
+                if secs > 0.0 {
+                    let written = used.saturating_sub(last_read.used);
+                    self.write_rate = Some(f64::from(written) / secs);
+                }
+            }
+            (None, false) => {}
+        }
+
+        // NOTE the target may have written more in the meantime; what's left counts as read later
+        self.last_read = Some(LastRead {
+            at: now,
+            used: self.used()?,
+        });
+        Ok(num_bytes_read)
+    }
+
+    /// Number of bytes in the buffer on the target, from its write and read offsets
+    fn used(&self) -> Result<u32, probe_rs_rtt::Error> {
+        let mut offsets = [0; 2];
+        // NOTE probe-rs-rtt accesses the buffers through core 0 as well
+        self.session
+            .lock()
+            .unwrap()
+            .core(0)?
+            .read_32(self.descriptor + O_WRITE, &mut offsets)?;
+        let [write, read] = offsets;
+        let size = self.buffer_size() as u32;
+        if write >= size || read >= size {
+            return Err(probe_rs_rtt::Error::ControlBlockCorrupted(format!(
+                "offsets {} (write) and {} (read) are out of the {} bytes of up channel {}",
+                write,
+                read,
+                size,
+                self.number()
+            )));
+        }
+        Ok((write + size - read) % size)
+    }
+
+    fn report_overrun(&mut self, lost: Option<u64>) {
+        if self.stats.overruns == 0 {
+            let amount = lost.map_or("data".to_string(), |lost| format!("~{} bytes", lost));
+            log::warn!(
+                "RTT channel {} was full; the target dropped {} it wrote meanwhile, so the logs \
+                are incomplete",
+                self.number(),
+                amount
+            );
+        }
+        self.stats.overruns += 1;
+        self.stats.lost += lost.unwrap_or(0);
+    }
+
+    pub fn had_overruns(&self) -> bool {
+        self.stats.overruns != 0
+    }
+
+    /// Forwards `data` read from this channel to its destination
+    pub fn forward(&mut self, data: &[u8], printer: &Printer) -> anyhow::Result<()> {
+        match &mut self.output {
+            Output::Defmt(frames) => {
+                frames.extend_from_slice(data);
+                printer.defmt(frames)
+            }
+            Output::Text => printer.text(data),
+            Output::File(file) => Ok(file.write_all(data)?),
+            Output::Plot(lines) => {
+                lines.extend_from_slice(data);
+                printer.plot(lines)
+            }
+        }
+    }
+}
+
+/// Prints how much data each channel transferred and how often its buffer was full
+pub fn print_stats(channels: &[Channel]) {
+    println!(
+        "{}",
+        theme::current().secondary.paint("RTT channel statistics:")
+    );
+    for channel in channels {
+        let stats = &channel.stats;
+        let secs = stats.start.elapsed().as_secs_f64();
+        let mut line = format!(
+            "  channel {}: {} bytes, {:.0} bytes/s",
+            channel.number(),
+            stats.bytes,
+            stats.bytes as f64 / secs
+        );
+        if stats.overruns != 0 {
+            let lost = if stats.lost == 0 {
+                "data was lost".to_string()
+            } else {
+                format!("~{} bytes lost", stats.lost)
+            };
+            line.push_str(&format!("; buffer full {} times, {}", stats.overruns, lost));
+        }
+        println!("{}", line);
+    }
+}
+
+/// Prints the data received from the target
+pub struct Printer<'a> {
+    pub table: Option<&'a Table>,
+    pub locs: Option<&'a Locations>,
+    pub current_dir: &'a Path,
+    pub log_filter: Option<&'a LogFilter>,
+    pub patterns: Option<&'a Patterns>,
+    pub tests: Option<&'a Tests>,
+    pub log_stats: Option<&'a LogStats>,
+    pub plot: Option<&'a Plot>,
+    pub log_file: Option<&'a LogFile>,
+    /// Decodes the defmt frames instead of `table`
+    pub external_decoder: Option<&'a ExternalDecoder>,
+    /// Only write the output to `log_file`, not to stdout
+    pub quiet_stdout: bool,
+    /// Host timestamps for the defmt frames; `None` when replaying a recording
+    pub timestamps: Option<&'a Timestamps>,
+    /// Links the source locations of the frames printed to stdout
+    pub hyperlinks: Option<&'a Hyperlinks>,
+    pub hooks: &'a Hooks,
+}
+
+impl Printer<'_> {
+    /// Decodes and prints all the complete defmt frames in `frames`, removing them from it
+    pub fn defmt(&self, frames: &mut Vec<u8>) -> anyhow::Result<()> {
+        if let Some(external_decoder) = self.external_decoder {
+            external_decoder.write(frames)?;
+            frames.clear();
+            return Ok(());
+        }
+
+        let table = self
+            .table
+            .ok_or_else(|| anyhow!("the firmware binary contains no defmt data"))?;
+
+        loop {
+            match table.decode(frames) {
+                Ok((frame, consumed)) => {
+                    if let Some(plot) = self.plot {
+                        if plot.message(&frame.message())? {
+                            drain(frames, consumed);
+                            continue;
+                        }
+                    }
+
+                    // NOTE(`[]` indexing) all indices in `table` have already been
+                    // verified to exist in the `locs` map
+                    let loc = self.locs.map(|locs| &locs[&frame.index()]);
+
+                    let (mut file, mut line, mut mod_path) = (None, None, None);
+                    if let Some(loc) = loc {
+                        let relpath = if let Ok(relpath) = loc.file.strip_prefix(&self.current_dir)
+                        {
+                            relpath
+                        } else {
+                            // not relative; use full path
+                            &loc.file
+                        };
+                        file = Some(relpath.display().to_string());
+                        line = Some(loc.line as u32);
+                        mod_path = Some(loc.module.clone());
+                    }
+
+                    if let Some(log_stats) = self.log_stats {
+                        log_stats.observe(
+                            json::level(frame.level()),
+                            mod_path.as_deref(),
+                            file.as_deref().zip(line),
+                            consumed,
+                        );
+                    }
+
+                    if self.patterns.is_some() || self.tests.is_some() {
+                        let message = frame.message();
+                        if let Some(patterns) = self.patterns {
+                            patterns.check_line(&message);
+                        }
+                        if let Some(tests) = self.tests {
+                            tests.observe(frame.level(), &message);
+                        }
+                    }
+
+                    let enabled = self.log_filter.map_or(true, |filter| {
+                        filter.enabled(mod_path.as_deref(), frame.level())
+                    });
+                    if enabled {
+                        self.print_frame(&frame, file.as_deref(), line, mod_path.as_deref())?;
+                    }
+
+                    drain(frames, consumed);
+                }
+                Err(defmt_decoder::DecodeError::UnexpectedEof) => return Ok(()),
+                Err(defmt_decoder::DecodeError::Malformed) => {
+                    log::error!("failed to decode defmt data: {:x?}", frames);
+                    return Err(defmt_decoder::DecodeError::Malformed.into());
+                }
+            }
+        }
+    }
+
+    fn print_frame(
+        &self,
+        frame: &Frame,
+        file: Option<&str>,
+        line: Option<u32>,
+        mod_path: Option<&str>,
+    ) -> anyhow::Result<()> {
+        let timestamp = frame.timestamp();
+        let mode = self.timestamps.map_or(Mode::Target, Timestamps::mode);
+        let stamp = match self.timestamps {
+            Some(timestamps) if mode != Mode::Target => {
+                Some(timestamps.stamp(timestamp.as_deref()))
+            }
+            _ => None,
+        };
+
+        self.hooks.emit(&Record::Log {
+            level: json::level(frame.level()),
+            timestamp,
+            host_timestamp: stamp.as_ref().map(|stamp| stamp.host.clone()),
+            drift: stamp.as_ref().and_then(|stamp| stamp.drift),
+            module: mod_path,
+            file,
+            line,
+            message: frame.message(),
+        });
+        let prefix = stamp.and_then(|stamp| stamp.prefix(mode));
+        if let Some(log_file) = self.log_file {
+            log_file.frame(prefix.as_deref(), frame, file, line, mod_path)?;
+        }
+        if self.stdout_enabled() {
+            if let Some(prefix) = prefix {
+                print!("{} ", theme::current().secondary.paint(&prefix));
+            }
+            // NOTE only the file name is linked; the logger prints the line number after it
+            let linked_file = match (self.hyperlinks, file, line) {
+                (Some(hyperlinks), Some(file), Some(line)) => {
+                    Some(hyperlinks.link(file, Path::new(file), u64::from(line), self.current_dir))
+                }
+                _ => None,
+            };
+            frame.log(linked_file.as_deref().or(file), line, mod_path);
+        }
+        Ok(())
+    }
+
+    /// Records the samples in the complete lines of `lines`, removing them from it
+    pub fn plot(&self, lines: &mut Vec<u8>) -> anyhow::Result<()> {
+        let plot = self.plot.ok_or_else(|| {
+            anyhow!("an RTT channel is mapped to `plot` but `--plot-out` is not set")
+        })?;
+        plot.lines(lines)
+    }
+
+    pub fn text(&self, data: &[u8]) -> anyhow::Result<()> {
+        if let Some(patterns) = self.patterns {
+            patterns.check_text(data);
+        }
+
+        self.hooks.emit(&Record::Output {
+            data: &String::from_utf8_lossy(data),
+        });
+        self.write_stdout(data)
+    }
+
+    /// Prints the data written to ITM stimulus `port`
+    pub fn itm(&self, port: u8, data: &[u8]) -> anyhow::Result<()> {
+        if let Some(patterns) = self.patterns {
+            patterns.check_text(data);
+        }
+
+        self.hooks.emit(&Record::Itm {
+            port,
+            data: &String::from_utf8_lossy(data),
+        });
+        self.write_stdout(data)
+    }
+
+    /// Prints the new value of a variable watched with `--watch-var`
+    pub fn variable(&self, name: &str, value: &str) {
+        self.hooks.emit(&Record::Variable { name, value });
+        if self.stdout_enabled() {
+            println!(
+                "{} {} = {}",
+                theme::current().secondary.paint("(watch)"),
+                name,
+                value
+            );
+        }
+    }
+
+    /// Marks the point in the output where the program reset itself
+    pub fn reset(&self, count: u32) -> anyhow::Result<()> {
+        self.hooks.emit(&Record::Reset { count });
+        self.write_stdout(format!("=== target reset (#{}) ===\n", count).as_bytes())
+    }
+
+    fn write_stdout(&self, data: &[u8]) -> anyhow::Result<()> {
+        if let Some(log_file) = self.log_file {
+            log_file.text(data)?;
+        }
+        if self.stdout_enabled() {
+            let stdout = io::stdout();
+            let mut stdout = stdout.lock();
+            stdout.write_all(data)?;
+            stdout.flush()?;
+        }
+
+        Ok(())
+    }
+
+    fn stdout_enabled(&self) -> bool {
+        !self.hooks.json && !self.quiet_stdout
+    }
+}
+
+/// Removes the first `consumed` bytes of `frames`
+fn drain(frames: &mut Vec<u8>, consumed: usize) {
+    let num_frames = frames.len();
+    frames.rotate_left(consumed);
+    frames.truncate(num_frames - consumed);
+}
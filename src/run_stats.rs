@@ -0,0 +1,91 @@
+//! `--stats`: how long each phase of the run took, to track the latency of the development loop
+//!
+//! The phases are attaching to the probe, flashing, the time from the reset until the program's
+//! first log data arrived, and streaming the logs until the run ended.
+
+use std::time::{Duration, Instant};
+
+use crate::{hooks::Hooks, json::Record, theme};
+
+pub struct RunStats {
+    start: Instant,
+    attach: Option<Duration>,
+    /// Unset if flashing was skipped (`--no-flash`, `--attach`)
+    flash: Option<Duration>,
+    reset: Option<Instant>,
+    first_log: Option<Instant>,
+}
+
+impl RunStats {
+    /// Starts measuring the run
+    pub fn start() -> Self {
+        Self {
+            start: Instant::now(),
+            attach: None,
+            flash: None,
+            reset: None,
+            first_log: None,
+        }
+    }
+
+    pub fn attached(&mut self, duration: Duration) {
+        self.attach = Some(duration);
+    }
+
+    pub fn flashed(&mut self, duration: Duration) {
+        self.flash = Some(duration);
+    }
+
+    /// The device is reset (or, with `--attach`, halted) to start the program
+    pub fn reset(&mut self) {
+        self.reset = Some(Instant::now());
+    }
+
+    /// Log data arrived; only the first call counts
+    pub fn data_received(&mut self) {
+        if self.first_log.is_none() {
+            self.first_log = Some(Instant::now());
+        }
+    }
+
+    /// Prints the summary, or emits it as a JSON record, at the end of the run
+    pub fn report(&self, defmt_frames: u64, hooks: &Hooks) {
+        let end = Instant::now();
+        let total = end - self.start;
+        let first_log = match (self.reset, self.first_log) {
+            (Some(reset), Some(first_log)) => Some(first_log - reset),
+            _ => None,
+        };
+        let streaming = self.first_log.map(|first_log| end - first_log);
+
+        hooks.emit(&Record::RunStats {
+            total_secs: total.as_secs_f64(),
+            attach_secs: self.attach.map(|duration| duration.as_secs_f64()),
+            flash_secs: self.flash.map(|duration| duration.as_secs_f64()),
+            first_log_secs: first_log.map(|duration| duration.as_secs_f64()),
+            streaming_secs: streaming.map(|duration| duration.as_secs_f64()),
+            defmt_frames,
+        });
+        if hooks.json {
+            return;
+        }
+
+        let mut phases = vec![];
+        let mut phase = |name: &str, duration: Option<Duration>| {
+            if let Some(duration) = duration {
+                phases.push(format!("{} {:.2}s", name, duration.as_secs_f64()));
+            }
+        };
+        phase("attach", self.attach);
+        phase("flash", self.flash);
+        phase("reset to first log", first_log);
+        phase("streaming", streaming);
+        println!(
+            "{} {:.2}s ({}), {} defmt frames",
+            theme::current().secondary.paint("run time:"),
+            total.as_secs_f64(),
+            phases.join(", "),
+            defmt_frames
+        );
+    }
+}
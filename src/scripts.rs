@@ -0,0 +1,103 @@
+//! `--pre-run` and `--post-run`: shell commands that prepare and clean up the test fixture
+//!
+//! The pre-run command runs right before `probe-run` connects to the probe (again on every retry
+//! and `--watch` restart), e.g. to power-cycle the board; if it fails, so does `probe-run`. The
+//! post-run command runs once `probe-run` is done, whether the program succeeded or not; its
+//! failure is only reported, so `probe-run` still exits with the program's exit code.
+
+use std::{
+    cell::RefCell,
+    path::Path,
+    process::{Command, ExitStatus},
+};
+
+use anyhow::{bail, Context as _};
+use probe_rs::DebugProbeInfo;
+
+use crate::cli::Opts;
+
+pub struct Scripts<'a> {
+    pre_run: Option<&'a str>,
+    post_run: Option<&'a str>,
+    /// `PROBE_RUN_*` variables passed to the commands
+    env: RefCell<Vec<(&'static str, String)>>,
+}
+
+impl<'a> Scripts<'a> {
+    pub fn new(opts: &'a Opts, elf_path: &Path) -> Self {
+        let env = vec![
+            ("PROBE_RUN_ELF", elf_path.display().to_string()),
+            ("PROBE_RUN_CHIP", opts.chip.clone().unwrap_or_default()),
+            ("PROBE_RUN_PROBE_SERIAL", String::new()),
+        ];
+        Self {
+            pre_run: opts.pre_run.as_deref(),
+            post_run: opts.post_run.as_deref(),
+            env: RefCell::new(env),
+        }
+    }
+
+    /// Runs the pre-run command, if any, for the selected probe and chip
+    pub fn pre_run(&self, probe_info: &DebugProbeInfo, chip: &str) -> anyhow::Result<()> {
+        self.set("PROBE_RUN_CHIP", chip.to_string());
+        self.set(
+            "PROBE_RUN_PROBE_SERIAL",
+            probe_info.serial_number.clone().unwrap_or_default(),
+        );
+
+        if let Some(command) = self.pre_run {
+            log::info!("running pre-run command `{}`", command);
+            let status = self.execute(command)?;
+            if !status.success() {
+                bail!("pre-run command `{}` failed ({})", command, status);
+            }
+        }
+        Ok(())
+    }
+
+    /// Runs the post-run command, if any, with the exit code `probe-run` is about to exit with
+    pub fn post_run(&self, exit_code: i32) {
+        let command = match self.post_run {
+            Some(command) => command,
+            None => return,
+        };
+
+        self.set("PROBE_RUN_EXIT_CODE", exit_code.to_string());
+        log::info!("running post-run command `{}`", command);
+        match self.execute(command) {
+            Ok(status) if status.success() => {}
+            Ok(status) => log::warn!("post-run command `{}` failed ({})", command, status),
+            Err(e) => log::warn!("{:?}", e),
+        }
+    }
+
+    fn set(&self, name: &'static str, value: String) {
+        let mut env = self.env.borrow_mut();
+        match env.iter_mut().find(|(var, _)| *var == name) {
+            Some((_, old)) => *old = value,
+            None => env.push((name, value)),
+        }
+    }
+
+    fn execute(&self, command: &str) -> anyhow::Result<ExitStatus> {
+        shell(command)
+            .envs(self.env.borrow().iter().cloned())
+            .status()
+            .with_context(|| format!("failed to run `{}`", command))
+    }
+}
+
+/// Runs `command` through the platform's shell
+pub fn shell(command: &str) -> Command {
+    let mut shell = if cfg!(windows) {
+        let mut shell = Command::new("cmd");
+        shell.arg("/C");
+        shell
+    } else {
+        let mut shell = Command::new("sh");
+        shell.arg("-c");
+        shell
+    };
+    shell.arg(command);
+    shell
+}
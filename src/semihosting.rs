@@ -0,0 +1,274 @@
+//! ARM semihosting: the program requests host services by executing `BKPT 0xAB`
+//!
+//! The operation number is passed in `r0` and a pointer to its parameter block in `r1`; the result
+//! is returned in `r0`.
+
+use std::{
+    collections::BTreeMap,
+    convert::TryInto,
+    fs::{File, OpenOptions},
+    io::{self, Read as _, Write as _},
+    path::{Component, Path, PathBuf},
+};
+
+use probe_rs::{Core, CoreRegisterAddress, MemoryInterface};
+
+use crate::{cortexm, rtt::Printer};
+
+/// Thumb encoding of `BKPT 0xAB`
+const BKPT_SEMIHOSTING: u16 = 0xBEAB;
+
+const R0: CoreRegisterAddress = CoreRegisterAddress(0);
+const R1: CoreRegisterAddress = CoreRegisterAddress(1);
+
+const SYS_OPEN: u32 = 0x01;
+const SYS_CLOSE: u32 = 0x02;
+const SYS_WRITEC: u32 = 0x03;
+const SYS_WRITE0: u32 = 0x04;
+const SYS_WRITE: u32 = 0x05;
+const SYS_READ: u32 = 0x06;
+const SYS_EXIT: u32 = 0x18;
+const SYS_EXIT_EXTENDED: u32 = 0x20;
+
+/// `SYS_EXIT` reason reported by a program that ran to completion
+const ADP_STOPPED_APPLICATION_EXIT: u32 = 0x20026;
+
+/// Value of `r0` that signals an error
+const FAILURE: u32 = u32::MAX;
+
+/// Most bytes moved between the target and the host at once; the lengths come from the program,
+/// which may pass garbage, so they must not size the host's buffers
+const CHUNK_SIZE: u32 = 4 * 1024;
+
+/// What happened to the program after a semihosting request
+pub enum Outcome {
+    /// The request was serviced and the program resumed
+    Resumed,
+    /// The program exited with this exit code
+    Exited(i32),
+}
+
+enum HostFile {
+    Stdin,
+    Stdout,
+    Stderr,
+    File(File),
+}
+
+pub struct Semihosting {
+    /// Directory the program's files are opened in; file I/O is disabled if `None`
+    fs_root: Option<PathBuf>,
+    files: BTreeMap<u32, HostFile>,
+    next_handle: u32,
+}
+
+impl Semihosting {
+    pub fn new(fs_root: Option<PathBuf>) -> Self {
+        Self {
+            fs_root,
+            files: BTreeMap::new(),
+            next_handle: 1,
+        }
+    }
+
+    /// Services the semihosting request the halted core is stopped at
+    ///
+    /// Returns `None` if the core was halted for another reason.
+    pub fn handle(
+        &mut self,
+        core: &mut Core<'_>,
+        printer: &Printer,
+    ) -> anyhow::Result<Option<Outcome>> {
+        let pc = core.read_core_reg(cortexm::PC)?;
+        let mut instruction = [0; 2];
+        core.read_8(pc, &mut instruction)?;
+        if u16::from_le_bytes(instruction) != BKPT_SEMIHOSTING {
+            return Ok(None);
+        }
+
+        let operation = core.read_core_reg(R0)?;
+        let parameter = core.read_core_reg(R1)?;
+        log::trace!(
+            "semihosting operation 0x{:02X} with parameter 0x{:08X}",
+            operation,
+            parameter
+        );
+
+        let result = match operation {
+            SYS_OPEN => {
+                let args = read_args(core, parameter, 3)?;
+                let name = read_string(core, args[0], args[2])?;
+                self.open(&name, args[1])
+            }
+            SYS_CLOSE => {
+                let args = read_args(core, parameter, 1)?;
+                match self.files.remove(&args[0]) {
+                    Some(_) => 0,
+                    None => FAILURE,
+                }
+            }
+            SYS_WRITEC => {
+                let mut c = [0];
+                core.read_8(parameter, &mut c)?;
+                printer.text(&c)?;
+                0
+            }
+            SYS_WRITE0 => {
+                let mut string = vec![];
+                let mut address = parameter;
+                loop {
+                    let mut c = [0];
+                    core.read_8(address, &mut c)?;
+                    if c[0] == 0 {
+                        break;
+                    }
+                    string.push(c[0]);
+                    address += 1;
+                }
+                printer.text(&string)?;
+                0
+            }
+            SYS_WRITE => {
+                let args = read_args(core, parameter, 3)?;
+                let (address, len) = (args[1], args[2]);
+                let mut buf = vec![0; len.min(CHUNK_SIZE) as usize];
+                let mut written = 0;
+                while written < len {
+                    let chunk = &mut buf[..(len - written).min(CHUNK_SIZE) as usize];
+                    core.read_8(address.wrapping_add(written), chunk)?;
+                    let ok = match self.files.get_mut(&args[0]) {
+                        Some(HostFile::Stdout) => printer.text(chunk).map(|_| true)?,
+                        Some(HostFile::Stderr) => io::stderr().write_all(chunk).is_ok(),
+                        Some(HostFile::File(file)) => file.write_all(chunk).is_ok(),
+                        Some(HostFile::Stdin) | None => false,
+                    };
+                    if !ok {
+                        break;
+                    }
+                    written += chunk.len() as u32;
+                }
+                // returns the number of bytes that were *not* written
+                len - written
+            }
+            SYS_READ => {
+                let args = read_args(core, parameter, 3)?;
+                // NOTE reads may be short; the program asks again for the rest
+                let mut data = vec![0; args[2].min(CHUNK_SIZE) as usize];
+                let num_read = match self.files.get_mut(&args[0]) {
+                    Some(HostFile::Stdin) => io::stdin().read(&mut data).ok(),
+                    Some(HostFile::File(file)) => file.read(&mut data).ok(),
+                    Some(HostFile::Stdout) | Some(HostFile::Stderr) | None => None,
+                };
+                // returns the number of bytes that were *not* read
+                match num_read {
+                    Some(n) => {
+                        core.write_8(args[1], &data[..n])?;
+                        args[2] - n as u32
+                    }
+                    None => FAILURE,
+                }
+            }
+            SYS_EXIT => return Ok(Some(Outcome::Exited(exit_code(parameter, 0)))),
+            SYS_EXIT_EXTENDED => {
+                let args = read_args(core, parameter, 2)?;
+                return Ok(Some(Outcome::Exited(exit_code(args[0], args[1]))));
+            }
+            _ => {
+                log::warn!("unsupported semihosting operation 0x{:02X}", operation);
+                FAILURE
+            }
+        };
+
+        core.write_core_reg(R0, result)?;
+        // skip the `BKPT` instruction
+        core.write_core_reg(cortexm::PC, pc + 2)?;
+        core.run()?;
+        Ok(Some(Outcome::Resumed))
+    }
+
+    fn open(&mut self, name: &str, mode: u32) -> u32 {
+        // `mode` is the index of an `fopen` mode in "r", "rb", "r+", "r+b", "w", "wb", "w+", "w+b",
+        // "a", "ab", "a+", "a+b"
+        let (write, append, update) = (mode / 4 == 1, mode / 4 == 2, mode % 4 >= 2);
+
+        let file = if name == ":tt" {
+            match mode / 4 {
+                0 => HostFile::Stdin,
+                1 => HostFile::Stdout,
+                _ => HostFile::Stderr,
+            }
+        } else {
+            let path = match self
+                .fs_root
+                .as_deref()
+                .and_then(|root| sandboxed_path(root, name))
+            {
+                Some(path) => path,
+                None => {
+                    log::warn!(
+                        "program tried to open `{}`; pass `--semihosting-fs` to allow access to \
+                        the files of a directory",
+                        name
+                    );
+                    return FAILURE;
+                }
+            };
+
+            let file = OpenOptions::new()
+                .read(!(write || append) || update)
+                .write(write || update)
+                .append(append)
+                .create(write || append)
+                .truncate(write)
+                .open(&path);
+            match file {
+                Ok(file) => HostFile::File(file),
+                Err(e) => {
+                    log::debug!("failed to open {}: {}", path.display(), e);
+                    return FAILURE;
+                }
+            }
+        };
+
+        let handle = self.next_handle;
+        self.next_handle += 1;
+        self.files.insert(handle, file);
+        handle
+    }
+}
+
+/// Maps the reason and subcode of `SYS_EXIT` to a process exit code
+fn exit_code(reason: u32, subcode: u32) -> i32 {
+    if reason == ADP_STOPPED_APPLICATION_EXIT {
+        subcode as i32
+    } else {
+        log::warn!("program exited with reason 0x{:X}", reason);
+        1
+    }
+}
+
+/// Joins `name` to `root` unless it would escape it
+fn sandboxed_path(root: &Path, name: &str) -> Option<PathBuf> {
+    let path = Path::new(name);
+    let is_contained = path
+        .components()
+        .all(|component| matches!(component, Component::Normal(_) | Component::CurDir));
+
+    if is_contained {
+        Some(root.join(path))
+    } else {
+        None
+    }
+}
+
+fn read_args(core: &mut Core<'_>, address: u32, count: usize) -> anyhow::Result<Vec<u32>> {
+    let mut args = vec![0; count];
+    core.read_32(address, &mut args)?;
+    Ok(args)
+}
+
+fn read_string(core: &mut Core<'_>, address: u32, len: u32) -> anyhow::Result<String> {
+    let mut bytes = vec![0; len.try_into()?];
+    core.read_8(address, &mut bytes)?;
+    Ok(String::from_utf8_lossy(&bytes).into_owned())
+}
@@ -0,0 +1,157 @@
+//! `--serial`: reads the program's logs from a serial port instead of, or in addition to, RTT
+//!
+//! The port is opened lazily: a USB CDC port only shows up once the program has enumerated it.
+
+use std::{
+    io::Read as _,
+    str::FromStr,
+    time::{Duration, Instant},
+};
+
+use anyhow::anyhow;
+use serialport::SerialPort;
+
+use crate::rtt::Printer;
+
+const DEFAULT_BAUD: u32 = 115_200;
+/// How often to try opening a port that doesn't exist (yet)
+const RETRY_INTERVAL: Duration = Duration::from_millis(250);
+
+/// The serial port to read: `PORT[:BAUD]`, e.g. `/dev/ttyACM0:115200` or `COM3`
+#[derive(Clone, Debug)]
+pub struct SerialSpec {
+    pub path: String,
+    pub baud: u32,
+}
+
+impl FromStr for SerialSpec {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (path, baud) = match s.rfind(':') {
+            // NOTE the last `:` separates the baud rate only if a number follows it
+            Some(pos) if s[pos + 1..].chars().all(|c| c.is_ascii_digit()) => {
+                let baud = s[pos + 1..]
+                    .parse()
+                    .map_err(|_| anyhow!("invalid baud rate in `{}`", s))?;
+                (&s[..pos], baud)
+            }
+            _ => (s, DEFAULT_BAUD),
+        };
+        if path.is_empty() {
+            return Err(anyhow!("no serial port in `{}`", s));
+        }
+
+        Ok(Self {
+            path: path.to_string(),
+            baud,
+        })
+    }
+}
+
+/// How the data received over the serial port is decoded
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SerialFormat {
+    Defmt,
+    Text,
+}
+
+impl FromStr for SerialFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "defmt" => Ok(Self::Defmt),
+            "text" => Ok(Self::Text),
+            _ => Err(anyhow!("unknown serial format `{}`", s)),
+        }
+    }
+}
+
+pub struct Serial {
+    spec: SerialSpec,
+    format: SerialFormat,
+    port: Option<Box<dyn SerialPort>>,
+    last_attempt: Option<Instant>,
+    /// Incomplete defmt frames
+    frames: Vec<u8>,
+}
+
+impl Serial {
+    pub fn new(spec: SerialSpec, format: SerialFormat) -> Self {
+        Self {
+            spec,
+            format,
+            port: None,
+            last_attempt: None,
+            frames: vec![],
+        }
+    }
+
+    pub fn uses_defmt(&self) -> bool {
+        self.format == SerialFormat::Defmt
+    }
+
+    /// Tries to open the port, unless the last attempt was only a moment ago
+    fn try_open(&mut self) -> bool {
+        if self.last_attempt.map_or(false, |last_attempt| {
+            last_attempt.elapsed() < RETRY_INTERVAL
+        }) {
+            return false;
+        }
+        self.last_attempt = Some(Instant::now());
+
+        match serialport::new(&self.spec.path, self.spec.baud).open() {
+            Ok(port) => {
+                log::info!("reading logs from {}", self.spec.path);
+                self.port = Some(port);
+                true
+            }
+            Err(e) => {
+                log::debug!("could not open {} (yet): {}", self.spec.path, e);
+                false
+            }
+        }
+    }
+
+    /// Reads the data received since the last call, if any, and prints it
+    pub fn poll(&mut self, buf: &mut [u8], printer: &Printer) -> anyhow::Result<()> {
+        if self.port.is_none() && !self.try_open() {
+            return Ok(());
+        }
+        // NOTE(unwrap) opened above
+        let port = self.port.as_mut().unwrap();
+
+        // NOTE only read what's there so that the run loop doesn't block
+        let result = port
+            .bytes_to_read()
+            .map_err(anyhow::Error::from)
+            .and_then(|available| {
+                let available = (available as usize).min(buf.len());
+                Ok(port.read(&mut buf[..available])?)
+            });
+        let data = match result {
+            Ok(0) => return Ok(()),
+            Ok(num_bytes_read) => &buf[..num_bytes_read],
+            Err(e) => {
+                // e.g. the program reset the USB peripheral; the port comes back later
+                log::warn!("lost {}: {}", self.spec.path, e);
+                self.port = None;
+                return Ok(());
+            }
+        };
+
+        if let Some(server) = &printer.hooks.server {
+            if self.uses_defmt() {
+                server.raw(data);
+            }
+        }
+        match self.format {
+            SerialFormat::Defmt => {
+                self.frames.extend_from_slice(data);
+                printer.defmt(&mut self.frames)
+            }
+            SerialFormat::Text => printer.text(data),
+        }
+    }
+}
@@ -0,0 +1,170 @@
+//! `--serve`: broadcasts the defmt stream to the clients of TCP or Unix sockets
+//!
+//! Each client receives the decoded frames as newline-delimited JSON, in the format of
+//! `--message-format=json`, or the raw defmt stream with `--serve-raw`. Clients that connect late
+//! miss the earlier frames; clients that can't keep up are disconnected rather than slowing down
+//! the run. `probe-run tail` is such a client.
+
+use std::{
+    fmt,
+    io::{self, Write},
+    mem,
+    net::TcpListener,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::{Arc, Mutex},
+    thread,
+};
+
+use anyhow::{bail, Context as _};
+
+use crate::json::{self, Record};
+
+/// A socket to serve the defmt stream on
+#[derive(Clone, Debug)]
+pub enum Endpoint {
+    /// `tcp://<address>:<port>`, or just `<address>:<port>`
+    Tcp(String),
+    /// `unix:<path>`
+    Unix(PathBuf),
+}
+
+impl FromStr for Endpoint {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(address) = s.strip_prefix("tcp://") {
+            Ok(Self::Tcp(address.to_string()))
+        } else if let Some(path) = s.strip_prefix("unix:") {
+            Ok(Self::Unix(PathBuf::from(path)))
+        } else if !s.contains("://") && s.contains(':') {
+            Ok(Self::Tcp(s.to_string()))
+        } else {
+            bail!(
+                "invalid endpoint `{}`; expected `tcp://<address>:<port>` or `unix:<path>`",
+                s
+            )
+        }
+    }
+}
+
+impl fmt::Display for Endpoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Tcp(address) => write!(f, "tcp://{}", address),
+            Self::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+type Clients = Arc<Mutex<Vec<Box<dyn Write + Send>>>>;
+
+pub struct Server {
+    raw: bool,
+    clients: Clients,
+}
+
+impl Server {
+    /// Starts listening on `endpoints`; `None` if there are none
+    pub fn bind(endpoints: &[Endpoint], raw: bool) -> anyhow::Result<Option<Self>> {
+        if endpoints.is_empty() {
+            return Ok(None);
+        }
+
+        let clients = Clients::default();
+        for endpoint in endpoints {
+            match endpoint {
+                Endpoint::Tcp(address) => {
+                    let listener = TcpListener::bind(address)
+                        .with_context(|| format!("failed to listen on tcp://{}", address))?;
+                    log::info!(
+                        "serving the defmt stream on tcp://{}",
+                        listener.local_addr()?
+                    );
+                    accept_clients(clients.clone(), move || {
+                        let (stream, _) = listener.accept()?;
+                        stream.set_nonblocking(true)?;
+                        Ok(stream)
+                    });
+                }
+                Endpoint::Unix(path) => bind_unix(path, clients.clone())?,
+            }
+        }
+
+        Ok(Some(Self { raw, clients }))
+    }
+
+    /// Sends a decoded frame to the clients, unless they receive the raw stream
+    pub fn frame(&self, record: &Record) {
+        if !self.raw {
+            let mut line = json::to_string(record);
+            line.push('\n');
+            self.broadcast(line.as_bytes());
+        }
+    }
+
+    /// Sends data received on a defmt channel to the clients, if they receive the raw stream
+    pub fn raw(&self, data: &[u8]) {
+        if self.raw {
+            self.broadcast(data);
+        }
+    }
+
+    fn broadcast(&self, data: &[u8]) {
+        let mut clients = self.clients.lock().unwrap();
+        *clients = mem::take(&mut *clients)
+            .into_iter()
+            .filter_map(|mut client| match client.write_all(data) {
+                Ok(()) => Some(client),
+                Err(e) => {
+                    log::debug!("disconnected a client: {}", e);
+                    None
+                }
+            })
+            .collect();
+    }
+}
+
+/// Adds the clients `accept` returns to `clients`, in a background thread
+fn accept_clients<S>(clients: Clients, mut accept: impl FnMut() -> io::Result<S> + Send + 'static)
+where
+    S: Write + Send + 'static,
+{
+    thread::spawn(move || loop {
+        match accept() {
+            Ok(stream) => clients.lock().unwrap().push(Box::new(stream)),
+            Err(e) => log::warn!("failed to accept a client: {}", e),
+        }
+    });
+}
+
+#[cfg(unix)]
+fn bind_unix(path: &Path, clients: Clients) -> anyhow::Result<()> {
+    use std::{
+        fs,
+        os::unix::{fs::FileTypeExt as _, net::UnixListener},
+    };
+
+    // NOTE a socket file left behind by an earlier run would make binding fail; anything else at
+    // `path` is not ours to delete
+    match fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.file_type().is_socket() => fs::remove_file(path)?,
+        Ok(_) => bail!("{} exists and is not a socket", path.display()),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e).with_context(|| format!("failed to inspect {}", path.display())),
+    }
+    let listener = UnixListener::bind(path)
+        .with_context(|| format!("failed to listen on unix:{}", path.display()))?;
+    log::info!("serving the defmt stream on unix:{}", path.display());
+    accept_clients(clients, move || {
+        let (stream, _) = listener.accept()?;
+        stream.set_nonblocking(true)?;
+        Ok(stream)
+    });
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn bind_unix(_: &Path, _: Clients) -> anyhow::Result<()> {
+    bail!("Unix sockets are not supported on this platform")
+}
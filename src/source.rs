@@ -0,0 +1,64 @@
+//! Source code shown next to the backtrace frames: `--backtrace-context` and `--source-map`
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use anyhow::bail;
+
+/// A `--source-map` rule: files under `from` (e.g. a path inside a container) are looked up
+/// under `to`
+#[derive(Clone, Debug)]
+pub struct SourceMap {
+    from: PathBuf,
+    to: PathBuf,
+}
+
+impl FromStr for SourceMap {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.find('=') {
+            Some(pos) if pos != 0 && pos + 1 != s.len() => Ok(Self {
+                from: PathBuf::from(&s[..pos]),
+                to: PathBuf::from(&s[pos + 1..]),
+            }),
+            _ => bail!("invalid source map `{}`; use `OLD=NEW`", s),
+        }
+    }
+}
+
+/// Applies the first rule of `maps` that matches `path`
+pub fn remap(path: &Path, maps: &[SourceMap]) -> PathBuf {
+    maps.iter()
+        .find_map(|map| {
+            path.strip_prefix(&map.from)
+                .ok()
+                .map(|rest| map.to.join(rest))
+        })
+        .unwrap_or_else(|| path.to_owned())
+}
+
+/// Reads line `line` of `path` and up to `context` lines before and after it; returns `None` if
+/// the file can't be read or is shorter than expected
+pub fn lines(path: &Path, line: u64, context: u32) -> Option<Vec<(u64, String)>> {
+    let contents = fs::read_to_string(path).ok()?;
+    let first = line.saturating_sub(context.into()).max(1);
+    let last = line + u64::from(context);
+
+    let lines = contents
+        .lines()
+        .zip(1..)
+        .skip_while(|(_, number)| *number < first)
+        .take_while(|(_, number)| *number <= last)
+        .map(|(text, number)| (number, text.to_string()))
+        .collect::<Vec<_>>();
+    if lines.iter().any(|(number, _)| *number == line) {
+        Some(lines)
+    } else {
+        log::debug!("{} has no line {}", path.display(), line);
+        None
+    }
+}
@@ -0,0 +1,87 @@
+//! `--speed`: the probe's clock frequency, or `auto` to find the fastest one that works
+//!
+//! The speed `auto` settles on is cached per probe and chip, so later runs start from it instead
+//! of negotiating again.
+
+use std::str::FromStr;
+
+use anyhow::{anyhow, Context as _};
+use probe_rs::{DebugProbeInfo, Session, Target};
+
+use crate::cache::Cache;
+
+/// Speeds `auto` tries, fastest first, in kHz
+const CANDIDATES: &[u32] = &[24_000, 12_000, 8_000, 4_000, 2_000, 1_000, 500, 100];
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Speed {
+    Khz(u32),
+    Auto,
+}
+
+impl FromStr for Speed {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "auto" => Ok(Self::Auto),
+            _ => s
+                .parse()
+                .map(Self::Khz)
+                .map_err(|_| anyhow!("invalid speed `{}`; expected kHz or `auto`", s)),
+        }
+    }
+}
+
+/// Attaches to the target at the fastest speed that gets through a read of the core's status
+///
+/// Starts at the cached speed of this probe and chip, if any, and steps down on errors.
+pub fn negotiate(
+    probe_info: &DebugProbeInfo,
+    target: &Target,
+    under_reset: bool,
+    cache: &Cache,
+) -> anyhow::Result<Session> {
+    let cached = cache.state().speed;
+    let start = cached
+        .and_then(|cached| CANDIDATES.iter().position(|speed| *speed <= cached))
+        .unwrap_or(0);
+
+    let mut last_error = None;
+    for &speed in &CANDIDATES[start..] {
+        match attach(probe_info, target.clone(), under_reset, speed) {
+            Ok((sess, actual_speed)) => {
+                log::debug!("probe speed: {} kHz", actual_speed);
+                if cached != Some(actual_speed) {
+                    cache.update(|state| state.speed = Some(actual_speed));
+                }
+                return Ok(sess);
+            }
+            Err(e) => {
+                log::debug!("failed to communicate at {} kHz: {}", speed, e);
+                last_error = Some(e);
+            }
+        }
+    }
+
+    // NOTE(unwrap) `CANDIDATES` is not empty
+    Err(last_error.unwrap()).context("failed to communicate with the target at any probe speed")
+}
+
+/// Returns the session and the speed the probe actually runs at
+fn attach(
+    probe_info: &DebugProbeInfo,
+    target: Target,
+    under_reset: bool,
+    speed: u32,
+) -> anyhow::Result<(Session, u32)> {
+    let mut probe = probe_info.open()?;
+    let actual_speed = probe.set_speed(speed)?;
+    let mut sess = if under_reset {
+        probe.attach_under_reset(target)?
+    } else {
+        probe.attach(target)?
+    };
+    sess.core(0)?.status()?;
+    Ok((sess, actual_speed))
+}
@@ -0,0 +1,91 @@
+//! `--defer-decode`: defmt data spilled to a file while the program runs, decoded afterwards
+//!
+//! At very high log rates, decoding and printing the frames in the polling loop can fall behind
+//! the target, which then blocks on its full RTT buffer. Spilling the raw bytes takes a fraction of
+//! the time, so the loop drains the channels as fast as the probe allows.
+
+use std::{
+    collections::BTreeMap,
+    env,
+    fs::{self, File},
+    io::{BufWriter, Write as _},
+    path::PathBuf,
+    process,
+};
+
+use anyhow::{bail, Context as _};
+use arrayref::array_ref;
+
+use crate::rtt::Printer;
+
+/// Large enough that most chunks don't cause a write to the file
+const BUFFER_SIZE: usize = 1024 * 1024;
+/// Channel number and data length, each a little endian `u32`
+const CHUNK_HEADER_SIZE: usize = 8;
+
+pub struct Spill {
+    path: PathBuf,
+    file: BufWriter<File>,
+    /// Bytes of defmt data spilled so far
+    len: u64,
+}
+
+impl Spill {
+    pub fn create() -> anyhow::Result<Self> {
+        let path = env::temp_dir().join(format!("probe-run-spill-{}.bin", process::id()));
+        let file =
+            File::create(&path).with_context(|| format!("failed to create {}", path.display()))?;
+        Ok(Self {
+            path,
+            file: BufWriter::with_capacity(BUFFER_SIZE, file),
+            len: 0,
+        })
+    }
+
+    /// Appends `data`, read from up channel `channel`
+    pub fn write(&mut self, channel: usize, data: &[u8]) -> anyhow::Result<()> {
+        self.file.write_all(&(channel as u32).to_le_bytes())?;
+        self.file.write_all(&(data.len() as u32).to_le_bytes())?;
+        self.file.write_all(data)?;
+        self.len += data.len() as u64;
+        Ok(())
+    }
+
+    /// Decodes and prints the spilled frames, in the order they were read
+    pub fn decode(mut self, printer: &Printer) -> anyhow::Result<()> {
+        self.file.flush()?;
+        if self.len == 0 {
+            return Ok(());
+        }
+        log::info!("decoding {} bytes of deferred defmt data", self.len);
+
+        let spilled = fs::read(&self.path)?;
+        let mut reader = &spilled[..];
+        // holds the incomplete defmt frames of each channel
+        let mut frames = BTreeMap::<u32, Vec<u8>>::new();
+        while !reader.is_empty() {
+            if reader.len() < CHUNK_HEADER_SIZE {
+                bail!("the spill file is truncated");
+            }
+            let channel = u32::from_le_bytes(*array_ref!(reader, 0, 4));
+            let len = u32::from_le_bytes(*array_ref!(reader, 4, 4)) as usize;
+            reader = &reader[CHUNK_HEADER_SIZE..];
+            if reader.len() < len {
+                bail!("the spill file is truncated");
+            }
+            let (data, rest) = reader.split_at(len);
+            reader = rest;
+
+            let frames = frames.entry(channel).or_default();
+            frames.extend_from_slice(data);
+            printer.defmt(frames)?;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Spill {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
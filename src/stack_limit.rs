@@ -0,0 +1,65 @@
+//! Hardware stack overflow detection through the MSPLIM register of ARMv8-M Mainline cores
+//!
+//! The core raises a UsageFault (`STKOF`), escalated to a HardFault unless enabled, as soon as the
+//! main stack pointer would go below MSPLIM; unlike the canary, this catches the overflow when it
+//! happens rather than when the painted area is read back, and there's nothing to paint.
+
+use probe_rs::{Core, CoreRegisterAddress, MemoryInterface};
+
+use crate::{cortexm, target_info::TargetInfo};
+
+/// CPUID Base Register
+const CPUID: u32 = 0xE000_ED00;
+const IMPLEMENTER_ARM: u32 = 0x41;
+/// Part numbers of the ARMv8-M Mainline cores: Cortex-M33, -M55, -M85 and -M35P
+const MAINLINE_PARTNOS: [u32; 4] = [0xD21, 0xD22, 0xD23, 0xD31];
+
+/// DCRSR register selectors of the main stack limit of each security state
+const MSPLIM_S: CoreRegisterAddress = CoreRegisterAddress(0x1C);
+const MSPLIM_NS: CoreRegisterAddress = CoreRegisterAddress(0x1E);
+
+/// MSPLIM ignores the lowest 3 bits; the limit is 8-byte aligned
+const ALIGNMENT: u32 = 8;
+
+/// Sets MSPLIM to the bottom of the stack if the halted `core` has one; returns the limit
+///
+/// NOTE a reset clears MSPLIM, so this must happen after the core was reset
+pub fn set(
+    core: &mut Core<'_>,
+    target_info: &TargetInfo,
+    uses_heap: bool,
+) -> anyhow::Result<Option<u32>> {
+    let cpuid = core.read_word_32(CPUID)?;
+    let (implementer, partno) = (cpuid >> 24, (cpuid >> 4) & 0xFFF);
+    if implementer != IMPLEMENTER_ARM || !MAINLINE_PARTNOS.contains(&partno) {
+        log::debug!(
+            "the core has no stack limit registers (CPUID={:#010x})",
+            cpuid
+        );
+        return Ok(None);
+    }
+
+    let stack_info = match &target_info.stack_info {
+        Some(stack_info) => stack_info,
+        None => return Ok(None),
+    };
+    // the heap grows up into the space below the stack; the stack must stop where the heap ends
+    let bottom = match (&target_info.heap_range, uses_heap) {
+        (Some(heap), _) if stack_info.range.contains(&heap.end) => heap.end,
+        (_, true) => {
+            log::debug!("the heap's bounds are unknown, not setting MSPLIM");
+            return Ok(None);
+        }
+        _ => *stack_info.range.start(),
+    };
+    let limit = (bottom + ALIGNMENT - 1) & !(ALIGNMENT - 1);
+
+    let register = if cortexm::is_secure(core)? {
+        MSPLIM_S
+    } else {
+        MSPLIM_NS
+    };
+    log::debug!("setting MSPLIM to 0x{:08X}", limit);
+    core.write_core_reg(register, limit)?;
+    Ok(Some(limit))
+}
@@ -1,5 +1,7 @@
 use probe_rs::{Core, MemoryInterface};
 
+use crate::cortexm;
+
 /// Registers stacked on exception entry.
 #[derive(Debug)]
 pub struct Stacked {
@@ -21,6 +23,11 @@ impl Stacked {
     /// Number of 32-bit words stacked in an extended frame.
     const WORDS_EXTENDED: usize = Self::WORDS_BASIC + 17; // 16 FPU regs + 1 status word
 
+    /// The exception that was active when this one was taken; `0` for thread mode
+    pub fn ipsr(&self) -> u32 {
+        self.xpsr & cortexm::IPSR_MASK
+    }
+
     pub fn read(core: &mut Core<'_>, sp: u32, fpu: bool) -> anyhow::Result<Self> {
         let mut storage = [0; Self::WORDS_EXTENDED];
         let registers: &mut [_] = if fpu {
@@ -40,23 +47,10 @@ impl Stacked {
             pc: registers[6],
             xpsr: registers[7],
             fpu_regs: if fpu {
+                let mut s = [0; 16];
+                s.copy_from_slice(&registers[8..24]);
                 Some(StackedFpuRegs {
-                    s0: f32::from_bits(registers[8]),
-                    s1: f32::from_bits(registers[9]),
-                    s2: f32::from_bits(registers[10]),
-                    s3: f32::from_bits(registers[11]),
-                    s4: f32::from_bits(registers[12]),
-                    s5: f32::from_bits(registers[13]),
-                    s6: f32::from_bits(registers[14]),
-                    s7: f32::from_bits(registers[15]),
-                    s8: f32::from_bits(registers[16]),
-                    s9: f32::from_bits(registers[17]),
-                    s10: f32::from_bits(registers[18]),
-                    s11: f32::from_bits(registers[19]),
-                    s12: f32::from_bits(registers[20]),
-                    s13: f32::from_bits(registers[21]),
-                    s14: f32::from_bits(registers[22]),
-                    s15: f32::from_bits(registers[23]),
+                    s,
                     fpscr: registers[24],
                 })
             } else {
@@ -65,6 +59,11 @@ impl Stacked {
         })
     }
 
+    /// The stacked floating-point registers; `None` for a basic frame
+    pub fn fpu_regs(&self) -> Option<&StackedFpuRegs> {
+        self.fpu_regs.as_ref()
+    }
+
     /// Returns the in-memory size of these stacked registers, in Bytes.
     pub fn size(&self) -> u32 {
         let num_words = if self.fpu_regs.is_none() {
@@ -77,23 +76,9 @@ impl Stacked {
     }
 }
 
+/// Floating-point registers stacked on exception entry, as raw bits
 #[derive(Debug)]
-struct StackedFpuRegs {
-    s0: f32,
-    s1: f32,
-    s2: f32,
-    s3: f32,
-    s4: f32,
-    s5: f32,
-    s6: f32,
-    s7: f32,
-    s8: f32,
-    s9: f32,
-    s10: f32,
-    s11: f32,
-    s12: f32,
-    s13: f32,
-    s14: f32,
-    s15: f32,
-    fpscr: u32,
+pub struct StackedFpuRegs {
+    pub s: [u32; 16],
+    pub fpscr: u32,
 }
@@ -0,0 +1,172 @@
+//! Waiting for the program to reach `main`, where the RTT control block and the arguments are set
+//! up
+//!
+//! Firmware that hangs during startup, e.g. waiting for a clock that never becomes ready, never
+//! gets there and so never prints anything. Instead of waiting silently, `probe-run` halts the
+//! core after every `--startup-grace-period`, reports where the program is and lets it continue.
+//!
+//! The same handshake runs again when the program resets itself, so that every boot gets its
+//! arguments and blocking RTT.
+
+use std::{
+    collections::HashSet,
+    env, thread,
+    time::{Duration, Instant},
+};
+
+use anyhow::bail;
+use object::read::File as ElfFile;
+use probe_rs::{Core, MemoryInterface as _};
+
+use crate::{arch::Arch, args::Args, backtrace, source::SourceMap, TIMEOUT};
+
+/// How often the core is checked for having halted at `main`
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Where the program is stuck, for the reports
+pub struct Locator<'a> {
+    pub arch: Arch,
+    /// Address of `main`, which has the breakpoint
+    pub main: u32,
+    pub elf: &'a ElfFile<'a>,
+    pub live_functions: &'a HashSet<&'a str>,
+    pub source_map: &'a [SourceMap],
+}
+
+/// What the program is handed at `main`, once it has initialized its static variables
+pub struct Handshake<'a> {
+    /// Address of the RTT control block; its first up channel is switched to blocking mode
+    pub rtt: Option<u32>,
+    pub args: Option<&'a Args>,
+    pub test_filter: Option<&'a Args>,
+    pub grace_period: Duration,
+}
+
+impl Handshake<'_> {
+    /// Whether the program has to be stopped at `main` at all
+    pub fn is_needed(&self) -> bool {
+        self.rtt.is_some() || self.args.is_some() || self.test_filter.is_some()
+    }
+
+    /// Runs the halted `core` to `main`, where it stays halted, and hands the program its settings
+    pub fn perform(
+        &self,
+        core: &mut Core<'_>,
+        deadline: Option<Instant>,
+        locator: &Locator,
+    ) -> anyhow::Result<()> {
+        core.set_hw_breakpoint(locator.main)?;
+        wait_for_main(
+            core,
+            self.grace_period,
+            deadline,
+            self.rtt.is_some(),
+            locator,
+        )?;
+        if let Some(rtt) = self.rtt {
+            const OFFSET: u32 = 44;
+            const FLAG: u32 = 2; // BLOCK_IF_FULL
+            core.write_word_32(rtt + OFFSET, FLAG)?;
+        }
+        if let Some(args) = self.args {
+            args.write(core)?;
+        }
+        if let Some(test_filter) = self.test_filter {
+            test_filter.write(core)?;
+        }
+        core.clear_hw_breakpoint(locator.main)?;
+        Ok(())
+    }
+}
+
+/// Runs the core until it halts at the breakpoint on `main`; reports where the program is every
+/// `grace_period` it takes and gives up at `deadline`. `rtt` says whether the logs are waiting
+/// for it
+pub fn wait_for_main(
+    core: &mut Core<'_>,
+    grace_period: Duration,
+    deadline: Option<Instant>,
+    rtt: bool,
+    locator: &Locator,
+) -> anyhow::Result<()> {
+    core.run()?;
+    let start = Instant::now();
+    let mut next_report = grace_period;
+    while !core.core_halted()? {
+        let elapsed = start.elapsed();
+        let timed_out = deadline.map_or(false, |deadline| Instant::now() >= deadline);
+        if !timed_out && elapsed < next_report {
+            thread::sleep(POLL_INTERVAL);
+            continue;
+        }
+
+        core.halt(TIMEOUT)?;
+        let pc = locator
+            .arch
+            .instruction_address(core.read_core_reg(locator.arch.program_counter())?);
+        // NOTE the core may have reached `main` right before it was halted
+        if pc == locator.main {
+            return Ok(());
+        }
+        let location = locator.describe(pc);
+        if timed_out {
+            bail!(
+                "the program did not reach `main` before `--timeout` elapsed; it's stuck in {}",
+                location
+            );
+        }
+        core.run()?;
+
+        let consequence = if rtt {
+            "; RTT is not initialized yet, so there are no logs"
+        } else {
+            ""
+        };
+        log::warn!(
+            "the program hasn't reached `main` after {}; it's stuck in {}{}",
+            humantime::format_duration(next_report),
+            location,
+            consequence
+        );
+        next_report += grace_period;
+    }
+    Ok(())
+}
+
+/// Parses `--startup-grace-period`; a period of zero would halt the core all the time
+pub fn parse_grace_period(s: &str) -> anyhow::Result<Duration> {
+    let grace_period = humantime::parse_duration(s)?;
+    if grace_period == Duration::from_secs(0) {
+        bail!("the grace period must be longer than zero");
+    }
+    Ok(grace_period)
+}
+
+impl Locator<'_> {
+    /// The function at `pc` and its source location, as far as they are known
+    fn describe(&self, pc: u32) -> String {
+        let current_dir = match env::current_dir() {
+            Ok(current_dir) => current_dir,
+            Err(_) => return format!("0x{:08X}", pc),
+        };
+        let symbols = backtrace::symbolicate(
+            pc,
+            self.arch,
+            self.elf,
+            self.live_functions,
+            &current_dir,
+            self.source_map,
+        );
+        // NOTE the first symbol is the innermost of the functions inlined at `pc`
+        match symbols.ok().and_then(|symbols| symbols.into_iter().next()) {
+            Some(backtrace::Symbol {
+                name,
+                file: Some(file),
+                line: Some(line),
+                ..
+            }) => format!("`{}` at {}:{} (0x{:08X})", name, file, line, pc),
+            Some(symbol) => format!("`{}` (0x{:08X})", symbol.name, pc),
+            None => format!("0x{:08X}", pc),
+        }
+    }
+}
@@ -0,0 +1,196 @@
+//! `--svd` and `--dump-peripherals`: the state of selected peripherals after a crash
+//!
+//! The registers are described by a CMSIS-SVD file. Their values are printed along with the bit
+//! fields that are not zero, e.g. the enable flags and error bits.
+
+use std::{fs, path::Path};
+
+use anyhow::{anyhow, bail, Context as _};
+use probe_rs::{Core, MemoryInterface};
+use svd_parser::{Access, Device, RegisterCluster, RegisterInfo};
+
+use crate::theme;
+
+/// A register to dump, resolved to its address
+struct Selected {
+    name: String,
+    address: u32,
+    /// In bits
+    size: u32,
+    /// Name, bit offset and width of each field
+    fields: Vec<(String, u32, u32)>,
+}
+
+/// The peripherals (`USART2`) and registers (`USART2.SR`) to dump
+pub struct Peripherals {
+    /// Peripheral name, base address and registers
+    peripherals: Vec<(String, u32, Vec<Selected>)>,
+}
+
+impl Peripherals {
+    pub fn load(svd_path: &Path, selection: &[String]) -> anyhow::Result<Self> {
+        let device = parse(svd_path)?;
+
+        let mut peripherals = vec![];
+        for selected in selection {
+            let mut parts = selected.splitn(2, '.');
+            // NOTE(unwrap) `splitn` yields at least one part
+            let peripheral_name = parts.next().unwrap();
+            let register_name = parts.next();
+            let (base_address, registers) = find_peripheral(&device, peripheral_name)?;
+
+            let registers = registers
+                .into_iter()
+                .filter(|register| {
+                    register_name.map_or(true, |name| register.name.eq_ignore_ascii_case(name))
+                })
+                // NOTE reading a write-only register is meaningless and may fault
+                .filter(|register| register.access != Some(Access::WriteOnly))
+                .map(|register| select(base_address, register))
+                .collect::<Vec<_>>();
+            if let (Some(name), true) = (register_name, registers.is_empty()) {
+                bail!("register `{}` not found in `{}`", name, peripheral_name);
+            }
+
+            peripherals.push((selected.clone(), base_address, registers));
+        }
+
+        Ok(Self { peripherals })
+    }
+
+    /// Reads and prints the selected registers of the halted `core`
+    pub fn dump(&self, core: &mut Core<'_>) -> anyhow::Result<()> {
+        for (name, base_address, registers) in &self.peripherals {
+            println!(
+                "{} @ {:#010x}",
+                theme::current().emphasis.paint(name),
+                base_address
+            );
+            for register in registers {
+                let value = match read(core, register) {
+                    Ok(value) => value,
+                    Err(e) => {
+                        println!("    {:<12} <{}>", register.name, e);
+                        continue;
+                    }
+                };
+
+                let fields = register
+                    .fields
+                    .iter()
+                    .filter_map(|(name, offset, width)| {
+                        let mask = if *width >= 32 {
+                            u32::MAX
+                        } else {
+                            (1 << width) - 1
+                        };
+                        let field = (value >> offset) & mask;
+                        if field == 0 {
+                            None
+                        } else if *width == 1 {
+                            Some(name.clone())
+                        } else {
+                            Some(format!("{}={:#x}", name, field))
+                        }
+                    })
+                    .collect::<Vec<_>>();
+                let digits = register.size as usize / 4;
+                println!(
+                    "    {:<12} 0x{:0digits$x}  {}",
+                    register.name,
+                    value,
+                    theme::current().secondary.paint(&fields.join(" ")),
+                    digits = digits
+                );
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The name and base address of every peripheral described by the SVD file
+pub fn peripheral_bases(svd_path: &Path) -> anyhow::Result<Vec<(String, u32)>> {
+    let device = parse(svd_path)?;
+    Ok(device
+        .peripherals
+        .iter()
+        // NOTE(as) SVD addresses are 32 bits wide
+        .map(|peripheral| (peripheral.name.clone(), peripheral.base_address as u32))
+        .collect())
+}
+
+fn parse(svd_path: &Path) -> anyhow::Result<Device> {
+    let xml = fs::read_to_string(svd_path)
+        .with_context(|| format!("failed to read {}", svd_path.display()))?;
+    svd_parser::parse(&xml)
+        .map_err(|e| anyhow!("{}", e))
+        .with_context(|| format!("failed to parse {}", svd_path.display()))
+}
+
+/// The base address and registers of `name`, following `derivedFrom`
+fn find_peripheral<'d>(
+    device: &'d Device,
+    name: &str,
+) -> anyhow::Result<(u32, Vec<&'d RegisterInfo>)> {
+    let peripheral = device
+        .peripherals
+        .iter()
+        .find(|peripheral| peripheral.name.eq_ignore_ascii_case(name))
+        .ok_or_else(|| anyhow!("peripheral `{}` not found in the SVD file", name))?;
+
+    let described = match &peripheral.derived_from {
+        Some(parent) => device
+            .peripherals
+            .iter()
+            .find(|peripheral| &peripheral.name == parent)
+            .ok_or_else(|| anyhow!("`{}` is derived from unknown `{}`", name, parent))?,
+        None => peripheral,
+    };
+    let registers = described
+        .registers
+        .iter()
+        .flatten()
+        .filter_map(|register_cluster| match register_cluster {
+            RegisterCluster::Register(register) => Some(&**register),
+            // NOTE clusters (e.g. the channels of a DMA controller) are not supported
+            RegisterCluster::Cluster(_) => None,
+        })
+        .collect();
+
+    // NOTE(as) SVD addresses are 32 bits wide
+    Ok((peripheral.base_address as u32, registers))
+}
+
+fn select(base_address: u32, register: &RegisterInfo) -> Selected {
+    let fields = register
+        .fields
+        .iter()
+        .flatten()
+        .map(|field| {
+            (
+                field.name.clone(),
+                field.bit_range.offset,
+                field.bit_range.width,
+            )
+        })
+        .collect();
+
+    Selected {
+        name: register.name.clone(),
+        address: base_address + register.address_offset,
+        size: register.size.unwrap_or(32),
+        fields,
+    }
+}
+
+fn read(core: &mut Core<'_>, register: &Selected) -> anyhow::Result<u32> {
+    Ok(match register.size {
+        8 => u32::from(core.read_word_8(register.address)?),
+        16 => {
+            let mut bytes = [0; 2];
+            core.read_8(register.address, &mut bytes)?;
+            u32::from(u16::from_le_bytes(bytes))
+        }
+        _ => core.read_word_32(register.address)?,
+    })
+}
@@ -0,0 +1,158 @@
+//! `--symbolizer-port`: answers address lookups of other host tools over HTTP while probe-run
+//! runs, with the same symbolication as the backtraces
+//!
+//! `GET /symbolize?address=0x1a8` (several addresses separated by commas) returns a JSON array with
+//! the functions at each address, innermost (inlined) first, and their source locations. The ELF
+//! file is read for every request, so lookups keep working after the program was rebuilt.
+
+use std::{
+    collections::HashSet,
+    env, fs,
+    io::{BufRead as _, BufReader, Write as _},
+    net::{Ipv4Addr, TcpListener, TcpStream},
+    path::{Path, PathBuf},
+    thread,
+};
+
+use anyhow::{anyhow, bail, Context as _};
+use object::{
+    read::{File as ElfFile, Object as _, ObjectSection as _, ObjectSymbol as _},
+    SymbolSection,
+};
+use serde::Serialize;
+
+use crate::{arch::Arch, backtrace, image, source::SourceMap};
+
+#[derive(Serialize)]
+struct Lookup {
+    address: u32,
+    frames: Vec<Frame>,
+}
+
+#[derive(Serialize)]
+struct Frame {
+    function: String,
+    file: Option<String>,
+    line: Option<u64>,
+    inline: bool,
+}
+
+#[derive(Serialize)]
+struct Error {
+    error: String,
+}
+
+/// Starts serving lookups in the symbols of `elf_path` on `port` of the loopback interface
+pub fn spawn(port: u16, elf_path: PathBuf, source_map: Vec<SourceMap>) -> anyhow::Result<()> {
+    // NOTE only local tools may ask; the ELF file's paths and symbols aren't for the network
+    let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, port))
+        .with_context(|| format!("failed to listen on port {}", port))?;
+    log::info!(
+        "symbolizer listening on http://{}/symbolize",
+        listener.local_addr()?
+    );
+
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let result = stream
+                .map_err(anyhow::Error::from)
+                .and_then(|stream| handle(stream, &elf_path, &source_map));
+            if let Err(e) = result {
+                log::debug!("symbolizer request failed: {}", e);
+            }
+        }
+    });
+    Ok(())
+}
+
+fn handle(mut stream: TcpStream, elf_path: &Path, source_map: &[SourceMap]) -> anyhow::Result<()> {
+    let mut reader = BufReader::new(&stream);
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    // NOTE the headers are read, but not used
+    let mut header = String::new();
+    while reader.read_line(&mut header)? > 2 {
+        header.clear();
+    }
+
+    let (status, body) = match respond(&request_line, elf_path, source_map) {
+        Ok(body) => ("200 OK", body),
+        Err(e) => (
+            "400 Bad Request",
+            serde_json::to_string(&Error {
+                error: format!("{:#}", e),
+            })?,
+        ),
+    };
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\
+        Connection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    )?;
+    Ok(())
+}
+
+/// The JSON body of the response to `request_line`, e.g. `GET /symbolize?address=0x1a8 HTTP/1.1`
+fn respond(
+    request_line: &str,
+    elf_path: &Path,
+    source_map: &[SourceMap],
+) -> anyhow::Result<String> {
+    let target = match request_line.split_whitespace().collect::<Vec<_>>()[..] {
+        ["GET", target, _] => target,
+        _ => bail!("expected `GET /symbolize?address=<address>`"),
+    };
+    let addresses = target
+        .strip_prefix("/symbolize?")
+        .and_then(|query| {
+            query
+                .split('&')
+                .find_map(|pair| pair.strip_prefix("address="))
+        })
+        .ok_or_else(|| anyhow!("expected `GET /symbolize?address=<address>`"))?
+        .split(',')
+        .map(image::parse_address)
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let bytes = fs::read(elf_path)?;
+    let elf = ElfFile::parse(&bytes)?;
+    let arch = Arch::from_elf(&elf)?;
+    let text = elf
+        .section_by_name(".text")
+        .map(|section| section.index())
+        .ok_or_else(|| anyhow!("`.text` section is missing"))?;
+    let live_functions = elf
+        .symbols()
+        .filter(|symbol| symbol.section() == SymbolSection::Section(text))
+        .map(|symbol| symbol.name())
+        .collect::<Result<HashSet<_>, _>>()?;
+    let current_dir = env::current_dir()?;
+
+    let lookups = addresses
+        .into_iter()
+        .map(|address| {
+            let symbols = backtrace::symbolicate(
+                address,
+                arch,
+                &elf,
+                &live_functions,
+                &current_dir,
+                source_map,
+            )?;
+            let frames = symbols
+                .into_iter()
+                .map(|symbol| Frame {
+                    function: symbol.name,
+                    file: symbol.file,
+                    line: symbol.line,
+                    inline: symbol.inline,
+                })
+                .collect();
+            Ok(Lookup { address, frames })
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    Ok(serde_json::to_string(&lookups)?)
+}
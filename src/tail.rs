@@ -0,0 +1,121 @@
+//! `probe-run tail`: follows the decoded defmt frames that another `probe-run` serves with
+//! `--publish` (or `--serve`)
+//!
+//! The client only reads from the socket, so any number of them can watch a run, e.g. on a lab
+//! machine, without touching the probe. Frames are printed like `probe-run` prints them, or passed
+//! through as JSON with `--message-format=json`. The client exits when the run ends.
+
+use std::{
+    io::{BufRead as _, BufReader, Read},
+    net::TcpStream,
+    path::Path,
+};
+
+use anyhow::Context as _;
+use log::Level;
+use serde::Deserialize;
+
+use crate::{log_filter::LogFilter, serve::Endpoint, theme};
+
+/// A `"type": "log"` record, as `--serve` sends it
+#[derive(Deserialize)]
+struct Frame {
+    level: String,
+    timestamp: Option<String>,
+    module: Option<String>,
+    file: Option<String>,
+    line: Option<u32>,
+    message: String,
+}
+
+/// Prints the frames served on `endpoint` until the serving `probe-run` exits
+pub fn run(endpoint: &Endpoint, log_filter: Option<&LogFilter>, json: bool) -> anyhow::Result<()> {
+    let stream = match endpoint {
+        Endpoint::Tcp(address) => Box::new(
+            TcpStream::connect(address)
+                .with_context(|| format!("failed to connect to {}", endpoint))?,
+        ) as Box<dyn Read>,
+        Endpoint::Unix(path) => connect_unix(path)?,
+    };
+    log::info!("following {}", endpoint);
+
+    for line in BufReader::new(stream).lines() {
+        let line = line?;
+        let frame = match serde_json::from_str::<Frame>(&line) {
+            Ok(frame) => frame,
+            Err(e) => {
+                // NOTE e.g. the other end serves the raw stream (`--serve-raw`)
+                log::warn!("ignoring data that is not a decoded frame: {}", e);
+                continue;
+            }
+        };
+        // NOTE(unwrap_or) the level names of `--message-format=json` are those of `log`
+        let level = frame.level.parse().unwrap_or(Level::Info);
+        if let Some(log_filter) = log_filter {
+            if !log_filter.enabled(frame.module.as_deref(), defmt_level(level)) {
+                continue;
+            }
+        }
+
+        if json {
+            println!("{}", line);
+        } else {
+            print(&frame, level);
+        }
+    }
+
+    log::info!("{} closed the connection; the run has ended", endpoint);
+    Ok(())
+}
+
+fn print(frame: &Frame, level: Level) {
+    let theme = theme::current();
+    let level_name = format!("{:<5}", level);
+    match &frame.timestamp {
+        Some(timestamp) => println!(
+            "{} {} {}",
+            timestamp,
+            theme.level(level).paint(&level_name),
+            frame.message
+        ),
+        None => println!(
+            "{} {}",
+            theme.level(level).paint(&level_name),
+            frame.message
+        ),
+    }
+    if let (Some(file), Some(line)) = (&frame.file, frame.line) {
+        let location = format!(
+            "{}{} @ {}:{}",
+            theme.location_prefix,
+            frame.module.as_deref().unwrap_or(""),
+            file,
+            line
+        );
+        println!("{}", theme.secondary.paint(&location));
+    }
+}
+
+fn defmt_level(level: Level) -> defmt_decoder::Level {
+    match level {
+        Level::Trace => defmt_decoder::Level::Trace,
+        Level::Debug => defmt_decoder::Level::Debug,
+        Level::Info => defmt_decoder::Level::Info,
+        Level::Warn => defmt_decoder::Level::Warn,
+        Level::Error => defmt_decoder::Level::Error,
+    }
+}
+
+#[cfg(unix)]
+fn connect_unix(path: &Path) -> anyhow::Result<Box<dyn Read>> {
+    use std::os::unix::net::UnixStream;
+
+    let stream = UnixStream::connect(path)
+        .with_context(|| format!("failed to connect to unix:{}", path.display()))?;
+    Ok(Box::new(stream))
+}
+
+#[cfg(not(unix))]
+fn connect_unix(_: &Path) -> anyhow::Result<Box<dyn Read>> {
+    anyhow::bail!("Unix sockets are not supported on this platform")
+}
@@ -0,0 +1,398 @@
+use std::{
+    convert::TryFrom,
+    ops::{Range, RangeInclusive},
+    path::PathBuf,
+};
+
+use anyhow::{anyhow, bail, Context as _};
+use object::{
+    read::{File as ElfFile, Object as _, ObjectSection as _, ObjectSymbol as _},
+    SectionKind,
+};
+use probe_rs::{
+    config::{registry, MemoryRegion, RamRegion},
+    DebugProbeInfo, Target,
+};
+
+use crate::{arch::EntryPoint, chip_detection, cortexm};
+
+/// Information about the chip probe-run is going to run the firmware on
+pub struct TargetInfo {
+    pub probe_target: Target,
+    /// The RAM region that contains the initial stack pointer, i.e. the call stack
+    pub active_ram_region: Option<RamRegion>,
+    pub stack_info: Option<StackInfo>,
+    /// The heap region, if the linker script defines its bounds
+    pub heap_range: Option<Range<u32>>,
+}
+
+/// Where the call stack can live without colliding with the program's static data
+#[derive(Debug)]
+pub struct StackInfo {
+    /// Valid values of the stack pointer; the end is the initial stack pointer
+    pub range: RangeInclusive<u32>,
+    /// Whether `.data`/`.bss` sit right below the stack, i.e. a stack overflow corrupts them
+    pub data_below_stack: bool,
+    /// Bytes of `.data` and `.bss` in the stack's RAM region, below the stack
+    pub static_data: u32,
+}
+
+impl StackInfo {
+    /// How many bytes the stack can grow to
+    pub fn size(&self) -> u32 {
+        self.range.end() - self.range.start()
+    }
+}
+
+/// RAM and flash regions that replace the ones of the probe-rs registry, e.g. when they are wrong
+pub struct RegionOverrides<'a> {
+    pub ram: &'a [Range<u32>],
+    pub flash: &'a [Range<u32>],
+}
+
+impl TargetInfo {
+    /// Looks up `chip` in the probe-rs registry; if `None` the chip connected to the probe is
+    /// detected instead, which needs a `probe_info`
+    ///
+    /// The stack doesn't extend into the `skipped` address ranges, e.g. RAM a SoftDevice reserves.
+    pub fn new(
+        chip: Option<&str>,
+        elf: &ElfFile,
+        entry_point: &EntryPoint,
+        probe_info: Option<&DebugProbeInfo>,
+        skipped: &[Range<u32>],
+        overrides: &RegionOverrides,
+    ) -> anyhow::Result<Self> {
+        let mut probe_target = match (chip, probe_info) {
+            (Some(chip), _) => registry::get_target_by_name(chip)?,
+            (None, Some(probe_info)) => chip_detection::detect(elf, entry_point, probe_info)?,
+            (None, None) => bail!("`--chip` must be specified; there's no probe to detect it with"),
+        };
+        override_regions(&mut probe_target, overrides)?;
+        // NOTE the initial stack pointer is unknown (`0`) for some Cortex-A/R programs
+        let active_ram_region = match entry_point.initial_stack_pointer {
+            0 => None,
+            initial_stack_pointer => {
+                extract_active_ram_region(&probe_target, initial_stack_pointer)
+            }
+        };
+        let stack_info = match &active_ram_region {
+            Some(ram) => extract_stack_info(
+                elf,
+                &probe_target,
+                ram,
+                entry_point.initial_stack_pointer,
+                skipped,
+            )?,
+            None => None,
+        };
+        let heap_range = extract_heap_range(elf)?;
+
+        Ok(Self {
+            probe_target,
+            active_ram_region,
+            stack_info,
+            heap_range,
+        })
+    }
+}
+
+/// Replaces the RAM (flash) regions of `target` with the overriding ones, if any are given
+fn override_regions(target: &mut Target, overrides: &RegionOverrides) -> anyhow::Result<()> {
+    if !overrides.ram.is_empty() {
+        // NOTE the new regions are copies of an existing one, so that they keep its core
+        // assignment
+        let template = target
+            .memory_map
+            .iter()
+            .find_map(|region| match region {
+                MemoryRegion::Ram(region) => Some(region.clone()),
+                _ => None,
+            })
+            .ok_or_else(|| anyhow!("{} has no RAM region to override", target.name))?;
+        target
+            .memory_map
+            .retain(|region| !matches!(region, MemoryRegion::Ram(_)));
+        for range in overrides.ram {
+            log::debug!(
+                "RAM region overridden: 0x{:08X}-0x{:08X}",
+                range.start,
+                range.end - 1
+            );
+            let mut region = template.clone();
+            region.range = range.clone();
+            region.is_boot_memory = false;
+            target.memory_map.push(MemoryRegion::Ram(region));
+        }
+    }
+
+    if !overrides.flash.is_empty() {
+        let template = target
+            .memory_map
+            .iter()
+            .find_map(|region| match region {
+                MemoryRegion::Nvm(region) => Some(region.clone()),
+                _ => None,
+            })
+            .ok_or_else(|| anyhow!("{} has no flash region to override", target.name))?;
+        target
+            .memory_map
+            .retain(|region| !matches!(region, MemoryRegion::Nvm(_)));
+        for (i, range) in overrides.flash.iter().enumerate() {
+            log::debug!(
+                "flash region overridden: 0x{:08X}-0x{:08X}",
+                range.start,
+                range.end - 1
+            );
+            let mut region = template.clone();
+            region.range = range.clone();
+            // NOTE the chip boots from the first flash region given
+            region.is_boot_memory = i == 0;
+            target.memory_map.push(MemoryRegion::Nvm(region));
+        }
+    }
+    Ok(())
+}
+
+/// Adds the chips described in probe-rs target description (YAML) files to the registry, so that
+/// `--chip` and chip detection find them
+pub fn load_chip_descriptions(paths: &[PathBuf]) -> anyhow::Result<()> {
+    for path in paths {
+        log::debug!("loading chip description {}", path.display());
+        registry::add_target_from_yaml(path)
+            .with_context(|| format!("failed to load chip description {}", path.display()))?;
+    }
+    Ok(())
+}
+
+fn extract_active_ram_region(target: &Target, initial_stack_pointer: u32) -> Option<RamRegion> {
+    if let Some(region) = find_ram_region(target, initial_stack_pointer) {
+        return Some(region);
+    }
+
+    // ARMv8-M chips with TrustZone map their RAM twice, but probe-rs usually only knows about the
+    // Non-secure alias; a Secure program's stack lives in the other one
+    let alias = initial_stack_pointer ^ cortexm::SECURITY_ALIAS_BIT;
+    find_ram_region(target, alias).map(|mut region| {
+        log::debug!("the stack lives in the secure/non-secure alias of a RAM region");
+        region.range = region.range.start ^ cortexm::SECURITY_ALIAS_BIT
+            ..region.range.end ^ cortexm::SECURITY_ALIAS_BIT;
+        region
+    })
+}
+
+fn find_ram_region(target: &Target, initial_stack_pointer: u32) -> Option<RamRegion> {
+    // NOTE stack is full descending; meaning the stack pointer can be `ORIGIN(RAM) + LENGTH(RAM)`
+    // and the first byte the stack uses is the one below it. When RAM regions are adjacent (e.g.
+    // SRAM1 and SRAM2) that byte decides which of them holds the stack
+    let last_used = initial_stack_pointer.checked_sub(1)?;
+    ram_regions(target)
+        .find(|region| region.range.contains(&last_used))
+        .cloned()
+}
+
+fn ram_regions(target: &Target) -> impl Iterator<Item = &RamRegion> {
+    target.memory_map.iter().filter_map(|region| match region {
+        MemoryRegion::Ram(region) => Some(region),
+        _ => None,
+    })
+}
+
+/// A section that occupies memory when the program runs
+struct AllocatedSection {
+    name: String,
+    range: Range<u64>,
+    /// `.data` or `.bss`, rather than code or read-only data placed in RAM
+    is_static_data: bool,
+}
+
+// NOTE with `--ram-exec` the code lives in RAM as well, so every allocated section counts; debug
+// info and other non-allocated sections have address 0, which is in RAM on some chips
+fn allocated_sections(elf: &ElfFile) -> Vec<AllocatedSection> {
+    elf.sections()
+        .filter(|sect| {
+            sect.size() != 0
+                && matches!(
+                    sect.kind(),
+                    SectionKind::Text
+                        | SectionKind::Data
+                        | SectionKind::ReadOnlyData
+                        | SectionKind::UninitializedData
+                )
+        })
+        .map(|sect| AllocatedSection {
+            name: sect.name().unwrap_or("<unknown>").to_string(),
+            range: sect.address()..sect.address() + sect.size(),
+            is_static_data: matches!(
+                sect.kind(),
+                SectionKind::Data | SectionKind::UninitializedData
+            ),
+        })
+        .collect()
+}
+
+/// Bytes of `.data` and `.bss` in `range`
+fn static_data_in(sections: &[AllocatedSection], range: &Range<u64>) -> u64 {
+    sections
+        .iter()
+        .filter(|sect| sect.is_static_data)
+        .map(|sect| {
+            sect.range
+                .end
+                .min(range.end)
+                .saturating_sub(sect.range.start.max(range.start))
+        })
+        .sum()
+}
+
+/// Works out where the stack can grow within `ram`, the RAM region that holds the initial stack
+/// pointer
+///
+/// Chips like the STM32H7 have several RAM regions, e.g. the stack in DTCM and `.bss` in AXI SRAM;
+/// only the sections in the stack's region limit it, but the ones in an adjacent region right
+/// below it are corrupted by a stack overflow all the same.
+fn extract_stack_info(
+    elf: &ElfFile,
+    target: &Target,
+    ram: &RamRegion,
+    initial_stack_pointer: u32,
+    skipped: &[Range<u32>],
+) -> anyhow::Result<Option<StackInfo>> {
+    log::debug!(
+        "RAM region: 0x{:08X}-0x{:08X}",
+        ram.range.start,
+        ram.range.end - 1
+    );
+
+    // NOTE the initial SP points one past the highest address the stack uses
+    if initial_stack_pointer == 0 || !ram.range.contains(&(initial_stack_pointer - 1)) {
+        return Ok(None);
+    }
+
+    let sections = allocated_sections(elf);
+    for region in ram_regions(target) {
+        let range = u64::from(region.range.start)..u64::from(region.range.end);
+        let used = static_data_in(&sections, &range);
+        if used != 0 {
+            log::debug!(
+                "RAM region 0x{:08X}-0x{:08X} holds {} bytes of static data{}",
+                region.range.start,
+                region.range.end - 1,
+                used,
+                if region.range == ram.range {
+                    " (stack region)"
+                } else {
+                    ""
+                }
+            );
+        }
+    }
+
+    // the stack starts right after the highest RAM address a section below it occupies
+    // NOTE sections above the stack (e.g. when linking with `flip-link`) don't limit its range
+    // NOTE 64-bit ELF files may have sections beyond the 32-bit address space
+    let ram_range = u64::from(ram.range.start)..u64::from(ram.range.end);
+    let mut highest_ram_addr_in_use = 0;
+    for sect in &sections {
+        let last_addr = sect.range.end - 1;
+        if ram_range.contains(&last_addr) && last_addr < u64::from(initial_stack_pointer) {
+            // NOTE(`as`) within the RAM region, which is in the 32-bit address space
+            let last_addr = last_addr as u32;
+            log::debug!(
+                "section `{}` is in RAM at 0x{:08X}-0x{:08X}",
+                sect.name,
+                sect.range.start,
+                last_addr,
+            );
+            highest_ram_addr_in_use = highest_ram_addr_in_use.max(last_addr);
+        }
+    }
+    let below_stack = ram_range.start..u64::from(initial_stack_pointer);
+    // NOTE(`as`) at most the size of the RAM region
+    let static_data = static_data_in(&sections, &below_stack) as u32;
+
+    // memory reserved by a bootloader or SoftDevice is "in use" as well
+    for range in skipped {
+        if range.start < range.end
+            && ram.range.contains(&range.start)
+            && range.end <= initial_stack_pointer
+        {
+            log::debug!(
+                "skipped region is in RAM at 0x{:08X}-0x{:08X}",
+                range.start,
+                range.end - 1
+            );
+            highest_ram_addr_in_use = highest_ram_addr_in_use.max(range.end - 1);
+        }
+    }
+
+    let stack_start = if highest_ram_addr_in_use != 0 {
+        highest_ram_addr_in_use + 1
+    } else {
+        ram.range.start
+    };
+    // with nothing below the stack in its own region, an overflow runs into the region that ends
+    // where the stack's begins, e.g. from SRAM2 into the `.bss` in SRAM1
+    let data_below_stack = highest_ram_addr_in_use != 0
+        || ram_regions(target).any(|region| {
+            region.range.end == ram.range.start
+                && static_data_in(
+                    &sections,
+                    &(u64::from(region.range.start)..u64::from(region.range.end)),
+                ) != 0
+        });
+
+    Ok(Some(StackInfo {
+        range: stack_start..=initial_stack_pointer,
+        data_below_stack,
+        static_data,
+    }))
+}
+
+/// Finds the heap region from the symbols linker scripts define for it: `__sheap` and `__eheap`,
+/// or `riscv-rt`'s `_sheap` and `_heap_size`
+fn extract_heap_range(elf: &ElfFile) -> anyhow::Result<Option<Range<u32>>> {
+    let (mut start, mut end, mut size) = (None, None, None);
+    for symbol in elf.symbols() {
+        let address = symbol.address();
+        match symbol.name() {
+            Ok("__sheap") | Ok("_sheap") => start = Some(address),
+            Ok("__eheap") => end = Some(address),
+            // NOTE this symbol's "address" is the size of the heap
+            Ok("_heap_size") => size = Some(address),
+            _ => {}
+        }
+    }
+
+    let start = match start {
+        Some(start) => start,
+        None => return Ok(None),
+    };
+    let end = match (end, size) {
+        (Some(end), _) => end,
+        (None, Some(size)) => match start.checked_add(size) {
+            Some(end) => end,
+            None => return Ok(None),
+        },
+        (None, None) => {
+            log::debug!("heap starts at 0x{:08X} but its end is unknown", start);
+            return Ok(None);
+        }
+    };
+    if start >= end {
+        return Ok(None);
+    }
+
+    log::debug!("heap region: 0x{:08X}-0x{:08X}", start, end - 1);
+    Ok(Some(address(start)?..address(end)?))
+}
+
+/// Converts an address of a (possibly 64-bit) ELF file into one the probe can access
+pub fn address(address: u64) -> anyhow::Result<u32> {
+    u32::try_from(address).map_err(|_| {
+        anyhow!(
+            "address 0x{:X} is beyond the 32-bit address space probe-run supports",
+            address
+        )
+    })
+}
@@ -0,0 +1,195 @@
+//! `--color` and `--theme`: whether the output is colored, and how it's styled
+//!
+//! All of probe-run's human-readable output takes its styles from the current theme, so a theme
+//! can restyle the log levels, the backtraces and the error banners in one place. Colors are only
+//! emitted if the color policy allows them; the defmt logger follows the same policy.
+
+use std::{
+    env,
+    str::FromStr,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use anyhow::anyhow;
+use colored::{Color, ColoredString, Colorize as _};
+use log::Level;
+
+/// Whether to emit colors: `auto` colors a terminal, honoring `NO_COLOR` and `CLICOLOR_FORCE`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ColorChoice {
+    Auto,
+    Always,
+    Never,
+}
+
+impl FromStr for ColorChoice {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "auto" => Ok(Self::Auto),
+            "always" => Ok(Self::Always),
+            "never" => Ok(Self::Never),
+            _ => Err(anyhow!("unknown color choice `{}`", s)),
+        }
+    }
+}
+
+/// How a piece of text is rendered, if colors are enabled
+#[derive(Clone, Copy, Debug)]
+pub struct Style {
+    color: Option<Color>,
+    bold: bool,
+    dimmed: bool,
+}
+
+impl Style {
+    const PLAIN: Style = Style {
+        color: None,
+        bold: false,
+        dimmed: false,
+    };
+    const DIMMED: Style = Style {
+        dimmed: true,
+        ..Style::PLAIN
+    };
+    const BOLD: Style = Style {
+        bold: true,
+        ..Style::PLAIN
+    };
+
+    const fn color(color: Color) -> Self {
+        Style {
+            color: Some(color),
+            ..Style::PLAIN
+        }
+    }
+
+    const fn bold_color(color: Color) -> Self {
+        Style {
+            color: Some(color),
+            bold: true,
+            dimmed: false,
+        }
+    }
+
+    pub fn paint(&self, text: &str) -> ColoredString {
+        let mut painted = ColoredString::from(text);
+        if let Some(color) = self.color {
+            painted = painted.color(color);
+        }
+        if self.bold {
+            painted = painted.bold();
+        }
+        if self.dimmed {
+            painted = painted.dimmed();
+        }
+        painted
+    }
+}
+
+pub struct Theme {
+    name: &'static str,
+    trace: Style,
+    debug: Style,
+    info: Style,
+    warn: Style,
+    error: Style,
+    /// Headings, separators, source locations and other text around the actual output
+    pub secondary: Style,
+    /// Names that stand out, e.g. a peripheral or the REPL prompt
+    pub emphasis: Style,
+    /// Passed tests, successful runs
+    pub success: Style,
+    /// Failed tests, failed runs and error banners like `fault:`
+    pub failure: Style,
+    /// Repeated to draw the line around the program's output
+    pub separator: &'static str,
+    /// Put in front of the source location of a log frame
+    pub location_prefix: &'static str,
+}
+
+impl Theme {
+    pub fn level(&self, level: Level) -> Style {
+        match level {
+            Level::Trace => self.trace,
+            Level::Debug => self.debug,
+            Level::Info => self.info,
+            Level::Warn => self.warn,
+            Level::Error => self.error,
+        }
+    }
+}
+
+const DEFAULT: Theme = Theme {
+    name: "default",
+    trace: Style::DIMMED,
+    debug: Style::PLAIN,
+    info: Style::color(Color::Green),
+    warn: Style::color(Color::Yellow),
+    error: Style::color(Color::Red),
+    secondary: Style::DIMMED,
+    emphasis: Style::BOLD,
+    success: Style::bold_color(Color::Green),
+    failure: Style::bold_color(Color::Red),
+    separator: "─",
+    location_prefix: "└─ ",
+};
+
+/// Like the default theme, but only draws with ASCII characters, e.g. for CI logs that mangle
+/// Unicode
+const ASCII: Theme = Theme {
+    name: "ascii",
+    separator: "-",
+    location_prefix: "`- ",
+    ..DEFAULT
+};
+
+/// To add a theme, define it above and list it here
+const THEMES: &[Theme] = &[DEFAULT, ASCII];
+
+static CURRENT: AtomicUsize = AtomicUsize::new(0);
+
+/// The name of a theme passed to `--theme`
+pub fn parse_name(name: &str) -> anyhow::Result<String> {
+    if THEMES.iter().any(|theme| theme.name == name) {
+        Ok(name.to_string())
+    } else {
+        let names = THEMES.iter().map(|theme| theme.name).collect::<Vec<_>>();
+        Err(anyhow!(
+            "unknown theme `{}`; expected one of: {}",
+            name,
+            names.join(", ")
+        ))
+    }
+}
+
+/// Applies the color policy and selects theme `name`; call this before anything is printed
+pub fn init(color: ColorChoice, name: &str) {
+    colored::control::set_override(colors_enabled(color));
+    if let Some(index) = THEMES.iter().position(|theme| theme.name == name) {
+        CURRENT.store(index, Ordering::Relaxed);
+    }
+}
+
+/// The theme selected with `--theme`
+pub fn current() -> &'static Theme {
+    &THEMES[CURRENT.load(Ordering::Relaxed)]
+}
+
+fn colors_enabled(color: ColorChoice) -> bool {
+    match color {
+        ColorChoice::Always => true,
+        ColorChoice::Never => false,
+        // NOTE see https://no-color.org and https://bixense.com/clicolors
+        ColorChoice::Auto => {
+            if env::var_os("NO_COLOR").map_or(false, |value| !value.is_empty()) {
+                false
+            } else if env::var("CLICOLOR_FORCE").map_or(false, |value| value != "0") {
+                true
+            } else {
+                atty::is(atty::Stream::Stdout)
+            }
+        }
+    }
+}
@@ -0,0 +1,103 @@
+//! `--timestamp`: host timestamps for defmt frames and their drift from the target's timestamps
+
+use std::{
+    cell::Cell,
+    str::FromStr,
+    time::{Instant, SystemTime},
+};
+
+use anyhow::bail;
+
+/// Which timestamps are printed in front of the defmt frames
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Mode {
+    /// The host's wall clock time when the frame was received
+    Host,
+    /// The timestamp the firmware attached to the frame, if any
+    Target,
+    /// Both, and how far the target's clock has drifted from the host's
+    Both,
+}
+
+impl Default for Mode {
+    fn default() -> Self {
+        Mode::Target
+    }
+}
+
+impl FromStr for Mode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "host" => Ok(Self::Host),
+            "target" => Ok(Self::Target),
+            "both" => Ok(Self::Both),
+            _ => bail!("unknown timestamp mode `{}`", s),
+        }
+    }
+}
+
+/// The timestamps of a frame
+pub struct Stamp {
+    /// RFC 3339, in UTC
+    pub host: String,
+    /// How much further the target's clock has advanced than the host's since the first frame, in
+    /// seconds; known if the target's timestamps are in seconds
+    pub drift: Option<f64>,
+}
+
+pub struct Timestamps {
+    mode: Mode,
+    start: Instant,
+    /// Host time (since `start`) minus target time of the first frame with a target timestamp
+    offset: Cell<Option<f64>>,
+}
+
+impl Timestamps {
+    pub fn new(mode: Mode) -> Self {
+        Self {
+            mode,
+            start: Instant::now(),
+            offset: Cell::new(None),
+        }
+    }
+
+    pub fn mode(&self) -> Mode {
+        self.mode
+    }
+
+    /// Timestamps a frame that was just received; `target` is the frame's timestamp as displayed
+    pub fn stamp(&self, target: Option<&str>) -> Stamp {
+        let host = humantime::format_rfc3339_micros(SystemTime::now()).to_string();
+        let elapsed = self.start.elapsed().as_secs_f64();
+
+        // NOTE only timestamps with a display hint such as `{=u64:µs}` are in seconds, e.g.
+        // `1.000123`; plain integers are ticks of an unknown clock
+        let target_secs = target
+            .filter(|target| target.contains('.'))
+            .and_then(|target| target.parse::<f64>().ok());
+        let drift = target_secs.map(|target_secs| {
+            let offset = elapsed - target_secs;
+            let first_offset = self.offset.get().unwrap_or(offset);
+            self.offset.set(Some(first_offset));
+            first_offset - offset
+        });
+
+        Stamp { host, drift }
+    }
+}
+
+impl Stamp {
+    /// What is printed in front of the frame; the logger already prints the target timestamp
+    pub fn prefix(&self, mode: Mode) -> Option<String> {
+        match mode {
+            Mode::Target => None,
+            Mode::Host => Some(self.host.clone()),
+            Mode::Both => Some(match self.drift {
+                Some(drift) => format!("{} (drift {:+.6}s)", self.host, drift),
+                None => self.host.clone(),
+            }),
+        }
+    }
+}
@@ -0,0 +1,173 @@
+//! `--chip-unlock`: removes the readout protection of a locked chip by erasing it
+//!
+//! Each vendor has its own sequence; all of them erase the whole flash, so the program has to be
+//! flashed again afterwards. A locked nRF chip doesn't even let probe-rs attach to it, so its
+//! CTRL-AP is driven through the bare debug port, before the session is opened.
+
+use std::{
+    thread,
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, bail};
+use probe_rs::{
+    architecture::arm::{DAPAccess, PortType},
+    DebugProbeInfo, Session,
+};
+
+use crate::{cli::Opts, option_bytes, speed::Speed};
+
+/// Debug port register that selects the access port (`APSEL`, bits 31:24) and its register bank
+const DP_SELECT: u16 = 0x8;
+const CTRL_AP_RESET: u16 = 0x000;
+const CTRL_AP_ERASEALL: u16 = 0x004;
+const CTRL_AP_ERASEALLSTATUS: u16 = 0x008;
+const CTRL_AP_APPROTECTSTATUS: u16 = 0x00C;
+
+/// An erase takes a few hundred milliseconds; the nRF5340 erases both of its cores
+const ERASE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The access ports of Nordic's CTRL-APs on `chip`, if it has any: the nRF5340 has one per core,
+/// and the other families keep theirs at different indices
+pub fn nrf_ctrl_aps(chip: &str) -> Option<&'static [u16]> {
+    let lowercase = chip.to_ascii_lowercase();
+    if lowercase.starts_with("nrf52") {
+        Some(&[1])
+    } else if lowercase.starts_with("nrf53") {
+        // NOTE APs 0 and 1 are the AHB-APs of the application and the network core
+        Some(&[2, 3])
+    } else if lowercase.starts_with("nrf91") {
+        Some(&[4])
+    } else {
+        None
+    }
+}
+
+/// Unlocks `chip` before probe-rs attaches to it, if it's a chip whose lock keeps probe-rs from
+/// attaching; returns whether it did
+pub fn unlock_before_attach(
+    probe_info: &DebugProbeInfo,
+    chip: &str,
+    opts: &Opts,
+) -> anyhow::Result<bool> {
+    let ctrl_aps = match nrf_ctrl_aps(chip) {
+        Some(ctrl_aps) => ctrl_aps,
+        None => return Ok(false),
+    };
+    log::info!(
+        "unlocking {} through the CTRL-AP; this erases the flash and UICR",
+        chip
+    );
+    with_debug_port(probe_info, opts, |interface| {
+        nrf_erase_all(interface, ctrl_aps)
+    })?;
+    Ok(true)
+}
+
+/// Unlocks `chip` through the open session, erasing its flash
+pub fn unlock(sess: &mut Session, chip: &str) -> anyhow::Result<()> {
+    let lowercase = chip.to_ascii_lowercase();
+    if let Some(ctrl_aps) = nrf_ctrl_aps(chip) {
+        log::info!(
+            "unlocking {} through the CTRL-AP; this erases the flash and UICR",
+            chip
+        );
+        nrf_erase_all(&mut **sess.get_arm_interface()?, ctrl_aps)
+    } else if lowercase.starts_with("stm32f4") {
+        log::info!(
+            "unlocking {} by regressing its readout protection to level 0",
+            chip
+        );
+        option_bytes::regress_stm32f4_rdp(sess)
+    } else {
+        bail!(
+            "don't know how to unlock `{}`; only nRF52, nRF53, nRF91 and STM32F4 chips are \
+            supported",
+            chip
+        )
+    }
+}
+
+/// Explains why probe-rs failed to attach to `chip`, if it's a locked nRF chip
+pub fn explain_attach_error(
+    probe_info: &DebugProbeInfo,
+    chip: &str,
+    opts: &Opts,
+    e: anyhow::Error,
+) -> anyhow::Error {
+    let ctrl_aps = match nrf_ctrl_aps(chip) {
+        Some(ctrl_aps) => ctrl_aps,
+        None => return e,
+    };
+    // NOTE the session that failed to open released the probe
+    match with_debug_port(probe_info, opts, |interface| {
+        is_nrf_locked(interface, ctrl_aps[0])
+    }) {
+        Ok(true) => anyhow!(
+            "flash is read-protected (APPROTECT): run with `--chip-unlock` to mass-erase it"
+        ),
+        Ok(false) => e,
+        Err(status_error) => {
+            log::debug!("failed to read the APPROTECT status: {}", status_error);
+            e
+        }
+    }
+}
+
+/// Whether APPROTECT keeps the debugger out of the core behind `ctrl_ap`
+pub fn is_nrf_locked<I>(interface: &mut I, ctrl_ap: u16) -> anyhow::Result<bool>
+where
+    I: DAPAccess + ?Sized,
+{
+    interface.write_register(PortType::DebugPort, DP_SELECT, u32::from(ctrl_ap) << 24)?;
+    let status = interface.read_register(PortType::AccessPort(ctrl_ap), CTRL_AP_APPROTECTSTATUS)?;
+    interface.write_register(PortType::DebugPort, DP_SELECT, 0)?;
+    Ok(status & 1 == 0)
+}
+
+/// Runs `f` on the probe's ARM debug port without attaching to a core, which a locked chip
+/// refuses; the probe is closed again afterwards
+fn with_debug_port<T>(
+    probe_info: &DebugProbeInfo,
+    opts: &Opts,
+    f: impl FnOnce(&mut dyn DAPAccess) -> anyhow::Result<T>,
+) -> anyhow::Result<T> {
+    let mut probe = probe_info.open()?;
+    if let Some(Speed::Khz(speed)) = opts.speed {
+        probe.set_speed(speed)?;
+    }
+    probe.attach_to_unspecified()?;
+    let mut interface = probe
+        .into_arm_interface()?
+        .ok_or_else(|| anyhow!("the probe can't talk to the ARM debug port"))?;
+    let result = f(&mut *interface);
+    drop(interface.close());
+    result
+}
+
+/// Erases the flash, RAM and UICR of the nRF cores behind `ctrl_aps`, which disables APPROTECT
+fn nrf_erase_all<I>(interface: &mut I, ctrl_aps: &[u16]) -> anyhow::Result<()>
+where
+    I: DAPAccess + ?Sized,
+{
+    for &ctrl_ap in ctrl_aps {
+        interface.write_register(PortType::DebugPort, DP_SELECT, u32::from(ctrl_ap) << 24)?;
+        interface.write_register(PortType::AccessPort(ctrl_ap), CTRL_AP_ERASEALL, 1)?;
+
+        let start = Instant::now();
+        while interface.read_register(PortType::AccessPort(ctrl_ap), CTRL_AP_ERASEALLSTATUS)? != 0 {
+            if start.elapsed() > ERASE_TIMEOUT {
+                bail!("timed out waiting for the CTRL-AP to erase the chip");
+            }
+            thread::sleep(Duration::from_millis(100));
+        }
+    }
+
+    // reset the chip so that it comes up unlocked
+    let ctrl_ap = ctrl_aps[0];
+    interface.write_register(PortType::DebugPort, DP_SELECT, u32::from(ctrl_ap) << 24)?;
+    interface.write_register(PortType::AccessPort(ctrl_ap), CTRL_AP_RESET, 1)?;
+    interface.write_register(PortType::AccessPort(ctrl_ap), CTRL_AP_RESET, 0)?;
+    interface.write_register(PortType::DebugPort, DP_SELECT, 0)?;
+    Ok(())
+}
@@ -0,0 +1,90 @@
+//! `--watch`: re-runs the program whenever the ELF file changes
+
+use std::{
+    cell::Cell,
+    fs,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread,
+    time::{Duration, Instant, SystemTime},
+};
+
+use signal_hook::consts::signal;
+
+/// How often the ELF file's modification time is checked
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Notices modifications of the ELF file
+pub struct Watcher {
+    path: PathBuf,
+    modified: Cell<Option<SystemTime>>,
+    last_check: Cell<Instant>,
+}
+
+impl Watcher {
+    pub fn new(path: &Path) -> Self {
+        Self {
+            path: path.to_owned(),
+            modified: Cell::new(modified(path)),
+            last_check: Cell::new(Instant::now()),
+        }
+    }
+
+    /// Returns `true` if the file was modified since the last call; cheap to call in a loop
+    pub fn changed(&self) -> bool {
+        if self.last_check.get().elapsed() < POLL_INTERVAL {
+            return false;
+        }
+        self.last_check.set(Instant::now());
+
+        let modified = modified(&self.path);
+        if modified == self.modified.get() {
+            return false;
+        }
+
+        // the linker may still be writing the file; wait until it stops changing
+        let mut modified = modified;
+        loop {
+            thread::sleep(POLL_INTERVAL);
+            let latest = self::modified(&self.path);
+            if latest == modified && latest.is_some() {
+                break;
+            }
+            modified = latest;
+        }
+        self.modified.set(modified);
+        true
+    }
+
+    /// Blocks until the file is modified; returns `false` if Ctrl+C was pressed instead
+    pub fn wait(&self) -> anyhow::Result<bool> {
+        log::info!(
+            "waiting for {} to change (press Ctrl+C to exit)",
+            self.path.display()
+        );
+
+        let exit = Arc::new(AtomicBool::new(false));
+        let sigid = signal_hook::flag::register(signal::SIGINT, exit.clone())?;
+        let changed = loop {
+            if exit.load(Ordering::Relaxed) {
+                break false;
+            }
+            if self.changed() {
+                break true;
+            }
+            thread::sleep(POLL_INTERVAL);
+        };
+        signal_hook::low_level::unregister(sigid);
+
+        Ok(changed)
+    }
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path)
+        .and_then(|metadata| metadata.modified())
+        .ok()
+}
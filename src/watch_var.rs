@@ -0,0 +1,313 @@
+//! `--watch-var`: periodically reads global variables while the program runs
+//!
+//! The variable is found through the ELF symbol table; its type comes from the command line or,
+//! for primitive types, from the DWARF info.
+
+use std::{
+    convert::TryInto,
+    str::FromStr,
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, bail};
+use gimli::{AttributeValue, Unit, UnitOffset};
+use object::read::{File as ElfFile, Object as _, ObjectSymbol as _};
+use probe_rs::{Core, MemoryInterface};
+
+use crate::{
+    dwarf::{self, Reader},
+    rtt::Printer,
+};
+
+const DEFAULT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// A variable passed to `--watch-var`: `NAME[:TYPE][@INTERVAL]`
+#[derive(Debug)]
+pub struct Spec {
+    name: String,
+    ty: Option<Type>,
+    interval: Duration,
+}
+
+impl FromStr for Spec {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (rest, interval) = match s.rfind('@') {
+            Some(pos) => (&s[..pos], humantime::parse_duration(&s[pos + 1..])?),
+            None => (s, DEFAULT_INTERVAL),
+        };
+        let (name, ty) = match rest.rfind(':') {
+            // NOTE `::` separates the modules of a path; it's not a type
+            Some(pos) if !rest[..pos].ends_with(':') => {
+                (&rest[..pos], Some(rest[pos + 1..].parse()?))
+            }
+            _ => (rest, None),
+        };
+        if name.is_empty() {
+            bail!("`--watch-var` needs a variable name");
+        }
+
+        Ok(Self {
+            name: name.to_string(),
+            ty,
+            interval,
+        })
+    }
+}
+
+/// The primitive types a watched variable can have
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Type {
+    Bool,
+    Char,
+    /// Holds the size in bytes
+    Unsigned(u8),
+    Signed(u8),
+    Float(u8),
+}
+
+impl FromStr for Type {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "bool" => Self::Bool,
+            "char" => Self::Char,
+            "u8" => Self::Unsigned(1),
+            "u16" => Self::Unsigned(2),
+            "u32" | "usize" => Self::Unsigned(4),
+            "u64" => Self::Unsigned(8),
+            "i8" => Self::Signed(1),
+            "i16" => Self::Signed(2),
+            "i32" | "isize" => Self::Signed(4),
+            "i64" => Self::Signed(8),
+            "f32" => Self::Float(4),
+            "f64" => Self::Float(8),
+            _ => bail!(
+                "unsupported type `{}`; only primitive types can be watched",
+                s
+            ),
+        })
+    }
+}
+
+impl Type {
+    fn size(self) -> usize {
+        match self {
+            Type::Bool => 1,
+            Type::Char => 4,
+            Type::Unsigned(size) | Type::Signed(size) | Type::Float(size) => size.into(),
+        }
+    }
+
+    fn format(self, bytes: &[u8]) -> String {
+        let mut buf = [0; 8];
+        buf[..bytes.len()].copy_from_slice(bytes);
+        let raw = u64::from_le_bytes(buf);
+        let bits = bytes.len() * 8;
+
+        match self {
+            Type::Bool => (raw != 0).to_string(),
+            Type::Char => std::char::from_u32(raw as u32).map_or_else(
+                || format!("<invalid char {:#x}>", raw),
+                |c| format!("{:?}", c),
+            ),
+            Type::Unsigned(_) => raw.to_string(),
+            // sign-extend
+            Type::Signed(_) => (((raw << (64 - bits)) as i64) >> (64 - bits)).to_string(),
+            Type::Float(4) => f32::from_bits(raw as u32).to_string(),
+            Type::Float(_) => f64::from_bits(raw).to_string(),
+        }
+    }
+}
+
+struct Variable {
+    name: String,
+    address: u32,
+    ty: Type,
+    interval: Duration,
+    last_read: Option<Instant>,
+    last_value: Option<String>,
+}
+
+/// The variables being watched
+pub struct Watches {
+    variables: Vec<Variable>,
+}
+
+impl Watches {
+    /// Looks up the address and type of each variable in the ELF file
+    pub fn new(specs: &[Spec], elf: &ElfFile) -> anyhow::Result<Self> {
+        let mut variables = vec![];
+        for spec in specs {
+            let (address, ty) = resolve(spec, elf)?;
+            log::debug!("watching `{}` ({:?}) at {:#010x}", spec.name, ty, address);
+
+            variables.push(Variable {
+                name: spec.name.clone(),
+                address,
+                ty,
+                interval: spec.interval,
+                last_read: None,
+                last_value: None,
+            });
+        }
+        Ok(Self { variables })
+    }
+
+    /// Reads the variables that are due and prints the ones whose value changed
+    pub fn poll(&mut self, core: &mut Core<'_>, printer: &Printer) -> anyhow::Result<()> {
+        for variable in &mut self.variables {
+            if let Some(last_read) = variable.last_read {
+                if last_read.elapsed() < variable.interval {
+                    continue;
+                }
+            }
+            variable.last_read = Some(Instant::now());
+
+            let mut bytes = vec![0; variable.ty.size()];
+            core.read_8(variable.address, &mut bytes)?;
+            let value = variable.ty.format(&bytes);
+            if variable.last_value.as_ref() != Some(&value) {
+                printer.variable(&variable.name, &value);
+                variable.last_value = Some(value);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Reads the variable `spec` once and formats its value; its interval is ignored
+pub fn read(spec: &Spec, elf: &ElfFile, core: &mut Core<'_>) -> anyhow::Result<String> {
+    let (address, ty) = resolve(spec, elf)?;
+    let mut bytes = vec![0; ty.size()];
+    core.read_8(address, &mut bytes)?;
+    Ok(ty.format(&bytes))
+}
+
+/// Looks up the address and, unless `spec` gives it, the type of the variable
+fn resolve(spec: &Spec, elf: &ElfFile) -> anyhow::Result<(u32, Type)> {
+    let address = find_symbol(elf, &spec.name)?;
+    let ty = match spec.ty {
+        Some(ty) => ty,
+        None => dwarf_type(elf, address)?.ok_or_else(|| {
+            anyhow!(
+                "could not determine the type of `{}` from the debug info; \
+                give it as `{}:<type>`",
+                spec.name,
+                spec.name
+            )
+        })?,
+    };
+    Ok((address, ty))
+}
+
+/// Finds the address of the symbol `name`; a Rust path (e.g. `app::COUNTER`) or its last
+/// component match the demangled symbol name
+fn find_symbol(elf: &ElfFile, name: &str) -> anyhow::Result<u32> {
+    let suffix = format!("::{}", name);
+    let mut matches = elf
+        .symbols()
+        .filter_map(|symbol| {
+            let symbol_name = symbol.name().ok()?;
+            let demangled = format!("{:#}", rustc_demangle::demangle(symbol_name));
+            if symbol_name == name || demangled == name || demangled.ends_with(&suffix) {
+                Some((demangled, symbol.address()))
+            } else {
+                None
+            }
+        })
+        .collect::<Vec<_>>();
+    matches.dedup_by_key(|(_, address)| *address);
+
+    match &matches[..] {
+        [] => bail!("symbol `{}` not found in the ELF file", name),
+        [(_, address)] => Ok((*address).try_into()?),
+        _ => bail!(
+            "`{}` matches several symbols: {}; use the full path",
+            name,
+            matches
+                .iter()
+                .map(|(name, _)| format!("`{}`", name))
+                .collect::<Vec<_>>()
+                .join(", ")
+        ),
+    }
+}
+
+/// Looks for the variable stored at `address` in the DWARF info and returns its type, if it's
+/// primitive (or a wrapper around one, such as `AtomicU32` or `Cell<u8>`)
+fn dwarf_type(elf: &ElfFile, address: u32) -> anyhow::Result<Option<Type>> {
+    let dwarf = dwarf::load(elf)?;
+    let dwarf = dwarf::borrow(&dwarf);
+
+    let mut units = dwarf.units();
+    while let Some(header) = units.next()? {
+        let unit = dwarf.unit(header)?;
+        let mut entries = unit.entries();
+        while let Some((_, entry)) = entries.next_dfs()? {
+            if dwarf::static_address(&unit, entry)? != Some(u64::from(address)) {
+                continue;
+            }
+
+            return match entry.attr_value(gimli::DW_AT_type)? {
+                Some(AttributeValue::UnitRef(offset)) => primitive_type(&unit, offset),
+                _ => Ok(None),
+            };
+        }
+    }
+
+    Ok(None)
+}
+
+fn primitive_type(unit: &Unit<Reader>, mut offset: UnitOffset) -> anyhow::Result<Option<Type>> {
+    loop {
+        let entry = unit.entry(offset)?;
+        match entry.tag() {
+            gimli::DW_TAG_typedef | gimli::DW_TAG_volatile_type | gimli::DW_TAG_const_type => {
+                match entry.attr_value(gimli::DW_AT_type)? {
+                    Some(AttributeValue::UnitRef(next)) => offset = next,
+                    _ => return Ok(None),
+                }
+            }
+            gimli::DW_TAG_base_type => {
+                let size = match entry.attr_value(gimli::DW_AT_byte_size)? {
+                    Some(AttributeValue::Udata(size)) if size <= 8 => size as u8,
+                    _ => return Ok(None),
+                };
+                let encoding = match entry.attr_value(gimli::DW_AT_encoding)? {
+                    Some(AttributeValue::Encoding(encoding)) => encoding,
+                    _ => return Ok(None),
+                };
+                return Ok(match encoding {
+                    gimli::DW_ATE_boolean => Some(Type::Bool),
+                    gimli::DW_ATE_UTF if size == 4 => Some(Type::Char),
+                    gimli::DW_ATE_unsigned | gimli::DW_ATE_unsigned_char => {
+                        Some(Type::Unsigned(size))
+                    }
+                    gimli::DW_ATE_signed | gimli::DW_ATE_signed_char => Some(Type::Signed(size)),
+                    gimli::DW_ATE_float if size == 4 || size == 8 => Some(Type::Float(size)),
+                    _ => None,
+                });
+            }
+            // newtypes like `AtomicU32` and `UnsafeCell<T>`: a struct with a single field
+            gimli::DW_TAG_structure_type => {
+                let mut tree = unit.entries_tree(Some(offset))?;
+                let root = tree.root()?;
+                let mut children = root.children();
+                let mut members = vec![];
+                while let Some(child) = children.next()? {
+                    if child.entry().tag() == gimli::DW_TAG_member {
+                        members.push(child.entry().attr_value(gimli::DW_AT_type)?);
+                    }
+                }
+                match &members[..] {
+                    [Some(AttributeValue::UnitRef(next))] => offset = *next,
+                    _ => return Ok(None),
+                }
+            }
+            _ => return Ok(None),
+        }
+    }
+}